@@ -0,0 +1,374 @@
+extern crate rusty_math;
+
+use rusty_math::*;
+
+use camera::PerspectiveCamera;
+use transform::AnimatedTransform;
+use transform::Transform;
+use transform::TransformInterpolation;
+
+//
+// keyframe animation
+//
+// a track holds a value at a few points in time and fills in the rest. every kind of interpolation is
+// turned into a cubic bezier between two keys, so keys using different kinds can sit next to each other
+
+// anything a track can hold, it only has to be blended linearly
+pub trait Animatable: Sized {
+    fn add(&self, other: &Self) -> Self;
+    fn scale(&self, factor: f64) -> Self;
+
+    fn sub(&self, other: &Self) -> Self {
+        return self.add(&other.scale(-1f64));
+    }
+}
+
+impl Animatable for f64 {
+    fn add(&self, other: &f64) -> f64 {
+        return self + other;
+    }
+
+    fn scale(&self, factor: f64) -> f64 {
+        return self * factor;
+    }
+}
+
+impl Animatable for Vec3 {
+    fn add(&self, other: &Vec3) -> Vec3 {
+        return self + other;
+    }
+
+    fn scale(&self, factor: f64) -> Vec3 {
+        return factor * self;
+    }
+}
+
+// how the value leaves a key, and arrives at it from the key before
+pub enum Interpolation<T> {
+    // straight towards the neighbouring keys
+    Linear,
+    // control points given by hand, in_handle shapes the curve arriving at the key and out_handle the one leaving it
+    Bezier { in_handle: T, out_handle: T },
+    // Kochanek-Bartels spline through the neighbouring keys, all 0 is a catmull-rom spline
+    // tension tightens the curve, continuity puts a corner in it and bias overshoots one side or the other
+    Tcb { tension: f64, continuity: f64, bias: f64 },
+}
+
+pub struct Keyframe<T> {
+    // in seconds
+    pub time: f64,
+    pub value: T,
+    pub interpolation: Interpolation<T>,
+}
+
+impl<T> Keyframe<T> {
+    pub fn new(time: f64, value: T, interpolation: Interpolation<T>) -> Keyframe<T> {
+        return Keyframe {
+            time: time,
+            value: value,
+            interpolation: interpolation,
+        };
+    }
+}
+
+//
+// Track
+//
+pub struct Track<T> {
+    // sorted by time
+    m_keys: Vec<Keyframe<T>>,
+}
+
+impl<T: Animatable> Track<T> {
+    // keys are sorted here, a track needs at least one
+    pub fn new(mut keys: Vec<Keyframe<T>>) -> Track<T> {
+        assert!(!keys.is_empty(), "a track needs at least one key");
+        keys.sort_by(|a, b| a.time.partial_cmp(&b.time).unwrap_or(::std::cmp::Ordering::Equal));
+        return Track { m_keys: keys };
+    }
+
+    // the same value for all time
+    pub fn constant(value: T) -> Track<T> {
+        return Track::new(vec![Keyframe::new(0f64, value, Interpolation::Linear)]);
+    }
+
+    // holds the first and last values outside the keys
+    pub fn at(&self, time: f64) -> T {
+        let keys = &self.m_keys;
+        let last = keys.len() - 1;
+        if time <= keys[0].time {
+            return keys[0].value.scale(1f64);
+        }
+        if time >= keys[last].time {
+            return keys[last].value.scale(1f64);
+        }
+        // first key after time, the segment runs from the key before it
+        let next = keys.iter().position(|key| key.time > time).unwrap_or(last);
+        let index = next - 1;
+        let span = keys[next].time - keys[index].time;
+        if span <= 0f64 {
+            return keys[next].value.scale(1f64);
+        }
+        let t = (time - keys[index].time) / span;
+
+        let p0 = &keys[index].value;
+        let p1 = &keys[next].value;
+        let c0 = self.out_control(index);
+        let c1 = self.in_control(next);
+
+        let s = 1f64 - t;
+        return p0.scale(s * s * s)
+            .add(&c0.scale(3f64 * s * s * t))
+            .add(&c1.scale(3f64 * s * t * t))
+            .add(&p1.scale(t * t * t));
+    }
+
+    // second control point of the segment leaving key index
+    fn out_control(&self, index: usize) -> T {
+        let key = &self.m_keys[index];
+        let next = &self.m_keys[index + 1].value;
+        return match key.interpolation {
+            Interpolation::Linear => key.value.add(&next.sub(&key.value).scale(1f64 / 3f64)),
+            Interpolation::Bezier { ref out_handle, .. } => out_handle.scale(1f64),
+            Interpolation::Tcb { tension, continuity, bias } => {
+                let tangent = self.tcb_tangent(index, tension, continuity, bias, true);
+                key.value.add(&tangent.scale(1f64 / 3f64))
+            }
+        };
+    }
+
+    // third control point of the segment arriving at key index
+    fn in_control(&self, index: usize) -> T {
+        let key = &self.m_keys[index];
+        let previous = &self.m_keys[index - 1].value;
+        return match key.interpolation {
+            Interpolation::Linear => key.value.sub(&key.value.sub(previous).scale(1f64 / 3f64)),
+            Interpolation::Bezier { ref in_handle, .. } => in_handle.scale(1f64),
+            Interpolation::Tcb { tension, continuity, bias } => {
+                let tangent = self.tcb_tangent(index, tension, continuity, bias, false);
+                key.value.sub(&tangent.scale(1f64 / 3f64))
+            }
+        };
+    }
+
+    // tangent at key index over the segment leaving it (outgoing) or arriving at it, in value per segment.
+    // a missing neighbour at either end of the track reuses the other side's difference. keys that are
+    // not evenly spaced in time get their tangents rescaled so the speed does not jump across the key
+    fn tcb_tangent(&self, index: usize, tension: f64, continuity: f64, bias: f64, outgoing: bool) -> T {
+        let keys = &self.m_keys;
+        let last = keys.len() - 1;
+        let before = if index > 0 { index - 1 } else { index };
+        let after = if index < last { index + 1 } else { index };
+
+        let incoming_difference = keys[index].value.sub(&keys[before].value);
+        let outgoing_difference = keys[after].value.sub(&keys[index].value);
+        let incoming_span = keys[index].time - keys[before].time;
+        let outgoing_span = keys[after].time - keys[index].time;
+        let (incoming_difference, incoming_span) = if index == 0 {
+            (outgoing_difference.scale(1f64), outgoing_span)
+        } else {
+            (incoming_difference, incoming_span)
+        };
+        let (outgoing_difference, outgoing_span) = if index == last {
+            (incoming_difference.scale(1f64), incoming_span)
+        } else {
+            (outgoing_difference, outgoing_span)
+        };
+
+        let (a, b, span) = if outgoing {
+            (
+                0.5f64 * (1f64 - tension) * (1f64 + continuity) * (1f64 + bias),
+                0.5f64 * (1f64 - tension) * (1f64 - continuity) * (1f64 - bias),
+                outgoing_span,
+            )
+        } else {
+            (
+                0.5f64 * (1f64 - tension) * (1f64 - continuity) * (1f64 + bias),
+                0.5f64 * (1f64 - tension) * (1f64 + continuity) * (1f64 - bias),
+                incoming_span,
+            )
+        };
+        let tangent = incoming_difference.scale(a).add(&outgoing_difference.scale(b));
+        let total_span = incoming_span + outgoing_span;
+        if total_span <= 0f64 {
+            return tangent;
+        }
+        return tangent.scale(2f64 * span / total_span);
+    }
+}
+
+//
+// TransformAnimation
+//
+// scale, then rotation about x, y and z in that order, then translation
+pub struct TransformAnimation {
+    pub translation: Track<Vec3>,
+    // euler angles in degrees
+    pub rotation_degrees: Track<Vec3>,
+    pub scale: Track<Vec3>,
+}
+
+impl TransformAnimation {
+    pub fn fixed() -> TransformAnimation {
+        return TransformAnimation {
+            translation: Track::constant(Vec3::new(0f64, 0f64, 0f64)),
+            rotation_degrees: Track::constant(Vec3::new(0f64, 0f64, 0f64)),
+            scale: Track::constant(Vec3::new(1f64, 1f64, 1f64)),
+        };
+    }
+
+    pub fn at(&self, time: f64) -> Transform {
+        let rotation = self.rotation_degrees.at(time);
+        return Transform::scale(&self.scale.at(time))
+            .then(&Transform::rotate(&Vec3::new(1f64, 0f64, 0f64), rotation.x))
+            .then(&Transform::rotate(&Vec3::new(0f64, 1f64, 0f64), rotation.y))
+            .then(&Transform::rotate(&Vec3::new(0f64, 0f64, 1f64), rotation.z))
+            .then(&Transform::translate(&self.translation.at(time)));
+    }
+
+    // the motion between open and close, mapped onto a shutter that runs from 0 to 1.
+    // local is applied first, it places the object before the animation moves it
+    pub fn over_shutter(&self, local: &Transform, open: f64, close: f64) -> AnimatedTransform {
+        return AnimatedTransform::new(
+            local.then(&self.at(open)), 0f64,
+            local.then(&self.at(close)), 1f64,
+            TransformInterpolation::Decomposed
+        );
+    }
+}
+
+//
+// CameraAnimation
+//
+pub struct CameraAnimation {
+    pub position: Track<Vec3>,
+    pub look_at: Track<Vec3>,
+    pub vertical_fov_degrees: Track<f64>,
+    pub focus_distance: Track<f64>,
+}
+
+impl CameraAnimation {
+    // a perspective camera posed for time, without lens shift or tilt
+    pub fn at(&self, time: f64, up: &Vec3, aspect_ratio: f64, aperture: f64) -> PerspectiveCamera {
+        return PerspectiveCamera::new(
+            &self.position.at(time),
+            &self.look_at.at(time),
+            Vec3::new(up.x, up.y, up.z),
+            self.vertical_fov_degrees.at(time),
+            aspect_ratio,
+            aperture,
+            self.focus_distance.at(time),
+            (0f64, 0f64),
+            (0f64, 0f64)
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use camera::Camera;
+
+    fn assert_near(a: &Vec3, b: &Vec3) {
+        assert!((a - b).length_squared() < 1e-18f64, "{} {} {} != {} {} {}", a.x, a.y, a.z, b.x, b.y, b.z);
+    }
+
+    fn keyed(keys: &[(f64, f64)], interpolation: fn() -> Interpolation<f64>) -> Track<f64> {
+        return Track::new(keys.iter().map(|&(time, value)| Keyframe::new(time, value, interpolation())).collect());
+    }
+
+    fn linear() -> Interpolation<f64> {
+        return Interpolation::Linear;
+    }
+
+    fn catmull_rom() -> Interpolation<f64> {
+        return Interpolation::Tcb { tension: 0f64, continuity: 0f64, bias: 0f64 };
+    }
+
+    #[test]
+    fn linear_tracks_pass_through_their_keys_and_hold_outside() {
+        // given out of order
+        let track = keyed(&[(2f64, 4f64), (0f64, 0f64), (1f64, 1f64)], linear);
+        assert_eq!(track.at(0f64), 0f64);
+        assert_eq!(track.at(1f64), 1f64);
+        assert!((track.at(0.25f64) - 0.25f64).abs() < 1e-12f64);
+        assert!((track.at(1.5f64) - 2.5f64).abs() < 1e-12f64);
+        assert_eq!(track.at(-3f64), 0f64);
+        assert_eq!(track.at(7f64), 4f64);
+        assert_eq!(Track::constant(2.5f64).at(100f64), 2.5f64);
+    }
+
+    #[test]
+    fn bezier_handles_are_the_control_points() {
+        let track = Track::new(vec![
+            Keyframe::new(0f64, 0f64, Interpolation::Bezier { in_handle: 0f64, out_handle: 2f64 }),
+            Keyframe::new(1f64, 1f64, Interpolation::Bezier { in_handle: 3f64, out_handle: 0f64 }),
+        ]);
+        // (p0 + 3 c0 + 3 c1 + p1) / 8 halfway
+        assert!((track.at(0.5f64) - (0f64 + 6f64 + 9f64 + 1f64) / 8f64).abs() < 1e-12f64);
+    }
+
+    #[test]
+    fn catmull_rom_follows_a_parabola_through_evenly_spaced_keys() {
+        let track = keyed(&[(0f64, 0f64), (1f64, 1f64), (2f64, 4f64), (3f64, 9f64)], catmull_rom);
+        for &time in [1.25f64, 1.5f64, 1.75f64].iter() {
+            assert!((track.at(time) - time * time).abs() < 1e-12f64, "{} at {}", track.at(time), time);
+        }
+    }
+
+    #[test]
+    fn tcb_speed_is_continuous_across_unevenly_spaced_keys() {
+        let track = keyed(&[(0f64, 0f64), (1f64, 1f64), (4f64, 2f64), (5f64, 6f64)], catmull_rom);
+        let h = 1e-6f64;
+        for &key in [1f64, 4f64].iter() {
+            let before = (track.at(key) - track.at(key - h)) / h;
+            let after = (track.at(key + h) - track.at(key)) / h;
+            assert!((before - after).abs() < 1e-4f64, "speed {} before and {} after the key at {}", before, after, key);
+        }
+        // a corner with continuity
+        let cornered = keyed(&[(0f64, 0f64), (1f64, 1f64), (2f64, 0f64)], || Interpolation::Tcb { tension: 0f64, continuity: -1f64, bias: 0f64 });
+        let before = (cornered.at(1f64) - cornered.at(1f64 - h)) / h;
+        let after = (cornered.at(1f64 + h) - cornered.at(1f64)) / h;
+        assert!(before > 0.5f64 && after < -0.5f64);
+    }
+
+    #[test]
+    fn transform_animation_scales_turns_then_moves() {
+        let animation = TransformAnimation {
+            translation: Track::new(vec![
+                Keyframe::new(0f64, Vec3::new(0f64, 0f64, 0f64), Interpolation::Linear),
+                Keyframe::new(1f64, Vec3::new(2f64, 0f64, 0f64), Interpolation::Linear),
+            ]),
+            rotation_degrees: Track::constant(Vec3::new(0f64, 0f64, 90f64)),
+            scale: Track::constant(Vec3::new(2f64, 1f64, 1f64)),
+        };
+        // x is doubled, turned onto y and moved along by the track
+        assert_near(&animation.at(0.5f64).transform_point(&Vec3::new(1f64, 0f64, 0f64)), &Vec3::new(1f64, 2f64, 0f64));
+        assert_near(&TransformAnimation::fixed().at(3f64).transform_point(&Vec3::new(1f64, 2f64, 3f64)), &Vec3::new(1f64, 2f64, 3f64));
+
+        // the shutter runs from 0 to 1 over the frame's open to close, after the local placement
+        let local = Transform::translate(&Vec3::new(0f64, 0f64, 1f64));
+        let motion = animation.over_shutter(&local, 0.25f64, 0.75f64);
+        let origin = Vec3::new(0f64, 0f64, 0f64);
+        assert_near(&motion.interpolate(0f64).transform_point(&origin), &Vec3::new(0.5f64, 0f64, 1f64));
+        assert_near(&motion.interpolate(1f64).transform_point(&origin), &Vec3::new(1.5f64, 0f64, 1f64));
+    }
+
+    #[test]
+    fn camera_animation_poses_the_camera() {
+        let animation = CameraAnimation {
+            position: Track::new(vec![
+                Keyframe::new(0f64, Vec3::new(0f64, 1f64, 0f64), Interpolation::Linear),
+                Keyframe::new(2f64, Vec3::new(0f64, 1f64, 4f64), Interpolation::Linear),
+            ]),
+            look_at: Track::constant(Vec3::new(0f64, 1f64, -5f64)),
+            vertical_fov_degrees: Track::constant(40f64),
+            focus_distance: Track::constant(5f64),
+        };
+        let camera = animation.at(1f64, &Vec3::new(0f64, 1f64, 0f64), 1.5f64, 0f64);
+        assert_near(&camera.get_origin(), &Vec3::new(0f64, 1f64, 2f64));
+        // straight down the middle of the image at the look at point
+        let ray = camera.get_ray(0.5f64, 0.5f64);
+        assert_near(&ray.dir.normalize(), &Vec3::new(0f64, 0f64, -1f64));
+    }
+}
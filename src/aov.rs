@@ -0,0 +1,317 @@
+extern crate rusty_math;
+
+use rusty_math::*;
+use std::f64;
+
+use renderable::HitRecord;
+use renderable::RenderList;
+use renderable::MaterialPackage;
+use renderable::MaterialOutput;
+use render_buffer::RenderBufferF64;
+use render_buffer::RenderBufferLayers;
+use spectrum;
+use spectrum::Wavelengths;
+
+//
+// arbitrary output variables
+//
+// extra images rendered alongside the beauty for compositing. the lighting passes split the beauty by how
+// many times the light scattered before reaching the camera and by whether the first scatter was specular,
+// so emission + the four direct/indirect passes add back up to the beauty of the path integrator
+#[derive(Clone, Copy, PartialEq)]
+pub enum Aov {
+    // distance from the camera to the first surface, infinite where the ray escapes
+    Depth,
+    // world space normal of the first surface
+    Normal,
+    // reflectance of the first surface
+    Albedo,
+    // primitives in the render list then instances, -1 where the ray escapes
+    ObjectId,
+    // in the order the render list first met the materials, -1 where the ray escapes
+    MaterialId,
+    DiffuseDirect,
+    DiffuseIndirect,
+    SpecularDirect,
+    SpecularIndirect,
+    // light that reached the camera without scattering, lights and the sky seen directly
+    Emission,
+    SampleCount,
+    // beauty of the even and of the odd numbered samples, how far apart they are tells how noisy a pixel is.
+    // only integrators that work one camera sample at a time fill these in
+    BeautyHalfA,
+    BeautyHalfB,
+}
+
+impl Aov {
+    pub fn all() -> Vec<Aov> {
+        return vec![
+            Aov::Depth,
+            Aov::Normal,
+            Aov::Albedo,
+            Aov::ObjectId,
+            Aov::MaterialId,
+            Aov::DiffuseDirect,
+            Aov::DiffuseIndirect,
+            Aov::SpecularDirect,
+            Aov::SpecularIndirect,
+            Aov::Emission,
+            Aov::SampleCount,
+            Aov::BeautyHalfA,
+            Aov::BeautyHalfB,
+        ];
+    }
+
+    pub fn name(&self) -> &'static str {
+        return match *self {
+            Aov::Depth => "depth",
+            Aov::Normal => "normal",
+            Aov::Albedo => "albedo",
+            Aov::ObjectId => "object_id",
+            Aov::MaterialId => "material_id",
+            Aov::DiffuseDirect => "diffuse_direct",
+            Aov::DiffuseIndirect => "diffuse_indirect",
+            Aov::SpecularDirect => "specular_direct",
+            Aov::SpecularIndirect => "specular_indirect",
+            Aov::Emission => "emission",
+            Aov::SampleCount => "sample_count",
+            Aov::BeautyHalfA => "beauty_a",
+            Aov::BeautyHalfB => "beauty_b",
+        };
+    }
+
+    pub fn channels(&self) -> usize {
+        return match *self {
+            Aov::Depth | Aov::ObjectId | Aov::MaterialId | Aov::SampleCount => 1,
+            _ => 3,
+        };
+    }
+
+    // averaging depths or ids across an edge gives values that belong to nothing, these keep the first sample
+    fn is_point_sampled(&self) -> bool {
+        return match *self {
+            Aov::Depth | Aov::ObjectId | Aov::MaterialId => true,
+            _ => false,
+        };
+    }
+}
+
+// what one camera sample found, lighting is in path space (like the integrators) until to_rgb
+pub struct AovSample {
+    // rgb estimate of the sample, filled in by whoever converts it out of path space
+    pub beauty: Vec3,
+    pub hit: bool,
+    pub depth: f64,
+    pub normal: Vec3,
+    pub albedo: Vec3,
+    pub object_id: f64,
+    pub material_id: f64,
+    pub emission: Vec3,
+    pub diffuse_direct: Vec3,
+    pub diffuse_indirect: Vec3,
+    pub specular_direct: Vec3,
+    pub specular_indirect: Vec3,
+    // whether the first scattering event along the path was specular
+    pub specular: bool,
+}
+
+impl AovSample {
+    pub fn new() -> AovSample {
+        return AovSample {
+            beauty: Vec3::new(0f64, 0f64, 0f64),
+            hit: false,
+            depth: f64::INFINITY,
+            normal: Vec3::new(0f64, 0f64, 0f64),
+            albedo: Vec3::new(0f64, 0f64, 0f64),
+            object_id: -1f64,
+            material_id: -1f64,
+            emission: Vec3::new(0f64, 0f64, 0f64),
+            diffuse_direct: Vec3::new(0f64, 0f64, 0f64),
+            diffuse_indirect: Vec3::new(0f64, 0f64, 0f64),
+            specular_direct: Vec3::new(0f64, 0f64, 0f64),
+            specular_indirect: Vec3::new(0f64, 0f64, 0f64),
+            specular: false,
+        };
+    }
+
+    // geometry only, for integrators that cannot split their estimate into lighting passes
+    pub fn record_first_hit(&mut self, ray: &Ray, shutter_time: f64, render_list: &RenderList) {
+        let mut hit_record = HitRecord::new();
+        if !render_list.try_get_hit_record(ray, shutter_time, 0.001f64, f64::MAX, &mut hit_record) {
+            return;
+        }
+        let material_package = render_list.get_material_package(&hit_record);
+        let mut material_output = MaterialOutput::new();
+        let scattered = material_package.material.apply(&material_package.material_input, &mut material_output);
+        self.record_surface(&hit_record, &material_package, &material_output, scattered, render_list);
+    }
+
+    // the first surface along the camera ray, material_output only counts when the material scattered
+    pub fn record_surface(
+        &mut self,
+        hit_record: &HitRecord,
+        material_package: &MaterialPackage,
+        material_output: &MaterialOutput,
+        scattered: bool,
+        render_list: &RenderList
+    ) {
+        let input = &material_package.material_input;
+        self.hit = true;
+        self.depth = hit_record.time * input.incoming_ray.dir.length_squared().sqrt();
+        self.normal = Vec3::new(input.normal.x, input.normal.y, input.normal.z);
+        if scattered {
+            let attenuation = &material_output.attenuation;
+            self.albedo = Vec3::new(attenuation.x, attenuation.y, attenuation.z);
+        }
+        self.object_id = render_list.object_id(hit_record) as f64;
+        self.material_id = match render_list.material_id(material_package.material) {
+            Some(id) => id as f64,
+            None => -1f64,
+        };
+    }
+
+    // a collision inside a heterogeneous volume before any surface
+    pub fn record_volume(&mut self, distance: f64, albedo: &Vec3) {
+        self.hit = true;
+        self.depth = distance;
+        self.albedo = Vec3::new(albedo.x, albedo.y, albedo.z);
+    }
+
+    // light found after the given number of scattering events
+    pub fn add_lighting(&mut self, contribution: &Vec3, bounces: i32) {
+        if bounces == 0 {
+            self.emission += contribution;
+        } else if bounces == 1 {
+            if self.specular {
+                self.specular_direct += contribution;
+            } else {
+                self.diffuse_direct += contribution;
+            }
+        } else {
+            if self.specular {
+                self.specular_indirect += contribution;
+            } else {
+                self.diffuse_indirect += contribution;
+            }
+        }
+    }
+
+    // moves the lighting passes from wavelength lanes to rgb
+    pub fn to_rgb(&mut self, wavelengths: &Wavelengths) {
+        self.emission = spectrum::wavelengths_to_rgb(&self.emission, wavelengths);
+        self.diffuse_direct = spectrum::wavelengths_to_rgb(&self.diffuse_direct, wavelengths);
+        self.diffuse_indirect = spectrum::wavelengths_to_rgb(&self.diffuse_indirect, wavelengths);
+        self.specular_direct = spectrum::wavelengths_to_rgb(&self.specular_direct, wavelengths);
+        self.specular_indirect = spectrum::wavelengths_to_rgb(&self.specular_indirect, wavelengths);
+    }
+}
+
+fn channels_of(v: &Vec3) -> [f64; 3] {
+    return [v.x, v.y, v.z];
+}
+
+// the requested aovs and the layers they are written to, plus a "beauty" layer with the linear image
+pub struct AovTarget<'a> {
+    m_layers: &'a mut RenderBufferLayers,
+    m_aovs: Vec<Aov>,
+    m_handles: Vec<usize>,
+    m_beauty: usize,
+}
+
+impl<'a> AovTarget<'a> {
+    pub fn new(layers: &'a mut RenderBufferLayers, aovs: &[Aov]) -> AovTarget<'a> {
+        let beauty = layers.add_layer("beauty", 3);
+        let handles = aovs.iter().map(|aov| layers.add_layer(aov.name(), aov.channels())).collect();
+        return AovTarget {
+            m_layers: layers,
+            m_aovs: aovs.to_vec(),
+            m_handles: handles,
+            m_beauty: beauty,
+        };
+    }
+
+    pub fn width(&self) -> usize {
+        return self.m_layers.width;
+    }
+
+    pub fn height(&self) -> usize {
+        return self.m_layers.height;
+    }
+
+    // sample_index counts the samples of pixel x, y, point sampled aovs keep the first one
+    pub fn accumulate(&mut self, x: usize, y: usize, sample: &AovSample, sample_index: i32) {
+        for i in 0..self.m_aovs.len() {
+            let aov = self.m_aovs[i];
+            let layer = self.m_handles[i];
+            let values: [f64; 3] = match aov {
+                Aov::Depth => [sample.depth, 0f64, 0f64],
+                Aov::Normal => channels_of(&sample.normal),
+                Aov::Albedo => channels_of(&sample.albedo),
+                Aov::ObjectId => [sample.object_id, 0f64, 0f64],
+                Aov::MaterialId => [sample.material_id, 0f64, 0f64],
+                Aov::DiffuseDirect => channels_of(&sample.diffuse_direct),
+                Aov::DiffuseIndirect => channels_of(&sample.diffuse_indirect),
+                Aov::SpecularDirect => channels_of(&sample.specular_direct),
+                Aov::SpecularIndirect => channels_of(&sample.specular_indirect),
+                Aov::Emission => channels_of(&sample.emission),
+                Aov::SampleCount => [1f64, 0f64, 0f64],
+                Aov::BeautyHalfA => {
+                    if sample_index % 2 != 0 {
+                        continue;
+                    }
+                    channels_of(&sample.beauty)
+                }
+                Aov::BeautyHalfB => {
+                    if sample_index % 2 == 0 {
+                        continue;
+                    }
+                    channels_of(&sample.beauty)
+                }
+            };
+            if aov.is_point_sampled() {
+                if sample_index == 0 {
+                    self.m_layers.set(layer, x, y, &values);
+                }
+            } else {
+                self.m_layers.add(layer, x, y, &values);
+            }
+        }
+    }
+
+    // copies a tile rendered into layers of its own, with the same aovs, into place at x0, y0
+    pub fn add_tile(&mut self, x0: usize, y0: usize, tile: &RenderBufferLayers) {
+        for tile_layer in 0..tile.layer_count() {
+            let layer = match self.m_layers.find_layer(tile.name(tile_layer)) {
+                Some(layer) => layer,
+                None => continue,
+            };
+            for y in 0..tile.height {
+                for x in 0..tile.width {
+                    self.m_layers.set(layer, x0 + x, y0 + y, tile.get(tile_layer, x, y));
+                }
+            }
+        }
+    }
+
+    // turns the sums into averages and copies the accumulated film into the beauty layer
+    pub fn resolve(&mut self, film: &RenderBufferF64, samples_per_pixel: i32) {
+        let scale = 1f64 / samples_per_pixel.max(1) as f64;
+        for y in 0..film.height {
+            for x in 0..film.width {
+                let (r, g, b) = film.get(x, y);
+                self.m_layers.set(self.m_beauty, x, y, &[r * scale, g * scale, b * scale]);
+            }
+        }
+        let samples_a = (samples_per_pixel.max(1) + 1) / 2;
+        let samples_b = (samples_per_pixel.max(1) / 2).max(1);
+        for i in 0..self.m_aovs.len() {
+            let layer = self.m_handles[i];
+            match self.m_aovs[i] {
+                Aov::Depth | Aov::ObjectId | Aov::MaterialId | Aov::SampleCount => {}
+                Aov::BeautyHalfA => self.m_layers.scale(layer, 1f64 / samples_a as f64),
+                Aov::BeautyHalfB => self.m_layers.scale(layer, 1f64 / samples_b as f64),
+                _ => self.m_layers.scale(layer, scale),
+            }
+        }
+    }
+}
@@ -0,0 +1,347 @@
+use std::f64::consts;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+
+use sampler;
+
+//
+// physical aperture
+//
+// the shape of the opening light passes through decides the shape of out of focus highlights. points
+// on the aperture are in units of its radius, so circles and polygons fit the unit disk and masks
+// cover the square around it
+
+pub enum ApertureShape {
+    Circle,
+    // straight blades, a regular polygon with its corners on the unit circle
+    Polygon { blades: u32, rotation_degrees: f64 },
+    Mask(ApertureMask),
+}
+
+pub struct Aperture {
+    pub shape: ApertureShape,
+    // how far the lens barrel's opening slides across the aperture towards the image corners, it clips
+    // the bokeh there into cat's eyes and darkens the corners. 0 leaves it round everywhere
+    pub cats_eye: f64,
+    // 0 to 1, how much of the cos^4 falloff towards the image corners is let through
+    pub natural_vignetting: f64,
+}
+
+impl Aperture {
+    pub fn circle() -> Aperture {
+        return Aperture {
+            shape: ApertureShape::Circle,
+            cats_eye: 0f64,
+            natural_vignetting: 0f64,
+        };
+    }
+
+    pub fn polygon(blades: u32, rotation_degrees: f64) -> Aperture {
+        return Aperture {
+            shape: ApertureShape::Polygon { blades: blades.max(3), rotation_degrees: rotation_degrees },
+            cats_eye: 0f64,
+            natural_vignetting: 0f64,
+        };
+    }
+
+    pub fn mask(mask: ApertureMask) -> Aperture {
+        return Aperture {
+            shape: ApertureShape::Mask(mask),
+            cats_eye: 0f64,
+            natural_vignetting: 0f64,
+        };
+    }
+
+    // a point uniform over the open part of the aperture
+    pub fn sample(&self) -> (f64, f64) {
+        return match self.shape {
+            ApertureShape::Circle => {
+                let radius = sampler::next_f64().sqrt();
+                let angle = 2f64 * consts::PI * sampler::next_f64();
+                (radius * angle.cos(), radius * angle.sin())
+            }
+            ApertureShape::Polygon { blades, rotation_degrees } => {
+                // every wedge between two corners and the center has the same area
+                let wedge = sampler::next_index(blades as usize) as f64;
+                let step = 2f64 * consts::PI / blades as f64;
+                let first = rotation_degrees * consts::PI / 180f64 + wedge * step;
+                let second = first + step;
+                let mut a = sampler::next_f64();
+                let mut b = sampler::next_f64();
+                if a + b > 1f64 {
+                    a = 1f64 - a;
+                    b = 1f64 - b;
+                }
+                (a * first.cos() + b * second.cos(), a * first.sin() + b * second.sin())
+            }
+            ApertureShape::Mask(ref mask) => mask.sample(),
+        };
+    }
+
+    // fraction of the light through lens_point that reaches the film at image_offset, which runs from
+    // -1 to 1 across the image from its center. cos_theta is the ray's angle to the optical axis
+    pub fn transmission(&self, lens_point: (f64, f64), image_offset: (f64, f64), cos_theta: f64) -> f64 {
+        let mut transmission = 1f64;
+        if self.cats_eye > 0f64 {
+            let dx = lens_point.0 - self.cats_eye * image_offset.0;
+            let dy = lens_point.1 - self.cats_eye * image_offset.1;
+            if dx * dx + dy * dy > 1f64 {
+                return 0f64;
+            }
+        }
+        if self.natural_vignetting > 0f64 {
+            let cos2 = cos_theta * cos_theta;
+            transmission *= 1f64 - self.natural_vignetting * (1f64 - cos2 * cos2);
+        }
+        return transmission;
+    }
+}
+
+//
+// ApertureMask
+//
+// a grayscale picture of the aperture, brighter pixels let more light through. bokeh takes its shape
+pub struct ApertureMask {
+    width: usize,
+    height: usize,
+    // running sum of pixel weights, rows top down
+    m_cdf: Vec<f64>,
+}
+
+impl ApertureMask {
+    // f gets x and y in [-1, 1], y up, and returns how open the aperture is there
+    pub fn from_fn<F>(width: usize, height: usize, f: F) -> ApertureMask
+        where F: Fn(f64, f64) -> f64
+    {
+        let mut weights = Vec::with_capacity(width * height);
+        for row in 0..height {
+            for column in 0..width {
+                let x = 2f64 * (column as f64 + 0.5f64) / width as f64 - 1f64;
+                let y = 1f64 - 2f64 * (row as f64 + 0.5f64) / height as f64;
+                weights.push(f(x, y).max(0f64));
+            }
+        }
+        return ApertureMask::from_weights(width, height, weights);
+    }
+
+    // binary (P6) or plain (P3) ppm, the luminance of each pixel is its weight
+    pub fn from_ppm(path: &Path) -> io::Result<ApertureMask> {
+        let mut bytes: Vec<u8> = Vec::new();
+        File::open(path)?.read_to_end(&mut bytes)?;
+
+        // header is magic, width, height and maximum value, separated by whitespace and comments
+        let mut header: Vec<String> = Vec::new();
+        let mut position = 0usize;
+        while header.len() < 4 && position < bytes.len() {
+            if bytes[position] == b'#' {
+                while position < bytes.len() && bytes[position] != b'\n' {
+                    position += 1;
+                }
+            } else if (bytes[position] as char).is_whitespace() {
+                position += 1;
+            } else {
+                let start = position;
+                while position < bytes.len() && !(bytes[position] as char).is_whitespace() {
+                    position += 1;
+                }
+                header.push(String::from_utf8_lossy(&bytes[start..position]).into_owned());
+            }
+        }
+        if header.len() < 4 || (header[0] != "P3" && header[0] != "P6") {
+            return Err(invalid_ppm("expected a P3 or P6 header"));
+        }
+        let width: usize = header[1].parse().map_err(|_| invalid_ppm("bad width"))?;
+        let height: usize = header[2].parse().map_err(|_| invalid_ppm("bad height"))?;
+        let max_value: f64 = header[3].parse().map_err(|_| invalid_ppm("bad maximum value"))?;
+
+        let values: Vec<f64> = if header[0] == "P6" {
+            // a single whitespace byte separates the header from the samples, 8 bit below 256
+            let data = &bytes[(position + 1).min(bytes.len())..];
+            if max_value < 256f64 {
+                data.iter().map(|b| *b as f64).collect()
+            } else {
+                data.chunks(2).filter(|c| c.len() == 2).map(|c| ((c[0] as u32) << 8 | c[1] as u32) as f64).collect()
+            }
+        } else {
+            String::from_utf8_lossy(&bytes[position..])
+                .split_whitespace()
+                .filter_map(|token| token.parse::<f64>().ok())
+                .collect()
+        };
+        if values.len() < width * height * 3 {
+            return Err(invalid_ppm("not enough pixels"));
+        }
+
+        let weights = values.chunks(3).take(width * height)
+            .map(|rgb| (0.2126f64 * rgb[0] + 0.7152f64 * rgb[1] + 0.0722f64 * rgb[2]) / max_value)
+            .collect();
+        return Ok(ApertureMask::from_weights(width, height, weights));
+    }
+
+    fn from_weights(width: usize, height: usize, weights: Vec<f64>) -> ApertureMask {
+        let mut cdf: Vec<f64> = Vec::with_capacity(weights.len());
+        let mut total = 0f64;
+        for weight in weights.iter() {
+            total += *weight;
+            cdf.push(total);
+        }
+        return ApertureMask {
+            width: width,
+            height: height,
+            m_cdf: cdf,
+        };
+    }
+
+    // a point in [-1, 1]^2 picked in proportion to the mask, the center if the mask is all black
+    fn sample(&self) -> (f64, f64) {
+        let total = match self.m_cdf.last() {
+            Some(total) if *total > 0f64 => *total,
+            _ => return (0f64, 0f64),
+        };
+        let target = sampler::next_f64() * total;
+        let pixel = match self.m_cdf.binary_search_by(|c| c.partial_cmp(&target).unwrap_or(::std::cmp::Ordering::Less)) {
+            Ok(index) => index + 1,
+            Err(index) => index,
+        }.min(self.m_cdf.len() - 1);
+        let column = (pixel % self.width) as f64 + sampler::next_f64();
+        let row = (pixel / self.width) as f64 + sampler::next_f64();
+        return (2f64 * column / self.width as f64 - 1f64, 1f64 - 2f64 * row / self.height as f64);
+    }
+}
+
+fn invalid_ppm(message: &str) -> io::Error {
+    return io::Error::new(io::ErrorKind::InvalidData, format!("aperture mask: {}", message));
+}
+
+//
+// PhysicalLens
+//
+// the numbers on a lens and a camera body. scene units are taken to be meters
+pub struct PhysicalLens {
+    pub focal_length_mm: f64,
+    pub f_number: f64,
+    // 24 for a full frame still camera, around 13.4 for super 35 at 2.39:1
+    pub sensor_height_mm: f64,
+}
+
+impl PhysicalLens {
+    pub fn vertical_fov_degrees(&self) -> f64 {
+        return 2f64 * (self.sensor_height_mm / (2f64 * self.focal_length_mm)).atan() * 180f64 / consts::PI;
+    }
+
+    // diameter of the entrance pupil in meters, focal length over f-number
+    pub fn aperture_diameter(&self) -> f64 {
+        return self.focal_length_mm / self.f_number / 1000f64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    fn mean_of_samples(aperture: &Aperture, count: usize) -> (f64, f64) {
+        let mut sum = (0f64, 0f64);
+        for _ in 0..count {
+            let (x, y) = aperture.sample();
+            sum = (sum.0 + x, sum.1 + y);
+        }
+        return (sum.0 / count as f64, sum.1 / count as f64);
+    }
+
+    #[test]
+    fn circle_and_polygon_samples_stay_inside() {
+        let circle = Aperture::circle();
+        let hexagon = Aperture::polygon(6, 0f64);
+        // a corner of the hexagon sits at angle 0, its edges are cos(30 degrees) from the center
+        let apothem = (consts::PI / 6f64).cos();
+        for _ in 0..10000 {
+            let (x, y) = circle.sample();
+            assert!(x * x + y * y <= 1f64);
+            let (x, y) = hexagon.sample();
+            for edge in 0..6 {
+                let angle = (2f64 * edge as f64 + 1f64) * consts::PI / 6f64;
+                assert!(x * angle.cos() + y * angle.sin() <= apothem + 1e-12f64);
+            }
+        }
+        let (x, y) = mean_of_samples(&hexagon, 100000);
+        assert!(x.abs() < 0.01f64 && y.abs() < 0.01f64, "hexagon samples center on {}, {}", x, y);
+    }
+
+    #[test]
+    fn polygons_need_three_blades() {
+        match Aperture::polygon(1, 0f64).shape {
+            ApertureShape::Polygon { blades, .. } => assert_eq!(blades, 3),
+            _ => panic!("expected a polygon"),
+        }
+    }
+
+    #[test]
+    fn cats_eye_and_vignetting_darken_the_corners() {
+        let mut aperture = Aperture::circle();
+        assert_eq!(aperture.transmission((0.9f64, 0f64), (1f64, 1f64), 0.5f64), 1f64);
+        aperture.cats_eye = 0.3f64;
+        // the barrel's opening slid towards the top right corner cuts off the left of the lens, not the right
+        assert_eq!(aperture.transmission((-0.9f64, 0f64), (1f64, 1f64), 1f64), 0f64);
+        assert_eq!(aperture.transmission((0.9f64, 0f64), (1f64, 1f64), 1f64), 1f64);
+        // in the center of the image the whole lens is open
+        assert_eq!(aperture.transmission((-0.9f64, 0f64), (0f64, 0f64), 1f64), 1f64);
+        aperture.natural_vignetting = 0.5f64;
+        // half of the cos^4 falloff, which at 60 degrees lets through 1/16
+        let transmission = aperture.transmission((0f64, 0f64), (0f64, 0f64), 0.5f64);
+        assert!((transmission - (1f64 - 0.5f64 * 15f64 / 16f64)).abs() < 1e-12f64);
+    }
+
+    #[test]
+    fn masks_sample_their_open_pixels() {
+        // only the right half lets light through, twice as much in its top quarter
+        let mask = ApertureMask::from_fn(8, 8, |x, y| if x < 0f64 { 0f64 } else if y > 0f64 { 2f64 } else { 1f64 });
+        let aperture = Aperture::mask(mask);
+        let mut top = 0;
+        let count = 30000;
+        for _ in 0..count {
+            let (x, y) = aperture.sample();
+            assert!(x >= 0f64 && x <= 1f64 && y >= -1f64 && y <= 1f64);
+            if y > 0f64 {
+                top += 1;
+            }
+        }
+        assert!((top as f64 / count as f64 - 2f64 / 3f64).abs() < 0.02f64);
+        // all black falls back to the center
+        let closed = Aperture::mask(ApertureMask::from_fn(4, 4, |_, _| 0f64));
+        assert_eq!(closed.sample(), (0f64, 0f64));
+    }
+
+    fn temp_file(name: &str, contents: &[u8]) -> ::std::path::PathBuf {
+        let path = env::temp_dir().join(format!("aperture_test_{}_{}", process::id(), name));
+        fs::write(&path, contents).unwrap();
+        return path;
+    }
+
+    #[test]
+    fn masks_read_plain_and_binary_ppm() {
+        // 2 x 1, the left pixel black and the right one white, so every sample lands on the right
+        let plain = temp_file("plain.ppm", b"P3\n# a comment\n2 1\n255\n0 0 0 255 255 255\n");
+        let mut binary_bytes = b"P6 2 1 255\n".to_vec();
+        binary_bytes.extend_from_slice(&[0, 0, 0, 255, 255, 255]);
+        let binary = temp_file("binary.ppm", &binary_bytes);
+        for path in [plain, binary].iter() {
+            let aperture = Aperture::mask(ApertureMask::from_ppm(path).unwrap());
+            for _ in 0..100 {
+                assert!(aperture.sample().0 >= 0f64);
+            }
+            fs::remove_file(path).unwrap();
+        }
+
+        let broken = temp_file("broken.ppm", b"P5 2 1 255\n");
+        assert_eq!(ApertureMask::from_ppm(&broken).err().unwrap().kind(), io::ErrorKind::InvalidData);
+        fs::remove_file(&broken).unwrap();
+        let short = temp_file("short.ppm", b"P3 2 1 255 0 0 0\n");
+        assert!(ApertureMask::from_ppm(&short).is_err());
+        fs::remove_file(&short).unwrap();
+    }
+}
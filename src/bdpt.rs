@@ -0,0 +1,591 @@
+extern crate rusty_math;
+
+use rusty_math::*;
+use std::f64;
+use std::f64::consts;
+
+use renderable::HitRecord;
+use renderable::RenderList;
+use renderable::Material;
+use renderable::MaterialInput;
+use renderable::MaterialOutput;
+use renderable::random_unit_vector;
+use renderer::Integrator;
+use renderer::copy;
+use renderer::is_black;
+use renderer::mul;
+use camera::Camera;
+use render_buffer::RenderBufferF64;
+use spectrum;
+use spectrum::Wavelengths;
+use stats;
+
+//
+// bidirectional path tracing, Veach 1997 with the bookkeeping from pbrt
+//
+// a subpath is traced from the camera and another from a light, then every prefix of one is connected to
+// every prefix of the other. each connection is a different way of sampling the same path, multiple
+// importance sampling weights them so that the strategy best suited to a path dominates it
+// paths that end on the camera lens (t = 1) are light tracing and get splatted to whatever pixel they land on
+//
+// media and heterogeneous volumes are ignored, rays pass straight through them
+// the sky is not a light we can sample, camera paths that escape pick it up unweighted
+pub struct BdptIntegrator {
+    // bounces, a path with max_depth bounces has max_depth + 2 vertices
+    pub max_depth: usize,
+}
+
+impl BdptIntegrator {
+    pub fn new() -> BdptIntegrator {
+        return BdptIntegrator { max_depth: 8 };
+    }
+}
+
+#[derive(PartialEq, Clone, Copy)]
+enum VertexKind {
+    Camera,
+    Light,
+    Surface,
+}
+
+struct Vertex<'a> {
+    kind: VertexKind,
+    point: Vec3,
+    // zero on the camera, the lens has no cosine in our importance
+    normal: Vec3,
+    material: Option<&'a Material>,
+    // throughput up to and including this vertex, divided by the pdfs
+    beta: Vec3,
+    // light vertices: radiance leaving the light, surfaces: radiance emitted back toward the previous vertex
+    emission: Vec3,
+    // sampled from a specular material, nothing can connect to it
+    delta: bool,
+    // area densities of reaching this vertex from the previous one, and from the next one going backwards
+    pdf_fwd: f64,
+    pdf_rev: f64,
+    // a dispersive event happened before this vertex, only the hero wavelength is still valid
+    hero_only: bool,
+}
+
+fn remap0(pdf: f64) -> f64 {
+    return if pdf != 0f64 { pdf } else { 1f64 };
+}
+
+impl<'a> Vertex<'a> {
+    fn is_connectible(&self) -> bool {
+        return match self.kind {
+            VertexKind::Camera | VertexKind::Light => true,
+            VertexKind::Surface => match self.material {
+                Some(material) => !material.is_specular(),
+                None => false,
+            },
+        };
+    }
+
+    fn is_on_surface(&self) -> bool {
+        return self.kind != VertexKind::Camera;
+    }
+
+    // the material sees the ray arriving from the previous vertex
+    fn material_input(&self, from: &Vec3, wavelengths: Option<&Wavelengths>) -> MaterialInput {
+        return MaterialInput {
+            incoming_ray: Ray::new(copy(from), &self.point - from),
+            point: copy(&self.point),
+            normal: copy(&self.normal),
+            wavelength: match wavelengths {
+                Some(w) => w.hero(),
+                None => 0f64,
+            },
+        };
+    }
+
+    // scattering (or emission for light vertices) from prev through this vertex toward next, no cosine
+    fn f(&self, prev: Option<&Vertex>, next: &Vec3, wavelengths: Option<&Wavelengths>) -> Vec3 {
+        let dir = next - &self.point;
+        return match self.kind {
+            VertexKind::Light => {
+                if dir.dot(&self.normal) > 0f64 {
+                    Vec3::new(1f64, 1f64, 1f64)
+                } else {
+                    Vec3::new(0f64, 0f64, 0f64)
+                }
+            }
+            VertexKind::Surface => match (self.material, prev) {
+                (Some(material), Some(prev)) => {
+                    let input = self.material_input(&prev.point, wavelengths);
+                    spectrum::radiance_to_path(&material.eval(&input, &dir), wavelengths)
+                }
+                _ => Vec3::new(0f64, 0f64, 0f64),
+            },
+            VertexKind::Camera => Vec3::new(0f64, 0f64, 0f64),
+        };
+    }
+
+    // solid angle density to area density at next
+    fn convert_density(&self, pdf: f64, next: &Vertex) -> f64 {
+        let w = &next.point - &self.point;
+        let dist_squared = w.length_squared();
+        if dist_squared == 0f64 {
+            return 0f64;
+        }
+        let mut result = pdf / dist_squared;
+        if next.is_on_surface() {
+            result *= next.normal.dot(&w).abs() / dist_squared.sqrt();
+        }
+        return result;
+    }
+
+    // density of emitting toward next, for vertices on a light
+    fn pdf_light(&self, next: &Vertex) -> f64 {
+        let dir = (&next.point - &self.point).normalize();
+        let cosine = dir.dot(&self.normal);
+        if cosine <= 0f64 {
+            return 0f64;
+        }
+        return self.convert_density(cosine / consts::PI, next);
+    }
+
+    // area density of this vertex sampling next when arriving from prev
+    fn pdf(&self, prev: Option<&Vertex>, next: &Vertex, camera: &Camera, wavelengths: Option<&Wavelengths>) -> f64 {
+        let dir = &next.point - &self.point;
+        let pdf = match self.kind {
+            VertexKind::Light => return self.pdf_light(next),
+            VertexKind::Camera => camera.direction_pdf(&self.point, &dir),
+            VertexKind::Surface => match (self.material, prev) {
+                (Some(material), Some(prev)) => {
+                    material.scattering_pdf(&self.material_input(&prev.point, wavelengths), &dir)
+                }
+                _ => 0f64,
+            },
+        };
+        return self.convert_density(pdf, next);
+    }
+}
+
+// continues a subpath from its last vertex, pdf_dir is the solid angle density ray was sampled with
+// returns the sky radiance (weighted by beta) if a camera subpath escaped
+fn random_walk<'a>(
+    render_list: &RenderList<'a>,
+    ray: Ray,
+    beta: Vec3,
+    pdf_dir: f64,
+    shutter_time: f64,
+    wavelengths: Option<&Wavelengths>,
+    max_vertices: usize,
+    from_camera: bool,
+    path: &mut Vec<Vertex<'a>>
+) -> Vec3 {
+    let mut ray = ray;
+    let mut beta = beta;
+    let mut pdf_dir = pdf_dir;
+    let mut hero_only = false;
+    let mut bounces = 0usize;
+    while path.len() < max_vertices {
+        let mut hit_record = HitRecord::new();
+        if !render_list.try_get_hit_record(&ray, shutter_time, 0.001f64, f64::MAX, &mut hit_record) {
+            if from_camera {
+                stats::count_path(bounces);
+                let sky = spectrum::radiance_to_path(&render_list.sky_color(&ray.dir), wavelengths);
+                let escaped = mul(&beta, &sky);
+                if hero_only && wavelengths.is_some() {
+                    return Vec3::new(3f64 * escaped.x, 0f64, 0f64);
+                }
+                return escaped;
+            }
+            break;
+        }
+
+        let mut material_package = render_list.get_material_package(&hit_record);
+        if let Some(w) = wavelengths {
+            material_package.material_input.wavelength = w.hero();
+        }
+        let material = material_package.material;
+        let input = &material_package.material_input;
+        let emission = if from_camera {
+            spectrum::radiance_to_path(&material.emitted(input), wavelengths)
+        } else {
+            Vec3::new(0f64, 0f64, 0f64)
+        };
+
+        let mut vertex = Vertex {
+            kind: VertexKind::Surface,
+            point: copy(&input.point),
+            normal: copy(&input.normal),
+            material: Some(material),
+            beta: copy(&beta),
+            emission: emission,
+            delta: false,
+            pdf_fwd: 0f64,
+            pdf_rev: 0f64,
+            hero_only: hero_only,
+        };
+        vertex.pdf_fwd = path[path.len() - 1].convert_density(pdf_dir, &vertex);
+
+        let mut material_output = MaterialOutput::new();
+        let scattered = material.apply(input, &mut material_output);
+        let specular = material.is_specular();
+        vertex.delta = specular;
+        path.push(vertex);
+        if !scattered || path.len() >= max_vertices {
+            break;
+        }
+
+        let attenuation = spectrum::reflectance_to_path(&material_output.attenuation, wavelengths);
+        beta = mul(&beta, &attenuation);
+        if material_output.wavelength_dependent {
+            hero_only = true;
+        }
+
+        // density of the reverse walk, arriving along the scattered ray and leaving back toward the previous vertex
+        let mut pdf_rev_dir = 0f64;
+        pdf_dir = 0f64;
+        if !specular {
+            pdf_dir = material.scattering_pdf(input, &material_output.scattered.dir);
+            let reverse_input = MaterialInput {
+                incoming_ray: Ray::new(
+                    &input.point + &material_output.scattered.dir,
+                    -1f64 * &material_output.scattered.dir
+                ),
+                point: copy(&input.point),
+                normal: copy(&input.normal),
+                wavelength: input.wavelength,
+            };
+            pdf_rev_dir = material.scattering_pdf(&reverse_input, &(-1f64 * &ray.dir));
+        }
+        let count = path.len();
+        let pdf_rev = path[count - 1].convert_density(pdf_rev_dir, &path[count - 2]);
+        path[count - 2].pdf_rev = pdf_rev;
+
+        if is_black(&beta) {
+            break;
+        }
+        ray = material_output.scattered;
+        bounces += 1;
+    }
+    if from_camera {
+        stats::count_path(bounces);
+    }
+    return Vec3::new(0f64, 0f64, 0f64);
+}
+
+impl BdptIntegrator {
+    // balance heuristic over every strategy that could have produced the s + t vertex path
+    fn mis_weight(
+        &self,
+        render_list: &RenderList,
+        camera: &Camera,
+        wavelengths: Option<&Wavelengths>,
+        light_path: &[Vertex],
+        camera_path: &[Vertex],
+        s: usize,
+        t: usize
+    ) -> f64 {
+        if s + t == 2 {
+            return 1f64;
+        }
+
+        // (pdf_fwd, pdf_rev, delta) as they would be for this strategy
+        let mut light: Vec<(f64, f64, bool)> = light_path[..s].iter().map(|v| (v.pdf_fwd, v.pdf_rev, v.delta)).collect();
+        let mut eye: Vec<(f64, f64, bool)> = camera_path[..t].iter().map(|v| (v.pdf_fwd, v.pdf_rev, v.delta)).collect();
+
+        let pt = &camera_path[t - 1];
+        let pt_minus = if t > 1 { Some(&camera_path[t - 2]) } else { None };
+        if s > 0 {
+            let qs = &light_path[s - 1];
+            let qs_minus = if s > 1 { Some(&light_path[s - 2]) } else { None };
+            // the connected vertices are sampled by evaluating, never specular here
+            light[s - 1].2 = false;
+            eye[t - 1].2 = false;
+            eye[t - 1].1 = qs.pdf(qs_minus, pt, camera, wavelengths);
+            if let Some(pt_minus) = pt_minus {
+                eye[t - 2].1 = pt.pdf(Some(qs), pt_minus, camera, wavelengths);
+            }
+            light[s - 1].1 = pt.pdf(pt_minus, qs, camera, wavelengths);
+            if let Some(qs_minus) = qs_minus {
+                light[s - 2].1 = qs.pdf(Some(pt), qs_minus, camera, wavelengths);
+            }
+        } else {
+            // the camera path hit a light on its own
+            let origin_pdf = render_list.light_pdf(&pt.point);
+            if origin_pdf == 0f64 {
+                // emitters we cannot sample are only found this way
+                return 1f64;
+            }
+            eye[t - 1].1 = origin_pdf;
+            if let Some(pt_minus) = pt_minus {
+                eye[t - 2].1 = pt.pdf_light(pt_minus);
+            }
+        }
+
+        let mut sum_ri = 0f64;
+        let mut ri = 1f64;
+        for i in (1..t).rev() {
+            ri *= remap0(eye[i].1) / remap0(eye[i].0);
+            if !eye[i].2 && !eye[i - 1].2 {
+                sum_ri += ri;
+            }
+        }
+        ri = 1f64;
+        for i in (0..s).rev() {
+            ri *= remap0(light[i].1) / remap0(light[i].0);
+            // area lights are never delta
+            let delta_before = if i > 0 { light[i - 1].2 } else { false };
+            if !light[i].2 && !delta_before {
+                sum_ri += ri;
+            }
+        }
+        return 1f64 / (1f64 + sum_ri);
+    }
+
+    // s and t are image coordinates as returned by Camera::project
+    fn splat(&self, film: &mut RenderBufferF64, s: f64, t: f64, value: &Vec3, wavelengths: Option<&Wavelengths>) {
+        let rgb = match wavelengths {
+            Some(w) => spectrum::wavelengths_to_rgb(value, w),
+            None => copy(value),
+        };
+        let x = (s * film.width as f64) as usize;
+        let y = (t * film.height as f64) as usize;
+        film.add(x, y, rgb.x, rgb.y, rgb.z);
+    }
+}
+
+impl Integrator for BdptIntegrator {
+    fn radiance(
+        &self,
+        ray: &Ray,
+        shutter_time: f64,
+        wavelengths: Option<Wavelengths>,
+        render_list: &RenderList,
+        camera: &Camera,
+        film: &mut RenderBufferF64
+    ) -> Vec3 {
+        let wavelengths = wavelengths.as_ref();
+
+        // camera subpath, the lens point comes from the ray
+        let mut camera_path: Vec<Vertex> = Vec::with_capacity(self.max_depth + 2);
+        camera_path.push(Vertex {
+            kind: VertexKind::Camera,
+            point: copy(&ray.origin),
+            normal: Vec3::new(0f64, 0f64, 0f64),
+            material: None,
+            beta: Vec3::new(1f64, 1f64, 1f64),
+            emission: Vec3::new(0f64, 0f64, 0f64),
+            // orthographic lenses can only be reached by the camera path itself
+            delta: camera.is_delta(),
+            pdf_fwd: 1f64,
+            pdf_rev: 0f64,
+            hero_only: false,
+        });
+        let mut result = random_walk(
+            render_list,
+            Ray::new(copy(&ray.origin), copy(&ray.dir)),
+            Vec3::new(1f64, 1f64, 1f64),
+            camera.direction_pdf(&ray.origin, &ray.dir),
+            shutter_time,
+            wavelengths,
+            self.max_depth + 2,
+            true,
+            &mut camera_path
+        );
+
+        // light subpath
+        let mut light_path: Vec<Vertex> = Vec::with_capacity(self.max_depth + 1);
+        if let Some(light_sample) = render_list.sample_light() {
+            let emission = spectrum::radiance_to_path(&light_sample.emission, wavelengths);
+            light_path.push(Vertex {
+                kind: VertexKind::Light,
+                point: copy(&light_sample.point),
+                normal: copy(&light_sample.normal),
+                material: None,
+                beta: &emission / light_sample.pdf,
+                emission: emission,
+                delta: false,
+                pdf_fwd: light_sample.pdf,
+                pdf_rev: 0f64,
+                hero_only: false,
+            });
+            // cosine weighted emission, the cosine and pi cancel against the pdf
+            let dir = &light_sample.normal + &random_unit_vector();
+            let cosine = dir.normalize().dot(&light_sample.normal);
+            if cosine > 0f64 {
+                random_walk(
+                    render_list,
+                    Ray::new(copy(&light_sample.point), dir),
+                    &light_path[0].beta * consts::PI,
+                    cosine / consts::PI,
+                    shutter_time,
+                    wavelengths,
+                    self.max_depth + 1,
+                    false,
+                    &mut light_path
+                );
+            }
+        }
+
+        for t in 1..(camera_path.len() + 1) {
+            for s in 0..(light_path.len() + 1) {
+                if s + t < 2 || (s == 1 && t == 1) || s + t - 2 > self.max_depth {
+                    continue;
+                }
+                let mut contribution: Vec3;
+                let hero_only: bool;
+                let mut splat_position: Option<(f64, f64)> = None;
+                if s == 0 {
+                    // camera path ran into an emitter
+                    let pt = &camera_path[t - 1];
+                    if pt.kind != VertexKind::Surface || is_black(&pt.emission) {
+                        continue;
+                    }
+                    contribution = mul(&pt.beta, &pt.emission);
+                    hero_only = pt.hero_only;
+                } else if t == 1 {
+                    // light tracing, connect to the lens point of this camera ray
+                    let qs = &light_path[s - 1];
+                    if !qs.is_connectible() {
+                        continue;
+                    }
+                    let lens_point = &camera_path[0].point;
+                    let image = match camera.project(lens_point, &qs.point) {
+                        Some(image) => image,
+                        None => continue,
+                    };
+                    let to_camera = lens_point - &qs.point;
+                    let dist_squared = to_camera.length_squared();
+                    let qs_minus = if s > 1 { Some(&light_path[s - 2]) } else { None };
+                    let f = qs.f(qs_minus, lens_point, wavelengths);
+                    if is_black(&f) {
+                        continue;
+                    }
+                    let cosine = qs.normal.dot(&to_camera).abs() / dist_squared.sqrt();
+                    let importance = camera.direction_pdf(lens_point, &(-1f64 * &to_camera));
+                    contribution = &mul(&qs.beta, &f) * (cosine * importance / dist_squared);
+                    if is_black(&contribution) || render_list.is_occluded(&qs.point, lens_point, shutter_time) {
+                        continue;
+                    }
+                    hero_only = qs.hero_only;
+                    splat_position = Some(image);
+                } else {
+                    let qs = &light_path[s - 1];
+                    let pt = &camera_path[t - 1];
+                    if !qs.is_connectible() || !pt.is_connectible() {
+                        continue;
+                    }
+                    let qs_minus = if s > 1 { Some(&light_path[s - 2]) } else { None };
+                    let pt_minus = Some(&camera_path[t - 2]);
+                    let fq = qs.f(qs_minus, &pt.point, wavelengths);
+                    let fp = pt.f(pt_minus, &qs.point, wavelengths);
+                    let w = &pt.point - &qs.point;
+                    let dist_squared = w.length_squared();
+                    let g = qs.normal.dot(&w).abs() * pt.normal.dot(&w).abs() / (dist_squared * dist_squared);
+                    contribution = &mul(&mul(&qs.beta, &fq), &mul(&fp, &pt.beta)) * g;
+                    if is_black(&contribution) || render_list.is_occluded(&qs.point, &pt.point, shutter_time) {
+                        continue;
+                    }
+                    hero_only = qs.hero_only || pt.hero_only;
+                }
+
+                let weight = self.mis_weight(render_list, camera, wavelengths, &light_path, &camera_path, s, t);
+                contribution = &contribution * weight;
+                if hero_only && wavelengths.is_some() {
+                    contribution = Vec3::new(3f64 * contribution.x, 0f64, 0f64);
+                }
+                match splat_position {
+                    Some((image_s, image_t)) => self.splat(film, image_s, image_t, &contribution, wavelengths),
+                    None => result += &contribution,
+                }
+            }
+        }
+        return result;
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use renderable::shapes::Sphere;
+    use renderable::materials::Lambertian;
+    use renderable::materials::DiffuseLight;
+    use renderer;
+    use renderer::IntegratorKind;
+    use renderer::RenderRegion;
+    use renderer::RenderSettings;
+    use camera::PerspectiveCamera;
+    use filter::Filter;
+
+    // average luminance per sample over a small image of a grey floor and ball under a big lamp, with the sky
+    // off, seen through a lens aperture wide, its plane in focus turned by tilt_degrees. render adds its
+    // samples to the film and returns how many there are per pixel. the other integrators check against it
+    pub fn lit_scene_mean<F>(aperture: f64, tilt_degrees: (f64, f64), render: F) -> f64
+        where F: FnOnce(&RenderList, &PerspectiveCamera, &mut RenderBufferF64) -> i32
+    {
+        let floor_mat = Lambertian { albedo: Vec3::new(0.6f64, 0.6f64, 0.6f64) };
+        let ball_mat = Lambertian { albedo: Vec3::new(0.8f64, 0.4f64, 0.2f64) };
+        let lamp_mat = DiffuseLight { emission: Vec3::new(4f64, 4f64, 4f64) };
+        let floor = Sphere { center: Vec3::new(0f64, -1000f64, 0f64), radius: 1000f64 };
+        let ball = Sphere { center: Vec3::new(0f64, 0.5f64, -3f64), radius: 0.5f64 };
+        let lamp = Sphere { center: Vec3::new(1f64, 3f64, -3f64), radius: 1f64 };
+        let mut world = RenderList::new();
+        world.add_sphere(&floor, &floor_mat);
+        world.add_sphere(&ball, &ball_mat);
+        world.add_light(&lamp, &lamp_mat);
+        world.set_sky_luminance(0f64);
+        world.build();
+
+        let (width, height) = (24, 16);
+        let camera = PerspectiveCamera::new(
+            &Vec3::new(0f64, 1.5f64, 1f64), &Vec3::new(0f64, 0.5f64, -3f64), Vec3::new(0f64, 1f64, 0f64),
+            50f64, width as f64 / height as f64, aperture, 4f64, (0f64, 0f64), tilt_degrees
+        );
+        let mut film = RenderBufferF64::new(width, height);
+        let samples_per_pixel = render(&world, &camera, &mut film);
+        let mut sum = 0f64;
+        for y in 0..height {
+            for x in 0..width {
+                let (r, g, b) = film.get(x, y);
+                sum += spectrum::luminance(&Vec3::new(r, g, b));
+            }
+        }
+        return sum / (width * height * samples_per_pixel as usize) as f64;
+    }
+
+    pub fn settings(integrator: IntegratorKind, samples_per_pixel: i32) -> RenderSettings {
+        return RenderSettings {
+            num_samples_per_pixel: samples_per_pixel,
+            spectral: false,
+            integrator: integrator,
+            max_depth: 6,
+            aovs: Vec::new(),
+            exposure: None,
+            filter: Filter::box_filter(),
+        };
+    }
+
+    // lit_scene_mean with the integrators that render a region camera sample by camera sample
+    pub fn mean_radiance(integrator: IntegratorKind, aperture: f64, tilt_degrees: (f64, f64)) -> f64 {
+        return lit_scene_mean(aperture, tilt_degrees, |world, camera, film| {
+            let settings = settings(integrator, 256);
+            let (width, height) = (film.width, film.height);
+            let region = RenderRegion { x0: 0, y0: 0, x1: width, y1: height, samples: settings.num_samples_per_pixel };
+            let (samples, splats) = renderer::render_region(world, camera, &settings, width, height, &region).unwrap();
+            *film = splats;
+            samples.resolve_into(film, settings.num_samples_per_pixel);
+            return settings.num_samples_per_pixel;
+        });
+    }
+
+    #[test]
+    fn bidirectional_agrees_with_the_path_tracer() {
+        let path = mean_radiance(IntegratorKind::Path, 0f64, (0f64, 0f64));
+        let bidirectional = mean_radiance(IntegratorKind::Bidirectional, 0f64, (0f64, 0f64));
+        assert!(path > 0.05f64);
+        assert!((bidirectional - path).abs() < 0.05f64 * path, "bdpt {} against path {}", bidirectional, path);
+    }
+
+    // light tracing splats through the lens, which needs the camera's density for the tilted plane in focus
+    #[test]
+    fn bidirectional_agrees_through_a_tilted_lens() {
+        let path = mean_radiance(IntegratorKind::Path, 0.4f64, (20f64, -10f64));
+        let bidirectional = mean_radiance(IntegratorKind::Bidirectional, 0.4f64, (20f64, -10f64));
+        assert!((bidirectional - path).abs() < 0.05f64 * path, "bdpt {} against path {}", bidirectional, path);
+    }
+}
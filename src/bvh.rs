@@ -0,0 +1,372 @@
+extern crate rusty_math;
+
+use rusty_math::*;
+use std::f64;
+use std::usize;
+
+use stats;
+
+//
+// Aabb
+//
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+// goes away once vec3 derives copy
+impl Clone for Aabb {
+    fn clone(&self) -> Aabb {
+        return Aabb {
+            min: Vec3::new(self.min.x, self.min.y, self.min.z),
+            max: Vec3::new(self.max.x, self.max.y, self.max.z),
+        };
+    }
+}
+
+impl Aabb {
+    pub fn new(min: Vec3, max: Vec3) -> Aabb {
+        return Aabb { min: min, max: max };
+    }
+
+    // inverted bounds so that growing by anything produces that thing
+    pub fn empty() -> Aabb {
+        return Aabb {
+            min: Vec3::new(f64::MAX, f64::MAX, f64::MAX),
+            max: Vec3::new(-f64::MAX, -f64::MAX, -f64::MAX),
+        };
+    }
+
+    pub fn grow(&mut self, p: &Vec3) {
+        self.min = Vec3::new(self.min.x.min(p.x), self.min.y.min(p.y), self.min.z.min(p.z));
+        self.max = Vec3::new(self.max.x.max(p.x), self.max.y.max(p.y), self.max.z.max(p.z));
+    }
+
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        let mut result = self.clone();
+        result.grow(&other.min);
+        result.grow(&other.max);
+        return result;
+    }
+
+    // may come back inverted (empty) if the boxes do not overlap
+    pub fn intersection(&self, other: &Aabb) -> Aabb {
+        return Aabb {
+            min: Vec3::new(self.min.x.max(other.min.x), self.min.y.max(other.min.y), self.min.z.max(other.min.z)),
+            max: Vec3::new(self.max.x.min(other.max.x), self.max.y.min(other.max.y), self.max.z.min(other.max.z)),
+        };
+    }
+
+    pub fn centroid(&self) -> Vec3 {
+        return 0.5f64 * &(&self.min + &self.max);
+    }
+
+    pub fn extent(&self) -> Vec3 {
+        return &self.max - &self.min;
+    }
+
+    pub fn largest_axis(&self) -> usize {
+        let e = self.extent();
+        if e.x > e.y && e.x > e.z {
+            return 0;
+        }
+        if e.y > e.z {
+            return 1;
+        }
+        return 2;
+    }
+
+    // the part of [time_min, time_max] the ray spends inside the box
+    pub fn clip(&self, ray: &Ray, time_min: f64, time_max: f64) -> Option<(f64, f64)> {
+        let mut t0 = time_min;
+        let mut t1 = time_max;
+        for axis in 0..3 {
+            let (origin, dir, lo, hi) = match axis {
+                0 => (ray.origin.x, ray.dir.x, self.min.x, self.max.x),
+                1 => (ray.origin.y, ray.dir.y, self.min.y, self.max.y),
+                _ => (ray.origin.z, ray.dir.z, self.min.z, self.max.z),
+            };
+            if dir == 0f64 {
+                if origin < lo || origin > hi {
+                    return None;
+                }
+                continue;
+            }
+            let near = ((lo - origin) / dir).min((hi - origin) / dir);
+            let far = ((lo - origin) / dir).max((hi - origin) / dir);
+            t0 = t0.max(near);
+            t1 = t1.min(far);
+            if t0 > t1 {
+                return None;
+            }
+        }
+        return Some((t0, t1));
+    }
+
+    // slab test, returns false if the ray misses the box within [time_min, time_max]
+    pub fn hit(&self, ray: &Ray, inv_dir: &Vec3, time_min: f64, time_max: f64) -> bool {
+        let mut t0 = time_min;
+        let mut t1 = time_max;
+        for axis in 0..3 {
+            let (origin, inv, lo, hi) = match axis {
+                0 => (ray.origin.x, inv_dir.x, self.min.x, self.max.x),
+                1 => (ray.origin.y, inv_dir.y, self.min.y, self.max.y),
+                _ => (ray.origin.z, inv_dir.z, self.min.z, self.max.z),
+            };
+            let mut near = (lo - origin) * inv;
+            let mut far = (hi - origin) * inv;
+            if near > far {
+                let tmp = near;
+                near = far;
+                far = tmp;
+            }
+            // NaN (origin on a slab with a zero direction) leaves the interval untouched
+            if near > t0 {
+                t0 = near;
+            }
+            if far < t1 {
+                t1 = far;
+            }
+            if t0 > t1 {
+                return false;
+            }
+        }
+        return true;
+    }
+}
+
+pub fn axis_value(v: &Vec3, axis: usize) -> f64 {
+    return match axis {
+        0 => v.x,
+        1 => v.y,
+        _ => v.z,
+    };
+}
+
+//
+// Bvh
+//
+static MAX_PRIMITIVES_PER_LEAF: usize = 2;
+
+struct BvhNode {
+    bounds: Aabb,
+    // interior: index of the left child, right holds the right child
+    // leaf: first entry into m_indices
+    first: usize,
+    right: usize,
+    count: usize,
+}
+
+// binary bvh over anything that can give us bounds, primitives are referenced by index
+pub struct Bvh {
+    m_nodes: Vec<BvhNode>,
+    m_indices: Vec<usize>,
+}
+
+impl Bvh {
+    pub fn new() -> Bvh {
+        return Bvh {
+            m_nodes: Vec::new(),
+            m_indices: Vec::new(),
+        };
+    }
+
+    pub fn build(primitive_bounds: &[Aabb]) -> Bvh {
+        let mut bvh = Bvh::new();
+        if primitive_bounds.is_empty() {
+            return bvh;
+        }
+        bvh.m_indices = (0..primitive_bounds.len()).collect();
+        let count = primitive_bounds.len();
+        bvh.build_node(primitive_bounds, 0, count);
+        return bvh;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.m_nodes.is_empty();
+    }
+
+    pub fn get_bounds(&self) -> Aabb {
+        if self.is_empty() {
+            return Aabb::empty();
+        }
+        return self.m_nodes[0].bounds.clone();
+    }
+
+    fn build_node(&mut self, primitive_bounds: &[Aabb], start: usize, end: usize) -> usize {
+        let mut bounds = Aabb::empty();
+        let mut centroid_bounds = Aabb::empty();
+        for i in start..end {
+            let b = &primitive_bounds[self.m_indices[i]];
+            bounds = bounds.union(b);
+            centroid_bounds.grow(&b.centroid());
+        }
+
+        let node_index = self.m_nodes.len();
+        self.m_nodes.push(BvhNode {
+            bounds: bounds,
+            first: start,
+            right: 0,
+            count: end - start,
+        });
+
+        if end - start <= MAX_PRIMITIVES_PER_LEAF {
+            return node_index;
+        }
+
+        // median split along the widest spread of centroids
+        let axis = centroid_bounds.largest_axis();
+        self.m_indices[start..end].sort_by(|a, b| {
+            let ca = axis_value(&primitive_bounds[*a].centroid(), axis);
+            let cb = axis_value(&primitive_bounds[*b].centroid(), axis);
+            return ca.partial_cmp(&cb).unwrap_or(::std::cmp::Ordering::Equal);
+        });
+        let mid = (start + end) / 2;
+
+        let left = self.build_node(primitive_bounds, start, mid);
+        let right = self.build_node(primitive_bounds, mid, end);
+        let node = &mut self.m_nodes[node_index];
+        node.first = left;
+        node.right = right;
+        node.count = 0;
+        return node_index;
+    }
+
+    // new bounds for the same primitives, the tree keeps its shape and only the node boxes grow or shrink.
+    // cheaper than a build, but the tree gets slower the further the primitives move from where it was built
+    pub fn refit(&mut self, primitive_bounds: &[Aabb]) {
+        // children always come after their parent, so walking backwards sees them first
+        for node_index in (0..self.m_nodes.len()).rev() {
+            let bounds = {
+                let node = &self.m_nodes[node_index];
+                if node.count > 0 {
+                    let mut bounds = Aabb::empty();
+                    for i in node.first..(node.first + node.count) {
+                        bounds = bounds.union(&primitive_bounds[self.m_indices[i]]);
+                    }
+                    bounds
+                } else {
+                    self.m_nodes[node.first].bounds.union(&self.m_nodes[node.right].bounds)
+                }
+            };
+            self.m_nodes[node_index].bounds = bounds;
+        }
+    }
+
+    // walks the tree depth first, left child before right whatever the ray's direction, and skips nodes the ray
+    // misses or only meets past the closest hit so far. hit_primitive returns the hit time or something
+    // <= time_min on a miss
+    // returns the closest primitive index (usize::MAX on a miss) and its hit time
+    pub fn traverse<F>(&self, ray: &Ray, time_min: f64, time_max: f64, mut hit_primitive: F) -> (usize, f64)
+        where F: FnMut(usize, f64) -> f64
+    {
+        let mut closest_index = usize::MAX;
+        let mut closest_time = time_max;
+        if self.is_empty() {
+            return (closest_index, closest_time);
+        }
+
+        let inv_dir = Vec3::new(1f64 / ray.dir.x, 1f64 / ray.dir.y, 1f64 / ray.dir.z);
+        let mut stack: Vec<usize> = Vec::with_capacity(64);
+        let (mut nodes_visited, mut intersection_tests) = (0u64, 0u64);
+        stack.push(0);
+        while let Some(node_index) = stack.pop() {
+            let node = &self.m_nodes[node_index];
+            nodes_visited += 1;
+            if !node.bounds.hit(ray, &inv_dir, time_min, closest_time) {
+                continue;
+            }
+            if node.count > 0 {
+                intersection_tests += node.count as u64;
+                for i in node.first..(node.first + node.count) {
+                    let primitive = self.m_indices[i];
+                    let hit_time = hit_primitive(primitive, closest_time);
+                    if hit_time > time_min && hit_time < closest_time {
+                        closest_time = hit_time;
+                        closest_index = primitive;
+                    }
+                }
+            } else {
+                stack.push(node.right);
+                stack.push(node.first);
+            }
+        }
+        stats::count_traversal(nodes_visited, intersection_tests);
+        return (closest_index, closest_time);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a 5 x 5 x 5 lattice of small boxes
+    fn lattice() -> Vec<Aabb> {
+        let mut boxes = Vec::new();
+        for i in 0..125 {
+            let corner = Vec3::new((i % 5) as f64, ((i / 5) % 5) as f64, (i / 25) as f64);
+            boxes.push(Aabb::new(Vec3::new(corner.x, corner.y, corner.z), &corner + &Vec3::new(0.4f64, 0.4f64, 0.4f64)));
+        }
+        return boxes;
+    }
+
+    fn closest_by_brute_force(boxes: &[Aabb], ray: &Ray) -> (usize, f64) {
+        let mut closest = (usize::MAX, f64::MAX);
+        for (index, bounds) in boxes.iter().enumerate() {
+            if let Some((entry, _)) = bounds.clip(ray, 0f64, f64::MAX) {
+                if entry < closest.1 {
+                    closest = (index, entry);
+                }
+            }
+        }
+        return closest;
+    }
+
+    #[test]
+    fn traverse_finds_the_same_closest_box_as_testing_them_all() {
+        let boxes = lattice();
+        let bvh = Bvh::build(&boxes);
+        for i in 0..50 {
+            let t = i as f64 * 0.37f64;
+            let ray = Ray::new(Vec3::new(-3f64, 2.2f64 + t.sin(), 2f64 + t.cos()), Vec3::new(1f64, 0.2f64 * t.cos(), 0.3f64 * t.sin()));
+            let expected = closest_by_brute_force(&boxes, &ray);
+            let found = bvh.traverse(&ray, 0f64, f64::MAX, |index, closest_time| {
+                return match boxes[index].clip(&ray, 0f64, closest_time) {
+                    Some((entry, _)) => entry,
+                    None => 0f64,
+                };
+            });
+            assert_eq!(found.0, expected.0);
+            if found.0 != usize::MAX {
+                assert!((found.1 - expected.1).abs() < 1e-9f64);
+            }
+        }
+    }
+
+    #[test]
+    fn refit_follows_boxes_that_moved() {
+        let mut boxes = lattice();
+        let mut bvh = Bvh::build(&boxes);
+        // the last box jumps far outside the lattice
+        boxes[124] = Aabb::new(Vec3::new(20f64, 20f64, 20f64), Vec3::new(21f64, 21f64, 21f64));
+        bvh.refit(&boxes);
+        assert!(bvh.get_bounds().max.x >= 21f64);
+        let ray = Ray::new(Vec3::new(20.5f64, 20.5f64, -5f64), Vec3::new(0f64, 0f64, 1f64));
+        let (index, time) = bvh.traverse(&ray, 0f64, f64::MAX, |index, closest_time| {
+            return match boxes[index].clip(&ray, 0f64, closest_time) {
+                Some((entry, _)) => entry,
+                None => 0f64,
+            };
+        });
+        assert_eq!(index, 124);
+        assert!((time - 25f64).abs() < 1e-9f64);
+    }
+
+    #[test]
+    fn an_empty_tree_misses_everything() {
+        let bvh = Bvh::build(&[]);
+        assert!(bvh.is_empty());
+        let ray = Ray::new(Vec3::new(0f64, 0f64, 0f64), Vec3::new(0f64, 0f64, 1f64));
+        assert_eq!(bvh.traverse(&ray, 0f64, f64::MAX, |_, _| 1f64).0, usize::MAX);
+    }
+}
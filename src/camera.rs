@@ -1,22 +1,122 @@
 extern crate rusty_math;
-extern crate rand;
 
 use rusty_math::*;
-use rand::Rng;
 use std::f64::consts;
 
-fn random_in_unit_disk() -> Vec3 {
-    let u = &Vec3::new(1f64, 1f64, 0f64);
-    let mut p = Vec3::new(2f64, 2f64, 0f64);
-    while p.dot(&p) >= 1.0f64 {
-        let rand_x = rand::thread_rng().gen_range(0f64, 1f64);
-        let rand_y = rand::thread_rng().gen_range(0f64, 1f64);
-        p = &(2.0f64 * &Vec3::new(rand_x, rand_y, 0f64)) - u;
+use sampler;
+use renderer::copy;
+use aperture::Aperture;
+use aperture::PhysicalLens;
+
+// film height PerspectiveCamera::new's field of view is taken to be for, full frame
+static FILM_HEIGHT: f64 = 0.024f64;
+
+// rays are spread uniformly over [open, close]
+pub struct Shutter {
+    pub open: f64,
+    pub close: f64,
+}
+
+impl Shutter {
+    pub fn new() -> Shutter {
+        return Shutter { open: 0f64, close: 0f64 };
     }
-    return p;
+
+    pub fn sample(&self) -> f64 {
+        if self.close <= self.open {
+            return self.open;
+        }
+        return sampler::next_range(self.open, self.close);
+    }
+}
+
+// turns image coordinates into rays, s and t are in [0, 1) with t = 0 at the bottom of the image
+// Sync so cameras can be shared between render threads
+pub trait Camera: Sync {
+    fn get_ray(&self, s: f64, t: f64) -> Ray;
+
+    // get_ray along with the fraction of the light arriving along it that reaches the film
+    // vignetting leaves less than 1 towards the corners, 0 when the ray is blocked
+    fn get_weighted_ray(&self, s: f64, t: f64) -> (Ray, f64) {
+        return (self.get_ray(s, t), 1f64);
+    }
+
+    fn get_origin(&self) -> Vec3;
+
+    fn shutter(&self) -> &Shutter;
+
+    fn shutter_mut(&mut self) -> &mut Shutter;
+
+    // solid angle density of get_ray producing dir from lens_point, 0 outside the view
+    // this doubles as the importance a light path splat carries, the pixel count aside
+    fn direction_pdf(&self, lens_point: &Vec3, dir: &Vec3) -> f64;
+
+    // the image coordinates (s, t as passed to get_ray) of the ray from lens_point through point
+    fn project(&self, lens_point: &Vec3, point: &Vec3) -> Option<(f64, f64)>;
+
+    // every point on the lens sees along a single direction, light paths can never be connected to it
+    fn is_delta(&self) -> bool {
+        return false;
+    }
+
+    // focal length over aperture diameter for cameras with a real aperture, exposure shares it with depth of field
+    fn f_number(&self) -> Option<f64> {
+        return None;
+    }
+
+    fn set_shutter(&mut self, open: f64, close: f64) {
+        let shutter = self.shutter_mut();
+        shutter.open = open;
+        shutter.close = close;
+    }
+
+    fn sample_shutter_time(&self) -> f64 {
+        return self.shutter().sample();
+    }
+}
+
+// orthonormal basis the projections below work in, the camera looks down -w with v up and u to the right
+pub struct Frame {
+    pub origin: Vec3,
+    pub u: Vec3,
+    pub v: Vec3,
+    pub w: Vec3,
 }
 
-pub struct Camera {
+impl Frame {
+    pub fn new(pos: &Vec3, look_at: &Vec3, up: &Vec3) -> Frame {
+        let w = (pos - look_at).normalize();
+        let u = up.cross(&w).normalize();
+        let v = w.cross(&u).normalize();
+        return Frame {
+            origin: copy(pos),
+            u: u,
+            v: v,
+            w: w,
+        };
+    }
+
+    pub fn to_world(&self, x: f64, y: f64, z: f64) -> Vec3 {
+        return &(&(x * &self.u) + &(y * &self.v)) + &(z * &self.w);
+    }
+
+    pub fn to_local(&self, dir: &Vec3) -> Vec3 {
+        return Vec3::new(dir.dot(&self.u), dir.dot(&self.v), dir.dot(&self.w));
+    }
+}
+
+fn in_image(s: f64, t: f64) -> Option<(f64, f64)> {
+    if s < 0f64 || s >= 1f64 || t < 0f64 || t >= 1f64 {
+        return None;
+    }
+    return Some((s, t));
+}
+
+//
+// PerspectiveCamera
+//
+// thin lens, points on the focus plane are sharp and everything else is blurred by the aperture
+pub struct PerspectiveCamera {
     pub origin:Vec3,
     pub lower_left:Vec3,
     pub horizontal:Vec3,
@@ -28,9 +128,20 @@ pub struct Camera {
     pub w:Vec3,
 
     pub lens_radius:f64,
+    // meters, with lens_radius it gives the f-number
+    pub focal_length:f64,
+    // shape of the opening and how it vignettes, lens_radius scales it
+    pub aperture: Aperture,
+    // normal of the plane in focus, w unless the lens is tilted. the plane goes through the center of the view
+    pub focus_normal:Vec3,
+
+    pub shutter: Shutter,
 }
 
-impl Camera {
+impl PerspectiveCamera {
+    // lens_shift slides the image across, in fractions of its width and height, the view direction stays put
+    // so verticals stay parallel. tilt_degrees turns the plane in focus (Scheimpflug): the first angle is
+    // about u, the top of the plane moving away from the camera, the second about v, the right side moving away
     pub fn new(
         pos: &Vec3,
         look_at: &Vec3,
@@ -39,7 +150,9 @@ impl Camera {
         aspect_ratio:f64,
         aperture:f64,
         focus_dist:f64,
-    ) -> Camera {
+        lens_shift:(f64, f64),
+        tilt_degrees:(f64, f64),
+    ) -> PerspectiveCamera {
         let theta_rads = vertical_fov_degrees * consts::PI / 180f64;
         let half_height = f64::tan(theta_rads / 2f64);
         let half_width = aspect_ratio * half_height;
@@ -47,10 +160,14 @@ impl Camera {
         let u = up.cross(&w).normalize();
         let v = w.cross(&u).normalize();
 
-        let left = &(half_width * focus_dist * &u);
-        let bottom = &(half_height * focus_dist * &v);
+        let left = &((half_width - 2f64 * half_width * lens_shift.0) * focus_dist * &u);
+        let bottom = &((half_height - 2f64 * half_height * lens_shift.1) * focus_dist * &v);
         let offset = &(focus_dist * &w);
-        return Camera {
+
+        let tilt = tilt_degrees.0 * consts::PI / 180f64;
+        let swing = tilt_degrees.1 * consts::PI / 180f64;
+        let focus_normal = &(&((swing.sin() * tilt.cos()) * &u) + &(tilt.sin() * &v)) + &((tilt.cos() * swing.cos()) * &w);
+        return PerspectiveCamera {
             origin: Vec3::new(pos.x, pos.y, pos.z),
             lower_left: &(&(pos - left) - bottom) - offset,
             horizontal: 2f64 * half_width * focus_dist * &u,
@@ -59,21 +176,626 @@ impl Camera {
             u: u,
             v: v,
             lens_radius: aperture / 2f64,
+            focal_length: 0.5f64 * FILM_HEIGHT / half_height,
+            aperture: Aperture::circle(),
+            focus_normal: focus_normal,
+            shutter: Shutter::new(),
         };
     }
 
-    pub fn get_origin(&self) -> Vec3 {
-        return Vec3::new(self.origin.x, self.origin.y, self.origin.z);
+    // fov and aperture from the focal length, f-number and sensor of a real lens, focus_dist in meters
+    pub fn from_lens(
+        pos: &Vec3,
+        look_at: &Vec3,
+        up:Vec3,
+        lens: &PhysicalLens,
+        aspect_ratio:f64,
+        focus_dist:f64,
+        lens_shift:(f64, f64),
+        tilt_degrees:(f64, f64),
+    ) -> PerspectiveCamera {
+        let mut camera = PerspectiveCamera::new(
+            pos,
+            look_at,
+            up,
+            lens.vertical_fov_degrees(),
+            aspect_ratio,
+            lens.aperture_diameter(),
+            focus_dist,
+            lens_shift,
+            tilt_degrees
+        );
+        camera.focal_length = lens.focal_length_mm / 1000f64;
+        return camera;
+    }
+
+    // opens or stops down the aperture, depth of field and exposure both follow
+    pub fn set_f_number(&mut self, f_number: f64) {
+        self.lens_radius = 0.5f64 * self.focal_length / f_number;
+    }
+
+    // distance from the lens to the plane lower_left, horizontal and vertical live in, which is also
+    // where the plane in focus crosses the view direction
+    fn focus_distance(&self) -> f64 {
+        return (&self.origin - &self.lower_left).dot(&self.w);
     }
 
-    pub fn get_ray(&self, s:f64, t:f64) -> Ray {
-        let rd = self.lens_radius * &random_in_unit_disk();
-        let offset = &(&self.u * rd.x) + &(&self.v * rd.y);
+    // where the line from point along dir meets the plane in focus, None if it never does
+    fn focus_point(&self, point: &Vec3, dir: &Vec3) -> Option<Vec3> {
+        let denom = dir.dot(&self.focus_normal);
+        if denom >= 0f64 {
+            return None;
+        }
+        let center = &self.origin - &(self.focus_distance() * &self.w);
+        let time = (&center - point).dot(&self.focus_normal) / denom;
+        return Some(point + &(time * dir));
+    }
+}
+
+impl Camera for PerspectiveCamera {
+    fn get_ray(&self, s:f64, t:f64) -> Ray {
+        return self.get_weighted_ray(s, t).0;
+    }
+
+    fn get_weighted_ray(&self, s:f64, t:f64) -> (Ray, f64) {
+        let lens_point = self.aperture.sample();
+        let offset = &(&self.u * (self.lens_radius * lens_point.0)) + &(&self.v * (self.lens_radius * lens_point.1));
         let a = &(s * &self.horizontal);
         let b = &(t * &self.vertical);
-        return Ray::new(
-            &self.origin + &offset,
-            &(&(&(&self.lower_left + a) + b) - &self.origin) - &offset,
+        // the pinhole ray picks the point that is sharp, every point on the lens aims at it
+        let pinhole_dir = &(&(&self.lower_left + a) + b) - &self.origin;
+        let ray = match self.focus_point(&self.origin, &pinhole_dir) {
+            Some(sharp) => Ray::new(&self.origin + &offset, &(&sharp - &self.origin) - &offset),
+            // the plane in focus tilted away from this direction, it is sharp at infinity
+            None => Ray::new(&self.origin + &offset, pinhole_dir),
+        };
+        let cos_theta = -ray.dir.normalize().dot(&self.w);
+        let weight = self.aperture.transmission(lens_point, (2f64 * s - 1f64, 2f64 * t - 1f64), cos_theta);
+        return (ray, weight);
+    }
+
+    fn get_origin(&self) -> Vec3 {
+        return Vec3::new(self.origin.x, self.origin.y, self.origin.z);
+    }
+
+    fn shutter(&self) -> &Shutter {
+        return &self.shutter;
+    }
+
+    fn shutter_mut(&mut self) -> &mut Shutter {
+        return &mut self.shutter;
+    }
+
+    // pixels are uniform over the image plane, their pinhole rays carry that over to the plane in focus and
+    // the lens point sees the plane in focus from its own angle. without tilt the planes are the same and
+    // this is the pinhole's focus_dist^2 / (area cos^3) whatever the lens point
+    fn direction_pdf(&self, lens_point: &Vec3, dir: &Vec3) -> f64 {
+        let focus_dist = self.focus_distance();
+        let area = (self.horizontal.length_squared() * self.vertical.length_squared()).sqrt();
+        let sharp = match self.focus_point(lens_point, dir) {
+            Some(sharp) => sharp,
+            // get_ray sends these along the pinhole direction from every lens point
+            None => {
+                let cos_theta = -dir.normalize().dot(&self.w);
+                if cos_theta <= 0f64 {
+                    return 0f64;
+                }
+                return focus_dist * focus_dist / (area * cos_theta * cos_theta * cos_theta);
+            }
+        };
+        let pinhole = &sharp - &self.origin;
+        let pinhole_length_squared = pinhole.length_squared();
+        let cos_image = -pinhole.dot(&self.w) / pinhole_length_squared.sqrt();
+        if cos_image <= 0f64 {
+            return 0f64;
+        }
+        let image_length_squared = focus_dist * focus_dist / (cos_image * cos_image);
+        let cos_pinhole = pinhole.dot(&self.focus_normal).abs() / pinhole_length_squared.sqrt();
+        let to_sharp = &sharp - lens_point;
+        let to_sharp_length_squared = to_sharp.length_squared();
+        let cos_lens = to_sharp.dot(&self.focus_normal).abs() / to_sharp_length_squared.sqrt();
+        if cos_lens <= 0f64 {
+            return 0f64;
+        }
+        // image plane area per area in focus, then area in focus per solid angle at the lens point
+        let image_per_focus = image_length_squared / pinhole_length_squared * cos_pinhole / cos_image;
+        return image_per_focus * to_sharp_length_squared / (cos_lens * area);
+    }
+
+    // a pinhole has no f-number to speak of
+    fn f_number(&self) -> Option<f64> {
+        if self.lens_radius <= 0f64 {
+            return None;
+        }
+        return Some(0.5f64 * self.focal_length / self.lens_radius);
+    }
+
+    fn project(&self, lens_point: &Vec3, point: &Vec3) -> Option<(f64, f64)> {
+        // the pixel is the one whose pinhole ray goes through the sharp point on the way
+        let sharp = self.focus_point(lens_point, &(point - lens_point))?;
+        let dir = &sharp - &self.origin;
+        let denom = dir.dot(&self.w);
+        if denom >= 0f64 {
+            return None;
+        }
+        let time = (&self.lower_left - &self.origin).dot(&self.w) / denom;
+        let on_plane = &(&self.origin + &(time * &dir)) - &self.lower_left;
+        let s = on_plane.dot(&self.horizontal) / self.horizontal.length_squared();
+        let t = on_plane.dot(&self.vertical) / self.vertical.length_squared();
+        return in_image(s, t);
+    }
+}
+
+//
+// OrthographicCamera
+//
+// parallel rays out of a rectangle facing look_at, sizes stay true to scale for elevations and plans
+pub struct OrthographicCamera {
+    frame: Frame,
+    lower_left: Vec3,
+    horizontal: Vec3,
+    vertical: Vec3,
+    pub shutter: Shutter,
+}
+
+impl OrthographicCamera {
+    // view_height is in world units, the width follows from the aspect ratio
+    pub fn new(pos: &Vec3, look_at: &Vec3, up: &Vec3, view_height: f64, aspect_ratio: f64) -> OrthographicCamera {
+        let frame = Frame::new(pos, look_at, up);
+        let horizontal = aspect_ratio * view_height * &frame.u;
+        let vertical = view_height * &frame.v;
+        let lower_left = &(pos - &(0.5f64 * &horizontal)) - &(0.5f64 * &vertical);
+        return OrthographicCamera {
+            frame: frame,
+            lower_left: lower_left,
+            horizontal: horizontal,
+            vertical: vertical,
+            shutter: Shutter::new(),
+        };
+    }
+}
+
+impl Camera for OrthographicCamera {
+    fn get_ray(&self, s: f64, t: f64) -> Ray {
+        let origin = &(&self.lower_left + &(s * &self.horizontal)) + &(t * &self.vertical);
+        return Ray::new(origin, -1f64 * &self.frame.w);
+    }
+
+    fn get_origin(&self) -> Vec3 {
+        return copy(&self.frame.origin);
+    }
+
+    fn shutter(&self) -> &Shutter {
+        return &self.shutter;
+    }
+
+    fn shutter_mut(&mut self) -> &mut Shutter {
+        return &mut self.shutter;
+    }
+
+    // every pixel looks straight ahead, there is no density to speak of
+    fn direction_pdf(&self, _lens_point: &Vec3, _dir: &Vec3) -> f64 {
+        return 0f64;
+    }
+
+    fn project(&self, _lens_point: &Vec3, _point: &Vec3) -> Option<(f64, f64)> {
+        return None;
+    }
+
+    fn is_delta(&self) -> bool {
+        return true;
+    }
+}
+
+//
+// FisheyeCamera
+//
+#[derive(Clone, Copy)]
+pub enum FisheyeMapping {
+    // distance from the image center grows linearly with the angle off the axis
+    Equidistant,
+    // equal areas on the image cover equal solid angles
+    Equisolid,
+}
+
+// pinhole that bends the view into an image circle, fov is the angle across the image height
+// the corners of a wider than tall image see past fov like a full frame fisheye, up to straight behind
+pub struct FisheyeCamera {
+    frame: Frame,
+    mapping: FisheyeMapping,
+    half_fov: f64,
+    aspect_ratio: f64,
+    pub shutter: Shutter,
+}
+
+impl FisheyeCamera {
+    pub fn new(
+        pos: &Vec3,
+        look_at: &Vec3,
+        up: &Vec3,
+        fov_degrees: f64,
+        aspect_ratio: f64,
+        mapping: FisheyeMapping
+    ) -> FisheyeCamera {
+        return FisheyeCamera {
+            frame: Frame::new(pos, look_at, up),
+            mapping: mapping,
+            half_fov: (fov_degrees * consts::PI / 360f64).min(consts::PI),
+            aspect_ratio: aspect_ratio,
+            shutter: Shutter::new(),
+        };
+    }
+
+    // angle off the axis at radius r, where r = 1 is the top edge of the image
+    fn theta(&self, r: f64) -> f64 {
+        let theta = match self.mapping {
+            FisheyeMapping::Equidistant => r * self.half_fov,
+            FisheyeMapping::Equisolid => {
+                let sine = r * (0.5f64 * self.half_fov).sin();
+                if sine >= 1f64 { consts::PI } else { 2f64 * sine.asin() }
+            }
+        };
+        return theta.min(consts::PI);
+    }
+
+    fn radius(&self, theta: f64) -> f64 {
+        return match self.mapping {
+            FisheyeMapping::Equidistant => theta / self.half_fov,
+            FisheyeMapping::Equisolid => (0.5f64 * theta).sin() / (0.5f64 * self.half_fov).sin(),
+        };
+    }
+
+    // d theta / d r
+    fn theta_derivative(&self, r: f64) -> f64 {
+        return match self.mapping {
+            FisheyeMapping::Equidistant => self.half_fov,
+            FisheyeMapping::Equisolid => {
+                let k = (0.5f64 * self.half_fov).sin();
+                2f64 * k / (1f64 - r * r * k * k).max(1e-12f64).sqrt()
+            }
+        };
+    }
+}
+
+impl Camera for FisheyeCamera {
+    fn get_ray(&self, s: f64, t: f64) -> Ray {
+        let x = (2f64 * s - 1f64) * self.aspect_ratio;
+        let y = 2f64 * t - 1f64;
+        let r = (x * x + y * y).sqrt();
+        let theta = self.theta(r);
+        let (cos_phi, sin_phi) = if r > 0f64 { (x / r, y / r) } else { (1f64, 0f64) };
+        let sin_theta = theta.sin();
+        let dir = self.frame.to_world(sin_theta * cos_phi, sin_theta * sin_phi, -theta.cos());
+        return Ray::new(copy(&self.frame.origin), dir);
+    }
+
+    fn get_origin(&self) -> Vec3 {
+        return copy(&self.frame.origin);
+    }
+
+    fn shutter(&self) -> &Shutter {
+        return &self.shutter;
+    }
+
+    fn shutter_mut(&mut self) -> &mut Shutter {
+        return &mut self.shutter;
+    }
+
+    // image area per unit r dr dphi, solid angle per unit sin(theta) dtheta dphi, the rest is the chain rule
+    fn direction_pdf(&self, _lens_point: &Vec3, dir: &Vec3) -> f64 {
+        let origin = copy(&self.frame.origin);
+        if self.project(&origin, &(&origin + dir)).is_none() {
+            return 0f64;
+        }
+        let local = self.frame.to_local(&dir.normalize());
+        let theta = (-local.z).max(-1f64).min(1f64).acos();
+        let r = self.radius(theta);
+        let derivative = self.theta_derivative(r);
+        // r / sin(theta) goes to 1 / derivative on the axis
+        let radial = if theta > 1e-6f64 { r / theta.sin() } else { 1f64 / derivative };
+        return radial / (derivative * 4f64 * self.aspect_ratio);
+    }
+
+    fn project(&self, lens_point: &Vec3, point: &Vec3) -> Option<(f64, f64)> {
+        let local = self.frame.to_local(&(point - lens_point).normalize());
+        let theta = (-local.z).max(-1f64).min(1f64).acos();
+        if theta >= consts::PI {
+            return None;
+        }
+        let r = self.radius(theta);
+        let sin_theta = theta.sin();
+        let (cos_phi, sin_phi) = if sin_theta > 0f64 { (local.x / sin_theta, local.y / sin_theta) } else { (1f64, 0f64) };
+        let x = r * cos_phi;
+        let y = r * sin_phi;
+        return in_image(0.5f64 * (x / self.aspect_ratio + 1f64), 0.5f64 * (y + 1f64));
+    }
+}
+
+//
+// EquirectangularCamera
+//
+// the whole sphere around pos, longitude across the image and latitude up it, for 360 panoramas
+// the center of the image looks at look_at, the image should be twice as wide as it is tall
+pub struct EquirectangularCamera {
+    frame: Frame,
+    pub shutter: Shutter,
+}
+
+impl EquirectangularCamera {
+    pub fn new(pos: &Vec3, look_at: &Vec3, up: &Vec3) -> EquirectangularCamera {
+        // level with the horizon whatever look_at is, only its heading matters
+        let v = up.normalize();
+        let back = pos - look_at;
+        let w = (&back - &(back.dot(&v) * &v)).normalize();
+        let u = v.cross(&w).normalize();
+        return EquirectangularCamera {
+            frame: Frame {
+                origin: copy(pos),
+                u: u,
+                v: v,
+                w: w,
+            },
+            shutter: Shutter::new(),
+        };
+    }
+}
+
+impl Camera for EquirectangularCamera {
+    fn get_ray(&self, s: f64, t: f64) -> Ray {
+        let phi = (s - 0.5f64) * 2f64 * consts::PI;
+        let latitude = (t - 0.5f64) * consts::PI;
+        let dir = self.frame.to_world(
+            latitude.cos() * phi.sin(),
+            latitude.sin(),
+            -latitude.cos() * phi.cos()
         );
+        return Ray::new(copy(&self.frame.origin), dir);
+    }
+
+    fn get_origin(&self) -> Vec3 {
+        return copy(&self.frame.origin);
+    }
+
+    fn shutter(&self) -> &Shutter {
+        return &self.shutter;
+    }
+
+    fn shutter_mut(&mut self) -> &mut Shutter {
+        return &mut self.shutter;
+    }
+
+    // 2 pi by pi of angles, squeezed by cos(latitude) towards the poles
+    fn direction_pdf(&self, _lens_point: &Vec3, dir: &Vec3) -> f64 {
+        let local = self.frame.to_local(&dir.normalize());
+        let cos_latitude = (local.x * local.x + local.z * local.z).sqrt();
+        if cos_latitude <= 0f64 {
+            return 0f64;
+        }
+        return 1f64 / (2f64 * consts::PI * consts::PI * cos_latitude);
+    }
+
+    fn project(&self, lens_point: &Vec3, point: &Vec3) -> Option<(f64, f64)> {
+        let local = self.frame.to_local(&(point - lens_point).normalize());
+        let phi = local.x.atan2(-local.z);
+        let latitude = local.y.max(-1f64).min(1f64).asin();
+        return in_image(phi / (2f64 * consts::PI) + 0.5f64, latitude / consts::PI + 0.5f64);
+    }
+}
+
+//
+// CylindricalCamera
+//
+// longitude across the image like a panorama, but straight up the image like a perspective camera
+// so verticals stay vertical. horizontal fov can go all the way to 360
+pub struct CylindricalCamera {
+    frame: Frame,
+    horizontal_fov: f64,
+    half_height: f64,
+    pub shutter: Shutter,
+}
+
+impl CylindricalCamera {
+    pub fn new(
+        pos: &Vec3,
+        look_at: &Vec3,
+        up: &Vec3,
+        horizontal_fov_degrees: f64,
+        vertical_fov_degrees: f64
+    ) -> CylindricalCamera {
+        let vertical_fov = (vertical_fov_degrees * consts::PI / 180f64).min(consts::PI * 0.99f64);
+        return CylindricalCamera {
+            frame: Frame::new(pos, look_at, up),
+            horizontal_fov: (horizontal_fov_degrees * consts::PI / 180f64).min(2f64 * consts::PI),
+            half_height: (0.5f64 * vertical_fov).tan(),
+            shutter: Shutter::new(),
+        };
+    }
+}
+
+impl Camera for CylindricalCamera {
+    fn get_ray(&self, s: f64, t: f64) -> Ray {
+        let phi = (s - 0.5f64) * self.horizontal_fov;
+        let height = (2f64 * t - 1f64) * self.half_height;
+        let dir = self.frame.to_world(phi.sin(), height, -phi.cos());
+        return Ray::new(copy(&self.frame.origin), dir);
+    }
+
+    fn get_origin(&self) -> Vec3 {
+        return copy(&self.frame.origin);
+    }
+
+    fn shutter(&self) -> &Shutter {
+        return &self.shutter;
+    }
+
+    fn shutter_mut(&mut self) -> &mut Shutter {
+        return &mut self.shutter;
+    }
+
+    // the image is a unit cylinder around the camera, seen at distance r and cosine 1 / r
+    fn direction_pdf(&self, _lens_point: &Vec3, dir: &Vec3) -> f64 {
+        let origin = copy(&self.frame.origin);
+        if self.project(&origin, &(&origin + dir)).is_none() {
+            return 0f64;
+        }
+        let local = self.frame.to_local(dir);
+        let height = local.y / (local.x * local.x + local.z * local.z).sqrt();
+        let r = (1f64 + height * height).sqrt();
+        return r * r * r / (self.horizontal_fov * 2f64 * self.half_height);
+    }
+
+    fn project(&self, lens_point: &Vec3, point: &Vec3) -> Option<(f64, f64)> {
+        let local = self.frame.to_local(&(point - lens_point));
+        let horizontal = (local.x * local.x + local.z * local.z).sqrt();
+        if horizontal <= 0f64 {
+            return None;
+        }
+        let phi = local.x.atan2(-local.z);
+        let height = local.y / horizontal;
+        return in_image(phi / self.horizontal_fov + 0.5f64, 0.5f64 * (height / self.half_height + 1f64));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pos() -> Vec3 {
+        return Vec3::new(1f64, 2f64, 3f64);
+    }
+
+    fn look_at() -> Vec3 {
+        return Vec3::new(0f64, 1.5f64, -2f64);
+    }
+
+    fn up() -> Vec3 {
+        return Vec3::new(0f64, 1f64, 0f64);
+    }
+
+    // one of each projection with a single center of projection, the perspective one a pinhole
+    fn central_cameras() -> Vec<(&'static str, Box<Camera>)> {
+        return vec![
+            ("perspective", Box::new(PerspectiveCamera::new(&pos(), &look_at(), up(), 40f64, 1.5f64, 0f64, 5f64, (0f64, 0f64), (0f64, 0f64))) as Box<Camera>),
+            ("equidistant fisheye", Box::new(FisheyeCamera::new(&pos(), &look_at(), &up(), 180f64, 1.5f64, FisheyeMapping::Equidistant))),
+            ("equisolid fisheye", Box::new(FisheyeCamera::new(&pos(), &look_at(), &up(), 150f64, 1f64, FisheyeMapping::Equisolid))),
+            ("equirectangular", Box::new(EquirectangularCamera::new(&pos(), &look_at(), &up()))),
+            ("cylindrical", Box::new(CylindricalCamera::new(&pos(), &look_at(), &up(), 270f64, 60f64))),
+        ];
+    }
+
+    #[test]
+    fn project_undoes_get_ray() {
+        for (name, camera) in central_cameras() {
+            let origin = camera.get_origin();
+            for i in 0..9 {
+                for j in 0..9 {
+                    let (s, t) = ((i as f64 + 0.5f64) / 9f64, (j as f64 + 0.5f64) / 9f64);
+                    let ray = camera.get_ray(s, t);
+                    let point = &ray.origin + &(3f64 * &ray.dir);
+                    let (ps, pt) = camera.project(&origin, &point).expect(name);
+                    assert!((ps - s).abs() < 1e-9f64 && (pt - t).abs() < 1e-9f64, "{} at {}, {} gave {}, {}", name, s, t, ps, pt);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn direction_pdf_integrates_to_one_over_the_view() {
+        // directions spread evenly over the sphere on a fibonacci spiral
+        let count = 400000;
+        let golden_angle = consts::PI * (3f64 - 5f64.sqrt());
+        for (name, camera) in central_cameras() {
+            let origin = camera.get_origin();
+            let mut integral = 0f64;
+            for i in 0..count {
+                let z = 1f64 - (2f64 * i as f64 + 1f64) / count as f64;
+                let radius = (1f64 - z * z).sqrt();
+                let phi = golden_angle * i as f64;
+                let dir = Vec3::new(radius * phi.cos(), radius * phi.sin(), z);
+                if camera.project(&origin, &(&origin + &dir)).is_some() {
+                    integral += camera.direction_pdf(&origin, &dir);
+                }
+            }
+            integral *= 4f64 * consts::PI / count as f64;
+            assert!((integral - 1f64).abs() < 0.01f64, "{} integrates to {}", name, integral);
+        }
+    }
+
+    // the direction from lens_point that get_ray takes for s, t: towards where the pinhole ray is sharp
+    fn lens_direction(camera: &PerspectiveCamera, lens_point: &Vec3, s: f64, t: f64) -> Vec3 {
+        let on_image = &(&camera.lower_left + &(s * &camera.horizontal)) + &(t * &camera.vertical);
+        let sharp = camera.focus_point(&camera.origin, &(&on_image - &camera.origin)).unwrap();
+        return (&sharp - lens_point).normalize();
+    }
+
+    #[test]
+    fn direction_pdf_follows_a_shifted_and_tilted_lens() {
+        let camera = PerspectiveCamera::new(&pos(), &look_at(), up(), 40f64, 1.5f64, 0.5f64, 4f64, (0.1f64, -0.05f64), (12f64, -8f64));
+        let lens_points = vec![
+            camera.get_origin(),
+            &camera.origin + &(0.2f64 * &camera.u),
+            &(&camera.origin - &(0.15f64 * &camera.u)) + &(0.2f64 * &camera.v),
+        ];
+        // the shift slides the image and leaves the view direction be, straight ahead is off center now
+        let ahead = &pos() + &(4f64 * &(&look_at() - &pos()).normalize());
+        let (s, t) = camera.project(&camera.get_origin(), &ahead).unwrap();
+        assert!((s - 0.4f64).abs() < 1e-9f64 && (t - 0.55f64).abs() < 1e-9f64, "straight ahead at {}, {}", s, t);
+        let h = 1e-5f64;
+        for lens_point in lens_points.iter() {
+            for &(s, t) in [(0.5f64, 0.5f64), (0.1f64, 0.9f64), (0.85f64, 0.2f64), (0.95f64, 0.95f64)].iter() {
+                // solid angle swept per unit of image, by central differences
+                let ds = &(&lens_direction(&camera, lens_point, s + h, t) - &lens_direction(&camera, lens_point, s - h, t)) * (0.5f64 / h);
+                let dt = &(&lens_direction(&camera, lens_point, s, t + h) - &lens_direction(&camera, lens_point, s, t - h)) * (0.5f64 / h);
+                let solid_angle = ds.cross(&dt).length_squared().sqrt();
+                let pdf = camera.direction_pdf(lens_point, &lens_direction(&camera, lens_point, s, t));
+                assert!((pdf * solid_angle - 1f64).abs() < 1e-5f64, "pdf {} against {} at {}, {}", pdf, 1f64 / solid_angle, s, t);
+            }
+        }
+    }
+
+    #[test]
+    fn orthographic_rays_are_parallel_and_fill_the_view() {
+        let camera = OrthographicCamera::new(&pos(), &look_at(), &up(), 2f64, 2f64);
+        let forward = (&look_at() - &pos()).normalize();
+        let corner = camera.get_ray(0f64, 0f64);
+        let center = camera.get_ray(0.5f64, 0.5f64);
+        assert!((center.dir.normalize().dot(&forward) - 1f64).abs() < 1e-12f64);
+        assert!((corner.dir.normalize().dot(&forward) - 1f64).abs() < 1e-12f64);
+        assert!((&center.origin - &pos()).length_squared() < 1e-24f64);
+        // the corner is half the 4 x 2 view across and half of it down
+        assert!(((&corner.origin - &center.origin).length_squared() - 5f64).abs() < 1e-12f64);
+        assert!(camera.is_delta());
+    }
+
+    #[test]
+    fn lens_numbers_set_the_field_of_view_and_f_number() {
+        let lens = PhysicalLens { focal_length_mm: 50f64, f_number: 2f64, sensor_height_mm: 24f64 };
+        // 2 atan(12 / 50)
+        assert!((lens.vertical_fov_degrees() - 26.9915f64).abs() < 1e-4f64);
+        assert!((lens.aperture_diameter() - 0.025f64).abs() < 1e-12f64);
+        let mut camera = PerspectiveCamera::from_lens(&pos(), &look_at(), up(), &lens, 1.5f64, 4f64, (0f64, 0f64), (0f64, 0f64));
+        assert!((camera.f_number().unwrap() - 2f64).abs() < 1e-12f64);
+        camera.set_f_number(8f64);
+        assert!((camera.f_number().unwrap() - 8f64).abs() < 1e-12f64);
+        assert!((camera.lens_radius - 0.003125f64).abs() < 1e-12f64);
+        let pinhole = PerspectiveCamera::new(&pos(), &look_at(), up(), 40f64, 1.5f64, 0f64, 4f64, (0f64, 0f64), (0f64, 0f64));
+        assert!(pinhole.f_number().is_none());
+    }
+
+    #[test]
+    fn rays_through_the_lens_meet_on_the_plane_in_focus() {
+        let mut camera = PerspectiveCamera::new(&pos(), &look_at(), up(), 40f64, 1.5f64, 0.5f64, 4f64, (0f64, 0f64), (0f64, 0f64));
+        camera.set_shutter(0f64, 1f64);
+        let forward = (&look_at() - &pos()).normalize();
+        for _ in 0..20 {
+            let ray = camera.get_ray(0.3f64, 0.7f64);
+            // as far along the view direction as the plane in focus is
+            let time = (4f64 - (&ray.origin - &pos()).dot(&forward)) / ray.dir.dot(&forward);
+            let sharp = &ray.origin + &(time * &ray.dir);
+            let (s, t) = camera.project(&ray.origin, &sharp).unwrap();
+            assert!((s - 0.3f64).abs() < 1e-9f64 && (t - 0.7f64).abs() < 1e-9f64);
+            let time = camera.sample_shutter_time();
+            assert!(time >= 0f64 && time <= 1f64);
+        }
     }
 }
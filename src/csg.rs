@@ -0,0 +1,325 @@
+extern crate rusty_math;
+
+use rusty_math::*;
+use std::f64;
+
+use bvh::Aabb;
+use renderable::Solid;
+use renderable::HitInterval;
+use renderable::Material;
+
+#[derive(Clone, Copy)]
+pub enum CsgOperation {
+    Union,
+    Intersection,
+    // left minus right
+    Difference,
+}
+
+// a span of a combined solid, each boundary remembers which operand it came from
+pub struct CsgSpan<'a> {
+    pub enter_time: f64,
+    pub enter_normal: Vec3,
+    pub enter_material: &'a Material,
+    pub exit_time: f64,
+    pub exit_normal: Vec3,
+    pub exit_material: &'a Material,
+}
+
+pub enum CsgNode<'a> {
+    Leaf {
+        solid: Box<Solid + 'a>,
+        material: &'a Material,
+    },
+    Operation {
+        operation: CsgOperation,
+        left: Box<CsgNode<'a>>,
+        right: Box<CsgNode<'a>>,
+    },
+}
+
+impl<'a> CsgNode<'a> {
+    pub fn leaf<S: Solid + 'a>(solid: S, material: &'a Material) -> CsgNode<'a> {
+        return CsgNode::Leaf {
+            solid: Box::new(solid),
+            material: material,
+        };
+    }
+
+    pub fn union(left: CsgNode<'a>, right: CsgNode<'a>) -> CsgNode<'a> {
+        return CsgNode::operation(CsgOperation::Union, left, right);
+    }
+
+    pub fn intersection(left: CsgNode<'a>, right: CsgNode<'a>) -> CsgNode<'a> {
+        return CsgNode::operation(CsgOperation::Intersection, left, right);
+    }
+
+    pub fn difference(left: CsgNode<'a>, right: CsgNode<'a>) -> CsgNode<'a> {
+        return CsgNode::operation(CsgOperation::Difference, left, right);
+    }
+
+    fn operation(operation: CsgOperation, left: CsgNode<'a>, right: CsgNode<'a>) -> CsgNode<'a> {
+        return CsgNode::Operation {
+            operation: operation,
+            left: Box::new(left),
+            right: Box::new(right),
+        };
+    }
+
+    pub fn get_bounds(&self) -> Aabb {
+        match *self {
+            CsgNode::Leaf { ref solid, .. } => return solid.get_bounds(),
+            CsgNode::Operation { operation, ref left, ref right } => {
+                return match operation {
+                    CsgOperation::Union => left.get_bounds().union(&right.get_bounds()),
+                    CsgOperation::Intersection => left.get_bounds().intersection(&right.get_bounds()),
+                    CsgOperation::Difference => left.get_bounds(),
+                };
+            }
+        }
+    }
+
+    pub fn for_each_material<F>(&self, found: &mut F)
+        where F: FnMut(&'a Material)
+    {
+        match *self {
+            CsgNode::Leaf { material, .. } => found(material),
+            CsgNode::Operation { ref left, ref right, .. } => {
+                left.for_each_material(found);
+                right.for_each_material(found);
+            }
+        }
+    }
+
+    // sorted, disjoint spans of the whole node along the ray
+    pub fn get_spans(&self, ray: &Ray, spans: &mut Vec<CsgSpan<'a>>) {
+        match *self {
+            CsgNode::Leaf { ref solid, material } => {
+                let mut intervals: Vec<HitInterval> = Vec::new();
+                solid.get_hit_intervals(ray, &mut intervals);
+                for interval in intervals {
+                    spans.push(CsgSpan {
+                        enter_time: interval.enter_time,
+                        enter_normal: interval.enter_normal,
+                        enter_material: material,
+                        exit_time: interval.exit_time,
+                        exit_normal: interval.exit_normal,
+                        exit_material: material,
+                    });
+                }
+            }
+            CsgNode::Operation { operation, ref left, ref right } => {
+                let mut left_spans = Vec::new();
+                let mut right_spans = Vec::new();
+                left.get_spans(ray, &mut left_spans);
+                // nothing can survive an empty left side unless we are a union
+                match operation {
+                    CsgOperation::Union => {}
+                    _ => {
+                        if left_spans.is_empty() {
+                            return;
+                        }
+                    }
+                }
+                right.get_spans(ray, &mut right_spans);
+                combine(operation, &left_spans, &right_spans, spans);
+            }
+        }
+    }
+
+    pub fn get_hit_time(&self, ray: &Ray, time_min: f64, time_max: f64) -> f64 {
+        let mut spans = Vec::new();
+        self.get_spans(ray, &mut spans);
+        for span in spans.iter() {
+            if span.enter_time > time_min && span.enter_time < time_max {
+                return span.enter_time;
+            }
+            if span.enter_time <= time_min && span.exit_time > time_min && span.exit_time < time_max {
+                return span.exit_time;
+            }
+        }
+        return time_min;
+    }
+
+    // normal and material of the boundary the ray crossed at hit_time
+    pub fn get_surface(&self, ray: &Ray, hit_time: f64) -> (Vec3, &'a Material) {
+        let mut spans = Vec::new();
+        self.get_spans(ray, &mut spans);
+
+        let mut best_distance = f64::MAX;
+        let mut best_normal = Vec3::new(0f64, 1f64, 0f64);
+        let mut best_material: Option<&'a Material> = None;
+        for span in spans.iter() {
+            if (span.enter_time - hit_time).abs() < best_distance {
+                best_distance = (span.enter_time - hit_time).abs();
+                best_normal = Vec3::new(span.enter_normal.x, span.enter_normal.y, span.enter_normal.z);
+                best_material = Some(span.enter_material);
+            }
+            if (span.exit_time - hit_time).abs() < best_distance {
+                best_distance = (span.exit_time - hit_time).abs();
+                best_normal = Vec3::new(span.exit_normal.x, span.exit_normal.y, span.exit_normal.z);
+                best_material = Some(span.exit_material);
+            }
+        }
+        return (best_normal, best_material.unwrap_or_else(|| self.get_any_material()));
+    }
+
+    fn get_any_material(&self) -> &'a Material {
+        match *self {
+            CsgNode::Leaf { material, .. } => return material,
+            CsgNode::Operation { ref left, .. } => return left.get_any_material(),
+        }
+    }
+}
+
+fn is_inside(operation: CsgOperation, in_left: bool, in_right: bool) -> bool {
+    return match operation {
+        CsgOperation::Union => in_left || in_right,
+        CsgOperation::Intersection => in_left && in_right,
+        CsgOperation::Difference => in_left && !in_right,
+    };
+}
+
+struct SpanEvent<'b, 'a: 'b> {
+    time: f64,
+    from_left: bool,
+    entering: bool,
+    normal: &'b Vec3,
+    material: &'a Material,
+}
+
+// walk both span lists in order and emit a boundary wherever the combined inside-ness flips
+fn combine<'a>(
+    operation: CsgOperation,
+    left: &[CsgSpan<'a>],
+    right: &[CsgSpan<'a>],
+    spans: &mut Vec<CsgSpan<'a>>
+) {
+    let mut events: Vec<SpanEvent> = Vec::with_capacity(2 * (left.len() + right.len()));
+    for &(list, from_left) in [(left, true), (right, false)].iter() {
+        for span in list.iter() {
+            events.push(SpanEvent {
+                time: span.enter_time,
+                from_left: from_left,
+                entering: true,
+                normal: &span.enter_normal,
+                material: span.enter_material,
+            });
+            events.push(SpanEvent {
+                time: span.exit_time,
+                from_left: from_left,
+                entering: false,
+                normal: &span.exit_normal,
+                material: span.exit_material,
+            });
+        }
+    }
+    events.sort_by(|a, b| a.time.partial_cmp(&b.time).unwrap_or(::std::cmp::Ordering::Equal));
+
+    let mut in_left = false;
+    let mut in_right = false;
+    let mut open: Option<(f64, Vec3, &'a Material)> = None;
+    for event in events.iter() {
+        let was_inside = is_inside(operation, in_left, in_right);
+        if event.from_left {
+            in_left = event.entering;
+        } else {
+            in_right = event.entering;
+        }
+        let now_inside = is_inside(operation, in_left, in_right);
+        if was_inside == now_inside {
+            continue;
+        }
+
+        // the subtracted operand is inside out, so its normals flip on the carved surface
+        let flip = match operation {
+            CsgOperation::Difference => !event.from_left,
+            _ => false,
+        };
+        let sign = if flip { -1f64 } else { 1f64 };
+        let normal = sign * event.normal;
+
+        if now_inside {
+            open = Some((event.time, normal, event.material));
+        } else if let Some((enter_time, enter_normal, enter_material)) = open.take() {
+            spans.push(CsgSpan {
+                enter_time: enter_time,
+                enter_normal: enter_normal,
+                enter_material: enter_material,
+                exit_time: event.time,
+                exit_normal: normal,
+                exit_material: event.material,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use renderable::shapes::Sphere;
+    use renderable::shapes::Quadric;
+    use renderable::materials::Lambertian;
+
+    // unit spheres on the x axis, overlapping between x = 0 and x = 1
+    fn sphere_at(x: f64) -> Sphere {
+        return Sphere { center: Vec3::new(x, 0f64, 0f64), radius: 1f64 };
+    }
+
+    fn spans_along_x(node: &CsgNode) -> Vec<(f64, f64)> {
+        let mut spans = Vec::new();
+        node.get_spans(&Ray::new(Vec3::new(-5f64, 0f64, 0f64), Vec3::new(1f64, 0f64, 0f64)), &mut spans);
+        return spans.iter().map(|span| (span.enter_time - 5f64, span.exit_time - 5f64)).collect();
+    }
+
+    fn assert_spans(found: &[(f64, f64)], expected: &[(f64, f64)]) {
+        assert_eq!(found.len(), expected.len(), "{:?}", found);
+        for (a, b) in found.iter().zip(expected.iter()) {
+            assert!((a.0 - b.0).abs() < 1e-9f64 && (a.1 - b.1).abs() < 1e-9f64, "{:?} != {:?}", found, expected);
+        }
+    }
+
+    #[test]
+    fn operations_combine_the_spans_of_their_operands() {
+        let grey = Lambertian { albedo: Vec3::new(0.5f64, 0.5f64, 0.5f64) };
+        let union = CsgNode::union(CsgNode::leaf(sphere_at(0f64), &grey), CsgNode::leaf(sphere_at(1f64), &grey));
+        assert_spans(&spans_along_x(&union), &[(-1f64, 2f64)]);
+        let intersection = CsgNode::intersection(CsgNode::leaf(sphere_at(0f64), &grey), CsgNode::leaf(sphere_at(1f64), &grey));
+        assert_spans(&spans_along_x(&intersection), &[(0f64, 1f64)]);
+        let difference = CsgNode::difference(CsgNode::leaf(sphere_at(0f64), &grey), CsgNode::leaf(sphere_at(1f64), &grey));
+        assert_spans(&spans_along_x(&difference), &[(-1f64, 0f64)]);
+        // disjoint operands stay two pieces
+        let apart = CsgNode::union(CsgNode::leaf(sphere_at(-1.5f64), &grey), CsgNode::leaf(sphere_at(1.5f64), &grey));
+        assert_spans(&spans_along_x(&apart), &[(-2.5f64, -0.5f64), (0.5f64, 2.5f64)]);
+    }
+
+    #[test]
+    fn carved_surfaces_take_the_normal_and_material_of_the_cutter() {
+        let outer = Lambertian { albedo: Vec3::new(1f64, 0f64, 0f64) };
+        let carved = Lambertian { albedo: Vec3::new(0f64, 0f64, 1f64) };
+        let difference = CsgNode::difference(CsgNode::leaf(sphere_at(0f64), &outer), CsgNode::leaf(sphere_at(1f64), &carved));
+        // from the right the ray passes through the bite and hits the wall it left at x = 0
+        let ray = Ray::new(Vec3::new(5f64, 0f64, 0f64), Vec3::new(-1f64, 0f64, 0f64));
+        let time = difference.get_hit_time(&ray, 1e-6f64, f64::MAX);
+        assert!((time - 5f64).abs() < 1e-9f64);
+        let (normal, material) = difference.get_surface(&ray, time);
+        // the cutter's normal is flipped so it faces out of what is left
+        assert!((normal.x - 1f64).abs() < 1e-9f64);
+        assert!(material as *const Material as *const u8 == &carved as *const Lambertian as *const u8);
+    }
+
+    #[test]
+    fn bounds_follow_the_operation() {
+        let grey = Lambertian { albedo: Vec3::new(0.5f64, 0.5f64, 0.5f64) };
+        let union = CsgNode::union(CsgNode::leaf(sphere_at(0f64), &grey), CsgNode::leaf(sphere_at(1f64), &grey));
+        let bounds = union.get_bounds();
+        assert!((bounds.min.x + 1f64).abs() < 1e-9f64 && (bounds.max.x - 2f64).abs() < 1e-9f64);
+        // an unbounded cone clipped by a bounded ellipsoid ends up bounded
+        let clipped = CsgNode::intersection(
+            CsgNode::leaf(Quadric::cone_y(&Vec3::new(0f64, 0f64, 0f64), 1f64), &grey),
+            CsgNode::leaf(Quadric::ellipsoid(&Vec3::new(0f64, 1f64, 0f64), &Vec3::new(1f64, 0.5f64, 2f64)), &grey)
+        );
+        let bounds = clipped.get_bounds();
+        assert!((bounds.min.y - 0.5f64).abs() < 1e-9f64 && (bounds.max.z - 2f64).abs() < 1e-9f64);
+    }
+}
@@ -0,0 +1,286 @@
+use std::f64;
+
+use render_buffer::RenderBufferLayers;
+use aov::Aov;
+
+// how much of the expected noise is taken off a colour difference before it counts against a neighbour
+static VARIANCE_CANCELLATION: f64 = 1f64;
+
+//
+// Denoiser
+//
+// joint non-local means after Rousselle et al. 2012. every pixel becomes a weighted average of its
+// neighbours, a neighbour counts when the patches around both pixels look alike once the noise is taken
+// into account, and when the albedo, normal and depth features (which are nearly noise free) agree.
+// the noise comes from the two beauty halves: they are independent estimates, so their difference says
+// how far the beauty can be off, and noisy pixels get filtered harder than clean ones
+pub struct Denoiser {
+    // neighbours up to this many pixels away can contribute
+    pub search_radius: usize,
+    // half the size of the patches compared around each pair of pixels
+    pub patch_radius: usize,
+    // larger is blurrier, how many standard deviations a patch difference may be put down to noise
+    pub strength: f64,
+    pub albedo_sigma: f64,
+    pub normal_sigma: f64,
+    // relative to the depth of the pixel being filtered
+    pub depth_sigma: f64,
+}
+
+impl Denoiser {
+    pub fn new() -> Denoiser {
+        return Denoiser {
+            search_radius: 7,
+            patch_radius: 3,
+            strength: 1f64,
+            albedo_sigma: 0.1f64,
+            normal_sigma: 0.3f64,
+            depth_sigma: 0.05f64,
+        };
+    }
+
+    // the aovs denoise needs besides the beauty
+    pub fn aovs() -> Vec<Aov> {
+        return vec![Aov::Albedo, Aov::Normal, Aov::Depth, Aov::BeautyHalfA, Aov::BeautyHalfB];
+    }
+
+    // filters the beauty into a new "denoised" layer and returns it. None without the layers of Denoiser::aovs
+    // or when the second half never got a sample
+    pub fn denoise(&self, layers: &mut RenderBufferLayers) -> Option<usize> {
+        let beauty = layers.find_layer("beauty")?;
+        let half_a = layers.find_layer(Aov::BeautyHalfA.name())?;
+        let half_b = layers.find_layer(Aov::BeautyHalfB.name())?;
+        let albedo = layers.find_layer(Aov::Albedo.name())?;
+        let normal = layers.find_layer(Aov::Normal.name())?;
+        let depth = layers.find_layer(Aov::Depth.name())?;
+        if layers.data(half_b).iter().all(|value| *value == 0f64) {
+            return None;
+        }
+
+        let width = layers.width;
+        let height = layers.height;
+        let count = width * height;
+        let color = layers.data(beauty);
+        let variance = self.estimate_variance(layers.data(half_a), layers.data(half_b), width, height);
+        let albedo = layers.data(albedo);
+        let normal = layers.data(normal);
+        let depth = layers.data(depth);
+
+        let radius = self.search_radius as i64;
+        let k_squared = self.strength * self.strength;
+        let mut sum = vec![0f64; count * 3];
+        let mut weight_sum = vec![0f64; count];
+        let mut distance = vec![0f64; count];
+        for dy in -radius..(radius + 1) {
+            for dx in -radius..(radius + 1) {
+                // colour distance between every pixel and its neighbour at dx, dy, clamped at the borders so
+                // patches that hang over the edge still have something to compare
+                for y in 0..height {
+                    let qy = clamp(y as i64 + dy, height);
+                    for x in 0..width {
+                        let qx = clamp(x as i64 + dx, width);
+                        let p = y * width + x;
+                        let q = qy * width + qx;
+                        let mut d = 0f64;
+                        for c in 0..3 {
+                            let vp = variance[p * 3 + c];
+                            let vq = variance[q * 3 + c];
+                            let difference = color[p * 3 + c] - color[q * 3 + c];
+                            d += (difference * difference - VARIANCE_CANCELLATION * (vp + vp.min(vq)))
+                                / (1e-10f64 + k_squared * (vp + vq));
+                        }
+                        distance[p] = d / 3f64;
+                    }
+                }
+                let patch_distance = box_filter(&distance, 1, width, height, self.patch_radius);
+
+                for y in 0..height {
+                    let qy = y as i64 + dy;
+                    if qy < 0 || qy >= height as i64 {
+                        continue;
+                    }
+                    for x in 0..width {
+                        let qx = x as i64 + dx;
+                        if qx < 0 || qx >= width as i64 {
+                            continue;
+                        }
+                        let p = y * width + x;
+                        let q = qy as usize * width + qx as usize;
+                        let mut weight = (-patch_distance[p].max(0f64)).exp();
+                        if weight <= 0f64 {
+                            continue;
+                        }
+                        weight *= self.feature_weight(albedo, normal, depth, p, q);
+                        for c in 0..3 {
+                            sum[p * 3 + c] += weight * color[q * 3 + c];
+                        }
+                        weight_sum[p] += weight;
+                    }
+                }
+            }
+        }
+
+        let mut denoised = vec![0f64; count * 3];
+        for p in 0..count {
+            for c in 0..3 {
+                // the pixel itself always has weight one, weight_sum is never zero
+                denoised[p * 3 + c] = sum[p * 3 + c] / weight_sum[p];
+            }
+        }
+        let layer = layers.add_layer("denoised", 3);
+        for y in 0..height {
+            for x in 0..width {
+                let p = y * width + x;
+                layers.set(layer, x, y, &denoised[p * 3..p * 3 + 3]);
+            }
+        }
+        return Some(layer);
+    }
+
+    // variance of the full beauty per pixel and channel. two halves of n / 2 samples differ by 4 times
+    // that on average, a single pixel's difference is a poor guess so it gets smoothed over its neighbours
+    fn estimate_variance(&self, half_a: &[f64], half_b: &[f64], width: usize, height: usize) -> Vec<f64> {
+        let raw: Vec<f64> = half_a.iter().zip(half_b.iter()).map(|(a, b)| {
+            return (a - b) * (a - b) / 4f64;
+        }).collect();
+        return box_filter(&raw, 3, width, height, 1);
+    }
+
+    fn feature_weight(&self, albedo: &[f64], normal: &[f64], depth: &[f64], p: usize, q: usize) -> f64 {
+        let mut albedo_distance = 0f64;
+        let mut normal_distance = 0f64;
+        for c in 0..3 {
+            let da = albedo[p * 3 + c] - albedo[q * 3 + c];
+            let dn = normal[p * 3 + c] - normal[q * 3 + c];
+            albedo_distance += da * da;
+            normal_distance += dn * dn;
+        }
+        let mut weight = (-albedo_distance / (self.albedo_sigma * self.albedo_sigma)).exp()
+            * (-normal_distance / (self.normal_sigma * self.normal_sigma)).exp();
+
+        // escaped rays have infinite depth, they only match each other
+        let (zp, zq) = (depth[p], depth[q]);
+        if zp.is_infinite() || zq.is_infinite() {
+            if zp.is_infinite() != zq.is_infinite() {
+                return 0f64;
+            }
+        } else {
+            let dz = (zp - zq) / (self.depth_sigma * zp).max(1e-6f64);
+            weight *= (-dz * dz).exp();
+        }
+        return weight;
+    }
+}
+
+fn clamp(coordinate: i64, size: usize) -> usize {
+    return coordinate.max(0).min(size as i64 - 1) as usize;
+}
+
+// mean over the (2 radius + 1) square around every pixel, shrunk at the borders
+// channels values per pixel, separable so the cost does not grow with the radius
+fn box_filter(values: &[f64], channels: usize, width: usize, height: usize, radius: usize) -> Vec<f64> {
+    let mut rows = vec![0f64; values.len()];
+    let mut prefix = vec![0f64; width.max(height) + 1];
+    for y in 0..height {
+        for c in 0..channels {
+            for x in 0..width {
+                prefix[x + 1] = prefix[x] + values[(y * width + x) * channels + c];
+            }
+            for x in 0..width {
+                let low = x.saturating_sub(radius);
+                let high = (x + radius + 1).min(width);
+                rows[(y * width + x) * channels + c] = (prefix[high] - prefix[low]) / (high - low) as f64;
+            }
+        }
+    }
+    let mut result = vec![0f64; values.len()];
+    for x in 0..width {
+        for c in 0..channels {
+            for y in 0..height {
+                prefix[y + 1] = prefix[y] + rows[(y * width + x) * channels + c];
+            }
+            for y in 0..height {
+                let low = y.saturating_sub(radius);
+                let high = (y + radius + 1).min(height);
+                result[(y * width + x) * channels + c] = (prefix[high] - prefix[low]) / (high - low) as f64;
+            }
+        }
+    }
+    return result;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand;
+    use rand::Rng;
+
+    // a flat grey wall seen head on, every beauty half is the grey plus its own noise
+    fn noisy_wall(width: usize, height: usize, noise: f64) -> RenderBufferLayers {
+        let mut layers = RenderBufferLayers::new(width, height);
+        let beauty = layers.add_layer("beauty", 3);
+        let half_a = layers.add_layer(Aov::BeautyHalfA.name(), 3);
+        let half_b = layers.add_layer(Aov::BeautyHalfB.name(), 3);
+        let albedo = layers.add_layer(Aov::Albedo.name(), 3);
+        let normal = layers.add_layer(Aov::Normal.name(), 3);
+        let depth = layers.add_layer(Aov::Depth.name(), 1);
+        let mut rng = rand::thread_rng();
+        for y in 0..height {
+            for x in 0..width {
+                let a: Vec<f64> = (0..3).map(|_| 0.5f64 + noise * (rng.gen::<f64>() - 0.5f64)).collect();
+                let b: Vec<f64> = (0..3).map(|_| 0.5f64 + noise * (rng.gen::<f64>() - 0.5f64)).collect();
+                let mean: Vec<f64> = (0..3).map(|c| 0.5f64 * (a[c] + b[c])).collect();
+                layers.set(beauty, x, y, &mean);
+                layers.set(half_a, x, y, &a);
+                layers.set(half_b, x, y, &b);
+                layers.set(albedo, x, y, &[0.5f64, 0.5f64, 0.5f64]);
+                layers.set(normal, x, y, &[0f64, 0f64, 1f64]);
+                layers.set(depth, x, y, &[2f64]);
+            }
+        }
+        return layers;
+    }
+
+    fn variance(values: &[f64]) -> f64 {
+        let mean = values.iter().sum::<f64>() / values.len() as f64;
+        return values.iter().map(|value| (value - mean) * (value - mean)).sum::<f64>() / values.len() as f64;
+    }
+
+    #[test]
+    fn denoising_a_noisy_wall_takes_out_most_of_the_noise() {
+        let mut layers = noisy_wall(24, 24, 0.4f64);
+        let before = variance(layers.data(layers.find_layer("beauty").unwrap()));
+        let denoised = Denoiser::new().denoise(&mut layers).unwrap();
+        let after = variance(layers.data(denoised));
+        assert!(after < 0.2f64 * before, "variance {} before, {} after", before, after);
+        // averaging noise around the grey keeps the grey
+        let mean = layers.data(denoised).iter().sum::<f64>() / (24 * 24 * 3) as f64;
+        assert!((mean - 0.5f64).abs() < 0.01f64, "mean {}", mean);
+    }
+
+    #[test]
+    fn needs_the_aovs_and_a_second_half() {
+        let mut bare = RenderBufferLayers::new(4, 4);
+        bare.add_layer("beauty", 3);
+        assert!(Denoiser::new().denoise(&mut bare).is_none());
+
+        let mut layers = noisy_wall(4, 4, 0.4f64);
+        let half_b = layers.find_layer(Aov::BeautyHalfB.name()).unwrap();
+        layers.scale(half_b, 0f64);
+        assert!(Denoiser::new().denoise(&mut layers).is_none());
+        assert!(layers.find_layer("denoised").is_none());
+    }
+
+    #[test]
+    fn box_filter_averages_over_the_window_shrunk_at_the_borders() {
+        let values: Vec<f64> = (0..12).map(|i| i as f64).collect();
+        // 4 x 3, one channel
+        let filtered = box_filter(&values, 1, 4, 3, 1);
+        // the corner averages 0, 1, 4, 5
+        assert_eq!(filtered[0], 2.5f64);
+        // the middle of the second row averages rows 0 to 2 and columns 0 to 2
+        assert_eq!(filtered[5], 5f64);
+        // radius 0 changes nothing
+        assert_eq!(box_filter(&values, 1, 4, 3, 0), values);
+    }
+}
@@ -0,0 +1,543 @@
+use std::collections::VecDeque;
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::net::Shutdown;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use filter::Filter;
+use filter::FilmTile;
+use preview::Preview;
+use render_buffer::RenderBufferF64;
+use renderer;
+use renderer::RenderRegion;
+
+//
+// distributed rendering
+//
+// a coordinator cuts an image into regions and hands them out to worker processes over tcp, usually this
+// same binary running on the machines of a farm. the scene is built in code from random numbers, so what
+// the coordinator sends as the scene is its command line and the seed of those numbers, every worker
+// builds the identical world from them. files the scene reads, like lens prescriptions, have to be where
+// the workers run too
+//
+// every message is a line of text, some followed by a binary payload
+//   worker       hello <version>
+//   coordinator  scene <seed> <bytes>                      the arguments, each ended by a 0 byte
+//   worker       ready                                     once the scene is built
+//   coordinator  render <id> <x0> <y0> <x1> <y1> <samples>
+//   worker       tile <id> <x0> <y0> <width> <height> <splats>
+//                the filtered sums around the region: width * height * 3 weighted colors then
+//                width * height weights, followed by the whole image's splats when splats is 1. all
+//                little endian f64, rows bottom up
+//   coordinator  done                                      nothing left, the worker can go
+// a worker that hangs up, sends something that does not fit or goes quiet for longer than the worker
+// timeout while it holds a region gives the region back, the next worker to ask renders it. workers can
+// join at any time, even after others have died
+
+static PROTOCOL_VERSION: u32 = 1;
+
+// square regions this many pixels across, each split into runs of at most REGION_SAMPLES samples per
+// pixel so slow machines never hold up the end of a frame for long
+static REGION_SIZE: usize = 64;
+static REGION_SAMPLES: i32 = 16;
+
+// how often the coordinator looks for new workers while it waits
+static ACCEPT_POLL_MILLISECONDS: u64 = 50;
+
+// everything a worker needs to build the coordinator's scene
+pub struct Scene {
+    pub seed: u32,
+    // the coordinator's command line, less whatever only concerns the coordinator
+    pub args: Vec<String>,
+}
+
+// the regions a width by height image with samples_per_pixel samples is rendered in
+pub fn split(width: usize, height: usize, samples_per_pixel: i32) -> Vec<RenderRegion> {
+    let mut regions: Vec<RenderRegion> = Vec::new();
+    for y0 in (0..height).step_by(REGION_SIZE).rev() {
+        for x0 in (0..width).step_by(REGION_SIZE) {
+            let mut first_sample = 0;
+            while first_sample < samples_per_pixel {
+                let samples = REGION_SAMPLES.min(samples_per_pixel - first_sample);
+                regions.push(RenderRegion {
+                    x0: x0,
+                    y0: y0,
+                    x1: (x0 + REGION_SIZE).min(width),
+                    y1: (y0 + REGION_SIZE).min(height),
+                    samples: samples,
+                });
+                first_sample += samples;
+            }
+        }
+    }
+    return regions;
+}
+
+//
+// coordinator
+//
+struct Coordination {
+    regions: Vec<RenderRegion>,
+    // waiting for a worker, front first
+    pending: VecDeque<usize>,
+    finished: Vec<bool>,
+    remaining: usize,
+    // everything merged so far
+    samples: FilmTile,
+    splats: RenderBufferF64,
+}
+
+struct Coordinator<'a> {
+    m_state: Mutex<Coordination>,
+    m_changed: Condvar,
+    m_scene: &'a Scene,
+    m_filter: Filter,
+    m_width: usize,
+    m_height: usize,
+    m_preview: Option<&'a Preview>,
+    // longest a worker may take over a region, or anything else it has to answer
+    m_worker_timeout: Duration,
+}
+
+// listens on address (a bare port means 127.0.0.1, give 0.0.0.0:port to let other machines in) until
+// workers have rendered every region of a width by height image. returns the filtered sums over the
+// whole image and the splats, like render_region would for the whole thing
+pub fn coordinate(
+    address: &str,
+    scene: &Scene,
+    filter: &Filter,
+    width: usize,
+    height: usize,
+    regions: Vec<RenderRegion>,
+    worker_timeout: Duration,
+    preview: Option<&Preview>
+) -> io::Result<(FilmTile, RenderBufferF64)> {
+    let address = if address.contains(':') { address.to_string() } else { format!("127.0.0.1:{}", address) };
+    let listener = TcpListener::bind(address.as_str())?;
+    // polled, so the coordinator notices it is done without another worker having to connect
+    listener.set_nonblocking(true)?;
+    println!("waiting for workers on {}", address);
+
+    let count = regions.len();
+    let coordinator = Coordinator {
+        m_state: Mutex::new(Coordination {
+            regions: regions,
+            pending: (0..count).collect(),
+            finished: vec![false; count],
+            remaining: count,
+            samples: FilmTile::around(0, 0, width, height, filter, width, height),
+            splats: RenderBufferF64::new(width, height),
+        }),
+        m_changed: Condvar::new(),
+        m_scene: scene,
+        m_filter: *filter,
+        m_width: width,
+        m_height: height,
+        m_preview: preview,
+        m_worker_timeout: worker_timeout,
+    };
+    if let Some(preview) = preview {
+        let samples_per_pixel = coordinator.m_state.lock().unwrap().regions.iter()
+            .filter(|region| region.x0 == 0 && region.y0 == 0)
+            .map(|region| region.samples)
+            .sum();
+        preview.begin(width, height, samples_per_pixel);
+    }
+
+    // streams of every worker that ever connected, shut down at the end so no thread stays stuck reading
+    let mut streams: Vec<TcpStream> = Vec::new();
+    thread::scope(|scope| {
+        let coordinator = &coordinator;
+        loop {
+            if coordinator.m_state.lock().unwrap().remaining == 0 {
+                break;
+            }
+            match listener.accept() {
+                Ok((stream, peer)) => {
+                    if stream.set_nonblocking(false).is_err() {
+                        continue;
+                    }
+                    if let Ok(clone) = stream.try_clone() {
+                        streams.push(clone);
+                    }
+                    println!("worker {} joined", peer);
+                    scope.spawn(move || {
+                        match coordinator.serve_worker(stream) {
+                            Err(why) => println!("lost worker {}: {}", peer, why),
+                            Ok(_) => println!("worker {} is done", peer),
+                        };
+                    });
+                }
+                Err(ref why) if why.kind() == io::ErrorKind::WouldBlock => {
+                    thread::sleep(Duration::from_millis(ACCEPT_POLL_MILLISECONDS));
+                }
+                Err(why) => println!("couldn't accept a worker: {}", why),
+            }
+        }
+        // wakes workers waiting for a region, they tell their process it is done
+        coordinator.m_changed.notify_all();
+        for stream in streams.iter() {
+            let _ = stream.shutdown(Shutdown::Read);
+        }
+    });
+
+    let state = coordinator.m_state.into_inner().unwrap();
+    return Ok((state.samples, state.splats));
+}
+
+impl<'a> Coordinator<'a> {
+    fn serve_worker(&self, stream: TcpStream) -> io::Result<()> {
+        // the reader shares the socket, and so the timeout
+        stream.set_read_timeout(Some(self.m_worker_timeout))?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = stream;
+
+        let hello = read_header(&mut reader)?;
+        if hello.len() != 2 || hello[0] != "hello" || hello[1] != PROTOCOL_VERSION.to_string() {
+            return Err(invalid_message("expected hello with our protocol version"));
+        }
+        let mut arguments: Vec<u8> = Vec::new();
+        for arg in self.m_scene.args.iter() {
+            arguments.extend_from_slice(arg.as_bytes());
+            arguments.push(0u8);
+        }
+        writer.write_all(format!("scene {} {}\n", self.m_scene.seed, arguments.len()).as_bytes())?;
+        writer.write_all(&arguments)?;
+        writer.flush()?;
+        let ready = read_header(&mut reader)?;
+        if ready.len() != 1 || ready[0] != "ready" {
+            return Err(invalid_message("expected ready"));
+        }
+
+        loop {
+            let (index, region) = match self.next_region() {
+                Some(assignment) => assignment,
+                None => {
+                    writer.write_all(b"done\n")?;
+                    return writer.flush();
+                }
+            };
+            match self.render_remotely(&mut reader, &mut writer, index, &region) {
+                Ok((samples, splats)) => self.finish_region(index, &region, &samples, splats.as_ref()),
+                Err(why) => {
+                    self.give_back(index);
+                    // a worker that went quiet may still be at it, hanging up tells it to stop
+                    let _ = writer.shutdown(Shutdown::Both);
+                    return Err(match why.kind() {
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            format!("distributed: no tile for region {} in {} seconds", index, self.m_worker_timeout.as_secs_f64())
+                        ),
+                        _ => why,
+                    });
+                }
+            }
+        }
+    }
+
+    // blocks until there is a region to hand out, None once every region is in
+    fn next_region(&self) -> Option<(usize, RenderRegion)> {
+        let mut state = self.m_state.lock().unwrap();
+        loop {
+            if let Some(index) = state.pending.pop_front() {
+                return Some((index, state.regions[index]));
+            }
+            if state.remaining == 0 {
+                return None;
+            }
+            state = self.m_changed.wait(state).unwrap();
+        }
+    }
+
+    fn give_back(&self, index: usize) {
+        let mut state = self.m_state.lock().unwrap();
+        if !state.finished[index] {
+            state.pending.push_front(index);
+        }
+        self.m_changed.notify_all();
+    }
+
+    fn finish_region(&self, index: usize, region: &RenderRegion, samples: &FilmTile, splats: Option<&RenderBufferF64>) {
+        let mut state = self.m_state.lock().unwrap();
+        if state.finished[index] {
+            return;
+        }
+        state.finished[index] = true;
+        state.remaining -= 1;
+        samples.merge_into(&mut state.samples);
+        if let Some(splats) = splats {
+            for i in 0..state.splats.buffer.len() {
+                state.splats.buffer[i] += splats.buffer[i];
+            }
+        }
+        if let Some(preview) = self.m_preview {
+            renderer::preview_tile(
+                preview, &state.samples, (region.x0, region.y0, region.x1, region.y1), self.m_height, region.samples
+            );
+        }
+        self.m_changed.notify_all();
+    }
+
+    fn render_remotely(
+        &self,
+        reader: &mut BufReader<TcpStream>,
+        writer: &mut TcpStream,
+        index: usize,
+        region: &RenderRegion
+    ) -> io::Result<(FilmTile, Option<RenderBufferF64>)> {
+        writer.write_all(format!(
+            "render {} {} {} {} {} {}\n", index, region.x0, region.y0, region.x1, region.y1, region.samples
+        ).as_bytes())?;
+        writer.flush()?;
+
+        let header = read_header(reader)?;
+        if header.len() != 7 || header[0] != "tile" {
+            return Err(invalid_message("expected a tile"));
+        }
+        let numbers: Vec<usize> = header[1..].iter().map(|n| n.parse::<usize>()).collect::<Result<Vec<usize>, _>>()
+            .map_err(|_| invalid_message("bad tile header"))?;
+        // the window has to be the one this region splats into, anything else would land in the wrong place
+        let expected = FilmTile::around(region.x0, region.y0, region.x1, region.y1, &self.m_filter, self.m_width, self.m_height);
+        if numbers[0] != index || numbers[1] != expected.x0 || numbers[2] != expected.y0
+            || numbers[3] != expected.width || numbers[4] != expected.height || numbers[5] > 1 {
+            return Err(invalid_message("the tile does not fit the region"));
+        }
+        let pixels = expected.width * expected.height;
+        let color = read_f64s(reader, pixels * 3)?;
+        let weights = read_f64s(reader, pixels)?;
+        let samples = match FilmTile::from_sums(expected.x0, expected.y0, expected.width, expected.height, color, weights) {
+            Some(samples) => samples,
+            None => return Err(invalid_message("the tile does not fit the region")),
+        };
+        let splats = if numbers[5] == 1 {
+            let mut splats = RenderBufferF64::new(self.m_width, self.m_height);
+            splats.buffer = read_f64s(reader, self.m_width * self.m_height * 3)?;
+            Some(splats)
+        } else {
+            None
+        };
+        return Ok((samples, splats));
+    }
+}
+
+//
+// worker
+//
+pub struct WorkerConnection {
+    m_reader: BufReader<TcpStream>,
+    m_writer: TcpStream,
+}
+
+impl WorkerConnection {
+    // introduces itself to the coordinator at address (host:port) and waits for the scene
+    pub fn connect(address: &str) -> io::Result<(WorkerConnection, Scene)> {
+        let stream = TcpStream::connect(address)?;
+        let mut connection = WorkerConnection {
+            m_reader: BufReader::new(stream.try_clone()?),
+            m_writer: stream,
+        };
+        connection.m_writer.write_all(format!("hello {}\n", PROTOCOL_VERSION).as_bytes())?;
+        connection.m_writer.flush()?;
+
+        let header = read_header(&mut connection.m_reader)?;
+        if header.len() != 3 || header[0] != "scene" {
+            return Err(invalid_message("expected a scene"));
+        }
+        let seed: u32 = header[1].parse().map_err(|_| invalid_message("bad scene seed"))?;
+        let length: usize = header[2].parse().map_err(|_| invalid_message("bad scene length"))?;
+        let mut arguments = vec![0u8; length];
+        connection.m_reader.read_exact(&mut arguments)?;
+        let args = arguments.split(|byte| *byte == 0u8)
+            .filter(|arg| !arg.is_empty())
+            .map(|arg| String::from_utf8_lossy(arg).into_owned())
+            .collect();
+        return Ok((connection, Scene { seed: seed, args: args }));
+    }
+
+    // tells the coordinator the scene is built, then renders the regions it sends until it has no more.
+    // render returns None for a region it can't do, the coordinator hands that to someone else
+    pub fn serve<F>(mut self, mut render: F) -> io::Result<()>
+        where F: FnMut(&RenderRegion) -> Option<(FilmTile, RenderBufferF64)>
+    {
+        self.m_writer.write_all(b"ready\n")?;
+        self.m_writer.flush()?;
+        loop {
+            let header = match read_header(&mut self.m_reader) {
+                Ok(header) => header,
+                // the coordinator finishing without saying goodbye is fine too
+                Err(ref why) if why.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(why) => return Err(why),
+            };
+            if header.len() == 1 && header[0] == "done" {
+                return Ok(());
+            }
+            if header.len() != 7 || header[0] != "render" {
+                return Err(invalid_message("expected a region to render"));
+            }
+            let numbers: Vec<usize> = header[1..6].iter().map(|n| n.parse::<usize>()).collect::<Result<Vec<usize>, _>>()
+                .map_err(|_| invalid_message("bad region"))?;
+            let samples: i32 = header[6].parse().map_err(|_| invalid_message("bad sample count"))?;
+            let region = RenderRegion {
+                x0: numbers[1],
+                y0: numbers[2],
+                x1: numbers[3],
+                y1: numbers[4],
+                samples: samples,
+            };
+            let (tile, splats) = match render(&region) {
+                Some(result) => result,
+                None => return Err(invalid_message("this integrator can't render a region on its own")),
+            };
+
+            let has_splats = splats.buffer.iter().any(|value| *value != 0f64);
+            self.m_writer.write_all(format!(
+                "tile {} {} {} {} {} {}\n", numbers[0], tile.x0, tile.y0, tile.width, tile.height, if has_splats { 1 } else { 0 }
+            ).as_bytes())?;
+            let (color, weights) = tile.sums();
+            write_f64s(&mut self.m_writer, color)?;
+            write_f64s(&mut self.m_writer, weights)?;
+            if has_splats {
+                write_f64s(&mut self.m_writer, &splats.buffer)?;
+            }
+            self.m_writer.flush()?;
+        }
+    }
+}
+
+//
+// wire format
+//
+
+// a header line split at spaces, a closed connection is an UnexpectedEof error
+fn read_header(reader: &mut BufReader<TcpStream>) -> io::Result<Vec<String>> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed"));
+    }
+    return Ok(line.split_whitespace().map(|word| word.to_string()).collect());
+}
+
+fn read_f64s(reader: &mut BufReader<TcpStream>, count: usize) -> io::Result<Vec<f64>> {
+    let mut bytes = vec![0u8; count * 8];
+    reader.read_exact(&mut bytes)?;
+    let mut values = Vec::with_capacity(count);
+    for chunk in bytes.chunks(8) {
+        let mut word = [0u8; 8];
+        word.copy_from_slice(chunk);
+        values.push(f64::from_bits(u64::from_le_bytes(word)));
+    }
+    return Ok(values);
+}
+
+fn write_f64s(writer: &mut TcpStream, values: &[f64]) -> io::Result<()> {
+    let mut bytes: Vec<u8> = Vec::with_capacity(values.len() * 8);
+    for value in values.iter() {
+        bytes.extend_from_slice(&value.to_bits().to_le_bytes());
+    }
+    return writer.write_all(&bytes);
+}
+
+fn invalid_message(message: &str) -> io::Error {
+    return io::Error::new(io::ErrorKind::InvalidData, format!("distributed: {}", message));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusty_math::*;
+    use std::time::Instant;
+
+    #[test]
+    fn regions_cover_every_pixel_with_every_sample() {
+        let (width, height, samples_per_pixel) = (150, 70, 40);
+        let regions = split(width, height, samples_per_pixel);
+        let mut samples = vec![0; width * height];
+        for region in regions.iter() {
+            assert!(region.x1 - region.x0 <= REGION_SIZE && region.y1 - region.y0 <= REGION_SIZE);
+            assert!(region.samples > 0 && region.samples <= REGION_SAMPLES);
+            for y in region.y0..region.y1 {
+                for x in region.x0..region.x1 {
+                    samples[y * width + x] += region.samples;
+                }
+            }
+        }
+        assert!(samples.iter().all(|count| *count == samples_per_pixel));
+        // the top of the image goes out first
+        assert_eq!(regions[0].y0, 64);
+    }
+
+    // connects to a coordinator that may not be listening yet
+    fn connect(address: &str) -> TcpStream {
+        let start = Instant::now();
+        loop {
+            match TcpStream::connect(address) {
+                Ok(stream) => return stream,
+                Err(why) => {
+                    assert!(start.elapsed() < Duration::from_secs(10), "couldn't reach the coordinator: {}", why);
+                    thread::sleep(Duration::from_millis(20));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn a_stalled_worker_gives_its_region_back() {
+        let (width, height, samples_per_pixel) = (100, 70, 20);
+        let address = format!("127.0.0.1:{}", TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port());
+        let filter = Filter::box_filter();
+        let coordinator = {
+            let address = address.clone();
+            thread::spawn(move || {
+                let scene = Scene { seed: 7, args: vec!["--spp".to_string(), "20".to_string()] };
+                let regions = split(width, height, samples_per_pixel);
+                return coordinate(&address, &scene, &filter, width, height, regions, Duration::from_millis(500), None);
+            })
+        };
+
+        // takes a region and never sends a tile
+        let stalled = connect(&address);
+        let mut reader = BufReader::new(stalled.try_clone().unwrap());
+        let mut writer = stalled.try_clone().unwrap();
+        writer.write_all(b"hello 1\n").unwrap();
+        let scene = read_header(&mut reader).unwrap();
+        let mut arguments = vec![0u8; scene[2].parse::<usize>().unwrap()];
+        reader.read_exact(&mut arguments).unwrap();
+        writer.write_all(b"ready\n").unwrap();
+        assert_eq!(read_header(&mut reader).unwrap()[0], "render");
+
+        // renders everything, the stalled worker's region included once its time is up
+        let (connection, scene) = WorkerConnection::connect(&address).unwrap();
+        assert_eq!((scene.seed, scene.args), (7, vec!["--spp".to_string(), "20".to_string()]));
+        connection.serve(|region| {
+            let mut tile = FilmTile::around(region.x0, region.y0, region.x1, region.y1, &filter, width, height);
+            for y in region.y0..region.y1 {
+                for x in region.x0..region.x1 {
+                    for _ in 0..region.samples {
+                        tile.add_sample(&filter, x as f64 + 0.5f64, y as f64 + 0.5f64, &Vec3::new(0.25f64, 0.5f64, 1f64));
+                    }
+                }
+            }
+            return Some((tile, RenderBufferF64::new(width, height)));
+        }).unwrap();
+
+        let (samples, splats) = coordinator.join().unwrap().unwrap();
+        let mut film = RenderBufferF64::new(width, height);
+        samples.resolve_into(&mut film, samples_per_pixel);
+        for y in 0..height {
+            for x in 0..width {
+                assert_eq!(film.get(x, y), (5f64, 10f64, 20f64));
+            }
+        }
+        assert!(splats.buffer.iter().all(|value| *value == 0f64));
+
+        // and the coordinator hung up on the stalled one
+        let mut rest = Vec::new();
+        assert_eq!(reader.read_to_end(&mut rest).unwrap(), 0);
+    }
+}
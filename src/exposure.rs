@@ -0,0 +1,201 @@
+extern crate rusty_math;
+
+use rusty_math::*;
+use std::f64::consts;
+
+use render_buffer::RenderBufferF64;
+use spectrum;
+
+//
+// physical camera exposure
+//
+// with lights given in photometric units the film receives luminance in nits, far past what the tone
+// mapping takes. exposure scales it the way iso, shutter speed and f-number would, or meters the image
+// like a camera's auto exposure. formulas from Lagarde and de Rousiers, Moving Frostbite to PBR 2014
+
+// reflected light meter calibration constant
+static METER_CALIBRATION: f64 = 12.5f64;
+
+#[derive(Clone, Copy)]
+pub enum Metering {
+    // iso, shutter speed and the f-number decide
+    Manual,
+    // the log-average luminance of the image ends up middle gray
+    Average,
+}
+
+#[derive(Clone, Copy)]
+pub struct Exposure {
+    pub iso: f64,
+    pub shutter_seconds: f64,
+    // only used when the camera has no aperture of its own, otherwise its depth of field setting counts
+    pub f_number: f64,
+    // extra stops of light on top of whatever was metered
+    pub compensation_ev: f64,
+    pub metering: Metering,
+}
+
+impl Exposure {
+    // sunny 16: iso 100, 1/125 s at f/16 is right for a sunlit scene
+    pub fn sunny_16() -> Exposure {
+        return Exposure {
+            iso: 100f64,
+            shutter_seconds: 1f64 / 125f64,
+            f_number: 16f64,
+            compensation_ev: 0f64,
+            metering: Metering::Manual,
+        };
+    }
+
+    pub fn auto() -> Exposure {
+        return Exposure {
+            metering: Metering::Average,
+            ..Exposure::sunny_16()
+        };
+    }
+
+    // exposure value at iso 100 for the settings
+    pub fn ev100(&self, f_number: f64) -> f64 {
+        return (f_number * f_number / self.shutter_seconds * 100f64 / self.iso).log2();
+    }
+
+    // factor that takes film luminance to the [0, 1] the tone mapping expects, 1 is the luminance that
+    // saturates the sensor. film holds num_samples sums, camera_f_number is the camera's aperture if it has one
+    pub fn scale(&self, camera_f_number: Option<f64>, film: &RenderBufferF64, num_samples: i32) -> f64 {
+        let ev100 = match self.metering {
+            Metering::Manual => self.ev100(camera_f_number.unwrap_or(self.f_number)),
+            Metering::Average => {
+                let average = log_average_luminance(film, num_samples);
+                (average * 100f64 / METER_CALIBRATION).log2()
+            }
+        } - self.compensation_ev;
+        return 1f64 / (1.2f64 * 2f64.powf(ev100));
+    }
+}
+
+// geometric mean of the pixel luminances, a few very bright pixels barely move it
+pub fn log_average_luminance(film: &RenderBufferF64, num_samples: i32) -> f64 {
+    let pixels = film.width * film.height;
+    if pixels == 0 {
+        return 1f64;
+    }
+    let mut sum = 0f64;
+    for y in 0..film.height {
+        for x in 0..film.width {
+            let (r, g, b) = film.get(x, y);
+            let luminance = spectrum::luminance(&Vec3::new(r, g, b)) / num_samples.max(1) as f64;
+            // keeps black pixels from sending the log to minus infinity
+            sum += (1e-4f64 + luminance.max(0f64)).ln();
+        }
+    }
+    return (sum / pixels as f64).exp();
+}
+
+//
+// photometric units
+//
+// emission and sky values in the renderer are luminance in nits (candela per square meter) when lights
+// are set up with these
+
+// emission for a light of the given color that is nits bright, the color only sets the hue
+pub fn emission_nits(color: &Vec3, nits: f64) -> Vec3 {
+    let luminance = spectrum::luminance(color);
+    if luminance <= 0f64 {
+        return Vec3::new(0f64, 0f64, 0f64);
+    }
+    return color * (nits / luminance);
+}
+
+// luminance of a diffuse sphere light of radius that gives off lumens in total
+pub fn sphere_light_nits(lumens: f64, radius: f64) -> f64 {
+    let area = 4f64 * consts::PI * radius * radius;
+    return lumens / (consts::PI * area);
+}
+
+// luminance of a uniform sky that lights a horizontal surface to lux
+pub fn sky_nits(lux: f64) -> f64 {
+    return lux / consts::PI;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f64, b: f64) -> bool {
+        return (a - b).abs() <= 1e-9f64 * a.abs().max(b.abs()).max(1f64);
+    }
+
+    // every pixel the same grey, as sums of num_samples samples
+    fn grey_film(luminance: f64, num_samples: i32) -> RenderBufferF64 {
+        let mut film = RenderBufferF64::new(4, 3);
+        let value = luminance * num_samples as f64;
+        for y in 0..3 {
+            for x in 0..4 {
+                film.add(x, y, value, value, value);
+            }
+        }
+        return film;
+    }
+
+    #[test]
+    fn ev100_counts_stops() {
+        let sunny = Exposure::sunny_16();
+        assert!(close(sunny.ev100(16f64), (16f64 * 16f64 * 125f64).log2()));
+        // a stop more light from each of the aperture, the shutter and the iso lowers it by one
+        assert!(close(sunny.ev100(16f64 / 2f64.sqrt()), sunny.ev100(16f64) - 1f64));
+        let slower = Exposure { shutter_seconds: 2f64 / 125f64, ..sunny };
+        assert!(close(slower.ev100(16f64), sunny.ev100(16f64) - 1f64));
+        let faster_film = Exposure { iso: 200f64, ..sunny };
+        assert!(close(faster_film.ev100(16f64), sunny.ev100(16f64) - 1f64));
+        // f/1, 1 s at iso 100 is ev 0
+        let one_second = Exposure { shutter_seconds: 1f64, ..sunny };
+        assert!(close(one_second.ev100(1f64), 0f64));
+    }
+
+    #[test]
+    fn manual_scale_follows_the_camera_aperture() {
+        let sunny = Exposure::sunny_16();
+        let film = grey_film(1f64, 4);
+        let scale = sunny.scale(None, &film, 4);
+        assert!(close(scale, 1f64 / (1.2f64 * 2f64.powf(sunny.ev100(16f64)))));
+        // the image does not matter, the camera's f/8 lets in two stops more than the f/16 of the settings
+        assert!(close(sunny.scale(None, &grey_film(50f64, 4), 4), scale));
+        assert!(close(sunny.scale(Some(8f64), &film, 4), 4f64 * scale));
+        let brighter = Exposure { compensation_ev: 1f64, ..sunny };
+        assert!(close(brighter.scale(None, &film, 4), 2f64 * scale));
+    }
+
+    #[test]
+    fn auto_exposure_brings_any_grey_to_the_same_level() {
+        let auto = Exposure::auto();
+        for &luminance in [0.5f64, 20f64, 3000f64].iter() {
+            let film = grey_film(luminance, 8);
+            let average = log_average_luminance(&film, 8);
+            assert!((average - luminance).abs() < 2e-4f64 * luminance.max(1f64));
+            // the meter calibration puts the average at 12.5 / 100 of the luminance that saturates, before the 1.2
+            let exposed = luminance * auto.scale(None, &film, 8);
+            assert!((exposed - METER_CALIBRATION / 120f64).abs() < 1e-3f64, "{} exposed to {}", luminance, exposed);
+        }
+        // a few hot pixels barely move the geometric mean
+        let mut film = grey_film(1f64, 1);
+        film.add(0, 0, 1000f64, 1000f64, 1000f64);
+        assert!(log_average_luminance(&film, 1) < 2f64);
+        // black stays finite
+        assert!(log_average_luminance(&grey_film(0f64, 1), 1) > 0f64);
+    }
+
+    #[test]
+    fn photometric_helpers() {
+        let warm = emission_nits(&Vec3::new(8f64, 6.5f64, 4f64), 1000f64);
+        assert!(close(spectrum::luminance(&warm), 1000f64));
+        // the hue stays
+        assert!(close(warm.x / warm.z, 2f64));
+        let black = emission_nits(&Vec3::new(0f64, 0f64, 0f64), 1000f64);
+        assert_eq!((black.x, black.y, black.z), (0f64, 0f64, 0f64));
+        // a lambertian emitter sends pi * nits lumens out of every square meter
+        let nits = sphere_light_nits(800f64, 0.1f64);
+        assert!(close(nits * consts::PI * 4f64 * consts::PI * 0.01f64, 800f64));
+        // and a uniform sky of nits lights the ground with pi * nits lux
+        assert!(close(sky_nits(120000f64) * consts::PI, 120000f64));
+    }
+}
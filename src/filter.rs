@@ -0,0 +1,352 @@
+extern crate rusty_math;
+
+use rusty_math::*;
+use std::f64::consts;
+
+use render_buffer::RenderBufferF64;
+
+//
+// pixel reconstruction filters
+//
+// a camera sample counts towards every pixel whose center is within radius of it, weighted by the filter.
+// each pixel ends up the weighted average of the samples around it. filters are separable, the weight
+// of a sample is the filter along x times the filter along y, distances are in pixels
+
+#[derive(Clone, Copy)]
+pub enum FilterKind {
+    // every sample in reach counts the same, radius 1/2 keeps samples in their own pixel
+    Box,
+    // falls off linearly to the radius
+    Tent,
+    // exp(-alpha x^2), shifted down so it reaches 0 at the radius
+    Gaussian { alpha: f64 },
+    // Mitchell and Netravali 1988, b = c = 1/3 is their recommendation. sharpens with small negative lobes
+    Mitchell { b: f64, c: f64 },
+    // sinc windowed by a wider sinc that reaches its first zero at the radius, sharp but rings
+    Lanczos,
+    // bell shaped window with very small side lobes, close to a gaussian that really reaches 0
+    BlackmanHarris,
+}
+
+#[derive(Clone, Copy)]
+pub struct Filter {
+    pub kind: FilterKind,
+    pub radius: f64,
+}
+
+impl Filter {
+    pub fn new(kind: FilterKind, radius: f64) -> Filter {
+        return Filter {
+            kind: kind,
+            radius: radius,
+        };
+    }
+
+    // averages the samples inside each pixel and nothing else
+    pub fn box_filter() -> Filter {
+        return Filter::new(FilterKind::Box, 0.5f64);
+    }
+
+    // parses the name given on the command line, each filter gets the radius it is usually used with
+    pub fn from_name(name: &str) -> Option<Filter> {
+        return match name {
+            "box" => Some(Filter::box_filter()),
+            "tent" => Some(Filter::new(FilterKind::Tent, 1f64)),
+            "gaussian" => Some(Filter::new(FilterKind::Gaussian { alpha: 2f64 }, 1.5f64)),
+            "mitchell" => Some(Filter::new(FilterKind::Mitchell { b: 1f64 / 3f64, c: 1f64 / 3f64 }, 2f64)),
+            "lanczos" => Some(Filter::new(FilterKind::Lanczos, 3f64)),
+            "blackman-harris" => Some(Filter::new(FilterKind::BlackmanHarris, 2f64)),
+            _ => None,
+        };
+    }
+
+    pub fn evaluate(&self, dx: f64, dy: f64) -> f64 {
+        return self.evaluate_1d(dx) * self.evaluate_1d(dy);
+    }
+
+    fn evaluate_1d(&self, x: f64) -> f64 {
+        let x = x.abs();
+        let radius = self.radius;
+        if x > radius {
+            return 0f64;
+        }
+        return match self.kind {
+            FilterKind::Box => 1f64,
+            FilterKind::Tent => radius - x,
+            FilterKind::Gaussian { alpha } => ((-alpha * x * x).exp() - (-alpha * radius * radius).exp()).max(0f64),
+            FilterKind::Mitchell { b, c } => {
+                // the cubic is defined over [-2, 2]
+                let x = 2f64 * x / radius;
+                if x < 1f64 {
+                    ((12f64 - 9f64 * b - 6f64 * c) * x * x * x
+                        + (-18f64 + 12f64 * b + 6f64 * c) * x * x
+                        + (6f64 - 2f64 * b)) / 6f64
+                } else {
+                    ((-b - 6f64 * c) * x * x * x
+                        + (6f64 * b + 30f64 * c) * x * x
+                        + (-12f64 * b - 48f64 * c) * x
+                        + (8f64 * b + 24f64 * c)) / 6f64
+                }
+            }
+            FilterKind::Lanczos => sinc(x) * sinc(x / radius),
+            FilterKind::BlackmanHarris => {
+                let n = 0.5f64 + 0.5f64 * x / radius;
+                let angle = 2f64 * consts::PI * n;
+                0.35875f64 - 0.48829f64 * angle.cos() + 0.14128f64 * (2f64 * angle).cos() - 0.01168f64 * (3f64 * angle).cos()
+            }
+        };
+    }
+}
+
+fn sinc(x: f64) -> f64 {
+    if x < 1e-5f64 {
+        return 1f64;
+    }
+    let pi_x = consts::PI * x;
+    return pi_x.sin() / pi_x;
+}
+
+//
+// FilmTile
+//
+// weighted sums of camera samples for a window of the image. a thread rendering the pixels x0..x0 + width
+// of a tile also reaches radius pixels into its neighbours, so the window is grown by the filter's reach
+// and the neighbours' share is added in when the tiles are merged, rather than two threads writing one pixel
+pub struct FilmTile {
+    pub x0: usize,
+    pub y0: usize,
+    pub width: usize,
+    pub height: usize,
+    m_color: Vec<f64>,
+    m_weights: Vec<f64>,
+}
+
+impl FilmTile {
+    // the window that pixels x0..x1, y0..y1 can splat into, clipped to an image of image_width by image_height
+    pub fn around(x0: usize, y0: usize, x1: usize, y1: usize, filter: &Filter, image_width: usize, image_height: usize) -> FilmTile {
+        let reach = (filter.radius - 0.5f64).max(0f64).ceil() as usize;
+        let window_x0 = x0.saturating_sub(reach);
+        let window_y0 = y0.saturating_sub(reach);
+        let window_x1 = (x1 + reach).min(image_width);
+        let window_y1 = (y1 + reach).min(image_height);
+        return FilmTile::new(window_x0, window_y0, window_x1 - window_x0, window_y1 - window_y0);
+    }
+
+    pub fn new(x0: usize, y0: usize, width: usize, height: usize) -> FilmTile {
+        return FilmTile {
+            x0: x0,
+            y0: y0,
+            width: width,
+            height: height,
+            m_color: vec![0f64; width * height * 3],
+            m_weights: vec![0f64; width * height],
+        };
+    }
+
+    // a sample at image position x, y in pixels, the bottom left corner of the image is 0, 0 and pixel
+    // centers sit at half pixels
+    pub fn add_sample(&mut self, filter: &Filter, x: f64, y: f64, color: &Vec3) {
+        let first_x = (x - 0.5f64 - filter.radius).ceil().max(self.x0 as f64) as usize;
+        let first_y = (y - 0.5f64 - filter.radius).ceil().max(self.y0 as f64) as usize;
+        let last_x = (x - 0.5f64 + filter.radius).floor();
+        let last_y = (y - 0.5f64 + filter.radius).floor();
+        if last_x < first_x as f64 || last_y < first_y as f64 {
+            return;
+        }
+        let last_x = (last_x as usize).min(self.x0 + self.width - 1);
+        let last_y = (last_y as usize).min(self.y0 + self.height - 1);
+        for pixel_y in first_y..(last_y + 1) {
+            for pixel_x in first_x..(last_x + 1) {
+                let weight = filter.evaluate(pixel_x as f64 + 0.5f64 - x, pixel_y as f64 + 0.5f64 - y);
+                if weight == 0f64 {
+                    continue;
+                }
+                let index = (pixel_y - self.y0) * self.width + (pixel_x - self.x0);
+                self.m_color[index * 3] += weight * color.x;
+                self.m_color[index * 3 + 1] += weight * color.y;
+                self.m_color[index * 3 + 2] += weight * color.z;
+                self.m_weights[index] += weight;
+            }
+        }
+    }
+
+    // a window put back together from what sums handed out, None if the sizes do not add up
+    pub fn from_sums(x0: usize, y0: usize, width: usize, height: usize, color: Vec<f64>, weights: Vec<f64>) -> Option<FilmTile> {
+        if color.len() != width * height * 3 || weights.len() != width * height {
+            return None;
+        }
+        return Some(FilmTile {
+            x0: x0,
+            y0: y0,
+            width: width,
+            height: height,
+            m_color: color,
+            m_weights: weights,
+        });
+    }
+
+    // the weighted colors, three to a pixel, and the weights, rows bottom up
+    pub fn sums(&self) -> (&[f64], &[f64]) {
+        return (&self.m_color, &self.m_weights);
+    }
+
+    // adds the window into a tile covering more of the image, the whole image usually
+    pub fn merge_into(&self, other: &mut FilmTile) {
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let (image_x, image_y) = (self.x0 + x, self.y0 + y);
+                if image_x < other.x0 || image_y < other.y0 || image_x >= other.x0 + other.width || image_y >= other.y0 + other.height {
+                    continue;
+                }
+                let from = y * self.width + x;
+                let to = (image_y - other.y0) * other.width + (image_x - other.x0);
+                for c in 0..3 {
+                    other.m_color[to * 3 + c] += self.m_color[from * 3 + c];
+                }
+                other.m_weights[to] += self.m_weights[from];
+            }
+        }
+    }
+
+    // weighted average at pixel x, y of the image so far, black where no weight landed yet
+    pub fn average(&self, x: usize, y: usize) -> Vec3 {
+        let index = (y - self.y0) * self.width + (x - self.x0);
+        let weight = self.m_weights[index];
+        if weight.abs() < 1e-12f64 {
+            return Vec3::new(0f64, 0f64, 0f64);
+        }
+        return Vec3::new(self.m_color[index * 3] / weight, self.m_color[index * 3 + 1] / weight, self.m_color[index * 3 + 2] / weight);
+    }
+
+    // adds the weighted average of every pixel into film, scaled to look like a sum of num_samples samples
+    // so it lines up with the light paths integrators splat there. pixels whose weights cancel out stay black
+    pub fn resolve_into(&self, film: &mut RenderBufferF64, num_samples: i32) {
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let index = y * self.width + x;
+                let weight = self.m_weights[index];
+                if weight.abs() < 1e-12f64 {
+                    continue;
+                }
+                let scale = num_samples as f64 / weight;
+                film.add(
+                    self.x0 + x,
+                    self.y0 + y,
+                    self.m_color[index * 3] * scale,
+                    self.m_color[index * 3 + 1] * scale,
+                    self.m_color[index * 3 + 2] * scale
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static NAMES: [&'static str; 6] = ["box", "tent", "gaussian", "mitchell", "lanczos", "blackman-harris"];
+
+    #[test]
+    fn filters_peak_in_the_middle_and_stop_at_their_radius() {
+        for name in NAMES.iter() {
+            let filter = Filter::from_name(name).unwrap();
+            let peak = filter.evaluate(0f64, 0f64);
+            assert!(peak > 0f64, "{}", name);
+            for i in 1..20 {
+                let x = filter.radius * i as f64 / 20f64;
+                assert!(filter.evaluate(x, 0f64) <= peak, "{} at {}", name, x);
+                assert_eq!(filter.evaluate(x, 0f64), filter.evaluate(-x, 0f64));
+            }
+            assert_eq!(filter.evaluate(filter.radius + 1e-9f64, 0f64), 0f64);
+            assert_eq!(filter.evaluate(0f64, -filter.radius - 1e-9f64), 0f64);
+        }
+        assert!(Filter::from_name("sharp").is_none());
+    }
+
+    #[test]
+    fn tent_and_mitchell_add_up_to_the_same_over_the_pixels() {
+        // with b + 2c = 1 the mitchell cubic, like the tent, weights a sample the same wherever it falls
+        for name in ["tent", "mitchell"].iter() {
+            let filter = Filter::from_name(name).unwrap();
+            let total = |offset: f64| (-3..4).map(|k| filter.evaluate(offset + k as f64, 0f64)).sum::<f64>();
+            for &offset in [0f64, 0.1f64, 0.3f64, 0.5f64].iter() {
+                assert!((total(offset) - total(0f64)).abs() < 1e-12f64, "{} at {}", name, offset);
+            }
+        }
+        // and it goes a little negative on the side
+        let mitchell = Filter::from_name("mitchell").unwrap();
+        assert!(mitchell.evaluate(1.5f64, 0f64) < 0f64);
+    }
+
+    fn scattered_samples() -> Vec<(f64, f64, Vec3)> {
+        // a fixed scatter over an 8 x 6 image, the colors change across it
+        return (0..400).map(|i| {
+            let x = (i as f64 * 0.618034f64).fract() * 8f64;
+            let y = (i as f64 * 0.414214f64).fract() * 6f64;
+            (x, y, Vec3::new(x, y, 1f64))
+        }).collect();
+    }
+
+    #[test]
+    fn tiles_merge_into_the_whole_image() {
+        let filter = Filter::from_name("gaussian").unwrap();
+        let mut whole = FilmTile::new(0, 0, 8, 6);
+        for &(x, y, ref color) in scattered_samples().iter() {
+            whole.add_sample(&filter, x, y, color);
+        }
+        // each tile takes the samples inside its pixels and reaches into the other one
+        let mut merged = FilmTile::new(0, 0, 8, 6);
+        for &(x0, x1) in [(0usize, 3usize), (3usize, 8usize)].iter() {
+            let mut tile = FilmTile::around(x0, 0, x1, 6, &filter, 8, 6);
+            assert!(tile.x0 < x0 || x0 == 0);
+            for &(x, y, ref color) in scattered_samples().iter() {
+                if x >= x0 as f64 && x < x1 as f64 {
+                    tile.add_sample(&filter, x, y, color);
+                }
+            }
+            tile.merge_into(&mut merged);
+        }
+        for y in 0..6 {
+            for x in 0..8 {
+                let (a, b) = (whole.average(x, y), merged.average(x, y));
+                assert!((&a - &b).length_squared() < 1e-20f64);
+            }
+        }
+    }
+
+    #[test]
+    fn a_box_keeps_samples_in_their_pixel() {
+        let filter = Filter::box_filter();
+        let mut tile = FilmTile::new(0, 0, 3, 3);
+        tile.add_sample(&filter, 1.9f64, 1.1f64, &Vec3::new(1f64, 2f64, 3f64));
+        tile.add_sample(&filter, 1.2f64, 1.7f64, &Vec3::new(3f64, 2f64, 1f64));
+        let (_, weights) = tile.sums();
+        assert_eq!(weights, &[0f64, 0f64, 0f64, 0f64, 2f64, 0f64, 0f64, 0f64, 0f64][..]);
+        let mean = tile.average(1, 1);
+        assert_eq!((mean.x, mean.y, mean.z), (2f64, 2f64, 2f64));
+
+        // the average goes to the film as if it were the sum of num_samples samples
+        let mut film = RenderBufferF64::new(3, 3);
+        tile.resolve_into(&mut film, 4);
+        assert_eq!(film.get(1, 1), (8f64, 8f64, 8f64));
+        assert_eq!(film.get(0, 0), (0f64, 0f64, 0f64));
+    }
+
+    #[test]
+    fn sums_come_back_as_the_same_tile() {
+        let filter = Filter::from_name("tent").unwrap();
+        let mut tile = FilmTile::around(2, 1, 5, 4, &filter, 8, 6);
+        for &(x, y, ref color) in scattered_samples().iter() {
+            tile.add_sample(&filter, x, y, color);
+        }
+        let (color, weights) = tile.sums();
+        let copy = FilmTile::from_sums(tile.x0, tile.y0, tile.width, tile.height, color.to_vec(), weights.to_vec()).unwrap();
+        assert_eq!((copy.x0, copy.y0, copy.width, copy.height), (tile.x0, tile.y0, tile.width, tile.height));
+        assert_eq!(copy.sums(), tile.sums());
+
+        // a payload for another window size is turned away
+        assert!(FilmTile::from_sums(0, 0, tile.width + 1, tile.height, color.to_vec(), weights.to_vec()).is_none());
+        assert!(FilmTile::from_sums(0, 0, tile.width, tile.height, color.to_vec(), weights[1..].to_vec()).is_none());
+    }
+}
@@ -0,0 +1,135 @@
+extern crate rusty_math;
+
+use rusty_math::*;
+
+use bvh::Aabb;
+use bvh::axis_value;
+
+//
+// KdTree
+//
+// balanced tree over a fixed set of points, built once and then queried for everything within a radius
+// the tree is implicit: every range [start, end) of m_indices keeps its splitting point in the middle,
+// with smaller coordinates along m_axes[middle] to the left
+pub struct KdTree {
+    m_points: Vec<Vec3>,
+    m_indices: Vec<usize>,
+    m_axes: Vec<usize>,
+}
+
+impl KdTree {
+    pub fn build(points: Vec<Vec3>) -> KdTree {
+        let count = points.len();
+        let mut tree = KdTree {
+            m_points: points,
+            m_indices: (0..count).collect(),
+            m_axes: vec![0; count],
+        };
+        tree.build_range(0, count);
+        return tree;
+    }
+
+    fn build_range(&mut self, start: usize, end: usize) {
+        if end - start <= 1 {
+            return;
+        }
+        let mut bounds = Aabb::empty();
+        for i in start..end {
+            bounds.grow(&self.m_points[self.m_indices[i]]);
+        }
+        let axis = bounds.largest_axis();
+        let points = &self.m_points;
+        self.m_indices[start..end].sort_by(|a, b| {
+            let ca = axis_value(&points[*a], axis);
+            let cb = axis_value(&points[*b], axis);
+            return ca.partial_cmp(&cb).unwrap_or(::std::cmp::Ordering::Equal);
+        });
+        let middle = (start + end) / 2;
+        self.m_axes[middle] = axis;
+        self.build_range(start, middle);
+        self.build_range(middle + 1, end);
+    }
+
+    // calls found with the index (into the points given to build) of every point within radius of center
+    pub fn for_each_in_radius<F>(&self, center: &Vec3, radius: f64, mut found: F)
+        where F: FnMut(usize)
+    {
+        let radius_squared = radius * radius;
+        let mut stack: Vec<(usize, usize)> = Vec::with_capacity(64);
+        stack.push((0, self.m_points.len()));
+        while let Some((start, end)) = stack.pop() {
+            if start >= end {
+                continue;
+            }
+            let middle = (start + end) / 2;
+            let index = self.m_indices[middle];
+            let point = &self.m_points[index];
+            if (point - center).length_squared() <= radius_squared {
+                found(index);
+            }
+            let axis = self.m_axes[middle];
+            let split = axis_value(point, axis);
+            let c = axis_value(center, axis);
+            if c - radius <= split {
+                stack.push((start, middle));
+            }
+            if c + radius >= split {
+                stack.push((middle + 1, end));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a scatter of points with a few exact duplicates and points piled on a plane, like photons on a floor
+    fn points() -> Vec<Vec3> {
+        let mut points: Vec<Vec3> = (0..600).map(|i| {
+            let t = i as f64;
+            let y = if i % 3 == 0 { 0f64 } else { (0.9f64 * t).cos() };
+            return Vec3::new((1.7f64 * t).sin() * 2f64, y, (2.3f64 * t + 0.4f64).sin() * 2f64);
+        }).collect();
+        for i in 0..20 {
+            let duplicate = Vec3::new(points[i].x, points[i].y, points[i].z);
+            points.push(duplicate);
+        }
+        return points;
+    }
+
+    #[test]
+    fn radius_queries_find_what_testing_every_point_finds() {
+        let tree = KdTree::build(points());
+        let points = points();
+        for q in 0..40 {
+            let t = q as f64;
+            let center = Vec3::new(2f64 * (0.7f64 * t).sin(), 0.5f64 * (1.1f64 * t).cos(), 2f64 * (0.3f64 * t).cos());
+            for &radius in [0f64, 0.1f64, 0.45f64, 1.5f64, 10f64].iter() {
+                let mut found: Vec<usize> = Vec::new();
+                tree.for_each_in_radius(&center, radius, |index| found.push(index));
+                found.sort();
+                let expected: Vec<usize> = (0..points.len())
+                    .filter(|&i| (&points[i] - &center).length_squared() <= radius * radius)
+                    .collect();
+                assert_eq!(found, expected);
+            }
+        }
+        // a point is within zero of itself, and so is its duplicate
+        let mut found = Vec::new();
+        tree.for_each_in_radius(&points[3], 0f64, |index| found.push(index));
+        found.sort();
+        assert_eq!(found, vec![3, 603]);
+    }
+
+    #[test]
+    fn empty_and_single_point_trees_answer_queries() {
+        let empty = KdTree::build(Vec::new());
+        empty.for_each_in_radius(&Vec3::new(0f64, 0f64, 0f64), 100f64, |_| panic!("nothing to find"));
+        let single = KdTree::build(vec![Vec3::new(1f64, 2f64, 3f64)]);
+        let mut count = 0;
+        single.for_each_in_radius(&Vec3::new(1f64, 2f64, 3.5f64), 0.5f64, |index| { assert_eq!(index, 0); count += 1; });
+        single.for_each_in_radius(&Vec3::new(1f64, 2f64, 3.6f64), 0.5f64, |_| count += 1);
+        assert_eq!(count, 1);
+    }
+}
@@ -0,0 +1,551 @@
+extern crate rusty_math;
+
+use rusty_math::*;
+use std::f64;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+
+use camera::Camera;
+use camera::Frame;
+use camera::Shutter;
+use sampler;
+
+//
+// realistic lens system, Kolb et al. 1995 with the exit pupil bounds from pbrt
+//
+// rays start on the film and are refracted through every element of a real lens prescription, so
+// distortion, vignetting and the change of framing while focusing (breathing) all come out of the glass
+//
+// lens space has the film at z = 0 and the elements towards -z, camera space flips z so the scene is
+// towards +z. both are in meters
+
+// radial bins the exit pupil is bounded in, from the film center to its corner
+static EXIT_PUPIL_BINS: usize = 64;
+// film positions and rear element grid resolution traced per bin
+static EXIT_PUPIL_FILM_SAMPLES: usize = 8;
+static EXIT_PUPIL_GRID: usize = 48;
+
+// one spherical interface of a lens, or the aperture stop
+pub struct LensElement {
+    // 0 for the aperture stop, positive when the center of curvature is towards the film
+    pub curvature_radius: f64,
+    // along the axis to the next element towards the film, for the last one that is the film itself
+    pub thickness: f64,
+    // of the glass behind this interface, 0 or 1 for air
+    pub eta: f64,
+    pub aperture_radius: f64,
+}
+
+// the common lens table text format: one element per line from the front of the lens to the film,
+// curvature radius, thickness, index of refraction and aperture diameter in millimeters. # comments
+pub fn read_lens_file(path: &Path) -> io::Result<Vec<LensElement>> {
+    let mut text = String::new();
+    File::open(path)?.read_to_string(&mut text)?;
+
+    let mut elements: Vec<LensElement> = Vec::new();
+    for (line_index, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        // a token that isn't a number spoils the whole line rather than shifting the columns
+        let values: Vec<f64> = match line.split_whitespace().map(|token| token.parse::<f64>()).collect() {
+            Ok(values) => values,
+            Err(_) => Vec::new(),
+        };
+        if values.len() != 4 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("lens file line {}: expected radius, thickness, ior and aperture", line_index + 1)
+            ));
+        }
+        elements.push(LensElement {
+            curvature_radius: values[0] * 0.001f64,
+            thickness: values[1] * 0.001f64,
+            eta: values[2],
+            aperture_radius: values[3] * 0.0005f64,
+        });
+    }
+    if elements.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "lens file has no elements"));
+    }
+    return Ok(elements);
+}
+
+// axis aligned rectangle on the plane of the rear element
+struct PupilBounds {
+    min_x: f64,
+    min_y: f64,
+    max_x: f64,
+    max_y: f64,
+}
+
+impl PupilBounds {
+    fn empty() -> PupilBounds {
+        return PupilBounds {
+            min_x: f64::MAX,
+            min_y: f64::MAX,
+            max_x: -f64::MAX,
+            max_y: -f64::MAX,
+        };
+    }
+
+    fn is_empty(&self) -> bool {
+        return self.min_x > self.max_x || self.min_y > self.max_y;
+    }
+
+    fn contains(&self, x: f64, y: f64) -> bool {
+        return x >= self.min_x && x <= self.max_x && y >= self.min_y && y <= self.max_y;
+    }
+
+    fn grow(&mut self, x: f64, y: f64) {
+        self.min_x = self.min_x.min(x);
+        self.min_y = self.min_y.min(y);
+        self.max_x = self.max_x.max(x);
+        self.max_y = self.max_y.max(y);
+    }
+
+    fn area(&self) -> f64 {
+        if self.is_empty() {
+            return 0f64;
+        }
+        return (self.max_x - self.min_x) * (self.max_y - self.min_y);
+    }
+}
+
+pub struct LensSystemCamera {
+    frame: Frame,
+    elements: Vec<LensElement>,
+    film_width: f64,
+    film_height: f64,
+    // where on the rear element rays from each radial band of the film can get through the lens
+    m_pupil_bounds: Vec<PupilBounds>,
+    pub shutter: Shutter,
+}
+
+impl LensSystemCamera {
+    // the film sits at pos, looking at look_at. film_diagonal_mm is 43.27 for full frame
+    pub fn new(
+        pos: &Vec3,
+        look_at: &Vec3,
+        up: &Vec3,
+        elements: Vec<LensElement>,
+        film_diagonal_mm: f64,
+        aspect_ratio: f64,
+        focus_dist: f64
+    ) -> LensSystemCamera {
+        let diagonal = film_diagonal_mm * 0.001f64;
+        let film_height = diagonal / (1f64 + aspect_ratio * aspect_ratio).sqrt();
+        let mut camera = LensSystemCamera {
+            frame: Frame::new(pos, look_at, up),
+            elements: elements,
+            film_width: aspect_ratio * film_height,
+            film_height: film_height,
+            m_pupil_bounds: Vec::new(),
+            shutter: Shutter::new(),
+        };
+        camera.focus(focus_dist);
+        return camera;
+    }
+
+    // moves the film so that things focus_dist in front of it are sharp, the framing breathes along
+    pub fn focus(&mut self, focus_dist: f64) {
+        if let Some(film_distance) = self.thick_lens_focus(focus_dist) {
+            let last = self.elements.len() - 1;
+            self.elements[last].thickness = film_distance;
+        }
+        self.m_pupil_bounds = (0..EXIT_PUPIL_BINS).map(|bin| {
+            let radius = 0.5f64 * self.film_diagonal();
+            let inner = radius * bin as f64 / EXIT_PUPIL_BINS as f64;
+            let outer = radius * (bin + 1) as f64 / EXIT_PUPIL_BINS as f64;
+            return self.bound_exit_pupil(inner, outer);
+        }).collect();
+    }
+
+    fn film_diagonal(&self) -> f64 {
+        return (self.film_width * self.film_width + self.film_height * self.film_height).sqrt();
+    }
+
+    // film to the front element
+    fn lens_front_z(&self) -> f64 {
+        return self.elements.iter().map(|element| element.thickness).sum();
+    }
+
+    // film to the rear element
+    fn lens_rear_z(&self) -> f64 {
+        return self.elements[self.elements.len() - 1].thickness;
+    }
+
+    // ray in camera space leaving the film, the camera space ray leaving the front element if it gets through
+    fn trace_from_film(&self, ray: &Ray) -> Option<Ray> {
+        let mut element_z = 0f64;
+        let mut lens_ray = flip_z(ray);
+        for i in (0..self.elements.len()).rev() {
+            let element = &self.elements[i];
+            element_z -= element.thickness;
+            let (time, normal) = intersect_element(element, element_z, &lens_ray)?;
+            let hit = lens_ray.point_at(time);
+            if hit.x * hit.x + hit.y * hit.y > element.aperture_radius * element.aperture_radius {
+                return None;
+            }
+            let mut dir = Vec3::new(lens_ray.dir.x, lens_ray.dir.y, lens_ray.dir.z);
+            if element.curvature_radius != 0f64 {
+                let eta_i = air_if_zero(element.eta);
+                let eta_t = if i > 0 { air_if_zero(self.elements[i - 1].eta) } else { 1f64 };
+                dir = refract(&(-1f64 * &dir.normalize()), &normal, eta_i / eta_t)?;
+            }
+            lens_ray = Ray::new(hit, dir);
+        }
+        return Some(flip_z(&lens_ray));
+    }
+
+    // the other way around, from the scene through the front element to the film side
+    fn trace_from_scene(&self, ray: &Ray) -> Option<Ray> {
+        let mut element_z = -self.lens_front_z();
+        let mut lens_ray = flip_z(ray);
+        for i in 0..self.elements.len() {
+            let element = &self.elements[i];
+            let (time, normal) = intersect_element(element, element_z, &lens_ray)?;
+            let hit = lens_ray.point_at(time);
+            if hit.x * hit.x + hit.y * hit.y > element.aperture_radius * element.aperture_radius {
+                return None;
+            }
+            let mut dir = Vec3::new(lens_ray.dir.x, lens_ray.dir.y, lens_ray.dir.z);
+            if element.curvature_radius != 0f64 {
+                let eta_i = if i == 0 { 1f64 } else { air_if_zero(self.elements[i - 1].eta) };
+                let eta_t = air_if_zero(element.eta);
+                dir = refract(&(-1f64 * &dir.normalize()), &normal, eta_i / eta_t)?;
+            }
+            lens_ray = Ray::new(hit, dir);
+            element_z += element.thickness;
+        }
+        return Some(flip_z(&lens_ray));
+    }
+
+    // thick lens approximation: a paraxial ray traced each way gives the principal plane and focal point
+    // on both sides, z in lens space. [0] is for light coming from the scene, [1] from the film
+    fn thick_lens(&self) -> Option<([f64; 2], [f64; 2])> {
+        let x = 0.001f64 * self.film_diagonal();
+        let from_scene = Ray::new(Vec3::new(x, 0f64, self.lens_front_z() + 1f64), Vec3::new(0f64, 0f64, -1f64));
+        let to_film = self.trace_from_scene(&from_scene)?;
+        let (principal_scene, focal_scene) = cardinal_points(&from_scene, &to_film)?;
+        let from_film = Ray::new(Vec3::new(x, 0f64, self.lens_rear_z() - 1f64), Vec3::new(0f64, 0f64, 1f64));
+        let to_scene = self.trace_from_film(&from_film)?;
+        let (principal_film, focal_film) = cardinal_points(&from_film, &to_scene)?;
+        return Some(([principal_scene, principal_film], [focal_scene, focal_film]));
+    }
+
+    // distance from the rear element to the film that brings focus_dist into focus
+    fn thick_lens_focus(&self, focus_dist: f64) -> Option<f64> {
+        let (principal, focal) = self.thick_lens()?;
+        let focal_length = focal[0] - principal[0];
+        let z = -focus_dist;
+        let c = (principal[1] - z - principal[0]) * (principal[1] - z - 4f64 * focal_length - principal[0]);
+        if c <= 0f64 {
+            return None;
+        }
+        let delta = 0.5f64 * (principal[1] - z + principal[0] - c.sqrt());
+        return Some(self.lens_rear_z() + delta);
+    }
+
+    // conservative bounds of where on the rear element rays from the film band between inner and outer
+    // (on the x axis, the lens is round) make it out of the front
+    fn bound_exit_pupil(&self, inner: f64, outer: f64) -> PupilBounds {
+        let rear_radius = self.elements[self.elements.len() - 1].aperture_radius;
+        let extent = 1.5f64 * rear_radius;
+        let rear_z = self.lens_rear_z();
+        let step = 2f64 * extent / (EXIT_PUPIL_GRID - 1) as f64;
+
+        let mut bounds = PupilBounds::empty();
+        for film_sample in 0..EXIT_PUPIL_FILM_SAMPLES {
+            let film_x = inner + (outer - inner) * (film_sample as f64 + 0.5f64) / EXIT_PUPIL_FILM_SAMPLES as f64;
+            let film_point = Vec3::new(film_x, 0f64, 0f64);
+            for row in 0..EXIT_PUPIL_GRID {
+                for column in 0..EXIT_PUPIL_GRID {
+                    let x = -extent + column as f64 * step;
+                    let y = -extent + row as f64 * step;
+                    if bounds.contains(x, y) {
+                        continue;
+                    }
+                    let ray = Ray::new(Vec3::new(film_x, 0f64, 0f64), &Vec3::new(x, y, rear_z) - &film_point);
+                    if self.trace_from_film(&ray).is_some() {
+                        bounds.grow(x, y);
+                    }
+                }
+            }
+        }
+        if bounds.is_empty() {
+            // nothing got through, fall back to the whole rear element
+            bounds.grow(-extent, -extent);
+            bounds.grow(extent, extent);
+            return bounds;
+        }
+        // points just outside the ones that made it may get through as well
+        bounds.min_x -= step;
+        bounds.min_y -= step;
+        bounds.max_x += step;
+        bounds.max_y += step;
+        return bounds;
+    }
+
+    // a point on the rear element for the film point at (x, y), and the area it was picked from
+    fn sample_exit_pupil(&self, x: f64, y: f64) -> (Vec3, f64) {
+        let radius = (x * x + y * y).sqrt();
+        let bin = ((radius / (0.5f64 * self.film_diagonal())) * EXIT_PUPIL_BINS as f64) as usize;
+        let bounds = &self.m_pupil_bounds[bin.min(EXIT_PUPIL_BINS - 1)];
+        let px = bounds.min_x + (bounds.max_x - bounds.min_x) * sampler::next_f64();
+        let py = bounds.min_y + (bounds.max_y - bounds.min_y) * sampler::next_f64();
+        // bounds were found on the x axis, turn them to where the film point is
+        let (sin, cos) = if radius > 0f64 { (y / radius, x / radius) } else { (0f64, 1f64) };
+        return (Vec3::new(cos * px - sin * py, sin * px + cos * py, self.lens_rear_z()), bounds.area());
+    }
+
+    fn to_world(&self, ray: &Ray) -> Ray {
+        // camera space x is right and y up like u and v, z looks down -w
+        let origin = &self.frame.origin + &self.frame.to_world(ray.origin.x, ray.origin.y, -ray.origin.z);
+        return Ray::new(origin, self.frame.to_world(ray.dir.x, ray.dir.y, -ray.dir.z));
+    }
+}
+
+impl Camera for LensSystemCamera {
+    fn get_ray(&self, s: f64, t: f64) -> Ray {
+        return self.get_weighted_ray(s, t).0;
+    }
+
+    // cos^4 falloff over the area the rear element was sampled in, 1 in the center of the film
+    fn get_weighted_ray(&self, s: f64, t: f64) -> (Ray, f64) {
+        // the image is upside down on the film
+        let film_x = -(s - 0.5f64) * self.film_width;
+        let film_y = -(t - 0.5f64) * self.film_height;
+        let film_point = Vec3::new(film_x, film_y, 0f64);
+        let (rear_point, pupil_area) = self.sample_exit_pupil(film_x, film_y);
+        let film_ray = Ray::new(Vec3::new(film_x, film_y, 0f64), &rear_point - &film_point);
+        return match self.trace_from_film(&film_ray) {
+            Some(ray) => {
+                let cos_theta = film_ray.dir.normalize().z;
+                let cos2 = cos_theta * cos_theta;
+                (self.to_world(&ray), cos2 * cos2 * pupil_area / self.m_pupil_bounds[0].area())
+            }
+            None => (Ray::new(self.get_origin(), -1f64 * &self.frame.w), 0f64),
+        };
+    }
+
+    fn get_origin(&self) -> Vec3 {
+        return Vec3::new(self.frame.origin.x, self.frame.origin.y, self.frame.origin.z);
+    }
+
+    fn shutter(&self) -> &Shutter {
+        return &self.shutter;
+    }
+
+    fn shutter_mut(&mut self) -> &mut Shutter {
+        return &mut self.shutter;
+    }
+
+    // tracing back in through the glass has no closed form, light paths are not connected to the lens
+    fn direction_pdf(&self, _lens_point: &Vec3, _dir: &Vec3) -> f64 {
+        return 0f64;
+    }
+
+    fn project(&self, _lens_point: &Vec3, _point: &Vec3) -> Option<(f64, f64)> {
+        return None;
+    }
+
+    fn is_delta(&self) -> bool {
+        return true;
+    }
+}
+
+fn flip_z(ray: &Ray) -> Ray {
+    return Ray::new(
+        Vec3::new(ray.origin.x, ray.origin.y, -ray.origin.z),
+        Vec3::new(ray.dir.x, ray.dir.y, -ray.dir.z)
+    );
+}
+
+fn air_if_zero(eta: f64) -> f64 {
+    return if eta == 0f64 { 1f64 } else { eta };
+}
+
+// time along ray to the element whose vertex is at element_z, and the normal facing back against the ray
+fn intersect_element(element: &LensElement, element_z: f64, ray: &Ray) -> Option<(f64, Vec3)> {
+    if element.curvature_radius == 0f64 {
+        // the stop is flat
+        if ray.dir.z == 0f64 {
+            return None;
+        }
+        let time = (element_z - ray.origin.z) / ray.dir.z;
+        if time < 0f64 {
+            return None;
+        }
+        return Some((time, Vec3::new(0f64, 0f64, -ray.dir.z.signum())));
+    }
+
+    let radius = element.curvature_radius;
+    let origin = Vec3::new(ray.origin.x, ray.origin.y, ray.origin.z - (element_z + radius));
+    let a = ray.dir.length_squared();
+    let b = 2f64 * ray.dir.dot(&origin);
+    let c = origin.length_squared() - radius * radius;
+    let discriminant = b * b - 4f64 * a * c;
+    if discriminant < 0f64 {
+        return None;
+    }
+    let root = discriminant.sqrt();
+    let t0 = (-b - root) / (2f64 * a);
+    let t1 = (-b + root) / (2f64 * a);
+    // the element is only the cap of the sphere facing the stop
+    let closer = (ray.dir.z > 0f64) != (radius < 0f64);
+    let time = if closer { t0.min(t1) } else { t0.max(t1) };
+    if time < 0f64 {
+        return None;
+    }
+    let mut normal = (&origin + &(time * &ray.dir)).normalize();
+    if normal.dot(&ray.dir) > 0f64 {
+        normal = -1f64 * &normal;
+    }
+    return Some((time, normal));
+}
+
+// incident points away from the surface like normal, eta is incident over transmitted. None on total internal reflection
+fn refract(incident: &Vec3, normal: &Vec3, eta: f64) -> Option<Vec3> {
+    let cos_i = normal.dot(incident);
+    let sin2_i = (1f64 - cos_i * cos_i).max(0f64);
+    let sin2_t = eta * eta * sin2_i;
+    if sin2_t >= 1f64 {
+        return None;
+    }
+    let cos_t = (1f64 - sin2_t).sqrt();
+    return Some(&(-eta * incident) + &((eta * cos_i - cos_t) * normal));
+}
+
+// principal plane and focal point z of a lens that took the axis parallel ray_in to ray_out
+fn cardinal_points(ray_in: &Ray, ray_out: &Ray) -> Option<(f64, f64)> {
+    if ray_out.dir.x == 0f64 {
+        return None;
+    }
+    let focal_time = -ray_out.origin.x / ray_out.dir.x;
+    let focal_z = -ray_out.point_at(focal_time).z;
+    let principal_time = (ray_in.origin.x - ray_out.origin.x) / ray_out.dir.x;
+    let principal_z = -ray_out.point_at(principal_time).z;
+    return Some((principal_z, focal_z));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    static DGAUSS: &'static str = "# D-GAUSS F/2 22deg HFOV, scaled to 50 mm
+29.475	3.76	1.67	25.2
+84.83	0.12	1	25.2
+19.275	4.025	1.67	23
+40.77	3.275	1.699	23
+12.75	5.705	1	18
+0	4.5	0	17.1
+-14.495	1.18	1.603	17
+40.77	6.065	1.658	20
+-20.385	0.19	1	20
+437.065	3.22	1.717	20
+-39.73	5.0	1	20
+";
+
+    fn read_table(name: &str, table: &str) -> io::Result<Vec<LensElement>> {
+        let path = env::temp_dir().join(format!("lens_system_test_{}_{}", process::id(), name));
+        fs::write(&path, table).unwrap();
+        let elements = read_lens_file(&path);
+        fs::remove_file(&path).unwrap();
+        return elements;
+    }
+
+    fn dgauss(focus_dist: f64) -> LensSystemCamera {
+        return LensSystemCamera::new(
+            &Vec3::new(0f64, 0f64, 0f64),
+            &Vec3::new(0f64, 0f64, -1f64),
+            &Vec3::new(0f64, 1f64, 0f64),
+            read_table("dgauss.dat", DGAUSS).unwrap(),
+            43.27f64,
+            1.5f64,
+            focus_dist
+        );
+    }
+
+    #[test]
+    fn lens_files_are_read_in_meters() {
+        let elements = read_table("dgauss.dat", DGAUSS).unwrap();
+        assert_eq!(elements.len(), 11);
+        assert!((elements[0].curvature_radius - 0.029475f64).abs() < 1e-12f64);
+        assert!((elements[0].thickness - 0.00376f64).abs() < 1e-12f64);
+        // diameter in the file, radius in the element
+        assert!((elements[0].aperture_radius - 0.0126f64).abs() < 1e-12f64);
+        assert_eq!(elements[5].curvature_radius, 0f64);
+
+        assert_eq!(read_table("short.dat", "29.475 3.76 1.67\n").err().unwrap().kind(), io::ErrorKind::InvalidData);
+        // the columns don't shift past a word
+        let bad = read_table("word.dat", "29.475 3.76 1.67 25.2\n10 x 1.5 20 30\n").err().unwrap();
+        assert_eq!(bad.kind(), io::ErrorKind::InvalidData);
+        assert!(bad.to_string().contains("line 2"), "{}", bad);
+        assert!(read_table("empty.dat", "# nothing but a comment\n").is_err());
+    }
+
+    #[test]
+    fn the_thick_lens_has_the_focal_length_on_the_box() {
+        let camera = dgauss(10f64);
+        let (principal, focal) = camera.thick_lens().unwrap();
+        let focal_length = (focal[0] - principal[0]).abs();
+        assert!((focal_length - 0.05f64).abs() < 0.002f64, "focal length {}", focal_length);
+        // the same from either side, it is air on both
+        assert!(((focal[1] - principal[1]).abs() - focal_length).abs() < 1e-4f64);
+    }
+
+    #[test]
+    fn focusing_brings_a_point_on_the_axis_together() {
+        for &focus_dist in [1f64, 3f64].iter() {
+            let camera = dgauss(focus_dist);
+            let rear_z = camera.lens_rear_z();
+            let rear_radius = camera.elements[camera.elements.len() - 1].aperture_radius;
+            // paraxial rays from the center of the film cross the axis focus_dist in front of the film
+            for &(x, y) in [(0.1f64, 0f64), (0f64, -0.1f64), (-0.07f64, 0.07f64)].iter() {
+                let rear = Vec3::new(x * rear_radius, y * rear_radius, rear_z);
+                let out = camera.trace_from_film(&Ray::new(Vec3::new(0f64, 0f64, 0f64), rear)).unwrap();
+                let radial = &Vec3::new(out.dir.x, out.dir.y, 0f64);
+                let time = -radial.dot(&Vec3::new(out.origin.x, out.origin.y, 0f64)) / radial.length_squared();
+                let crossing = out.point_at(time).z;
+                assert!((crossing - focus_dist).abs() < 0.02f64 * focus_dist, "focused at {} for {}", crossing, focus_dist);
+            }
+        }
+        // closer focus moves the film away from the lens
+        assert!(dgauss(1f64).lens_rear_z() > dgauss(10f64).lens_rear_z());
+    }
+
+    #[test]
+    fn rays_from_the_film_center_leave_forward() {
+        let camera = dgauss(5f64);
+        let mut through = 0;
+        for _ in 0..200 {
+            let (ray, weight) = camera.get_weighted_ray(0.5f64, 0.5f64);
+            if weight > 0f64 {
+                through += 1;
+                assert!(ray.dir.normalize().z < -0.99f64);
+                // only the cos^4 of the slant towards the edge of the rear element is taken off
+                assert!(weight > 0.5f64 && weight <= 1f64, "weight {}", weight);
+            }
+        }
+        assert!(through > 100, "{} of 200 got through", through);
+    }
+
+    #[test]
+    fn refraction_follows_snell_and_reflects_totally() {
+        let normal = Vec3::new(0f64, 0f64, 1f64);
+        let incident = Vec3::new(0.6f64, 0f64, 0.8f64);
+        let refracted = refract(&incident, &normal, 1f64 / 1.5f64).unwrap();
+        // sines in proportion, and on through the surface
+        assert!((refracted.normalize().x + 0.6f64 / 1.5f64).abs() < 1e-12f64);
+        assert!(refracted.z < 0f64);
+        // the other way out, at a sine of 0.8 from inside the glass there is nothing to refract into
+        assert!(refract(&Vec3::new(0.8f64, 0f64, 0.6f64), &normal, 1.5f64).is_none());
+    }
+}
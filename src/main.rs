@@ -2,41 +2,148 @@ extern crate rusty_math;
 extern crate rand;
 
 use std::error::Error;
-use std::fs::File;
-use std::io::prelude::*;
 use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
 use rusty_math::*;
 
 use rand::Rng;
 
+mod bvh;
+mod transform;
+mod csg;
+mod sdf;
+mod media;
+mod volume;
+mod spectrum;
+
 mod renderable;
 use renderable::RenderList;
+use renderable::Geometry;
 use renderable::shapes;
 
 use renderable::materials;
 
+use csg::CsgNode;
+use sdf::SdfShape;
+
+use bvh::Aabb;
+
+use media::HomogeneousMedium;
+
+use volume::VoxelGrid;
+use volume::DenseGrid;
+use volume::SparseBrickGrid;
+use volume::HeterogeneousVolume;
+
 mod camera;
 use camera::Camera;
+use camera::PerspectiveCamera;
+use camera::OrthographicCamera;
+use camera::FisheyeCamera;
+use camera::FisheyeMapping;
+use camera::EquirectangularCamera;
+use camera::CylindricalCamera;
+
+mod lens_system;
+use lens_system::LensSystemCamera;
+
+mod aperture;
+mod exposure;
+use exposure::Exposure;
+mod filter;
+use filter::Filter;
+use aperture::Aperture;
+use aperture::ApertureMask;
+use aperture::PhysicalLens;
+
+mod animation;
+use animation::CameraAnimation;
+use animation::TransformAnimation;
+use animation::Track;
+use animation::Keyframe;
+use animation::Interpolation;
+
+mod stereo;
+use stereo::StereoRig;
+use stereo::StereoConvergence;
+use stereo::StereoLayout;
+use stereo::OmniStereoCamera;
+
+use transform::Transform;
+use transform::AnimatedTransform;
+use transform::TransformInterpolation;
 
 mod render_buffer;
 use render_buffer::RenderBufferI32;
+use render_buffer::RenderBufferLayers;
 
 mod renderer;
+mod bdpt;
+mod kdtree;
+mod sppm;
+mod sampler;
+mod mlt;
+mod aov;
+use aov::Aov;
+mod output;
+mod preview;
+use preview::Preview;
+mod distributed;
+mod stats;
+mod denoiser;
+use denoiser::Denoiser;
 
 fn main() {
+    let mut args: Vec<String> = std::env::args().collect();
+
+    // --worker host:port renders for a coordinator, whatever scene and settings it sends replace the command line
+    let mut worker_connection = None;
+    let mut scene_seed: Option<u32> = None;
+    if let Some(address) = option_value(&args, "--worker") {
+        match distributed::WorkerConnection::connect(&address) {
+            Err(why) => panic!("couldn't join the coordinator at {}: {}", address, why.description()),
+            Ok((connection, scene)) => {
+                println!("joined the coordinator at {}", address);
+                args = scene.args;
+                scene_seed = Some(scene.seed);
+                worker_connection = Some(connection);
+            }
+        };
+    }
+    // --seed picks the random numbers the scene is built from, the same seed builds the same scene
+    let scene_seed = match (scene_seed, option_value(&args, "--seed")) {
+        (Some(seed), _) => seed,
+        (None, Some(seed)) => match seed.parse::<u32>() {
+            Ok(seed) => seed,
+            Err(_) => panic!("expected a number for --seed, got {}", seed),
+        },
+        (None, None) => rand::thread_rng().gen(),
+    };
+    let mut scene_rng = sampler::seeded_rng(scene_seed);
+    // wall clock time per phase, printed with the render statistics at the end
+    let mut timings = stats::Timings::new();
+    let mut phase_start = Instant::now();
+    let coordinator_address = option_value(&args, "--coordinator");
+    if (worker_connection.is_some() || coordinator_address.is_some())
+        && (option_value(&args, "--stereo").is_some() || option_value(&args, "--frames").is_some()) {
+        panic!("distributed rendering only renders single images");
+    }
+
     // setup the world
     // all materials must be declared before the renderlist references them
 
     // create our material pool
     let mut materials: Vec<Box<renderable::Material>> = Vec::new();
-    for material_index in 0..1000 {
-        let roll = rand::thread_rng().gen_range(0f64, 1f64);
+    for _material_index in 0..1000 {
+        let roll = scene_rng.gen_range(0f64, 1f64);
 
         if roll < 0.4f64 { // lambertian
             // random color
-            let r = rand::thread_rng().gen_range(0f64, 1f64) * rand::thread_rng().gen_range(0f64, 1f64);
-            let g = rand::thread_rng().gen_range(0f64, 1f64) * rand::thread_rng().gen_range(0f64, 1f64);
-            let b = rand::thread_rng().gen_range(0f64, 1f64) * rand::thread_rng().gen_range(0f64, 1f64);
+            let r = scene_rng.gen_range(0f64, 1f64) * scene_rng.gen_range(0f64, 1f64);
+            let g = scene_rng.gen_range(0f64, 1f64) * scene_rng.gen_range(0f64, 1f64);
+            let b = scene_rng.gen_range(0f64, 1f64) * scene_rng.gen_range(0f64, 1f64);
             materials.push(
                 Box::new(
                     materials::Lambertian {
@@ -46,10 +153,10 @@ fn main() {
             );
         }
         else if roll < 0.75f64 { // metal
-            let r = rand::thread_rng().gen_range(0.5f64, 1f64);
-            let g = rand::thread_rng().gen_range(0.5f64, 1f64);
-            let b = rand::thread_rng().gen_range(0.5f64, 1f64);
-            let fuzz = rand::thread_rng().gen_range(0.01f64, 0.4f64);
+            let r = scene_rng.gen_range(0.5f64, 1f64);
+            let g = scene_rng.gen_range(0.5f64, 1f64);
+            let b = scene_rng.gen_range(0.5f64, 1f64);
+            let fuzz = scene_rng.gen_range(0.01f64, 0.4f64);
             materials.push(
                 Box::new(
                     materials::Metal {
@@ -59,8 +166,14 @@ fn main() {
                 )
             );
         }
-        else { // dielectric
-            materials.push(Box::new(materials::Dielectric { refraction_index: 1.5f64 }));
+        else if roll < 0.9f64 { // dielectric, most don't disperse since that adds color noise
+            materials.push(Box::new(materials::Dielectric::new(1.5f64)));
+        }
+        else if roll < 0.95f64 { // glass that disperses
+            materials.push(Box::new(materials::Dielectric::crown_glass()));
+        }
+        else { // water
+            materials.push(Box::new(materials::Dielectric::water()));
         }
     }
 
@@ -69,21 +182,61 @@ fn main() {
         albedo: Vec3::new(0.70f64, 0.70f64, 0.70f64),
         fuzziness: 0.05f64,
     };
-    let center_mat_dielec = materials::Dielectric { refraction_index: 1.5f64 };
+    let center_mat_dielec = materials::Dielectric::flint_glass();
+    let csg_mat_outer = materials::Lambertian { albedo: Vec3::new(0.8f64, 0.2f64, 0.1f64) };
+    let csg_mat_carved = materials::Metal {
+        albedo: Vec3::new(0.9f64, 0.75f64, 0.3f64),
+        fuzziness: 0.1f64,
+    };
+    let sdf_mat = materials::Metal {
+        albedo: Vec3::new(0.6f64, 0.8f64, 0.65f64),
+        fuzziness: 0.2f64,
+    };
+    let bulb_mat = materials::Lambertian { albedo: Vec3::new(0.25f64, 0.35f64, 0.8f64) };
+    // --physical-units gives the lights in lumens and the sky in lux, exposed like a camera at sunny 16
+    // would. the numbers come out the same as the plain ones once exposed
+    let physical_units = args.iter().any(|arg| arg == "--physical-units");
+    let lamp_emission = Vec3::new(8f64, 6.5f64, 4f64);
+    let spot_emission = Vec3::new(12f64, 12f64, 12f64);
+    let lamp_mat = materials::DiffuseLight {
+        emission: if physical_units { exposure::emission_nits(&lamp_emission, exposure::sphere_light_nits(630000f64, 0.25f64)) } else { lamp_emission },
+    };
+    let spot_mat = materials::DiffuseLight {
+        emission: if physical_units { exposure::emission_nits(&spot_emission, exposure::sphere_light_nits(1640000f64, 0.3f64)) } else { spot_emission },
+    };
 
     let mut world = RenderList::new();
+    if physical_units {
+        // sun and sky at noon
+        world.set_sky_luminance(exposure::sky_nits(120000f64));
+    }
 
     for z in 0..25 {
         for x in -10..10 {
-            let x_variance = rand::thread_rng().gen_range(1f64, 1.3f64);
-            let z_variance = rand::thread_rng().gen_range(1f64, 1.3f64);
-            let radius = rand::thread_rng().gen_range(0.1f64, 0.3f64);
+            let x_variance = scene_rng.gen_range(1f64, 1.3f64);
+            let z_variance = scene_rng.gen_range(1f64, 1.3f64);
+            let radius = scene_rng.gen_range(0.1f64, 0.3f64);
             let sphere = shapes::Sphere {
                 center: Vec3::new(x as i64 as f64 * x_variance , radius, z as i64 as f64 * -z_variance),
                 radius: radius,
             };
 
-            let material_index = rand::thread_rng().gen_range(0, materials.len());
+            let material_index = scene_rng.gen_range(0, materials.len());
+
+            // every few balls bounce while the shutter is open
+            if scene_rng.gen_range(0f64, 1f64) < 0.2f64 {
+                let bounce = scene_rng.gen_range(0.1f64, 0.4f64);
+                let moving_sphere = shapes::MovingSphere {
+                    centers: vec![
+                        Vec3::new(sphere.center.x, sphere.center.y, sphere.center.z),
+                        Vec3::new(sphere.center.x, sphere.center.y + bounce, sphere.center.z),
+                    ],
+                    times: vec![0f64, 1f64],
+                    radius: radius,
+                };
+                world.add_moving_sphere(&moving_sphere, &*materials[material_index]);
+                continue;
+            }
             world.add_sphere(&sphere, &*materials[material_index]);
         }
     }
@@ -101,71 +254,786 @@ fn main() {
         center: Vec3::new(-2f64, 1.5f64, -7f64),
         radius: 1.5f64,
     };
+    // a small cluster shared by every instance in the ring behind the center spheres
+    let mut cluster = Geometry::new();
+    for i in 0..3 {
+        let cluster_sphere = shapes::Sphere {
+            center: Vec3::new(i as f64 * 0.6f64 - 0.6f64, 0.3f64, 0f64),
+            radius: 0.3f64,
+        };
+        cluster.add_sphere(&cluster_sphere, &*materials[i]);
+    }
+    cluster.build();
+
+    let ring = |angle: f64| {
+        return Transform::uniform_scale(0.75f64)
+            .then(&Transform::translate(&Vec3::new(0f64, 0f64, 6f64)))
+            .then(&Transform::rotate(&Vec3::new(0f64, 1f64, 0f64), angle))
+            .then(&Transform::translate(&Vec3::new(0f64, 0f64, -25f64)));
+    };
+    // (instance, angle) so a frame sequence can move them
+    let mut ring_instances: Vec<(usize, f64)> = Vec::new();
+    for i in 0..12 {
+        let angle = i as f64 * 30f64;
+        // the ring spins a few degrees while the shutter is open
+        let transform = AnimatedTransform::new(
+            ring(angle), 0f64,
+            ring(angle + 5f64), 1f64,
+            TransformInterpolation::Decomposed
+        );
+        ring_instances.push((world.add_moving_instance(&cluster, transform), angle));
+    }
+    // and two that stand still on the far side of the ring
+    for &(x, angle) in [(-3f64, 20f64), (3.5f64, -35f64)].iter() {
+        world.add_instance(&cluster, Transform::rotate(&Vec3::new(0f64, 1f64, 0f64), angle)
+            .then(&Transform::translate(&Vec3::new(x, 0f64, -36f64))));
+    }
+
+    // rounded cube with a hole drilled through it, the bore takes the metal of the cylinder
+    let csg_center = Vec3::new(1.7f64, 0.6f64, -2.6f64);
+    let csg_half = Vec3::new(0.55f64, 0.55f64, 0.55f64);
+    let drilled_cube = CsgNode::difference(
+        CsgNode::intersection(
+            CsgNode::leaf(
+                shapes::AxisBox { min: &csg_center - &csg_half, max: &csg_center + &csg_half },
+                &csg_mat_outer
+            ),
+            CsgNode::leaf(
+                shapes::Sphere { center: Vec3::new(csg_center.x, csg_center.y, csg_center.z), radius: 0.75f64 },
+                &csg_mat_outer
+            )
+        ),
+        CsgNode::leaf(shapes::Quadric::cylinder_y(&csg_center, 0.3f64), &csg_mat_carved)
+    );
+    world.add_csg(&drilled_cube);
+
+    // a spinning top balanced on its point, a squashed ellipsoid on a cone cut off below it
+    let top_tip = Vec3::new(0.6f64, 0f64, -2.3f64);
+    let spinning_top = CsgNode::union(
+        CsgNode::leaf(
+            shapes::Quadric::ellipsoid(&(&top_tip + &Vec3::new(0f64, 0.42f64, 0f64)), &Vec3::new(0.3f64, 0.1f64, 0.3f64)),
+            &csg_mat_outer
+        ),
+        CsgNode::intersection(
+            CsgNode::leaf(shapes::Quadric::cone_y(&top_tip, 0.7f64), &csg_mat_carved),
+            CsgNode::leaf(
+                shapes::AxisBox {
+                    min: &top_tip - &Vec3::new(0.3f64, 0f64, 0.3f64),
+                    max: &top_tip + &Vec3::new(0.3f64, 0.4f64, 0.3f64),
+                },
+                &csg_mat_carved
+            )
+        )
+    );
+    world.add_csg(&spinning_top);
+
+    // twisted column melting into a rippled ring, no closed form so it gets sphere traced
+    let sdf_center = Vec3::new(-1.5f64, 0f64, -2.4f64);
+    let twisted_blob = SdfShape::new(
+        Box::new(sdf::Translate {
+            sdf: sdf::SmoothUnion {
+                a: sdf::Translate {
+                    sdf: sdf::Twist {
+                        sdf: sdf::RoundBox {
+                            half_extents: Vec3::new(0.2f64, 0.5f64, 0.2f64),
+                            rounding: 0.04f64,
+                        },
+                        rate: 3f64,
+                        max_radius: 0.3f64,
+                    },
+                    offset: Vec3::new(0f64, 0.5f64, 0f64),
+                },
+                b: sdf::Translate {
+                    sdf: sdf::Displace {
+                        sdf: sdf::Torus { major_radius: 0.4f64, minor_radius: 0.1f64 },
+                        amplitude: 0.02f64,
+                        frequency: 20f64,
+                    },
+                    offset: Vec3::new(0f64, 0.1f64, 0f64),
+                },
+                blend_radius: 0.15f64,
+            },
+            offset: Vec3::new(sdf_center.x, sdf_center.y, sdf_center.z),
+        }),
+        Aabb::new(
+            &sdf_center + &Vec3::new(-0.6f64, 0f64, -0.6f64),
+            &sdf_center + &Vec3::new(0.6f64, 1.1f64, 0.6f64)
+        )
+    );
+    world.add_sdf(&twisted_blob, &sdf_mat);
+
+    // a mandelbulb next to a row of beads with a bite out of the top of each, one field for both
+    let beads_center = Vec3::new(0.1f64, 0.14f64, -3.6f64);
+    let bulb_center = Vec3::new(-0.35f64, 0.4f64, -3f64);
+    let bulb_and_beads = SdfShape::new(
+        Box::new(sdf::Union {
+            a: sdf::Translate {
+                sdf: sdf::Scale {
+                    sdf: sdf::Mandelbulb { power: 8f64, iterations: 8 },
+                    factor: 0.35f64,
+                },
+                offset: Vec3::new(bulb_center.x, bulb_center.y, bulb_center.z),
+            },
+            b: sdf::Translate {
+                sdf: sdf::Intersection {
+                    a: sdf::Repeat {
+                        sdf: sdf::Difference {
+                            a: sdf::Sphere { radius: 0.14f64 },
+                            b: sdf::Translate {
+                                sdf: sdf::Sphere { radius: 0.1f64 },
+                                offset: Vec3::new(0f64, 0.12f64, 0f64),
+                            },
+                        },
+                        // repeat along x only
+                        period: Vec3::new(0.36f64, 0f64, 0f64),
+                    },
+                    b: sdf::RoundBox {
+                        half_extents: Vec3::new(0.85f64, 0.2f64, 0.2f64),
+                        rounding: 0f64,
+                    },
+                },
+                offset: Vec3::new(beads_center.x, beads_center.y, beads_center.z),
+            },
+        }),
+        Aabb::new(Vec3::new(-0.8f64, 0f64, -3.8f64), Vec3::new(1f64, 0.8f64, -2.6f64))
+    );
+    world.add_sdf(&bulb_and_beads, &bulb_mat);
+
+    // a puff of smoke to the right of the metal ball and a light haze over everything
+    let smoke = HomogeneousMedium {
+        sigma_a: Vec3::new(0.1f64, 0.1f64, 0.1f64),
+        sigma_s: Vec3::new(2.5f64, 2.5f64, 2.5f64),
+        g: 0.3f64,
+    };
+    let haze = HomogeneousMedium {
+        sigma_a: Vec3::new(0f64, 0f64, 0f64),
+        sigma_s: Vec3::new(0.008f64, 0.01f64, 0.012f64),
+        g: 0f64,
+    };
+    let smoke_boundary = shapes::Sphere {
+        center: Vec3::new(2.6f64, 1.2f64, -8f64),
+        radius: 1.2f64,
+    };
+    world.add_medium(&smoke_boundary, &smoke);
+    world.set_fog(&haze, 60f64);
+
+    // small fireball off to the left, hot and dense in the middle, cooling towards the lumpy edge
+    // --volume swaps in a grid file (dense or sparse bricks) in its place, --save-volume writes ours out
+    let (fireball_grid, fireball_bounds): (Box<VoxelGrid>, Aabb) = match option_value(&args, "--volume") {
+        Some(file_name) => match volume::load_grid(Path::new(&file_name)) {
+            Err(why) => panic!("couldn't load the volume {}: {}", file_name, why.description()),
+            Ok(loaded) => loaded,
+        },
+        None => {
+            let bounds = Aabb::new(Vec3::new(-6f64, 0f64, -13f64), Vec3::new(-3f64, 3f64, -10f64));
+            let grid = DenseGrid::from_fn(48, 48, 48, 2, |x, y, z| {
+                let (dx, dy, dz) = (x - 0.5f64, y - 0.5f64, z - 0.5f64);
+                let r = (dx * dx + dy * dy + dz * dz).sqrt();
+                let lumps = 0.06f64 * (17f64 * x).sin() * (13f64 * y).sin() * (19f64 * z).sin();
+                let falloff = ((0.42f64 + lumps - r) / 0.1f64).max(0f64).min(1f64);
+                let temperature = 800f64 + 1600f64 * (1f64 - r / 0.45f64).max(0f64);
+                return vec![falloff as f32, temperature as f32];
+            });
+            if let Some(file_name) = option_value(&args, "--save-volume") {
+                // mostly empty space, so .sbrk files store it as bricks
+                let saved = if file_name.ends_with(".sbrk") {
+                    SparseBrickGrid::from_dense(&grid, 8).save(Path::new(&file_name), &bounds)
+                } else {
+                    grid.save(Path::new(&file_name), &bounds)
+                };
+                match saved {
+                    Err(why) => panic!("couldn't save the volume to {}: {}", file_name, why.description()),
+                    Ok(_) => println!("saved the volume to {}", file_name),
+                };
+            }
+            (Box::new(grid), bounds)
+        }
+    };
+    let mut fireball = HeterogeneousVolume::new(
+        fireball_grid,
+        fireball_bounds,
+        6f64,
+        Vec3::new(0.6f64, 0.6f64, 0.6f64),
+        0.2f64
+    );
+    fireball.set_blackbody(2f64, 1500f64);
+    world.add_volume(&fireball);
+
+    // a warm lamp hanging to the right of the metal ball, the light the bidirectional integrators start from
+    let lamp = shapes::Sphere {
+        center: Vec3::new(2.3f64, 2.5f64, -4.5f64),
+        radius: 0.25f64,
+    };
+    world.add_light(&lamp, &lamp_mat);
+    // and a small bright one over the glass ball that throws a caustic under it
+    let spot = shapes::Sphere {
+        center: Vec3::new(-2f64, 4.5f64, -7f64),
+        radius: 0.3f64,
+    };
+    world.add_light(&spot, &spot_mat);
+
     world.add_sphere(&sphere, &floor_mat);
     world.add_sphere(&center_sphere_metal, &center_mat_metal);
     world.add_sphere(&center_sphere_dielec, &center_mat_dielec);
+    timings.add("scene", phase_start.elapsed());
+    phase_start = Instant::now();
+    world.build();
+    timings.add("bvh", phase_start.elapsed());
 
     // render setting?
-    let image_width_pixels = 400f64;
-    let image_height_pixels = 300f64;
+    // --size WIDTHxHEIGHT in pixels
+    let (image_width_pixels, image_height_pixels) = match option_value(&args, "--size") {
+        Some(size) => match parse_size(&size) {
+            Some((width, height)) => (width as f64, height as f64),
+            None => panic!("expected --size WIDTHxHEIGHT, got {}", size),
+        },
+        None => (400f64, 300f64),
+    };
 
     let pos = Vec3::new(0f64, 2f64, 1f64);
     let look_at = Vec3::new(0f64, 1f64, -5f64);
-    let camera = Camera::new(
-        &pos,
-        &look_at,
-        Vec3::new(0f64, 1f64, 0f64), // up
-        40f64, // fov
-        image_width_pixels/image_height_pixels, // aspect ratio
-        0.2f64, //aperture
-        (&pos - &look_at).length_squared().sqrt(), // focus dist
-    );
+    let projection = option_value(&args, "--camera").unwrap_or(String::from("perspective"));
+
+    // --filter box|tent|gaussian|mitchell|lanczos|blackman-harris, --filter-radius in pixels overrides its reach
+    let mut filter = match option_value(&args, "--filter") {
+        Some(name) => match Filter::from_name(&name) {
+            Some(filter) => filter,
+            None => panic!("unknown filter: {}", name),
+        },
+        None => Filter::box_filter(),
+    };
+    if let Some(radius) = option_value(&args, "--filter-radius") {
+        filter.radius = match radius.parse::<f64>() {
+            Ok(radius) if radius > 0f64 => radius,
+            _ => panic!("expected a positive filter radius, got {}", radius),
+        };
+    }
+
+    // --integrator path|bdpt|sppm|mlt, only the path tracer sees media and volumes
+    let integrator = match option_value(&args, "--integrator") {
+        Some(name) => match renderer::IntegratorKind::from_name(&name) {
+            Some(integrator) => integrator,
+            None => panic!("unknown integrator: {}", name),
+        },
+        None => renderer::IntegratorKind::Path,
+    };
+    match integrator {
+        renderer::IntegratorKind::Path | renderer::IntegratorKind::Bidirectional => {}
+        _ => if worker_connection.is_some() || coordinator_address.is_some() {
+            panic!("distributed rendering only works with the path and bdpt integrators");
+        },
+    };
+
+    // --max-depth caps the bounces of a path, russian roulette ends most of them well before that
+    let max_depth = match option_value(&args, "--max-depth") {
+        Some(depth) => match depth.parse::<i32>() {
+            Ok(depth) if depth >= 0 => depth,
+            _ => panic!("expected a number of bounces for --max-depth, got {}", depth),
+        },
+        None => 50,
+    };
+
+    let samples_per_pixel = match option_value(&args, "--spp") {
+        Some(samples) => match samples.parse::<i32>() {
+            Ok(samples) if samples > 0 => samples,
+            _ => panic!("expected a positive number of samples for --spp, got {}", samples),
+        },
+        None => 50,
+    };
+
+    // --aov-output layers|files renders every aov, into the layers of image.exr or a file per layer
+    // (image.beauty.exr, image.normal.exr, ...) for compositors that want them apart. --denoise renders only
+    // the ones it needs, without either there are none
+    let aov_output = option_value(&args, "--aov-output");
+    match aov_output.as_ref().map(|output| output.as_str()) {
+        None | Some("layers") | Some("files") => {}
+        Some(output) => panic!("unknown aov output: {}, expected layers or files", output),
+    };
+    let denoise = args.iter().any(|arg| arg == "--denoise");
+    let aovs = if aov_output.is_some() { Aov::all() } else if denoise { Denoiser::aovs() } else { Vec::new() };
+
+    let render_settings = renderer::RenderSettings {
+        num_samples_per_pixel: samples_per_pixel,
+        // --spectral traces three wavelengths per path, glass only splits light into its colors with it
+        spectral: args.iter().any(|arg| arg == "--spectral"),
+        integrator: integrator,
+        max_depth: max_depth,
+        aovs: aovs,
+        // --auto-exposure meters the image, otherwise radiance goes to the film as it is unless it is in physical units
+        exposure: if args.iter().any(|arg| arg == "--auto-exposure") {
+            Some(Exposure::auto())
+        } else if physical_units {
+            Some(Exposure::sunny_16())
+        } else {
+            None
+        },
+        filter: filter,
+    };
+
+    // --preview <port> shows the render in a browser at http://127.0.0.1:<port>/ while it runs,
+    // --preview-terminal draws it into the terminal as $COLUMNS (or 80) characters across
+    let preview_port = option_value(&args, "--preview");
+    let preview_terminal = args.iter().any(|arg| arg == "--preview-terminal");
+    let live_preview: Option<Arc<Preview>> = if preview_port.is_some() || preview_terminal {
+        Some(Arc::new(Preview::new()))
+    } else {
+        None
+    };
+    if let (Some(port), Some(preview)) = (preview_port, live_preview.as_ref()) {
+        let port: u16 = match port.parse() {
+            Ok(port) => port,
+            Err(_) => panic!("expected a port for --preview, got {}", port),
+        };
+        match preview::serve(preview.clone(), port) {
+            Err(why) => panic!("couldn't start the preview server: {}", why.description()),
+            Ok(_) => println!("preview at http://127.0.0.1:{}/", port),
+        };
+    }
+    if let (true, Some(preview)) = (preview_terminal, live_preview.as_ref()) {
+        let columns = std::env::var("COLUMNS").ok().and_then(|columns| columns.parse::<usize>().ok()).unwrap_or(80);
+        preview.show_in_terminal(columns);
+    }
+    let preview = live_preview.as_ref().map(|preview| &**preview);
+
+    // an eye pair instead of a single view, equirectangular turns into omni-directional stereo
+    if let Some(layout_name) = option_value(&args, "--stereo") {
+        let layout = match layout_name.as_str() {
+            "side-by-side" => StereoLayout::SideBySide,
+            "top-bottom" => StereoLayout::TopBottom,
+            "separate" => StereoLayout::Separate,
+            _ => panic!("unknown stereo layout: {}", layout_name),
+        };
+        // --convergence toe-in turns the eyes in instead of shifting their images, for comparing against rigs that do
+        let convergence = match option_value(&args, "--convergence").as_ref().map(|name| name.as_str()) {
+            None | Some("off-axis") => StereoConvergence::OffAxis,
+            Some("toe-in") => StereoConvergence::ToeIn,
+            Some(name) => panic!("unknown stereo convergence: {}, expected off-axis or toe-in", name),
+        };
+        let up = Vec3::new(0f64, 1f64, 0f64);
+        let interaxial = 0.065f64;
+        let (mut left, mut right): (Box<Camera>, Box<Camera>) = if projection == "equirectangular" {
+            let (left, right) = OmniStereoCamera::pair(&pos, &look_at, &up, interaxial);
+            (Box::new(left), Box::new(right))
+        } else {
+            let rig = StereoRig {
+                pos: Vec3::new(pos.x, pos.y, pos.z),
+                look_at: Vec3::new(look_at.x, look_at.y, look_at.z),
+                up: up,
+                vertical_fov_degrees: 40f64,
+                aspect_ratio: image_width_pixels/image_height_pixels,
+                aperture: 0.2f64,
+                focus_dist: (&pos - &look_at).length_squared().sqrt(),
+                interaxial: interaxial,
+                convergence_distance: (&pos - &look_at).length_squared().sqrt(),
+                convergence: convergence,
+            };
+            let (left, right) = rig.eyes();
+            (Box::new(left), Box::new(right))
+        };
+        left.set_shutter(0f64, 1f64);
+        right.set_shutter(0f64, 1f64);
+
+        let eye_settings = renderer::RenderSettings { aovs: Vec::new(), ..render_settings };
+        phase_start = Instant::now();
+        let left_buffer = render_beauty(&world, &*left, &eye_settings, image_width_pixels as usize, image_height_pixels as usize, preview);
+        let right_buffer = render_beauty(&world, &*right, &eye_settings, image_width_pixels as usize, image_height_pixels as usize, preview);
+        timings.add("render", phase_start.elapsed());
+        phase_start = Instant::now();
+
+        let written = match stereo::combine(&left_buffer, &right_buffer, layout) {
+            Some(combined) => output::write_ppm(Path::new("image.ppm"), &combined),
+            None => output::write_ppm(Path::new("image_left.ppm"), &left_buffer)
+                .and_then(|_| output::write_ppm(Path::new("image_right.ppm"), &right_buffer)),
+        };
+        match written {
+            Err(why) => panic!("couldn't write stereo image: {}", why.description()),
+            Ok(_) => println!("write stereo to file successful"),
+        };
+        timings.add("output", phase_start.elapsed());
+        report_statistics(&args, &timings);
+        return;
+    }
+
+    // an image sequence along the timeline, the world is built once and only the instances move
+    if let Some(range) = option_value(&args, "--frames") {
+        let frames = match parse_frame_range(&range) {
+            Some(frames) => frames,
+            None => panic!("expected --frames start..end or start..=end, got {}", range),
+        };
+        let frames_per_second = 24f64;
+        // the camera pulls back and rises while the ring behind the center spheres turns and bobs
+        let camera_animation = CameraAnimation {
+            position: Track::new(vec![
+                Keyframe::new(0f64, Vec3::new(pos.x, pos.y, pos.z), Interpolation::Tcb { tension: 0f64, continuity: 0f64, bias: 0f64 }),
+                Keyframe::new(2f64, Vec3::new(-1.5f64, 2.5f64, 3f64), Interpolation::Tcb { tension: 0f64, continuity: 0f64, bias: 0f64 }),
+                Keyframe::new(4f64, Vec3::new(0f64, 4f64, 6f64), Interpolation::Tcb { tension: 0.5f64, continuity: 0f64, bias: 0f64 }),
+            ]),
+            look_at: Track::constant(Vec3::new(look_at.x, look_at.y, look_at.z)),
+            vertical_fov_degrees: Track::new(vec![
+                Keyframe::new(0f64, 40f64, Interpolation::Linear),
+                Keyframe::new(4f64, 30f64, Interpolation::Linear),
+            ]),
+            focus_distance: Track::new(vec![
+                Keyframe::new(0f64, (&pos - &look_at).length_squared().sqrt(), Interpolation::Linear),
+                Keyframe::new(4f64, 11.4f64, Interpolation::Linear),
+            ]),
+        };
+        let ring_animation = TransformAnimation {
+            translation: Track::new(vec![
+                Keyframe::new(0f64, Vec3::new(0f64, 0f64, 0f64), Interpolation::Bezier {
+                    in_handle: Vec3::new(0f64, 0f64, 0f64),
+                    out_handle: Vec3::new(0f64, 1.5f64, 0f64),
+                }),
+                Keyframe::new(4f64, Vec3::new(0f64, 0f64, 0f64), Interpolation::Bezier {
+                    in_handle: Vec3::new(0f64, 1.5f64, 0f64),
+                    out_handle: Vec3::new(0f64, 0f64, 0f64),
+                }),
+            ]),
+            rotation_degrees: Track::new(vec![
+                Keyframe::new(0f64, Vec3::new(0f64, 0f64, 0f64), Interpolation::Linear),
+                Keyframe::new(4f64, Vec3::new(0f64, 120f64, 0f64), Interpolation::Linear),
+            ]),
+            ..TransformAnimation::fixed()
+        };
+        let up = Vec3::new(0f64, 1f64, 0f64);
+        let frame_settings = renderer::RenderSettings { aovs: Vec::new(), ..render_settings };
+
+        for frame in frames {
+            // a 180 degree shutter, open for the first half of the frame
+            let open = frame as f64 / frames_per_second;
+            let close = (frame as f64 + 0.5f64) / frames_per_second;
+            phase_start = Instant::now();
+            for &(instance, angle) in ring_instances.iter() {
+                world.set_instance_transform(instance, ring_animation.over_shutter(&ring(angle), open, close));
+            }
+            world.refit();
+            timings.add("bvh", phase_start.elapsed());
+
+            let mut camera = camera_animation.at(open, &up, image_width_pixels/image_height_pixels, 0.2f64);
+            camera.set_shutter(0f64, 1f64);
+            phase_start = Instant::now();
+            let frame_buffer = render_beauty(
+                &world, &camera, &frame_settings, image_width_pixels as usize, image_height_pixels as usize,
+                preview
+            );
+            timings.add("render", phase_start.elapsed());
+            phase_start = Instant::now();
+            let file_name = format!("image_{:04}.ppm", frame);
+            match output::write_ppm(Path::new(&file_name), &frame_buffer) {
+                Err(why) => panic!("couldn't write {}: {}", file_name, why.description()),
+                Ok(_) => println!("write {} successful", file_name),
+            };
+            timings.add("output", phase_start.elapsed());
+        }
+        report_statistics(&args, &timings);
+        return;
+    }
+
+    let mut camera = build_camera(&args, &projection, &pos, &look_at, image_width_pixels/image_height_pixels);
+
+    camera.set_shutter(0f64, 1f64);
+
+    if let Some(connection) = worker_connection {
+        let (width, height) = (image_width_pixels as usize, image_height_pixels as usize);
+        phase_start = Instant::now();
+        match connection.serve(|region| renderer::render_region(&world, &*camera, &render_settings, width, height, region)) {
+            Err(why) => panic!("lost the coordinator: {}", why.description()),
+            Ok(_) => println!("the coordinator has everything it needs"),
+        };
+        timings.add("render", phase_start.elapsed());
+        report_statistics(&args, &timings);
+        return;
+    }
+
+    // --coordinator [host:]port hands the image out to workers and puts together what they send back,
+    // a worker that has not sent its region after --worker-timeout seconds (300) loses it to the others
+    if let Some(address) = coordinator_address {
+        let (width, height) = (image_width_pixels as usize, image_height_pixels as usize);
+        let worker_timeout = match option_value(&args, "--worker-timeout") {
+            Some(seconds) => match seconds.parse::<f64>() {
+                Ok(seconds) if seconds > 0f64 => Duration::from_secs_f64(seconds),
+                _ => panic!("expected a positive number of seconds for --worker-timeout, got {}", seconds),
+            },
+            None => Duration::from_secs(300),
+        };
+        let scene = distributed::Scene {
+            seed: scene_seed,
+            args: forwarded_args(&args),
+        };
+        let regions = distributed::split(width, height, render_settings.num_samples_per_pixel);
+        phase_start = Instant::now();
+        let (samples, splats) = match distributed::coordinate(&address, &scene, &filter, width, height, regions, worker_timeout, preview) {
+            Ok(result) => result,
+            Err(why) => panic!("couldn't coordinate the render: {}", why.description()),
+        };
+        let mut film = splats;
+        samples.resolve_into(&mut film, render_settings.num_samples_per_pixel);
+        let mut output_buffer = RenderBufferI32::new(width, height);
+        renderer::develop(&film, &*camera, &render_settings, &mut output_buffer);
+        if let Some(preview) = preview {
+            preview.finish(&output_buffer);
+        }
+        timings.add("render", phase_start.elapsed());
+        phase_start = Instant::now();
+        match output::write_ppm(Path::new("image.ppm"), &output_buffer) {
+            Err(why) => panic!("couldn't write image: {}", why.description()),
+            Ok(_) => println!("write to file successful"),
+        };
+        timings.add("output", phase_start.elapsed());
+        // the rays were counted by the workers, here there are only the timings
+        report_statistics(&args, &timings);
+        return;
+    }
 
     let mut output_buffer = RenderBufferI32::new(image_width_pixels as usize, image_height_pixels as usize);
+    let mut aov_buffer = if render_settings.aovs.is_empty() {
+        None
+    } else {
+        Some(RenderBufferLayers::new(image_width_pixels as usize, image_height_pixels as usize))
+    };
 
     // render
+    phase_start = Instant::now();
+    let exposure_scale;
     {
-        let render_settings = renderer::RenderSettings { num_samples_per_pixel: 50 };
         // create the package to render
         let mut render_package = renderer::RenderPackage {
         render_list: &world,
-        camera: &camera,
+        camera: &*camera,
         output_buffer: &mut output_buffer,
+        aov_buffer: aov_buffer.as_mut(),
+        preview: preview,
         };
 
-        renderer::render(&mut render_package, &render_settings);
+        exposure_scale = renderer::render(&mut render_package, &render_settings);
     }
+    timings.add("render", phase_start.elapsed());
 
-    // write header
-    let mut ppm_str = String::new();
-    ppm_str.push_str(
-        &format!(
-            "P3\n{} {}\n255\n",
-            output_buffer.width,
-            output_buffer.height
-        )
-    );
+    // write to file
+    phase_start = Instant::now();
+    match output::write_ppm(Path::new("image.ppm"), &output_buffer) {
+        Err(why) => panic!("couldn't write image: {}", why.description()),
+        Ok(_) => println!("write to file successful"),
+    };
+    timings.add("output", phase_start.elapsed());
+    // optional with --denoise, filters the beauty with the help of the aovs into another layer
+    phase_start = Instant::now();
+    let denoised = match aov_buffer.as_mut() {
+        Some(aov_buffer) if denoise => Denoiser::new().denoise(aov_buffer),
+        _ => None,
+    };
+    timings.add("denoise", phase_start.elapsed());
+    phase_start = Instant::now();
+    if let (Some(layer), Some(aov_buffer)) = (denoised, aov_buffer.as_ref()) {
+        let denoised_buffer = renderer::tone_map_layer(aov_buffer, layer, exposure_scale);
+        match output::write_ppm(Path::new("image_denoised.ppm"), &denoised_buffer) {
+            Err(why) => panic!("couldn't write denoised image: {}", why.description()),
+            Ok(_) => println!("write denoised image successful"),
+        };
+    }
+    if let (Some(aov_output), Some(aov_buffer)) = (aov_output, aov_buffer.as_ref()) {
+        let written = if aov_output == "files" {
+            output::write_exr_layer_files(Path::new("."), "image", aov_buffer)
+        } else {
+            output::write_exr(Path::new("image.exr"), aov_buffer)
+        };
+        match written {
+            Err(why) => panic!("couldn't write layers: {}", why.description()),
+            Ok(_) => println!("write layers successful"),
+        };
+    }
+    timings.add("output", phase_start.elapsed());
+    report_statistics(&args, &timings);
+}
 
-    let mut pixel_index: usize = 0;
-    while pixel_index < output_buffer.buffer.len() {
-        let ir = output_buffer.buffer[pixel_index];
-        let ig = output_buffer.buffer[pixel_index + 1];
-        let ib = output_buffer.buffer[pixel_index + 2];
+// prints what the run counted and how long it took, --stats-json <file> also writes it out as json
+fn report_statistics(args: &[String], timings: &stats::Timings) {
+    let counters = stats::totals();
+    println!("{}", stats::summary(&counters, timings));
+    if let Some(file_name) = option_value(args, "--stats-json") {
+        match std::fs::write(Path::new(&file_name), stats::json(&counters, timings)) {
+            Err(why) => panic!("couldn't write statistics to {}: {}", file_name, why.description()),
+            Ok(_) => println!("write statistics to {} successful", file_name),
+        };
+    }
+}
 
-        ppm_str.push_str(&format!("{} {} {}\n", ir, ig, ib));
+// a single image without aovs, one eye of a stereo pair or one frame of a sequence
+fn render_beauty(
+    world: &RenderList,
+    camera: &Camera,
+    render_settings: &renderer::RenderSettings,
+    width: usize,
+    height: usize,
+    preview: Option<&Preview>
+) -> RenderBufferI32 {
+    let mut output_buffer = RenderBufferI32::new(width, height);
+    {
+        let mut render_package = renderer::RenderPackage {
+            render_list: world,
+            camera: camera,
+            output_buffer: &mut output_buffer,
+            aov_buffer: None,
+            preview: preview,
+        };
+        renderer::render(&mut render_package, render_settings);
+    }
+    return output_buffer;
+}
+
+// the argument following name on the command line
+fn option_value(args: &[String], name: &str) -> Option<String> {
+    return match args.iter().position(|arg| arg == name) {
+        Some(index) if index + 1 < args.len() => Some(args[index + 1].clone()),
+        _ => None,
+    };
+}
 
-        pixel_index += 3;
+// the command line a worker builds the scene from, without what only concerns this process
+fn forwarded_args(args: &[String]) -> Vec<String> {
+    let mut forwarded = Vec::new();
+    let mut skip_value = false;
+    for arg in args.iter() {
+        if skip_value {
+            skip_value = false;
+            continue;
+        }
+        match arg.as_str() {
+            "--coordinator" | "--worker" | "--worker-timeout" | "--preview" | "--seed" | "--save-volume" | "--stats-json" => {
+                skip_value = true
+            }
+            "--preview-terminal" => {}
+            _ => forwarded.push(arg.clone()),
+        };
     }
+    return forwarded;
+}
 
-    // write to file
-    let path = Path::new("image.ppm");
+// the two comma separated numbers given for name, 0, 0 without it
+fn pair_option(args: &[String], name: &str) -> (f64, f64) {
+    let value = match option_value(args, name) {
+        Some(value) => value,
+        None => return (0f64, 0f64),
+    };
+    let numbers: Vec<f64> = value.split(',').filter_map(|number| number.trim().parse::<f64>().ok()).collect();
+    if numbers.len() != 2 || value.split(',').count() != 2 {
+        panic!("expected two numbers like 0.1,0 for {}, got {}", name, value);
+    }
+    return (numbers[0], numbers[1]);
+}
+
+// WIDTHxHEIGHT, both at least a pixel
+fn parse_size(size: &str) -> Option<(usize, usize)> {
+    let index = size.find('x')?;
+    let width: usize = size[..index].trim().parse().ok()?;
+    let height: usize = size[index + 1..].trim().parse().ok()?;
+    if width == 0 || height == 0 {
+        return None;
+    }
+    return Some((width, height));
+}
 
-    let mut file = match File::create(&path) {
-        Err(why) => panic!("couldn't open file: {}", why.description()),
-        Ok(file) => file,
+// start..end leaves end out, start..=end renders it too. None unless that is at least a frame
+fn parse_frame_range(range: &str) -> Option<std::ops::Range<i32>> {
+    let (start, end, inclusive) = match range.find("..=") {
+        Some(index) => (&range[..index], &range[index + 3..], true),
+        None => {
+            let index = range.find("..")?;
+            (&range[..index], &range[index + 2..], false)
+        }
     };
-    match file.write_all(ppm_str.as_bytes()) {
-        Err(why) => panic!("{}", why.description()),
-        Ok(_) => println!("write to file successful"),
+    let start: i32 = start.trim().parse().ok()?;
+    let end: i32 = end.trim().parse().ok()?;
+    let end = if inclusive { end.checked_add(1)? } else { end };
+    if end <= start {
+        return None;
+    }
+    return Some(start..end);
+}
+
+// the camera named by --camera, all of them frame the same view from pos towards look_at
+// --lens is the prescription the lens system camera traces through, --bokeh shapes the perspective camera's aperture
+fn build_camera(args: &[String], projection: &str, pos: &Vec3, look_at: &Vec3, aspect_ratio: f64) -> Box<Camera> {
+    let up = Vec3::new(0f64, 1f64, 0f64);
+    return match projection {
+        "perspective" => {
+            // --shift x,y slides the image by fractions of its size, --tilt a,b turns the plane in focus by
+            // degrees, the top away and the right side away
+            let lens_shift = pair_option(args, "--shift");
+            let tilt = pair_option(args, "--tilt");
+            let mut camera = if args.iter().any(|arg| arg == "--physical-units") {
+                // a 33 mm lens at f/16 on full frame, about the same framing. --f-number opens it up
+                let lens = PhysicalLens { focal_length_mm: 33f64, f_number: 16f64, sensor_height_mm: 24f64 };
+                let mut camera = PerspectiveCamera::from_lens(
+                    pos,
+                    look_at,
+                    Vec3::new(0f64, 1f64, 0f64),
+                    &lens,
+                    aspect_ratio,
+                    (pos - look_at).length_squared().sqrt(),
+                    lens_shift,
+                    tilt
+                );
+                if let Some(f_number) = option_value(args, "--f-number") {
+                    match f_number.parse::<f64>() {
+                        Ok(f_number) if f_number > 0f64 => camera.set_f_number(f_number),
+                        _ => panic!("expected a positive number for --f-number, got {}", f_number),
+                    };
+                }
+                camera
+            } else {
+                PerspectiveCamera::new(
+                    pos,
+                    look_at,
+                    Vec3::new(0f64, 1f64, 0f64), // up
+                    40f64, // fov
+                    aspect_ratio,
+                    0.2f64, //aperture
+                    (pos - look_at).length_squared().sqrt(), // focus dist
+                    lens_shift,
+                    tilt, // tilt, swing
+                )
+            };
+            if let Some(bokeh) = option_value(args, "--bokeh") {
+                camera.aperture = build_aperture(&bokeh);
+            }
+            Box::new(camera)
+        }
+        "orthographic" => Box::new(OrthographicCamera::new(pos, look_at, &up, 6f64, aspect_ratio)),
+        "fisheye" => Box::new(FisheyeCamera::new(pos, look_at, &up, 180f64, aspect_ratio, FisheyeMapping::Equidistant)),
+        "fisheye-equisolid" => Box::new(FisheyeCamera::new(pos, look_at, &up, 180f64, aspect_ratio, FisheyeMapping::Equisolid)),
+        "equirectangular" => Box::new(EquirectangularCamera::new(pos, look_at, &up)),
+        "cylindrical" => Box::new(CylindricalCamera::new(pos, look_at, &up, 360f64, 90f64)),
+        "lens" => {
+            let lens_file = option_value(args, "--lens").unwrap_or(String::from("lenses/dgauss.50mm.dat"));
+            let elements = match lens_system::read_lens_file(Path::new(&lens_file)) {
+                Err(why) => panic!("couldn't read lens file: {}", why.description()),
+                Ok(elements) => elements,
+            };
+            // full frame film
+            Box::new(LensSystemCamera::new(pos, look_at, &up, elements, 43.27f64, aspect_ratio, (pos - look_at).length_squared().sqrt()))
+        }
+        _ => panic!("unknown camera projection: {}", projection),
+    };
+}
+
+// --bokeh hexagon|donut|<mask.ppm>, out of focus highlights take the shape of the aperture and squash
+// into cat's eyes towards the corners, which also darken
+fn build_aperture(bokeh: &str) -> Aperture {
+    let mut aperture = match bokeh {
+        // six blades
+        "hexagon" => Aperture::polygon(6, 15f64),
+        // the central obstruction of a mirror lens
+        "donut" => Aperture::mask(ApertureMask::from_fn(64, 64, |x, y| {
+            let r2 = x * x + y * y;
+            if r2 <= 1f64 && r2 >= 0.25f64 { 1f64 } else { 0f64 }
+        })),
+        file_name => match ApertureMask::from_ppm(Path::new(file_name)) {
+            Err(why) => panic!("couldn't read aperture mask {}: {}", file_name, why.description()),
+            Ok(mask) => Aperture::mask(mask),
+        },
     };
+    aperture.cats_eye = 0.3f64;
+    aperture.natural_vignetting = 0.5f64;
+    return aperture;
 }
@@ -0,0 +1,238 @@
+extern crate rusty_math;
+
+use rusty_math::*;
+use std::f64;
+use std::f64::consts;
+
+use spectrum::Wavelengths;
+use spectrum::radiance_to_path;
+use renderer::mul;
+use sampler;
+
+//
+// HomogeneousMedium
+//
+// coefficients are per unit length and per color channel
+pub struct HomogeneousMedium {
+    pub sigma_a: Vec3, // absorption
+    pub sigma_s: Vec3, // scattering
+    pub g: f64, // henyey-greenstein asymmetry, -1 back scatter .. 1 forward scatter
+}
+
+// piece of a ray spent inside one medium
+pub struct MediumSegment<'a> {
+    pub medium: &'a HomogeneousMedium,
+    pub start_time: f64,
+    pub end_time: f64,
+}
+
+pub struct MediumScatter {
+    pub time: f64,
+    pub g: f64,
+}
+
+// result of free flight sampling, weight already divides by the sampling pdf
+pub struct MediumSample {
+    pub scatter: Option<MediumScatter>,
+    pub weight: Vec3,
+}
+
+//
+// phase function
+//
+// samples an outgoing direction around the unit direction of travel, cos_theta between the two averages g
+pub fn sample_henyey_greenstein(dir: &Vec3, g: f64) -> Vec3 {
+    let u1 = sampler::next_f64();
+    let u2 = sampler::next_f64();
+    let cos_theta = if g.abs() < 1e-3f64 {
+        1f64 - 2f64 * u1
+    } else {
+        let sqr = (1f64 - g * g) / (1f64 - g + 2f64 * g * u1);
+        (1f64 + g * g - sqr * sqr) / (2f64 * g)
+    };
+    let sin_theta = (1f64 - cos_theta * cos_theta).max(0f64).sqrt();
+    let phi = 2f64 * consts::PI * u2;
+
+    let w = dir.normalize();
+    let helper = if w.x.abs() > 0.9f64 { Vec3::new(0f64, 1f64, 0f64) } else { Vec3::new(1f64, 0f64, 0f64) };
+    let u = helper.cross(&w).normalize();
+    let v = w.cross(&u);
+    return &(&(&u * (sin_theta * phi.cos())) + &(&v * (sin_theta * phi.sin()))) + &(&w * cos_theta);
+}
+
+fn channel(v: &Vec3, index: usize) -> f64 {
+    return match index {
+        0 => v.x,
+        1 => v.y,
+        _ => v.z,
+    };
+}
+
+fn exp_neg(v: &Vec3, distance: f64) -> Vec3 {
+    return Vec3::new((-v.x * distance).exp(), (-v.y * distance).exp(), (-v.z * distance).exp());
+}
+
+// free flight sampling through overlapping homogeneous segments
+// the total extinction is piecewise constant between segment boundaries, so we walk the pieces and
+// sample an exponential in each, picking one color channel per path and weighting by the average pdf
+// in spectral mode the channels are the path's wavelengths and the rgb coefficients get upsampled
+pub fn sample_interaction(segments: &[MediumSegment], time_min: f64, time_max: f64,
+                          wavelengths: Option<&Wavelengths>) -> MediumSample {
+    let mut breakpoints: Vec<f64> = vec![time_min, time_max];
+    for segment in segments.iter() {
+        for &t in [segment.start_time, segment.end_time].iter() {
+            if t > time_min && t < time_max {
+                breakpoints.push(t);
+            }
+        }
+    }
+    breakpoints.sort_by(|a, b| a.partial_cmp(b).unwrap_or(::std::cmp::Ordering::Equal));
+
+    let sample_channel = sampler::next_index(3);
+    // per channel transmittance from time_min to the start of the current piece
+    let mut transmittance = Vec3::new(1f64, 1f64, 1f64);
+
+    for piece in 0..(breakpoints.len() - 1) {
+        let (start, end) = (breakpoints[piece], breakpoints[piece + 1]);
+        if end <= start {
+            continue;
+        }
+        let middle = 0.5f64 * (start + end);
+        let mut sigma_t = Vec3::new(0f64, 0f64, 0f64);
+        let mut sigma_s = Vec3::new(0f64, 0f64, 0f64);
+        for segment in segments.iter() {
+            if segment.start_time <= middle && middle < segment.end_time {
+                let segment_sigma_s = radiance_to_path(&segment.medium.sigma_s, wavelengths);
+                sigma_t += &radiance_to_path(&segment.medium.sigma_a, wavelengths);
+                sigma_t += &segment_sigma_s;
+                sigma_s += &segment_sigma_s;
+            }
+        }
+
+        let channel_sigma_t = channel(&sigma_t, sample_channel);
+        if channel_sigma_t > 0f64 {
+            let u = sampler::next_f64();
+            let distance = -(1f64 - u).ln() / channel_sigma_t;
+            if start + distance < end {
+                // scattered inside this piece
+                let time = start + distance;
+                let tr = mul(&transmittance, &exp_neg(&sigma_t, distance));
+                let pdf = (sigma_t.x * tr.x + sigma_t.y * tr.y + sigma_t.z * tr.z) / 3f64;
+                if pdf <= 0f64 {
+                    return MediumSample { scatter: None, weight: Vec3::new(0f64, 0f64, 0f64) };
+                }
+                return MediumSample {
+                    scatter: Some(MediumScatter {
+                        time: time,
+                        g: pick_phase_asymmetry(segments, time),
+                    }),
+                    weight: &mul(&tr, &sigma_s) / pdf,
+                };
+            }
+        }
+        transmittance = mul(&transmittance, &exp_neg(&sigma_t, end - start));
+    }
+
+    // made it through, the probability of that is the average surviving transmittance
+    let pdf = (transmittance.x + transmittance.y + transmittance.z) / 3f64;
+    if pdf <= 0f64 {
+        return MediumSample { scatter: None, weight: Vec3::new(0f64, 0f64, 0f64) };
+    }
+    return MediumSample {
+        scatter: None,
+        weight: &transmittance / pdf,
+    };
+}
+
+// overlapping media scatter with a mixture of phase functions, pick one in proportion to its scattering
+fn pick_phase_asymmetry(segments: &[MediumSegment], time: f64) -> f64 {
+    let mut total = 0f64;
+    for segment in segments.iter() {
+        if segment.start_time <= time && time < segment.end_time {
+            let s = &segment.medium.sigma_s;
+            total += s.x + s.y + s.z;
+        }
+    }
+    let mut roll = sampler::next_f64() * total;
+    let mut g = 0f64;
+    for segment in segments.iter() {
+        if segment.start_time <= time && time < segment.end_time {
+            let s = &segment.medium.sigma_s;
+            g = segment.medium.g;
+            roll -= s.x + s.y + s.z;
+            if roll <= 0f64 {
+                break;
+            }
+        }
+    }
+    return g;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // density over solid angle, cos_theta is between the direction of travel and the scattered direction
+    fn henyey_greenstein(cos_theta: f64, g: f64) -> f64 {
+        let denom = 1f64 + g * g - 2f64 * g * cos_theta;
+        return (1f64 - g * g) / (4f64 * consts::PI * denom * denom.sqrt());
+    }
+
+    #[test]
+    fn scattering_leans_the_way_g_says() {
+        let dir = Vec3::new(0.3f64, -0.5f64, 0.8f64).normalize();
+        for &g in [-0.7f64, -0.2f64, 0f64, 0.5f64, 0.9f64].iter() {
+            let count = 200000;
+            let mut mean_cos = 0f64;
+            for _ in 0..count {
+                let scattered = sample_henyey_greenstein(&dir, g);
+                assert!((scattered.length_squared() - 1f64).abs() < 1e-9f64);
+                mean_cos += scattered.dot(&dir) / count as f64;
+            }
+            assert!((mean_cos - g).abs() < 0.01f64, "mean cosine {} for g {}", mean_cos, g);
+        }
+    }
+
+    #[test]
+    fn sampled_cosines_follow_the_phase_function() {
+        let g = 0.6f64;
+        let dir = Vec3::new(0f64, 0f64, 1f64);
+        let (bins, count) = (20, 400000);
+        let mut histogram = vec![0f64; bins];
+        for _ in 0..count {
+            let cos_theta = sample_henyey_greenstein(&dir, g).z.max(-1f64).min(1f64 - 1e-12f64);
+            histogram[((cos_theta + 1f64) * 0.5f64 * bins as f64) as usize] += 1f64 / count as f64;
+        }
+        for (bin, found) in histogram.iter().enumerate() {
+            // the phase function integrated over the band of directions in this bin, midpoint rule
+            let width = 2f64 / bins as f64;
+            let cos_theta = -1f64 + (bin as f64 + 0.5f64) * width;
+            let expected = 2f64 * consts::PI * henyey_greenstein(cos_theta, g) * width;
+            assert!((found - expected).abs() < 0.05f64 * expected + 1e-3f64, "bin {}: {} vs {}", bin, found, expected);
+        }
+    }
+
+    #[test]
+    fn light_gets_through_as_often_as_beers_law_says() {
+        let medium = HomogeneousMedium {
+            sigma_a: Vec3::new(0.2f64, 0.2f64, 0.2f64),
+            sigma_s: Vec3::new(0.5f64, 0.5f64, 0.5f64),
+            g: 0f64,
+        };
+        let segments = [MediumSegment { medium: &medium, start_time: 1f64, end_time: 3f64 }];
+        let count = 100000;
+        let mut escaped = 0;
+        for _ in 0..count {
+            let sample = sample_interaction(&segments, 0f64, 5f64, None);
+            match sample.scatter {
+                Some(scatter) => assert!(scatter.time >= 1f64 && scatter.time < 3f64),
+                None => {
+                    escaped += 1;
+                    assert!((sample.weight.x - 1f64).abs() < 1e-9f64);
+                }
+            }
+        }
+        let expected = (-0.7f64 * 2f64).exp();
+        assert!((escaped as f64 / count as f64 - expected).abs() < 0.01f64);
+    }
+}
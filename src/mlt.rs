@@ -0,0 +1,292 @@
+extern crate rusty_math;
+extern crate rand;
+
+use rusty_math::*;
+use rand::Rng;
+use std::thread;
+
+use renderable::RenderList;
+use renderer::RenderSettings;
+use renderer::color;
+use camera::Camera;
+use render_buffer::RenderBufferF64;
+use sampler;
+use sampler::MltSampler;
+use spectrum;
+use spectrum::Wavelengths;
+use stats;
+
+//
+// primary sample space metropolis light transport, Kelemen et al. 2002
+//
+// the path tracer is a function from a vector of uniform numbers to an image position and a radiance.
+// markov chains wander over those vectors, mostly with small perturbations (so once a chain finds a
+// hard to reach bright path it explores its neighbours) and sometimes with fresh large steps. every
+// proposal is splatted, weighted by its acceptance probability, and the image is normalized by the
+// average brightness b estimated from independent bootstrap paths
+pub struct MltIntegrator {
+    pub bootstrap_samples: usize,
+    pub chains: usize,
+    pub threads: usize,
+    // standard deviation of a small step in primary sample space
+    pub sigma: f64,
+    pub large_step_probability: f64,
+}
+
+impl MltIntegrator {
+    pub fn new() -> MltIntegrator {
+        let threads = match thread::available_parallelism() {
+            Ok(count) => count.get(),
+            Err(_) => 1,
+        };
+        return MltIntegrator {
+            bootstrap_samples: 100000,
+            chains: 256,
+            threads: threads,
+            sigma: 0.01f64,
+            large_step_probability: 0.3f64,
+        };
+    }
+
+    // one camera path drawn from whatever sampler is installed, returns image position and rgb radiance
+    fn evaluate(&self, render_list: &RenderList, camera: &Camera, render_settings: &RenderSettings) -> (f64, f64, Vec3) {
+        let u = sampler::next_f64();
+        let v = sampler::next_f64();
+        let (ray, weight) = camera.get_weighted_ray(u, v);
+        stats::count_camera_ray();
+        let shutter_time = camera.sample_shutter_time();
+        if render_settings.spectral {
+            let wavelengths = Wavelengths::sample(sampler::next_f64());
+            let radiance = color(&ray, shutter_time, Some(wavelengths), render_list, render_settings.max_depth);
+            return (u, v, &spectrum::wavelengths_to_rgb(&radiance, &wavelengths) * weight);
+        }
+        return (u, v, &color(&ray, shutter_time, None, render_list, render_settings.max_depth) * weight);
+    }
+
+    fn new_sampler(&self, seed: usize) -> MltSampler {
+        return MltSampler::new(seed as u32, self.sigma, self.large_step_probability);
+    }
+
+    // num_samples_per_pixel mutations per pixel in total, film ends up scaled like a sum of that many samples
+    pub fn render(&self, render_list: &RenderList, camera: &Camera, render_settings: &RenderSettings, film: &mut RenderBufferF64) {
+        let width = film.width;
+        let height = film.height;
+        let threads = self.threads.max(1);
+        let total_mutations = render_settings.num_samples_per_pixel.max(0) as usize * width * height;
+        if total_mutations == 0 || self.bootstrap_samples == 0 {
+            return;
+        }
+
+        let (cdf, b) = self.bootstrap(render_list, camera, render_settings, threads);
+        if b <= 0f64 {
+            return;
+        }
+
+        // chains are handed out round robin, each thread splats into its own film
+        let chains = self.chains.max(1).min(total_mutations);
+        let cdf = &cdf;
+        let films: Vec<RenderBufferF64> = thread::scope(|scope| {
+            let handles: Vec<_> = (0..threads.min(chains)).map(|first| {
+                return scope.spawn(move || {
+                    let mut thread_film = RenderBufferF64::new(width, height);
+                    for chain in (first..chains).step_by(threads) {
+                        let mutations = total_mutations / chains + if chain < total_mutations % chains { 1 } else { 0 };
+                        self.run_chain(render_list, camera, render_settings, cdf, b, mutations, &mut thread_film);
+                    }
+                    stats::flush();
+                    return thread_film;
+                });
+            }).collect();
+            return handles.into_iter().map(|handle| handle.join().unwrap()).collect();
+        });
+        for thread_film in films.iter() {
+            for i in 0..film.buffer.len() {
+                film.buffer[i] += thread_film.buffer[i];
+            }
+        }
+    }
+
+    // the brightness of bootstrap_samples independent paths gives both where to start the chains, as a
+    // running sum to pick from, and b, their average
+    fn bootstrap(&self, render_list: &RenderList, camera: &Camera, render_settings: &RenderSettings, threads: usize) -> (Vec<f64>, f64) {
+        let mut weights = vec![0f64; self.bootstrap_samples];
+        thread::scope(|scope| {
+            let handles: Vec<_> = (0..threads).map(|first| {
+                return scope.spawn(move || {
+                    let mut found: Vec<(usize, f64)> = Vec::new();
+                    for index in (first..self.bootstrap_samples).step_by(threads) {
+                        sampler::install(Some(self.new_sampler(index)));
+                        let (_, _, radiance) = self.evaluate(render_list, camera, render_settings);
+                        found.push((index, spectrum::luminance(&radiance).max(0f64)));
+                    }
+                    sampler::install(None);
+                    stats::flush();
+                    return found;
+                });
+            }).collect();
+            for handle in handles {
+                for (index, weight) in handle.join().unwrap() {
+                    weights[index] = weight;
+                }
+            }
+        });
+
+        let mut cdf: Vec<f64> = Vec::with_capacity(weights.len());
+        let mut total = 0f64;
+        for weight in weights.iter() {
+            total += *weight;
+            cdf.push(total);
+        }
+        return (cdf, total / self.bootstrap_samples as f64);
+    }
+
+    fn run_chain(
+        &self,
+        render_list: &RenderList,
+        camera: &Camera,
+        render_settings: &RenderSettings,
+        cdf: &[f64],
+        b: f64,
+        mutations: usize,
+        film: &mut RenderBufferF64
+    ) {
+        // pick a bootstrap path in proportion to its brightness and replay it from its seed
+        let total = cdf[cdf.len() - 1];
+        let target = rand::thread_rng().gen_range(0f64, total);
+        let seed = match cdf.binary_search_by(|c| c.partial_cmp(&target).unwrap_or(::std::cmp::Ordering::Less)) {
+            Ok(index) => index,
+            Err(index) => index,
+        }.min(cdf.len() - 1);
+        sampler::install(Some(self.new_sampler(seed)));
+        let (mut current_u, mut current_v, mut current) = self.evaluate(render_list, camera, render_settings);
+        let mut current_weight = spectrum::luminance(&current).max(0f64);
+
+        for _ in 0..mutations {
+            sampler::with_installed(|s| s.start_iteration());
+            let (u, v, proposed) = self.evaluate(render_list, camera, render_settings);
+            let proposed_weight = spectrum::luminance(&proposed).max(0f64);
+            let accept = if current_weight > 0f64 { (proposed_weight / current_weight).min(1f64) } else { 1f64 };
+
+            // expected values, both states get their share of this step
+            if accept > 0f64 && proposed_weight > 0f64 {
+                splat(film, u, v, &(&proposed * (accept * b / proposed_weight)));
+            }
+            if accept < 1f64 && current_weight > 0f64 {
+                splat(film, current_u, current_v, &(&current * ((1f64 - accept) * b / current_weight)));
+            }
+
+            if rand::thread_rng().gen_range(0f64, 1f64) < accept {
+                current_u = u;
+                current_v = v;
+                current = proposed;
+                current_weight = proposed_weight;
+                sampler::with_installed(|s| s.accept());
+            } else {
+                sampler::with_installed(|s| s.reject());
+            }
+        }
+        sampler::install(None);
+    }
+}
+
+fn splat(film: &mut RenderBufferF64, u: f64, v: f64, value: &Vec3) {
+    let x = (u * film.width as f64) as usize;
+    let y = (v * film.height as f64) as usize;
+    film.add(x, y, value.x, value.y, value.z);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use renderable::shapes::Sphere;
+    use renderable::materials::Lambertian;
+    use renderable::materials::DiffuseLight;
+    use renderer::IntegratorKind;
+    use camera::PerspectiveCamera;
+    use filter::Filter;
+
+    fn settings() -> RenderSettings {
+        return RenderSettings {
+            num_samples_per_pixel: 4,
+            spectral: false,
+            integrator: IntegratorKind::Metropolis,
+            max_depth: 6,
+            aovs: Vec::new(),
+            exposure: None,
+            filter: Filter::box_filter(),
+        };
+    }
+
+    fn camera() -> PerspectiveCamera {
+        return PerspectiveCamera::new(
+            &Vec3::new(0f64, 1.5f64, 1f64), &Vec3::new(0f64, 0.5f64, -3f64), Vec3::new(0f64, 1f64, 0f64),
+            50f64, 1.5f64, 0f64, 4f64, (0f64, 0f64), (0f64, 0f64)
+        );
+    }
+
+    fn integrator() -> MltIntegrator {
+        let mut integrator = MltIntegrator::new();
+        integrator.bootstrap_samples = 20000;
+        integrator.chains = 8;
+        integrator.threads = 2;
+        return integrator;
+    }
+
+    #[test]
+    fn bootstrap_normalization_is_the_average_brightness_of_a_lit_scene() {
+        let floor_mat = Lambertian { albedo: Vec3::new(0.6f64, 0.6f64, 0.6f64) };
+        let lamp_mat = DiffuseLight { emission: Vec3::new(4f64, 4f64, 4f64) };
+        let floor = Sphere { center: Vec3::new(0f64, -1000f64, 0f64), radius: 1000f64 };
+        let lamp = Sphere { center: Vec3::new(1f64, 3f64, -3f64), radius: 1f64 };
+        let mut world = RenderList::new();
+        world.add_sphere(&floor, &floor_mat);
+        world.add_light(&lamp, &lamp_mat);
+        world.set_sky_luminance(0f64);
+        world.build();
+
+        let (integrator, camera, settings) = (integrator(), camera(), settings());
+        let (cdf, b) = integrator.bootstrap(&world, &camera, &settings, integrator.threads);
+        assert!(b > 0f64);
+        assert_eq!(cdf.len(), integrator.bootstrap_samples);
+        assert!((cdf[cdf.len() - 1] / integrator.bootstrap_samples as f64 - b).abs() < 1e-12f64);
+
+        // the same thing the plain path tracer sees on average
+        let count = 20000;
+        let mut mean = 0f64;
+        for _ in 0..count {
+            let (_, _, radiance) = integrator.evaluate(&world, &camera, &settings);
+            mean += spectrum::luminance(&radiance) / count as f64;
+        }
+        assert!((b - mean).abs() < 0.1f64 * mean, "b {} against a mean of {}", b, mean);
+
+        // and the chains spread that much brightness over the film
+        let mut film = RenderBufferF64::new(12, 8);
+        integrator.render(&world, &camera, &settings, &mut film);
+        let mut total = 0f64;
+        for y in 0..film.height {
+            for x in 0..film.width {
+                let (r, g, bl) = film.get(x, y);
+                total += spectrum::luminance(&Vec3::new(r, g, bl));
+            }
+        }
+        let average = total / (film.width * film.height * settings.num_samples_per_pixel as usize) as f64;
+        assert!((average - b).abs() < 1e-6f64 * b, "film average {} against b {}", average, b);
+    }
+
+    #[test]
+    fn a_dark_scene_leaves_the_film_black() {
+        let floor_mat = Lambertian { albedo: Vec3::new(0.6f64, 0.6f64, 0.6f64) };
+        let floor = Sphere { center: Vec3::new(0f64, -1000f64, 0f64), radius: 1000f64 };
+        let mut world = RenderList::new();
+        world.add_sphere(&floor, &floor_mat);
+        world.set_sky_luminance(0f64);
+        world.build();
+
+        let (integrator, camera, settings) = (integrator(), camera(), settings());
+        let (_, b) = integrator.bootstrap(&world, &camera, &settings, integrator.threads);
+        assert_eq!(b, 0f64);
+        let mut film = RenderBufferF64::new(12, 8);
+        integrator.render(&world, &camera, &settings, &mut film);
+        assert!(film.buffer.iter().all(|value| *value == 0f64));
+    }
+}
@@ -0,0 +1,438 @@
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+
+use render_buffer::RenderBufferI32;
+use render_buffer::RenderBufferLayers;
+
+// plain text ppm of the tone mapped image, rows are already top down
+pub fn write_ppm(path: &Path, output_buffer: &RenderBufferI32) -> io::Result<()> {
+    // write header
+    let mut ppm_str = String::new();
+    ppm_str.push_str(
+        &format!(
+            "P3\n{} {}\n255\n",
+            output_buffer.width,
+            output_buffer.height
+        )
+    );
+
+    let mut pixel_index: usize = 0;
+    while pixel_index < output_buffer.buffer.len() {
+        let ir = output_buffer.buffer[pixel_index];
+        let ig = output_buffer.buffer[pixel_index + 1];
+        let ib = output_buffer.buffer[pixel_index + 2];
+
+        ppm_str.push_str(&format!("{} {} {}\n", ir, ig, ib));
+
+        pixel_index += 3;
+    }
+
+    let mut file = File::create(path)?;
+    return file.write_all(ppm_str.as_bytes());
+}
+
+//
+// png
+//
+// 8 bit rgb without filtering or compression, the deflate stream is just stored blocks. big, but simple
+// enough to write on the fly for the live preview
+
+// rgb holds width * height pixels, rows top down
+pub fn encode_png(width: usize, height: usize, rgb: &[u8]) -> Vec<u8> {
+    let mut bytes: Vec<u8> = vec![0x89u8, b'P', b'N', b'G', b'\r', b'\n', 0x1au8, b'\n'];
+
+    let mut header: Vec<u8> = Vec::new();
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    // bit depth, truecolor, deflate, no filtering, no interlace
+    header.extend_from_slice(&[8u8, 2u8, 0u8, 0u8, 0u8]);
+    push_png_chunk(&mut bytes, b"IHDR", &header);
+
+    // every scanline starts with its filter type, 0 leaves the bytes as they are
+    let mut raw: Vec<u8> = Vec::with_capacity(height * (width * 3 + 1));
+    for row in 0..height {
+        raw.push(0u8);
+        raw.extend_from_slice(&rgb[row * width * 3..(row + 1) * width * 3]);
+    }
+
+    // zlib header for deflate with a 32k window, stored blocks of at most 65535 bytes, adler32 of the data
+    let mut zlib: Vec<u8> = vec![0x78u8, 0x01u8];
+    let mut blocks = raw.chunks(65535).peekable();
+    if blocks.peek().is_none() {
+        zlib.extend_from_slice(&[1u8, 0u8, 0u8, 0xffu8, 0xffu8]);
+    }
+    while let Some(block) = blocks.next() {
+        zlib.push(if blocks.peek().is_none() { 1u8 } else { 0u8 });
+        let length = block.len() as u16;
+        zlib.extend_from_slice(&length.to_le_bytes());
+        zlib.extend_from_slice(&(!length).to_le_bytes());
+        zlib.extend_from_slice(block);
+    }
+    let (mut a, mut b) = (1u32, 0u32);
+    for byte in raw.iter() {
+        a = (a + *byte as u32) % 65521u32;
+        b = (b + a) % 65521u32;
+    }
+    zlib.extend_from_slice(&((b << 16) | a).to_be_bytes());
+    push_png_chunk(&mut bytes, b"IDAT", &zlib);
+
+    push_png_chunk(&mut bytes, b"IEND", &[]);
+    return bytes;
+}
+
+fn push_png_chunk(bytes: &mut Vec<u8>, kind: &[u8], data: &[u8]) {
+    bytes.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = bytes.len();
+    bytes.extend_from_slice(kind);
+    bytes.extend_from_slice(data);
+    let crc = crc32(&bytes[start..]);
+    bytes.extend_from_slice(&crc.to_be_bytes());
+}
+
+// bit at a time, the images are small enough that a table is not worth it
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffffffffu32;
+    for byte in data.iter() {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { 0xedb88320u32 ^ (crc >> 1) } else { crc >> 1 };
+        }
+    }
+    return !crc;
+}
+
+//
+// openexr
+//
+// uncompressed single part scanline files with 32 bit float channels, about the smallest thing every
+// compositor reads. the "beauty" layer becomes the default R, G, B channels and every other layer is
+// prefixed with its name (normal.R, depth.Y) so they all travel in one file
+
+// every layer of layers in one multi-layer exr
+pub fn write_exr(path: &Path, layers: &RenderBufferLayers) -> io::Result<()> {
+    let mut channels: Vec<ExrChannel> = Vec::new();
+    for layer in 0..layers.layer_count() {
+        let prefix = if layers.name(layer) == "beauty" {
+            String::new()
+        } else {
+            format!("{}.", layers.name(layer))
+        };
+        add_layer_channels(layers, layer, &prefix, &mut channels);
+    }
+    return write_exr_channels(path, layers, channels);
+}
+
+// one exr per layer with plain channel names, next to each other as <stem>.<layer>.exr
+pub fn write_exr_layer_files(directory: &Path, stem: &str, layers: &RenderBufferLayers) -> io::Result<()> {
+    for layer in 0..layers.layer_count() {
+        let mut channels: Vec<ExrChannel> = Vec::new();
+        add_layer_channels(layers, layer, "", &mut channels);
+        let path = directory.join(format!("{}.{}.exr", stem, layers.name(layer)));
+        write_exr_channels(&path, layers, channels)?;
+    }
+    return Ok(());
+}
+
+struct ExrChannel {
+    name: String,
+    layer: usize,
+    channel: usize,
+}
+
+fn add_layer_channels(layers: &RenderBufferLayers, layer: usize, prefix: &str, channels: &mut Vec<ExrChannel>) {
+    let count = layers.channels(layer);
+    let suffixes: &[&str] = match count {
+        1 => &["Y"],
+        3 => &["R", "G", "B"],
+        4 => &["R", "G", "B", "A"],
+        _ => &[],
+    };
+    for channel in 0..count {
+        let name = if channel < suffixes.len() {
+            format!("{}{}", prefix, suffixes[channel])
+        } else {
+            format!("{}{}", prefix, channel)
+        };
+        channels.push(ExrChannel {
+            name: name,
+            layer: layer,
+            channel: channel,
+        });
+    }
+}
+
+fn write_exr_channels(path: &Path, layers: &RenderBufferLayers, mut channels: Vec<ExrChannel>) -> io::Result<()> {
+    // the format wants channels in alphabetical order, both in the header and in every scanline
+    channels.sort_by(|a, b| a.name.cmp(&b.name));
+    let width = layers.width;
+    let height = layers.height;
+
+    let mut bytes: Vec<u8> = Vec::new();
+    push_u32(&mut bytes, 20000630u32);
+    push_u32(&mut bytes, 2u32);
+
+    let mut channel_list: Vec<u8> = Vec::new();
+    for channel in channels.iter() {
+        channel_list.extend_from_slice(channel.name.as_bytes());
+        channel_list.push(0u8);
+        // float pixels, not linear, reserved, no subsampling
+        push_i32(&mut channel_list, 2i32);
+        channel_list.extend_from_slice(&[0u8, 0u8, 0u8, 0u8]);
+        push_i32(&mut channel_list, 1i32);
+        push_i32(&mut channel_list, 1i32);
+    }
+    channel_list.push(0u8);
+    push_attribute(&mut bytes, "channels", "chlist", &channel_list);
+    push_attribute(&mut bytes, "compression", "compression", &[0u8]);
+
+    let mut window: Vec<u8> = Vec::new();
+    push_i32(&mut window, 0i32);
+    push_i32(&mut window, 0i32);
+    push_i32(&mut window, width as i32 - 1);
+    push_i32(&mut window, height as i32 - 1);
+    push_attribute(&mut bytes, "dataWindow", "box2i", &window);
+    push_attribute(&mut bytes, "displayWindow", "box2i", &window);
+    // increasing y, top scanline first
+    push_attribute(&mut bytes, "lineOrder", "lineOrder", &[0u8]);
+    push_attribute(&mut bytes, "pixelAspectRatio", "float", &1f32.to_bits().to_le_bytes());
+    let mut center: Vec<u8> = Vec::new();
+    push_u32(&mut center, 0f32.to_bits());
+    push_u32(&mut center, 0f32.to_bits());
+    push_attribute(&mut bytes, "screenWindowCenter", "v2f", &center);
+    push_attribute(&mut bytes, "screenWindowWidth", "float", &1f32.to_bits().to_le_bytes());
+    bytes.push(0u8);
+
+    // offset table, one chunk per scanline
+    let scanline_size = 8 + width * channels.len() * 4;
+    let table_end = bytes.len() + height * 8;
+    for row in 0..height {
+        push_u64(&mut bytes, (table_end + row * scanline_size) as u64);
+    }
+
+    for row in 0..height {
+        push_i32(&mut bytes, row as i32);
+        push_i32(&mut bytes, (width * channels.len() * 4) as i32);
+        // our buffers keep the bottom row first
+        let y = height - 1 - row;
+        for channel in channels.iter() {
+            let count = layers.channels(channel.layer);
+            let data = layers.data(channel.layer);
+            for x in 0..width {
+                let value = data[(y * width + x) * count + channel.channel] as f32;
+                push_u32(&mut bytes, value.to_bits());
+            }
+        }
+    }
+
+    let mut file = File::create(path)?;
+    return file.write_all(&bytes);
+}
+
+fn push_attribute(bytes: &mut Vec<u8>, name: &str, kind: &str, value: &[u8]) {
+    bytes.extend_from_slice(name.as_bytes());
+    bytes.push(0u8);
+    bytes.extend_from_slice(kind.as_bytes());
+    bytes.push(0u8);
+    push_i32(bytes, value.len() as i32);
+    bytes.extend_from_slice(value);
+}
+
+fn push_u32(bytes: &mut Vec<u8>, value: u32) {
+    bytes.extend_from_slice(&value.to_le_bytes());
+}
+
+fn push_i32(bytes: &mut Vec<u8>, value: i32) {
+    bytes.extend_from_slice(&value.to_le_bytes());
+}
+
+fn push_u64(bytes: &mut Vec<u8>, value: u64) {
+    bytes.extend_from_slice(&value.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    // a 3 x 2 image with a beauty, a depth and a normal layer, every value tells where it came from
+    fn layers() -> RenderBufferLayers {
+        let mut layers = RenderBufferLayers::new(3, 2);
+        for &(name, channels) in [("beauty", 3), ("depth", 1), ("normal", 3)].iter() {
+            let layer = layers.add_layer(name, channels);
+            for y in 0..2 {
+                for x in 0..3 {
+                    let values: Vec<f64> = (0..channels).map(|c| (layer * 100 + y * 10 + x) as f64 + c as f64 * 0.25f64).collect();
+                    layers.set(layer, x, y, &values);
+                }
+            }
+        }
+        return layers;
+    }
+
+    struct ExrHeader {
+        channels: Vec<String>,
+        data_window: [i32; 4],
+        // where the offset table starts
+        header_end: usize,
+    }
+
+    fn read_i32(bytes: &[u8], at: usize) -> i32 {
+        return i32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]]);
+    }
+
+    fn read_string(bytes: &[u8], at: &mut usize) -> String {
+        let start = *at;
+        while bytes[*at] != 0u8 {
+            *at += 1;
+        }
+        *at += 1;
+        return String::from_utf8(bytes[start..*at - 1].to_vec()).unwrap();
+    }
+
+    fn read_header(bytes: &[u8]) -> ExrHeader {
+        assert_eq!(read_i32(bytes, 0), 20000630);
+        assert_eq!(read_i32(bytes, 4), 2);
+        let mut header = ExrHeader { channels: Vec::new(), data_window: [0; 4], header_end: 0 };
+        let mut at = 8;
+        while bytes[at] != 0u8 {
+            let name = read_string(bytes, &mut at);
+            let kind = read_string(bytes, &mut at);
+            let size = read_i32(bytes, at) as usize;
+            at += 4;
+            let value = &bytes[at..at + size];
+            if name == "channels" {
+                assert_eq!(kind, "chlist");
+                let mut channel_at = 0;
+                while value[channel_at] != 0u8 {
+                    header.channels.push(read_string(value, &mut channel_at));
+                    // float pixels
+                    assert_eq!(read_i32(value, channel_at), 2);
+                    channel_at += 16;
+                }
+            }
+            if name == "dataWindow" {
+                for i in 0..4 {
+                    header.data_window[i] = read_i32(value, 4 * i);
+                }
+            }
+            at += size;
+        }
+        header.header_end = at + 1;
+        return header;
+    }
+
+    #[test]
+    fn layers_share_one_exr_under_prefixed_channel_names() {
+        let path = env::temp_dir().join(format!("output_test_{}.exr", ::std::process::id()));
+        write_exr(&path, &layers()).unwrap();
+        let bytes = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let header = read_header(&bytes);
+        assert_eq!(header.channels, vec!["B", "G", "R", "depth.Y", "normal.B", "normal.G", "normal.R"]);
+        assert_eq!(header.data_window, [0, 0, 2, 1]);
+        // the first scanline is the top row, y = 1 in the buffer, and holds each channel's row in turn
+        let first = read_i32(&bytes, header.header_end) as usize;
+        assert_eq!(read_i32(&bytes, first), 0);
+        assert_eq!(read_i32(&bytes, first + 4), 3 * 7 * 4);
+        let pixel = |channel: usize, x: usize| {
+            let at = first + 8 + (channel * 3 + x) * 4;
+            return f32::from_bits(read_i32(&bytes, at) as u32);
+        };
+        // B of the beauty at x = 2, then depth at x = 0
+        assert_eq!(pixel(0, 2), 12.5f32);
+        assert_eq!(pixel(3, 0), 110f32);
+        assert_eq!(bytes.len(), first + 2 * (8 + 3 * 7 * 4));
+    }
+
+    #[test]
+    fn layer_files_keep_plain_channel_names() {
+        let directory = env::temp_dir().join(format!("output_test_{}", ::std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        write_exr_layer_files(&directory, "image", &layers()).unwrap();
+        let channels = |layer: &str| {
+            let bytes = fs::read(directory.join(format!("image.{}.exr", layer))).unwrap();
+            return read_header(&bytes).channels;
+        };
+        assert_eq!(channels("beauty"), vec!["B", "G", "R"]);
+        assert_eq!(channels("depth"), vec!["Y"]);
+        assert_eq!(channels("normal"), vec!["B", "G", "R"]);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn crc32_matches_the_check_values() {
+        assert_eq!(crc32(b"123456789"), 0xcbf43926u32);
+        assert_eq!(crc32(b"IEND"), 0xae426082u32);
+        assert_eq!(crc32(b""), 0u32);
+    }
+
+    fn read_u32(bytes: &[u8], at: usize) -> u32 {
+        return u32::from_be_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]]);
+    }
+
+    // the chunks of a png, with their crcs checked
+    fn png_chunks(png: &[u8]) -> Vec<(String, Vec<u8>)> {
+        assert_eq!(&png[..8], &[0x89u8, b'P', b'N', b'G', b'\r', b'\n', 0x1au8, b'\n']);
+        let mut chunks = Vec::new();
+        let mut at = 8;
+        while at < png.len() {
+            let length = read_u32(png, at) as usize;
+            let kind = String::from_utf8(png[at + 4..at + 8].to_vec()).unwrap();
+            assert_eq!(read_u32(png, at + 8 + length), crc32(&png[at + 4..at + 8 + length]), "crc of {}", kind);
+            chunks.push((kind, png[at + 8..at + 8 + length].to_vec()));
+            at += 12 + length;
+        }
+        assert_eq!(at, png.len());
+        return chunks;
+    }
+
+    // undoes the stored deflate blocks and checks the adler32
+    fn inflate_stored(zlib: &[u8]) -> Vec<u8> {
+        assert_eq!((zlib[0] as u32 * 256 + zlib[1] as u32) % 31, 0);
+        let mut raw = Vec::new();
+        let mut at = 2;
+        loop {
+            let last = zlib[at] & 1 == 1;
+            let length = u16::from_le_bytes([zlib[at + 1], zlib[at + 2]]);
+            assert_eq!(!length, u16::from_le_bytes([zlib[at + 3], zlib[at + 4]]));
+            raw.extend_from_slice(&zlib[at + 5..at + 5 + length as usize]);
+            at += 5 + length as usize;
+            if last {
+                break;
+            }
+        }
+        let (mut a, mut b) = (1u32, 0u32);
+        for byte in raw.iter() {
+            a = (a + *byte as u32) % 65521u32;
+            b = (b + a) % 65521u32;
+        }
+        assert_eq!(read_u32(zlib, at), (b << 16) | a);
+        assert_eq!(at + 4, zlib.len());
+        return raw;
+    }
+
+    #[test]
+    fn png_scanlines_come_back_out() {
+        // big enough to need two stored blocks
+        for &(width, height) in [(3usize, 2usize), (160usize, 150usize)].iter() {
+            let rgb: Vec<u8> = (0..width * height * 3).map(|i| (i * 7 % 251) as u8).collect();
+            let chunks = png_chunks(&encode_png(width, height, &rgb));
+            let kinds: Vec<&str> = chunks.iter().map(|chunk| chunk.0.as_str()).collect();
+            assert_eq!(kinds, vec!["IHDR", "IDAT", "IEND"]);
+            let header = &chunks[0].1;
+            assert_eq!((read_u32(header, 0) as usize, read_u32(header, 4) as usize), (width, height));
+            assert_eq!(&header[8..], &[8u8, 2u8, 0u8, 0u8, 0u8]);
+            let raw = inflate_stored(&chunks[1].1);
+            assert_eq!(raw.len(), height * (width * 3 + 1));
+            for row in 0..height {
+                let line = &raw[row * (width * 3 + 1)..(row + 1) * (width * 3 + 1)];
+                assert_eq!(line[0], 0u8);
+                assert_eq!(&line[1..], &rgb[row * width * 3..(row + 1) * width * 3]);
+            }
+        }
+    }
+}
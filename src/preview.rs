@@ -0,0 +1,594 @@
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use output;
+use render_buffer::RenderBufferI32;
+
+//
+// live preview
+//
+// a tiny http server on localhost that shows the render as it comes in. the renderer reports finished
+// tiles here, the server hands out
+//   /               a page that draws the tiles as they arrive
+//   /image.png      the image so far
+//   /progress.json  progress, samples per pixel, time left and camera rays per second
+//   /events         server-sent events, a "tile" event with a png of every tile as it finishes, "begin"
+//                   when a new image starts and "done" when the final image is ready at /image.png
+// nothing leaves the machine, the listener only binds the loopback address.
+// for ssh sessions without a browser the same image can also be drawn into the terminal instead, see
+// show_in_terminal
+
+// a reconnecting event stream hears from us at least this often
+static KEEP_ALIVE_SECONDS: u64 = 15;
+
+struct PreviewState {
+    width: usize,
+    height: usize,
+    // rows top down, 8 bit rgb
+    image: Vec<u8>,
+    samples_per_pixel: i32,
+    camera_rays: u64,
+    tiles_done: usize,
+    start: Instant,
+    // how long the image took, once it is done
+    elapsed: Option<Duration>,
+    // bumped by begin so event streams know to start over
+    generation: usize,
+    // the "tile" events sent so far for this image
+    events: Vec<String>,
+    // characters across the terminal preview, None when it is off
+    terminal_columns: Option<usize>,
+    terminal_drawn: Option<Instant>,
+    // lines the last terminal drawing of this image took, the next one draws over them
+    terminal_lines: usize,
+}
+
+// what progress_json and the terminal status line report
+struct Progress {
+    // 0 to 1
+    fraction: f64,
+    elapsed_seconds: f64,
+    eta_seconds: Option<f64>,
+    rays_per_second: f64,
+    done: bool,
+}
+
+impl PreviewState {
+    fn progress(&self) -> Progress {
+        let total = (self.width * self.height) as f64 * self.samples_per_pixel.max(0) as f64;
+        let done = self.elapsed.is_some();
+        let elapsed = match self.elapsed {
+            Some(elapsed) => elapsed,
+            None => self.start.elapsed(),
+        }.as_secs_f64();
+        let fraction = if done {
+            1f64
+        } else if total > 0f64 {
+            (self.camera_rays as f64 / total).min(1f64)
+        } else {
+            0f64
+        };
+        let rays_per_second = if elapsed > 0f64 { self.camera_rays as f64 / elapsed } else { 0f64 };
+        let eta = if done {
+            Some(0f64)
+        } else if rays_per_second > 0f64 {
+            Some((total - self.camera_rays as f64).max(0f64) / rays_per_second)
+        } else {
+            None
+        };
+        return Progress {
+            fraction: fraction,
+            elapsed_seconds: elapsed,
+            eta_seconds: eta,
+            rays_per_second: rays_per_second,
+            done: done,
+        };
+    }
+}
+
+// shared between the render threads and the server, Arc it for serve
+pub struct Preview {
+    m_state: Mutex<PreviewState>,
+    m_changed: Condvar,
+}
+
+impl Preview {
+    pub fn new() -> Preview {
+        return Preview {
+            m_state: Mutex::new(PreviewState {
+                width: 0,
+                height: 0,
+                image: Vec::new(),
+                samples_per_pixel: 0,
+                camera_rays: 0,
+                tiles_done: 0,
+                start: Instant::now(),
+                elapsed: None,
+                generation: 0,
+                events: Vec::new(),
+                terminal_columns: None,
+                terminal_drawn: None,
+                terminal_lines: 0,
+            }),
+            m_changed: Condvar::new(),
+        };
+    }
+
+    // a new image, black until tiles come in
+    pub fn begin(&self, width: usize, height: usize, samples_per_pixel: i32) {
+        let mut state = self.m_state.lock().unwrap();
+        state.width = width;
+        state.height = height;
+        state.image = vec![0u8; width * height * 3];
+        state.samples_per_pixel = samples_per_pixel;
+        state.camera_rays = 0;
+        state.tiles_done = 0;
+        state.start = Instant::now();
+        state.elapsed = None;
+        state.generation += 1;
+        state.events.clear();
+        state.terminal_drawn = None;
+        state.terminal_lines = 0;
+        self.m_changed.notify_all();
+    }
+
+    // also draws the image into the terminal, columns characters wide, each time tiles finish and once
+    // more when it is done. two pixels go in every character, the upper half block in the top one's
+    // color over the bottom one's, in 24 bit ansi color. a status line underneath keeps count
+    pub fn show_in_terminal(&self, columns: usize) {
+        let mut state = self.m_state.lock().unwrap();
+        state.terminal_columns = Some(columns.max(1));
+    }
+
+    // a finished tile at x0, y0 from the top left of the image, rgb rows top down. camera_rays is how many
+    // went into it
+    pub fn tile_finished(&self, x0: usize, y0: usize, width: usize, height: usize, rgb: &[u8], camera_rays: u64) {
+        let event = format!(
+            "event: tile\ndata: {{\"x\":{},\"y\":{},\"width\":{},\"height\":{},\"png\":\"{}\"}}\n\n",
+            x0, y0, width, height, base64(&output::encode_png(width, height, rgb))
+        );
+        let mut state = self.m_state.lock().unwrap();
+        let image_width = state.width;
+        if x0 + width > image_width || y0 + height > state.height {
+            return;
+        }
+        for row in 0..height {
+            let to = ((y0 + row) * image_width + x0) * 3;
+            state.image[to..to + width * 3].copy_from_slice(&rgb[row * width * 3..(row + 1) * width * 3]);
+        }
+        state.camera_rays += camera_rays;
+        state.tiles_done += 1;
+        state.events.push(event);
+        draw_terminal(&mut state, false);
+        self.m_changed.notify_all();
+    }
+
+    // the final tone mapped image replaces the tiles, exposure and everything
+    pub fn finish(&self, output_buffer: &RenderBufferI32) {
+        let mut state = self.m_state.lock().unwrap();
+        state.width = output_buffer.width;
+        state.height = output_buffer.height;
+        state.image = output_buffer.buffer.iter().map(|c| (*c).max(0).min(255) as u8).collect();
+        state.elapsed = Some(state.start.elapsed());
+        draw_terminal(&mut state, true);
+        self.m_changed.notify_all();
+    }
+
+    fn png(&self) -> Option<Vec<u8>> {
+        let state = self.m_state.lock().unwrap();
+        if state.width == 0 || state.height == 0 {
+            return None;
+        }
+        return Some(output::encode_png(state.width, state.height, &state.image));
+    }
+
+    fn progress_json(&self) -> String {
+        let state = self.m_state.lock().unwrap();
+        let progress = state.progress();
+        // null until there is a rate to go by
+        let eta = match progress.eta_seconds {
+            Some(eta) => format!("{:.1}", eta),
+            None => String::from("null"),
+        };
+        return format!(
+            "{{\"width\":{},\"height\":{},\"progress\":{:.4},\"spp\":{},\"tiles_done\":{},\"elapsed_seconds\":{:.1},\"eta_seconds\":{},\"rays_per_second\":{:.0},\"done\":{}}}",
+            state.width, state.height, progress.fraction, state.samples_per_pixel, state.tiles_done,
+            progress.elapsed_seconds, eta, progress.rays_per_second, progress.done
+        );
+    }
+}
+
+//
+// terminal preview
+//
+
+// redraws closer together than this are skipped, tiles can finish faster than a slow link shows them
+static TERMINAL_REDRAW_MILLISECONDS: u64 = 250;
+
+// force draws even if the last drawing was a moment ago
+fn draw_terminal(state: &mut PreviewState, force: bool) {
+    let columns = match state.terminal_columns {
+        Some(columns) => columns.min(state.width),
+        None => return,
+    };
+    if state.width == 0 || state.height == 0 {
+        return;
+    }
+    if let Some(drawn) = state.terminal_drawn {
+        if !force && drawn.elapsed() < Duration::from_millis(TERMINAL_REDRAW_MILLISECONDS) {
+            return;
+        }
+    }
+
+    let (text, lines) = terminal_text(state, columns);
+    let stdout = io::stdout();
+    let mut handle = stdout.lock();
+    // a terminal that went away is no reason to stop rendering
+    let _ = handle.write_all(text.as_bytes()).and_then(|_| handle.flush());
+    state.terminal_drawn = Some(Instant::now());
+    state.terminal_lines = lines;
+}
+
+// the drawing of the image columns characters wide and its status line, and how many lines that is
+fn terminal_text(state: &PreviewState, columns: usize) -> (String, usize) {
+    // terminal pixels are square with two to a character, each one averages the image pixels it covers
+    let pixel_rows = ((state.height * columns + state.width / 2) / state.width).max(2) & !1usize;
+    let mut pixels: Vec<(u32, u32, u32)> = Vec::with_capacity(columns * pixel_rows);
+    for row in 0..pixel_rows {
+        let (y0, y1) = cover(row, pixel_rows, state.height);
+        for column in 0..columns {
+            let (x0, x1) = cover(column, columns, state.width);
+            let mut sum = (0u32, 0u32, 0u32);
+            for y in y0..y1 {
+                for x in x0..x1 {
+                    let index = (y * state.width + x) * 3;
+                    sum.0 += state.image[index] as u32;
+                    sum.1 += state.image[index + 1] as u32;
+                    sum.2 += state.image[index + 2] as u32;
+                }
+            }
+            let count = ((y1 - y0) * (x1 - x0)) as u32;
+            pixels.push((sum.0 / count, sum.1 / count, sum.2 / count));
+        }
+    }
+
+    let mut text = String::new();
+    // back up over the last drawing of this image
+    if state.terminal_lines > 0 {
+        text.push_str(&format!("\x1b[{}F", state.terminal_lines));
+    }
+    for row in 0..pixel_rows / 2 {
+        for column in 0..columns {
+            let top = pixels[2 * row * columns + column];
+            let bottom = pixels[(2 * row + 1) * columns + column];
+            text.push_str(&format!(
+                "\x1b[38;2;{};{};{}m\x1b[48;2;{};{};{}m\u{2580}",
+                top.0, top.1, top.2, bottom.0, bottom.1, bottom.2
+            ));
+        }
+        text.push_str("\x1b[0m\n");
+    }
+    let progress = state.progress();
+    let eta = match progress.eta_seconds {
+        Some(eta) => format!("{:.0}s", eta),
+        None => String::from("?"),
+    };
+    // erase to the end of the line, the last status line may have been longer
+    text.push_str(&format!(
+        "{:5.1}%  {} spp  {:.1}s elapsed  eta {}  {:.0} camera rays/s{}\x1b[K\n",
+        100f64 * progress.fraction, state.samples_per_pixel, progress.elapsed_seconds, eta,
+        progress.rays_per_second, if progress.done { "  done" } else { "" }
+    ));
+    return (text, pixel_rows / 2 + 1);
+}
+
+// the range of count image pixels covered by cell index out of cells, never empty
+fn cover(index: usize, cells: usize, count: usize) -> (usize, usize) {
+    let start = index * count / cells;
+    let end = ((index + 1) * count / cells).max(start + 1).min(count);
+    return (start.min(count - 1), end);
+}
+
+// listens on 127.0.0.1:port in the background, every connection gets a thread of its own
+pub fn serve(preview: Arc<Preview>, port: u16) -> io::Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+            let preview = preview.clone();
+            thread::spawn(move || {
+                // the browser going away mid response is nothing to report
+                let _ = handle_connection(&preview, stream);
+            });
+        }
+    });
+    return Ok(());
+}
+
+fn handle_connection(preview: &Preview, mut stream: TcpStream) -> io::Result<()> {
+    // the request line is all we go by, the headers are read and dropped
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or("");
+    // the query string does not matter, the page adds one to get around caching
+    let path = parts.next().unwrap_or("").split('?').next().unwrap_or("");
+    if method != "GET" {
+        return respond(&mut stream, "405 Method Not Allowed", "text/plain", b"only GET\n");
+    }
+    return match path {
+        "/" => respond(&mut stream, "200 OK", "text/html; charset=utf-8", PAGE.as_bytes()),
+        "/image.png" => match preview.png() {
+            Some(png) => respond(&mut stream, "200 OK", "image/png", &png),
+            None => respond(&mut stream, "503 Service Unavailable", "text/plain", b"no image yet\n"),
+        },
+        "/progress.json" => respond(&mut stream, "200 OK", "application/json", preview.progress_json().as_bytes()),
+        "/events" => stream_events(preview, &mut stream),
+        _ => respond(&mut stream, "404 Not Found", "text/plain", b"not found\n"),
+    };
+}
+
+fn respond(stream: &mut TcpStream, status: &str, content_type: &str, body: &[u8]) -> io::Result<()> {
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n",
+        status, content_type, body.len()
+    );
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(body)?;
+    return stream.flush();
+}
+
+// runs until the browser hangs up, which shows up as a failed write
+fn stream_events(preview: &Preview, stream: &mut TcpStream) -> io::Result<()> {
+    stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-store\r\nConnection: keep-alive\r\n\r\n")?;
+    let mut generation = usize::max_value();
+    let mut sent = 0usize;
+    let mut done_sent = false;
+    loop {
+        let mut message = String::new();
+        {
+            let mut state = preview.m_state.lock().unwrap();
+            if state.generation == generation && sent == state.events.len() && state.elapsed.is_some() == done_sent {
+                state = preview.m_changed.wait_timeout(state, Duration::from_secs(KEEP_ALIVE_SECONDS)).unwrap().0;
+            }
+            if state.generation != generation {
+                generation = state.generation;
+                sent = 0;
+                done_sent = false;
+                message.push_str(&format!("event: begin\ndata: {{\"width\":{},\"height\":{}}}\n\n", state.width, state.height));
+            }
+            for event in state.events[sent..].iter() {
+                message.push_str(event);
+            }
+            sent = state.events.len();
+            if state.elapsed.is_some() && !done_sent {
+                message.push_str("event: done\ndata: {}\n\n");
+                done_sent = true;
+            }
+        }
+        if message.is_empty() {
+            message.push_str(": keep-alive\n\n");
+        }
+        stream.write_all(message.as_bytes())?;
+        stream.flush()?;
+    }
+}
+
+fn base64(bytes: &[u8]) -> String {
+    let alphabet = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity((bytes.len() + 2) / 3 * 4);
+    for chunk in bytes.chunks(3) {
+        let b0 = chunk[0] as u32;
+        let b1 = if chunk.len() > 1 { chunk[1] as u32 } else { 0u32 };
+        let b2 = if chunk.len() > 2 { chunk[2] as u32 } else { 0u32 };
+        let triple = (b0 << 16) | (b1 << 8) | b2;
+        encoded.push(alphabet[(triple >> 18) as usize & 63] as char);
+        encoded.push(alphabet[(triple >> 12) as usize & 63] as char);
+        encoded.push(if chunk.len() > 1 { alphabet[(triple >> 6) as usize & 63] as char } else { '=' });
+        encoded.push(if chunk.len() > 2 { alphabet[triple as usize & 63] as char } else { '=' });
+    }
+    return encoded;
+}
+
+static PAGE: &'static str = r##"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>render preview</title>
+<style>
+body { background: #222; color: #ddd; font: 14px monospace; margin: 16px; }
+canvas { image-rendering: pixelated; border: 1px solid #444; }
+</style>
+</head>
+<body>
+<canvas id="image" width="1" height="1"></canvas>
+<p id="status">waiting for the renderer</p>
+<script>
+var canvas = document.getElementById("image");
+var context = canvas.getContext("2d");
+var statusLine = document.getElementById("status");
+
+function drawImage(src, x, y) {
+    var image = new Image();
+    image.onload = function() { context.drawImage(image, x, y); };
+    image.src = src;
+}
+
+var events = new EventSource("/events");
+events.addEventListener("begin", function(event) {
+    var size = JSON.parse(event.data);
+    canvas.width = size.width;
+    canvas.height = size.height;
+    context.fillStyle = "#000";
+    context.fillRect(0, 0, size.width, size.height);
+    drawImage("/image.png?" + Date.now(), 0, 0);
+});
+events.addEventListener("tile", function(event) {
+    var tile = JSON.parse(event.data);
+    drawImage("data:image/png;base64," + tile.png, tile.x, tile.y);
+});
+events.addEventListener("done", function() {
+    drawImage("/image.png?" + Date.now(), 0, 0);
+});
+
+function poll() {
+    fetch("/progress.json").then(function(response) { return response.json(); }).then(function(p) {
+        var eta = p.eta_seconds === null ? "?" : p.eta_seconds.toFixed(0) + "s";
+        statusLine.textContent = (100 * p.progress).toFixed(1) + "%  " + p.spp + " spp  " +
+            p.elapsed_seconds.toFixed(0) + "s elapsed  eta " + eta + "  " +
+            Math.round(p.rays_per_second) + " camera rays/s" + (p.done ? "  done" : "");
+    }).catch(function() {
+        statusLine.textContent = "renderer is gone";
+    });
+}
+setInterval(poll, 1000);
+poll();
+</script>
+</body>
+</html>
+"##;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base64_pads_to_whole_quads() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+        assert_eq!(base64(&[0xffu8, 0xfeu8]), "//4=");
+    }
+
+    #[test]
+    fn tiles_land_in_the_image() {
+        let preview = Preview::new();
+        assert!(preview.png().is_none());
+        preview.begin(4, 3, 2);
+        // a 2 x 2 white tile in the bottom right, rows top down
+        preview.tile_finished(2, 1, 2, 2, &[255u8; 12], 8);
+        // hanging over the edge, dropped
+        preview.tile_finished(3, 2, 2, 2, &[255u8; 12], 8);
+        {
+            let state = preview.m_state.lock().unwrap();
+            assert_eq!(state.tiles_done, 1);
+            assert_eq!(state.events.len(), 1);
+            assert!(state.events[0].starts_with("event: tile\ndata: {\"x\":2,\"y\":1,\"width\":2,\"height\":2,\"png\":\""));
+            for y in 0..3 {
+                for x in 0..4 {
+                    let lit = x >= 2 && y >= 1;
+                    assert_eq!(state.image[(y * 4 + x) * 3], if lit { 255u8 } else { 0u8 });
+                }
+            }
+        }
+        // 8 of the 4 x 3 x 2 camera rays
+        assert!(preview.progress_json().contains("\"progress\":0.3333"));
+        let mut output_buffer = RenderBufferI32::new(4, 3);
+        output_buffer.buffer = vec![300; 36];
+        preview.finish(&output_buffer);
+        assert!(preview.progress_json().contains("\"done\":true"));
+        assert!(preview.m_state.lock().unwrap().image.iter().all(|c| *c == 255u8));
+    }
+
+    // the response to a request line sent to handle_connection over a loopback socket
+    fn request(preview: Arc<Preview>, request_line: &str) -> String {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            handle_connection(&preview, stream).unwrap();
+        });
+        let mut client = TcpStream::connect(address).unwrap();
+        client.write_all(format!("{}\r\nHost: localhost\r\n\r\n", request_line).as_bytes()).unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).unwrap();
+        server.join().unwrap();
+        return String::from_utf8_lossy(&response).into_owned();
+    }
+
+    #[test]
+    fn the_server_answers_by_path() {
+        let preview = Arc::new(Preview::new());
+        assert!(request(preview.clone(), "GET /image.png HTTP/1.1").starts_with("HTTP/1.1 503"));
+        preview.begin(2, 2, 1);
+        let page = request(preview.clone(), "GET /?reload=1 HTTP/1.1");
+        assert!(page.starts_with("HTTP/1.1 200 OK\r\nContent-Type: text/html"));
+        assert!(page.ends_with(PAGE));
+        let png = request(preview.clone(), "GET /image.png HTTP/1.1");
+        assert!(png.contains("Content-Type: image/png") && png.contains("PNG"));
+        let progress = request(preview.clone(), "GET /progress.json HTTP/1.1");
+        assert!(progress.contains("\r\n\r\n{\"width\":2,\"height\":2,"));
+        assert!(request(preview.clone(), "GET /missing HTTP/1.1").starts_with("HTTP/1.1 404"));
+        assert!(request(preview.clone(), "POST / HTTP/1.1").starts_with("HTTP/1.1 405"));
+    }
+
+    #[test]
+    fn cells_cover_every_pixel_once() {
+        for &(cells, count) in [(4usize, 10usize), (10usize, 4usize), (7usize, 7usize), (3usize, 200usize)].iter() {
+            let mut covered = vec![0usize; count];
+            for index in 0..cells {
+                let (start, end) = cover(index, cells, count);
+                assert!(start < end && end <= count);
+                for pixel in start..end {
+                    covered[pixel] += 1;
+                }
+            }
+            // with more cells than pixels some pixels show up in several cells, but none is left out
+            assert!(covered.iter().all(|c| *c >= 1));
+            if cells <= count {
+                assert!(covered.iter().all(|c| *c == 1));
+            }
+        }
+    }
+
+    #[test]
+    fn half_blocks_take_the_top_and_bottom_colors() {
+        let preview = Preview::new();
+        preview.begin(4, 4, 1);
+        // the top two rows red, the bottom two blue
+        let mut rgb = Vec::new();
+        for row in 0..4 {
+            for _ in 0..4 {
+                rgb.extend_from_slice(if row < 2 { &[255u8, 0u8, 0u8] } else { &[0u8, 0u8, 255u8] });
+            }
+        }
+        preview.tile_finished(0, 0, 4, 4, &rgb, 16);
+        let mut state = preview.m_state.lock().unwrap();
+        // two characters across, so a 2 x 2 pixel drawing on one line of half blocks, then the status
+        let (text, lines) = terminal_text(&state, 2);
+        assert_eq!(lines, 2);
+        let rows: Vec<&str> = text.split('\n').collect();
+        assert_eq!(rows.len(), 3);
+        let cell = "\x1b[38;2;255;0;0m\x1b[48;2;0;0;255m\u{2580}";
+        assert_eq!(rows[0], format!("{}{}\x1b[0m", cell, cell));
+        assert!(rows[1].starts_with("100.0%  1 spp"));
+        assert!(rows[1].ends_with("\x1b[K"));
+        assert_eq!(rows[2], "");
+
+        // the next drawing goes back up over this one
+        state.terminal_lines = lines;
+        assert!(terminal_text(&state, 2).0.starts_with("\x1b[2F"));
+    }
+}
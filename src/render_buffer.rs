@@ -28,3 +28,126 @@ impl RenderBufferI32 {
         self.buffer.push(b);
     }
 }
+
+// accumulation buffer, pixels can be added to in any order (light paths splat wherever they land)
+// (0, 0) is the bottom left pixel, matching the camera's image coordinates
+pub struct RenderBufferF64 {
+    pub width: usize,
+    pub height: usize,
+    pub buffer: Vec<f64>,
+}
+
+impl RenderBufferF64 {
+    pub fn new(width: usize, height: usize) -> RenderBufferF64 {
+        return RenderBufferF64 {
+            width: width,
+            height: height,
+            buffer: vec![0f64; width * height * CHANNELS_PER_PIXEL],
+        };
+    }
+
+    pub fn add(&mut self, x: usize, y: usize, r: f64, g: f64, b: f64) {
+        if x >= self.width || y >= self.height {
+            return;
+        }
+        let index = (y * self.width + x) * CHANNELS_PER_PIXEL;
+        self.buffer[index] += r;
+        self.buffer[index + 1] += g;
+        self.buffer[index + 2] += b;
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> (f64, f64, f64) {
+        let index = (y * self.width + x) * CHANNELS_PER_PIXEL;
+        return (self.buffer[index], self.buffer[index + 1], self.buffer[index + 2]);
+    }
+}
+
+// several named float images of the same size, so one pass over the image can fill all of them
+// (0, 0) is the bottom left pixel like RenderBufferF64, layers are parallel arrays indexed by the handle add_layer returns
+pub struct RenderBufferLayers {
+    pub width: usize,
+    pub height: usize,
+    m_names: Vec<String>,
+    m_channels: Vec<usize>,
+    m_buffers: Vec<Vec<f64>>,
+}
+
+impl RenderBufferLayers {
+    pub fn new(width: usize, height: usize) -> RenderBufferLayers {
+        return RenderBufferLayers {
+            width: width,
+            height: height,
+            m_names: Vec::new(),
+            m_channels: Vec::new(),
+            m_buffers: Vec::new(),
+        };
+    }
+
+    // adding a name twice hands back the existing layer
+    pub fn add_layer(&mut self, name: &str, channels: usize) -> usize {
+        if let Some(layer) = self.find_layer(name) {
+            return layer;
+        }
+        self.m_names.push(name.to_string());
+        self.m_channels.push(channels);
+        self.m_buffers.push(vec![0f64; self.width * self.height * channels]);
+        return self.m_names.len() - 1;
+    }
+
+    pub fn find_layer(&self, name: &str) -> Option<usize> {
+        return self.m_names.iter().position(|n| n == name);
+    }
+
+    pub fn layer_count(&self) -> usize {
+        return self.m_names.len();
+    }
+
+    pub fn name(&self, layer: usize) -> &str {
+        return &self.m_names[layer];
+    }
+
+    pub fn channels(&self, layer: usize) -> usize {
+        return self.m_channels[layer];
+    }
+
+    // the whole layer, channels of a pixel next to each other, rows bottom up
+    pub fn data(&self, layer: usize) -> &[f64] {
+        return &self.m_buffers[layer];
+    }
+
+    pub fn add(&mut self, layer: usize, x: usize, y: usize, values: &[f64]) {
+        if x >= self.width || y >= self.height {
+            return;
+        }
+        let channels = self.m_channels[layer];
+        let index = (y * self.width + x) * channels;
+        let buffer = &mut self.m_buffers[layer];
+        for c in 0..channels.min(values.len()) {
+            buffer[index + c] += values[c];
+        }
+    }
+
+    pub fn set(&mut self, layer: usize, x: usize, y: usize, values: &[f64]) {
+        if x >= self.width || y >= self.height {
+            return;
+        }
+        let channels = self.m_channels[layer];
+        let index = (y * self.width + x) * channels;
+        let buffer = &mut self.m_buffers[layer];
+        for c in 0..channels.min(values.len()) {
+            buffer[index + c] = values[c];
+        }
+    }
+
+    pub fn get(&self, layer: usize, x: usize, y: usize) -> &[f64] {
+        let channels = self.m_channels[layer];
+        let index = (y * self.width + x) * channels;
+        return &self.m_buffers[layer][index..index + channels];
+    }
+
+    pub fn scale(&mut self, layer: usize, factor: f64) {
+        for value in self.m_buffers[layer].iter_mut() {
+            *value *= factor;
+        }
+    }
+}
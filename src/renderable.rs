@@ -1,14 +1,31 @@
 extern crate rusty_math;
-extern crate rand;
 
 use rusty_math::*;
-use rand::Rng;
 use std::usize;
+use std::f64::consts;
+
+use bvh::Aabb;
+use bvh::Bvh;
+use transform::Transform;
+use transform::AnimatedTransform;
+use csg::CsgNode;
+use sdf::SdfShape;
+use media::HomogeneousMedium;
+use media::MediumSegment;
+use volume::HeterogeneousVolume;
+use volume::VolumeCollision;
+use sampler;
+use stats;
+use renderer::sky_color;
 
 pub struct HitRecord {
     pub ray: Ray,
     pub index: usize,
     pub time: f64,
+    // moment within the shutter interval the ray was fired at
+    pub shutter_time: f64,
+    // usize::MAX when the hit primitive lives directly in the render list
+    pub instance: usize,
 }
 
 impl HitRecord {
@@ -17,6 +34,8 @@ impl HitRecord {
             ray: Ray::new(Vec3::new(0f64, 0f64, 0f64), Vec3::new(0f64, 0f64, 0f64)),
             index: 0,
             time: 0f64,
+            shutter_time: 0f64,
+            instance: usize::MAX,
         };
     }
 }
@@ -25,31 +44,100 @@ fn random_in_unit_sphere() -> Vec3 {
     let u = &Vec3::new(1f64, 1f64, 1f64);
     let mut p = Vec3::new(2f64, 2f64, 2f64);
     while p.length_squared() >= 1.0f64 {
-        let rand_x = rand::thread_rng().gen_range(0f64, 1f64);
-        let rand_y = rand::thread_rng().gen_range(0f64, 1f64);
-        let rand_z = rand::thread_rng().gen_range(0f64, 1f64);
+        let rand_x = sampler::next_f64();
+        let rand_y = sampler::next_f64();
+        let rand_z = sampler::next_f64();
         p = &(2.0f64 * &Vec3::new(rand_x, rand_y, rand_z)) - u;
     }
     return p;
 }
 
-pub trait Renderable {
+// uniform on the unit sphere, added to a normal it gives cosine distributed directions
+pub fn random_unit_vector() -> Vec3 {
+    let z = sampler::next_range(-1f64, 1f64);
+    let phi = sampler::next_range(0f64, 2f64 * consts::PI);
+    let r = (1f64 - z * z).max(0f64).sqrt();
+    return Vec3::new(r * phi.cos(), r * phi.sin(), z);
+}
+
+// Sync so render lists can be shared between render threads
+pub trait Renderable: Sync {
     // the time along the ray where the intersection occurs
     fn get_hit_time(&self, ray: &Ray, time_min: f64, time_max: f64) -> f64;
+    // outward facing unit normal at a point on the surface
+    fn get_normal(&self, point: &Vec3) -> Vec3;
+    fn get_bounds(&self) -> Aabb;
 }
 
-// only supports spheres right now (avoid dynamic dispatch with explicit vector template)
-pub struct RenderList<'a> {
+// one span along a ray spent inside a closed shape, times may be infinite for unbounded solids
+pub struct HitInterval {
+    pub enter_time: f64,
+    pub enter_normal: Vec3,
+    pub exit_time: f64,
+    pub exit_normal: Vec3,
+}
+
+// closed shapes that can report every entry and exit, the building block for csg
+pub trait Solid: Renderable {
+    // appends all spans along the whole line (not just the positive half), sorted and disjoint
+    fn get_hit_intervals(&self, ray: &Ray, intervals: &mut Vec<HitInterval>);
+}
+
+// first span boundary strictly inside (time_min, time_max), time_min on a miss
+pub fn first_boundary_time(intervals: &[HitInterval], time_min: f64, time_max: f64) -> f64 {
+    for interval in intervals {
+        if interval.enter_time > time_min && interval.enter_time < time_max {
+            return interval.enter_time;
+        }
+        if interval.enter_time <= time_min && interval.exit_time > time_min && interval.exit_time < time_max {
+            return interval.exit_time;
+        }
+    }
+    return time_min;
+}
+
+// materials are shared by reference, so the address is what tells them apart
+fn material_address(material: &Material) -> usize {
+    return material as *const Material as *const u8 as usize;
+}
+
+// a bag of primitives with its own bvh, shared by any number of instances
+pub struct Geometry<'a> {
     // list of spheres to render with parallel array
     m_spheres: Vec<shapes::Sphere>,
     m_sphere_materials: Vec<&'a Material>, // dynamic dispatch on materials
+    // moving spheres are indexed after the static ones
+    m_moving_spheres: Vec<shapes::MovingSphere>,
+    m_moving_sphere_materials: Vec<&'a Material>,
+    // csg nodes carry their own materials per operand
+    m_csg_nodes: Vec<&'a CsgNode<'a>>,
+    m_sdf_shapes: Vec<&'a SdfShape>,
+    m_sdf_materials: Vec<&'a Material>,
+    // what the bvh indexes into, filled in by build
+    m_primitives: Vec<(PrimitiveKind, usize)>,
+    m_bvh: Bvh,
 }
 
-impl<'a> RenderList<'a> {
-    pub fn new() -> RenderList<'a> {
-        return RenderList {
+#[derive(Clone, Copy)]
+enum PrimitiveKind {
+    Sphere,
+    MovingSphere,
+    Csg,
+    Sdf,
+}
+
+impl<'a> Geometry<'a> {
+    pub fn new() -> Geometry<'a> {
+        return Geometry {
             m_spheres: Vec::new(),
             m_sphere_materials: Vec::new(),
+            m_moving_spheres: Vec::new(),
+            m_moving_sphere_materials: Vec::new(),
+            m_csg_nodes: Vec::new(),
+            m_sdf_shapes: Vec::new(),
+            m_sdf_materials: Vec::new(),
+            m_primitives: Vec::new(),
+            m_bvh: Bvh::new(),
         };
     }
 
@@ -64,28 +152,441 @@ impl<'a> RenderList<'a> {
         self.m_sphere_materials.push(material);
     }
 
+    pub fn add_moving_sphere(&mut self, sphere: &shapes::MovingSphere, material: &'a Material) {
+        let sphere_copy = shapes::MovingSphere {
+            centers: sphere.centers.iter().map(|c| Vec3::new(c.x, c.y, c.z)).collect(),
+            times: sphere.times.clone(),
+            radius: sphere.radius,
+        };
+        self.m_moving_spheres.push(sphere_copy);
+        self.m_moving_sphere_materials.push(material);
+    }
+
+    pub fn add_csg(&mut self, node: &'a CsgNode<'a>) {
+        self.m_csg_nodes.push(node);
+    }
+
+    pub fn add_sdf(&mut self, shape: &'a SdfShape, material: &'a Material) {
+        self.m_sdf_shapes.push(shape);
+        self.m_sdf_materials.push(material);
+    }
+
+    // must be called after the last add and before rendering
+    pub fn build(&mut self) {
+        self.m_primitives.clear();
+        let mut bounds: Vec<Aabb> = Vec::new();
+        for index in 0..self.m_spheres.len() {
+            self.m_primitives.push((PrimitiveKind::Sphere, index));
+            bounds.push(self.m_spheres[index].get_bounds());
+        }
+        // bounds cover the whole path so the bvh stays valid for any shutter time
+        for index in 0..self.m_moving_spheres.len() {
+            self.m_primitives.push((PrimitiveKind::MovingSphere, index));
+            bounds.push(self.m_moving_spheres[index].get_motion_bounds());
+        }
+        for index in 0..self.m_csg_nodes.len() {
+            self.m_primitives.push((PrimitiveKind::Csg, index));
+            bounds.push(self.m_csg_nodes[index].get_bounds());
+        }
+        for index in 0..self.m_sdf_shapes.len() {
+            self.m_primitives.push((PrimitiveKind::Sdf, index));
+            bounds.push(self.m_sdf_shapes[index].get_bounds());
+        }
+        self.m_bvh = Bvh::build(&bounds);
+    }
+
+    pub fn get_bounds(&self) -> Aabb {
+        return self.m_bvh.get_bounds();
+    }
+
+    pub fn primitive_count(&self) -> usize {
+        return self.m_primitives.len();
+    }
+
+    // every material a primitive can hand out, duplicates included
+    pub fn for_each_material<F>(&self, found: &mut F)
+        where F: FnMut(&'a Material)
+    {
+        for material in self.m_sphere_materials.iter() {
+            found(*material);
+        }
+        for material in self.m_moving_sphere_materials.iter() {
+            found(*material);
+        }
+        for node in self.m_csg_nodes.iter() {
+            node.for_each_material(found);
+        }
+        for material in self.m_sdf_materials.iter() {
+            found(*material);
+        }
+    }
+
+    // returns the closest primitive index (usize::MAX on a miss) and its hit time
+    pub fn get_closest_hit(&self, ray: &Ray, shutter_time: f64, time_min: f64, time_max: f64) -> (usize, f64) {
+        return self.m_bvh.traverse(ray, time_min, time_max, |index, closest_time| {
+            let (kind, kind_index) = self.m_primitives[index];
+            return match kind {
+                PrimitiveKind::Sphere => self.m_spheres[kind_index].get_hit_time(ray, time_min, closest_time),
+                PrimitiveKind::MovingSphere => {
+                    let sphere = self.m_moving_spheres[kind_index].sphere_at(shutter_time);
+                    sphere.get_hit_time(ray, time_min, closest_time)
+                }
+                PrimitiveKind::Csg => self.m_csg_nodes[kind_index].get_hit_time(ray, time_min, closest_time),
+                PrimitiveKind::Sdf => self.m_sdf_shapes[kind_index].get_hit_time(ray, time_min, closest_time),
+            };
+        });
+    }
+
+    // outward normal and material where the ray hit primitive index at hit_time
+    pub fn get_surface(&self, index: usize, ray: &Ray, hit_time: f64, shutter_time: f64) -> (Vec3, &'a Material) {
+        let (kind, kind_index) = self.m_primitives[index];
+        let point = ray.point_at(hit_time);
+        return match kind {
+            PrimitiveKind::Sphere => {
+                (self.m_spheres[kind_index].get_normal(&point), self.m_sphere_materials[kind_index])
+            }
+            PrimitiveKind::MovingSphere => {
+                let sphere = self.m_moving_spheres[kind_index].sphere_at(shutter_time);
+                (sphere.get_normal(&point), self.m_moving_sphere_materials[kind_index])
+            }
+            PrimitiveKind::Csg => self.m_csg_nodes[kind_index].get_surface(ray, hit_time),
+            PrimitiveKind::Sdf => {
+                (self.m_sdf_shapes[kind_index].get_normal(&point), self.m_sdf_materials[kind_index])
+            }
+        };
+    }
+}
+
+// places shared geometry in the world, rays are moved into object space instead of copying primitives
+pub struct Instance<'a> {
+    pub geometry: &'a Geometry<'a>,
+    pub transform: AnimatedTransform,
+}
+
+impl<'a> Instance<'a> {
+    // covers every shutter time
+    pub fn get_bounds(&self) -> Aabb {
+        return self.transform.transform_bounds(&self.geometry.get_bounds());
+    }
+}
+
+// two level: loose spheres and instances each get a bvh, instances carry their own bvh inside the geometry
+pub struct RenderList<'a> {
+    m_geometry: Geometry<'a>,
+    m_instances: Vec<Instance<'a>>,
+    m_instance_bvh: Bvh,
+    // media are not surfaces, rays pass through the boundaries and the integrator samples inside them
+    m_medium_boundaries: Vec<shapes::Sphere>,
+    m_media: Vec<&'a HomogeneousMedium>,
+    m_fog: Option<&'a HomogeneousMedium>,
+    m_fog_distance: f64,
+    m_volumes: Vec<&'a HeterogeneousVolume>,
+    // luminance of the sky's white horizon, in nits when the lights are given in photometric units
+    m_sky_luminance: f64,
+    // spherical area lights, also added as ordinary spheres so rays can hit them
+    m_lights: Vec<shapes::Sphere>,
+    m_light_materials: Vec<&'a materials::DiffuseLight>,
+    // material addresses in ascending order with the id each one was given, filled in by build
+    m_material_addresses: Vec<usize>,
+    m_material_ids: Vec<usize>,
+}
+
+// a point picked on one of the lights, pdf is per unit area and includes picking the light
+pub struct LightSample {
+    pub point: Vec3,
+    pub normal: Vec3,
+    pub emission: Vec3,
+    pub pdf: f64,
+}
+
+impl<'a> RenderList<'a> {
+    pub fn new() -> RenderList<'a> {
+        return RenderList {
+            m_geometry: Geometry::new(),
+            m_instances: Vec::new(),
+            m_instance_bvh: Bvh::new(),
+            m_medium_boundaries: Vec::new(),
+            m_media: Vec::new(),
+            m_fog: None,
+            m_fog_distance: 0f64,
+            m_volumes: Vec::new(),
+            m_sky_luminance: 1f64,
+            m_lights: Vec::new(),
+            m_light_materials: Vec::new(),
+            m_material_addresses: Vec::new(),
+            m_material_ids: Vec::new(),
+        };
+    }
+
+    pub fn add_sphere(&mut self, sphere: &shapes::Sphere, material: &'a Material) {
+        self.m_geometry.add_sphere(sphere, material);
+    }
+
+    pub fn add_moving_sphere(&mut self, sphere: &shapes::MovingSphere, material: &'a Material) {
+        self.m_geometry.add_moving_sphere(sphere, material);
+    }
+
+    pub fn add_csg(&mut self, node: &'a CsgNode<'a>) {
+        self.m_geometry.add_csg(node);
+    }
+
+    pub fn add_sdf(&mut self, shape: &'a SdfShape, material: &'a Material) {
+        self.m_geometry.add_sdf(shape, material);
+    }
+
+    // fills the inside of the boundary with the medium
+    pub fn add_medium(&mut self, boundary: &shapes::Sphere, medium: &'a HomogeneousMedium) {
+        self.m_medium_boundaries.push(shapes::Sphere {
+            center: Vec3::new(boundary.center.x, boundary.center.y, boundary.center.z),
+            radius: boundary.radius,
+        });
+        self.m_media.push(medium);
+    }
+
+    // fills the world out to distance from every ray origin, past that rays see the sky unattenuated
+    // (fog out to infinity would swallow every ray that escapes the scene)
+    pub fn set_fog(&mut self, medium: &'a HomogeneousMedium, distance: f64) {
+        self.m_fog = Some(medium);
+        self.m_fog_distance = distance;
+    }
+
+    pub fn set_sky_luminance(&mut self, nits: f64) {
+        self.m_sky_luminance = nits;
+    }
+
+    // radiance of the sky seen along dir
+    pub fn sky_color(&self, dir: &Vec3) -> Vec3 {
+        return &sky_color(dir) * self.m_sky_luminance;
+    }
+
+    pub fn add_volume(&mut self, volume: &'a HeterogeneousVolume) {
+        self.m_volumes.push(volume);
+    }
+
+    // an emissive sphere that integrators can sample directly
+    pub fn add_light(&mut self, sphere: &shapes::Sphere, material: &'a materials::DiffuseLight) {
+        self.m_geometry.add_sphere(sphere, material);
+        self.m_lights.push(shapes::Sphere {
+            center: Vec3::new(sphere.center.x, sphere.center.y, sphere.center.z),
+            radius: sphere.radius,
+        });
+        self.m_light_materials.push(material);
+    }
+
+    pub fn has_lights(&self) -> bool {
+        return !self.m_lights.is_empty();
+    }
+
+    // picks a light uniformly and a point uniformly on its surface
+    pub fn sample_light(&self) -> Option<LightSample> {
+        if self.m_lights.is_empty() {
+            return None;
+        }
+        let index = sampler::next_index(self.m_lights.len());
+        let light = &self.m_lights[index];
+        let normal = random_unit_vector();
+        let area = 4f64 * consts::PI * light.radius * light.radius;
+        let emission = &self.m_light_materials[index].emission;
+        return Some(LightSample {
+            point: &light.center + &(light.radius * &normal),
+            normal: normal,
+            emission: Vec3::new(emission.x, emission.y, emission.z),
+            pdf: 1f64 / (area * self.m_lights.len() as f64),
+        });
+    }
+
+    // density sample_light would have produced point with, 0 if the point is not on a light
+    pub fn light_pdf(&self, point: &Vec3) -> f64 {
+        for light in self.m_lights.iter() {
+            let distance = (point - &light.center).length_squared().sqrt();
+            if (distance - light.radius).abs() < 1e-4f64 * light.radius.max(1f64) {
+                let area = 4f64 * consts::PI * light.radius * light.radius;
+                return 1f64 / (area * self.m_lights.len() as f64);
+            }
+        }
+        return 0f64;
+    }
+
+    // shadow test for connecting two path vertices
+    pub fn is_occluded(&self, from: &Vec3, to: &Vec3, shutter_time: f64) -> bool {
+        let ray = Ray::new(Vec3::new(from.x, from.y, from.z), to - from);
+        let mut hit_record = HitRecord::new();
+        stats::count_shadow_ray();
+        return self.find_closest_hit(&ray, shutter_time, 1e-4f64, 1f64 - 1e-4f64, &mut hit_record);
+    }
+
+    pub fn has_media(&self) -> bool {
+        return self.m_fog.is_some() || !self.m_media.is_empty();
+    }
+
+    pub fn has_volumes(&self) -> bool {
+        return !self.m_volumes.is_empty();
+    }
+
+    // earliest real collision among the heterogeneous volumes, each one tracks independently
+    pub fn sample_volume_collision(&self, ray: &Ray, time_min: f64, time_max: f64) -> Option<VolumeCollision> {
+        let mut closest: Option<VolumeCollision> = None;
+        let mut closest_time = time_max;
+        for volume in self.m_volumes.iter() {
+            if let Some(collision) = volume.sample_collision(ray, time_min, closest_time) {
+                closest_time = collision.time;
+                closest = Some(collision);
+            }
+        }
+        return closest;
+    }
+
+    // every piece of [time_min, time_max] that passes through a medium
+    pub fn get_media_segments(&self, ray: &Ray, time_min: f64, time_max: f64) -> Vec<MediumSegment<'a>> {
+        let mut segments = Vec::new();
+        if let Some(fog) = self.m_fog {
+            let fog_time = self.m_fog_distance / ray.dir.length_squared().sqrt();
+            if fog_time > time_min {
+                segments.push(MediumSegment {
+                    medium: fog,
+                    start_time: time_min,
+                    end_time: time_max.min(fog_time),
+                });
+            }
+        }
+        let mut intervals: Vec<HitInterval> = Vec::new();
+        for index in 0..self.m_medium_boundaries.len() {
+            intervals.clear();
+            self.m_medium_boundaries[index].get_hit_intervals(ray, &mut intervals);
+            for interval in intervals.iter() {
+                let start = interval.enter_time.max(time_min);
+                let end = interval.exit_time.min(time_max);
+                if start < end {
+                    segments.push(MediumSegment {
+                        medium: self.m_media[index],
+                        start_time: start,
+                        end_time: end,
+                    });
+                }
+            }
+        }
+        return segments;
+    }
+
+    // geometry must already be built
+    pub fn add_instance(&mut self, geometry: &'a Geometry<'a>, transform: Transform) {
+        self.add_moving_instance(geometry, AnimatedTransform::fixed(transform));
+    }
+
+    // returns the instance's index for set_instance_transform
+    pub fn add_moving_instance(&mut self, geometry: &'a Geometry<'a>, transform: AnimatedTransform) -> usize {
+        self.m_instances.push(Instance {
+            geometry: geometry,
+            transform: transform,
+        });
+        return self.m_instances.len() - 1;
+    }
+
+    // moves an instance after build, call refit once all of them have moved
+    pub fn set_instance_transform(&mut self, index: usize, transform: AnimatedTransform) {
+        self.m_instances[index].transform = transform;
+    }
+
+    // brings the instance bvh up to date with the instance transforms without building anything again,
+    // the geometry inside the instances never changes so its bvhs are left alone
+    pub fn refit(&mut self) {
+        let bounds: Vec<Aabb> = self.m_instances.iter().map(|i| i.get_bounds()).collect();
+        self.m_instance_bvh.refit(&bounds);
+    }
+
+    // must be called after the last add and before rendering
+    pub fn build(&mut self) {
+        self.m_geometry.build();
+        let bounds: Vec<Aabb> = self.m_instances.iter().map(|i| i.get_bounds()).collect();
+        self.m_instance_bvh = Bvh::build(&bounds);
+
+        // materials are numbered in the order they are first met, so ids stay put between runs of the same scene
+        let mut addresses: Vec<(usize, usize)> = Vec::new();
+        {
+            let mut register = |material: &'a Material| {
+                let address = material_address(material);
+                if addresses.iter().all(|&(known, _)| known != address) {
+                    let id = addresses.len();
+                    addresses.push((address, id));
+                }
+            };
+            self.m_geometry.for_each_material(&mut register);
+            for instance in self.m_instances.iter() {
+                instance.geometry.for_each_material(&mut register);
+            }
+        }
+        addresses.sort_by_key(|&(address, _)| address);
+        self.m_material_addresses = addresses.iter().map(|&(address, _)| address).collect();
+        self.m_material_ids = addresses.iter().map(|&(_, id)| id).collect();
+    }
+
+    // primitives in the list come first, then one id per instance
+    pub fn object_id(&self, hit_record: &HitRecord) -> usize {
+        if hit_record.instance == usize::MAX {
+            return hit_record.index;
+        }
+        return self.m_geometry.primitive_count() + hit_record.instance;
+    }
+
+    // None for materials that were not in the list when it was built
+    pub fn material_id(&self, material: &Material) -> Option<usize> {
+        return match self.m_material_addresses.binary_search(&material_address(material)) {
+            Ok(index) => Some(self.m_material_ids[index]),
+            Err(_) => None,
+        };
+    }
+
     pub fn try_get_hit_record(
         &self,
         ray: &Ray,
+        shutter_time: f64,
         time_min: f64,
         time_max: f64,
         hit_record: &mut HitRecord
     ) -> bool {
-        let mut closest_hit_index: usize = usize::MAX;
-        let mut closest_time = time_max;
-        for index in 0..self.m_spheres.len() {
-            let sphere = &self.m_spheres[index];
-            let hit_time = sphere.get_hit_time(ray, time_min, closest_time);
-            if hit_time > time_min {
-                closest_time = hit_time;
-                closest_hit_index = index;
+        stats::count_closest_hit_ray();
+        return self.find_closest_hit(ray, shutter_time, time_min, time_max, hit_record);
+    }
+
+    // try_get_hit_record without being counted as a ray of its own
+    fn find_closest_hit(
+        &self,
+        ray: &Ray,
+        shutter_time: f64,
+        time_min: f64,
+        time_max: f64,
+        hit_record: &mut HitRecord
+    ) -> bool {
+        let (mut closest_hit_index, mut closest_time) = self.m_geometry.get_closest_hit(ray, shutter_time, time_min, time_max);
+        let mut closest_instance = usize::MAX;
+
+        let instances = &self.m_instances;
+        let mut instance_hit_index = usize::MAX;
+        let (instance_index, instance_time) = self.m_instance_bvh.traverse(ray, time_min, closest_time, |index, closest| {
+            let instance = &instances[index];
+            let transform = instance.transform.interpolate(shutter_time);
+            let object_ray = transform.inverse_transform_ray(ray);
+            let (hit_index, hit_time) = instance.geometry.get_closest_hit(&object_ray, shutter_time, time_min, closest);
+            if hit_index == usize::MAX {
+                return time_min;
             }
+            instance_hit_index = hit_index;
+            return hit_time;
+        });
+        // any hit reported by the closure is closer than the last, so instance_hit_index belongs to the winner
+        if instance_index != usize::MAX {
+            closest_hit_index = instance_hit_index;
+            closest_time = instance_time;
+            closest_instance = instance_index;
         }
+
         if closest_hit_index == usize::MAX {
             return false;
         }
         hit_record.time = closest_time;
+        hit_record.shutter_time = shutter_time;
         hit_record.index = closest_hit_index;
+        hit_record.instance = closest_instance;
         hit_record.ray = Ray::new(
             Vec3::new(ray.origin.x, ray.origin.y, ray.origin.z),
             Vec3::new(ray.dir.x, ray.dir.y, ray.dir.z)
@@ -93,24 +594,40 @@ impl<'a> RenderList<'a> {
         return true;
     }
 
-    pub fn get_material_package(
-        &self,
-        ray: &Ray,
-        hit_time: f64,
-        hit_index: usize
-    ) -> MaterialPackage<'a> {
-        let sphere = &self.m_spheres[hit_index];
+    pub fn get_material_package(&self, hit_record: &HitRecord) -> MaterialPackage<'a> {
+        let ray = &hit_record.ray;
+        let point = ray.point_at(hit_record.time);
+        let normal: Vec3;
+        let material: &'a Material;
+        if hit_record.instance == usize::MAX {
+            let (surface_normal, surface_material) = self.m_geometry.get_surface(
+                hit_record.index, ray, hit_record.time, hit_record.shutter_time
+            );
+            normal = surface_normal;
+            material = surface_material;
+        } else {
+            let instance = &self.m_instances[hit_record.instance];
+            let transform = instance.transform.interpolate(hit_record.shutter_time);
+            let object_ray = transform.inverse_transform_ray(ray);
+            let (object_normal, surface_material) = instance.geometry.get_surface(
+                hit_record.index, &object_ray, hit_record.time, hit_record.shutter_time
+            );
+            normal = transform.transform_normal(&object_normal).normalize();
+            material = surface_material;
+        }
+
         //@nicco: make the vec3 and ray classes implement the copy trait
         let origin = Vec3::new(ray.origin.x, ray.origin.y, ray.origin.z);
         let dir = Vec3::new(ray.dir.x, ray.dir.y, ray.dir.z);
         let material_input = MaterialInput {
-            point: ray.point_at(hit_time),
-            normal: &(&ray.point_at(hit_time) - &sphere.center) / sphere.radius,
+            point: point,
+            normal: normal,
             incoming_ray: Ray::new(origin, dir),
+            wavelength: 0f64,
         };
 
         let material_package = MaterialPackage {
-            material: self.m_sphere_materials[hit_index],
+            material: material,
             material_input: material_input,
         };
         return material_package;
@@ -120,6 +637,11 @@ impl<'a> RenderList<'a> {
 pub mod shapes {
     use rusty_math::*;
     use super::Renderable;
+    use super::Solid;
+    use super::HitInterval;
+    use super::first_boundary_time;
+    use bvh::Aabb;
+    use std::f64;
     //
     // Sphere
     //
@@ -152,6 +674,346 @@ pub mod shapes {
             }
             return time;
         }
+
+        fn get_normal(&self, point: &Vec3) -> Vec3 {
+            return &(point - &self.center) / self.radius;
+        }
+
+        fn get_bounds(&self) -> Aabb {
+            let r = Vec3::new(self.radius.abs(), self.radius.abs(), self.radius.abs());
+            return Aabb::new(&self.center - &r, &self.center + &r);
+        }
+    }
+
+    impl Solid for Sphere {
+        fn get_hit_intervals(&self, ray: &Ray, intervals: &mut Vec<HitInterval>) {
+            let offset_origin = &ray.origin - &self.center;
+            let a = ray.dir.dot(&ray.dir);
+            let b = 2f64 * offset_origin.dot(&ray.dir);
+            let c = offset_origin.dot(&offset_origin) - self.radius * self.radius;
+            let discriminant = b * b - 4f64 * a * c;
+            if discriminant <= 0f64 {
+                return;
+            }
+            let discriminant_root = discriminant.sqrt();
+            let enter_time = (-b - discriminant_root) / (2f64 * a);
+            let exit_time = (-b + discriminant_root) / (2f64 * a);
+            intervals.push(HitInterval {
+                enter_time: enter_time,
+                enter_normal: self.get_normal(&ray.point_at(enter_time)),
+                exit_time: exit_time,
+                exit_normal: self.get_normal(&ray.point_at(exit_time)),
+            });
+        }
+    }
+
+    //
+    // AxisBox
+    //
+    pub struct AxisBox {
+        pub min: Vec3,
+        pub max: Vec3,
+    }
+
+    fn axis_normal(axis: usize, sign: f64) -> Vec3 {
+        return match axis {
+            0 => Vec3::new(sign, 0f64, 0f64),
+            1 => Vec3::new(0f64, sign, 0f64),
+            _ => Vec3::new(0f64, 0f64, sign),
+        };
+    }
+
+    impl Renderable for AxisBox {
+        fn get_hit_time(&self, ray: &Ray, time_min: f64, time_max: f64) -> f64 {
+            let mut intervals = Vec::new();
+            self.get_hit_intervals(ray, &mut intervals);
+            return first_boundary_time(&intervals, time_min, time_max);
+        }
+
+        // picks the face the point is closest to
+        fn get_normal(&self, point: &Vec3) -> Vec3 {
+            let mut best_axis = 0;
+            let mut best_sign = 1f64;
+            let mut best_distance = f64::MAX;
+            for axis in 0..3 {
+                let (p, lo, hi) = match axis {
+                    0 => (point.x, self.min.x, self.max.x),
+                    1 => (point.y, self.min.y, self.max.y),
+                    _ => (point.z, self.min.z, self.max.z),
+                };
+                if (p - lo).abs() < best_distance {
+                    best_distance = (p - lo).abs();
+                    best_axis = axis;
+                    best_sign = -1f64;
+                }
+                if (hi - p).abs() < best_distance {
+                    best_distance = (hi - p).abs();
+                    best_axis = axis;
+                    best_sign = 1f64;
+                }
+            }
+            return axis_normal(best_axis, best_sign);
+        }
+
+        fn get_bounds(&self) -> Aabb {
+            return Aabb::new(
+                Vec3::new(self.min.x, self.min.y, self.min.z),
+                Vec3::new(self.max.x, self.max.y, self.max.z),
+            );
+        }
+    }
+
+    impl Solid for AxisBox {
+        fn get_hit_intervals(&self, ray: &Ray, intervals: &mut Vec<HitInterval>) {
+            let mut enter_time = -f64::MAX;
+            let mut exit_time = f64::MAX;
+            let mut enter_axis = 0;
+            let mut exit_axis = 0;
+            let mut enter_sign = -1f64;
+            let mut exit_sign = 1f64;
+            for axis in 0..3 {
+                let (origin, dir, lo, hi) = match axis {
+                    0 => (ray.origin.x, ray.dir.x, self.min.x, self.max.x),
+                    1 => (ray.origin.y, ray.dir.y, self.min.y, self.max.y),
+                    _ => (ray.origin.z, ray.dir.z, self.min.z, self.max.z),
+                };
+                if dir == 0f64 {
+                    if origin < lo || origin > hi {
+                        return;
+                    }
+                    continue;
+                }
+                let mut near = (lo - origin) / dir;
+                let mut far = (hi - origin) / dir;
+                // the face we enter through faces against the ray
+                let mut near_sign = -1f64;
+                if near > far {
+                    let tmp = near;
+                    near = far;
+                    far = tmp;
+                    near_sign = 1f64;
+                }
+                if near > enter_time {
+                    enter_time = near;
+                    enter_axis = axis;
+                    enter_sign = near_sign;
+                }
+                if far < exit_time {
+                    exit_time = far;
+                    exit_axis = axis;
+                    exit_sign = -near_sign;
+                }
+            }
+            if enter_time >= exit_time {
+                return;
+            }
+            intervals.push(HitInterval {
+                enter_time: enter_time,
+                enter_normal: axis_normal(enter_axis, enter_sign),
+                exit_time: exit_time,
+                exit_normal: axis_normal(exit_axis, exit_sign),
+            });
+        }
+    }
+
+    //
+    // Quadric
+    //
+    // a x^2 + b y^2 + c z^2 + d xy + e xz + f yz + g x + h y + i z + j = 0, inside where negative
+    pub struct Quadric {
+        pub coefficients: [f64; 10],
+    }
+
+    impl Quadric {
+        pub fn ellipsoid(center: &Vec3, radii: &Vec3) -> Quadric {
+            let (ix, iy, iz) = (1f64 / (radii.x * radii.x), 1f64 / (radii.y * radii.y), 1f64 / (radii.z * radii.z));
+            return Quadric {
+                coefficients: [
+                    ix, iy, iz,
+                    0f64, 0f64, 0f64,
+                    -2f64 * center.x * ix, -2f64 * center.y * iy, -2f64 * center.z * iz,
+                    center.x * center.x * ix + center.y * center.y * iy + center.z * center.z * iz - 1f64,
+                ],
+            };
+        }
+
+        // infinite cylinder along y through center
+        pub fn cylinder_y(center: &Vec3, radius: f64) -> Quadric {
+            return Quadric {
+                coefficients: [
+                    1f64, 0f64, 1f64,
+                    0f64, 0f64, 0f64,
+                    -2f64 * center.x, 0f64, -2f64 * center.z,
+                    center.x * center.x + center.z * center.z - radius * radius,
+                ],
+            };
+        }
+
+        // infinite double cone along y, radius grows by slope per unit of height from the apex
+        pub fn cone_y(apex: &Vec3, slope: f64) -> Quadric {
+            let s2 = slope * slope;
+            return Quadric {
+                coefficients: [
+                    1f64, -s2, 1f64,
+                    0f64, 0f64, 0f64,
+                    -2f64 * apex.x, 2f64 * s2 * apex.y, -2f64 * apex.z,
+                    apex.x * apex.x + apex.z * apex.z - s2 * apex.y * apex.y,
+                ],
+            };
+        }
+
+        pub fn evaluate(&self, p: &Vec3) -> f64 {
+            let k = &self.coefficients;
+            return k[0] * p.x * p.x + k[1] * p.y * p.y + k[2] * p.z * p.z
+                + k[3] * p.x * p.y + k[4] * p.x * p.z + k[5] * p.y * p.z
+                + k[6] * p.x + k[7] * p.y + k[8] * p.z + k[9];
+        }
+
+        fn gradient(&self, p: &Vec3) -> Vec3 {
+            let k = &self.coefficients;
+            return Vec3::new(
+                2f64 * k[0] * p.x + k[3] * p.y + k[4] * p.z + k[6],
+                2f64 * k[1] * p.y + k[3] * p.x + k[5] * p.z + k[7],
+                2f64 * k[2] * p.z + k[4] * p.x + k[5] * p.y + k[8],
+            );
+        }
+    }
+
+    impl Renderable for Quadric {
+        fn get_hit_time(&self, ray: &Ray, time_min: f64, time_max: f64) -> f64 {
+            let mut intervals = Vec::new();
+            self.get_hit_intervals(ray, &mut intervals);
+            return first_boundary_time(&intervals, time_min, time_max);
+        }
+
+        fn get_normal(&self, point: &Vec3) -> Vec3 {
+            return self.gradient(point).normalize();
+        }
+
+        // most quadrics are unbounded, clip them with a box in csg to get something finite
+        fn get_bounds(&self) -> Aabb {
+            let k = &self.coefficients;
+            let is_ellipsoid = k[0] > 0f64 && k[1] > 0f64 && k[2] > 0f64 && k[3] == 0f64 && k[4] == 0f64 && k[5] == 0f64;
+            if is_ellipsoid {
+                let center = Vec3::new(-k[6] / (2f64 * k[0]), -k[7] / (2f64 * k[1]), -k[8] / (2f64 * k[2]));
+                let scale = -self.evaluate(&center);
+                let radii = Vec3::new((scale / k[0]).sqrt(), (scale / k[1]).sqrt(), (scale / k[2]).sqrt());
+                return Aabb::new(&center - &radii, &center + &radii);
+            }
+            return Aabb::new(
+                Vec3::new(-f64::MAX, -f64::MAX, -f64::MAX),
+                Vec3::new(f64::MAX, f64::MAX, f64::MAX),
+            );
+        }
+    }
+
+    impl Solid for Quadric {
+        fn get_hit_intervals(&self, ray: &Ray, intervals: &mut Vec<HitInterval>) {
+            // substitute the ray and collect the polynomial in t
+            let k = &self.coefficients;
+            let (o, d) = (&ray.origin, &ray.dir);
+            let a = k[0] * d.x * d.x + k[1] * d.y * d.y + k[2] * d.z * d.z
+                + k[3] * d.x * d.y + k[4] * d.x * d.z + k[5] * d.y * d.z;
+            let b = 2f64 * (k[0] * o.x * d.x + k[1] * o.y * d.y + k[2] * o.z * d.z)
+                + k[3] * (o.x * d.y + o.y * d.x) + k[4] * (o.x * d.z + o.z * d.x) + k[5] * (o.y * d.z + o.z * d.y)
+                + k[6] * d.x + k[7] * d.y + k[8] * d.z;
+            let c = self.evaluate(o);
+
+            let mut roots: Vec<f64> = Vec::new();
+            if a.abs() < 1e-12f64 {
+                if b.abs() > 1e-12f64 {
+                    roots.push(-c / b);
+                }
+            } else {
+                let discriminant = b * b - 4f64 * a * c;
+                if discriminant > 0f64 {
+                    let discriminant_root = discriminant.sqrt();
+                    let r0 = (-b - discriminant_root) / (2f64 * a);
+                    let r1 = (-b + discriminant_root) / (2f64 * a);
+                    roots.push(r0.min(r1));
+                    roots.push(r0.max(r1));
+                }
+            }
+
+            // classify each piece of the line by sampling the polynomial inside it
+            let first_interval = intervals.len();
+            let inside_at = |t: f64| (a * t + b) * t + c < 0f64;
+            let mut boundaries = vec![-f64::MAX];
+            boundaries.extend(roots.iter());
+            boundaries.push(f64::MAX);
+            for piece in 0..(boundaries.len() - 1) {
+                let (start, end) = (boundaries[piece], boundaries[piece + 1]);
+                let sample = if start == -f64::MAX {
+                    if end == f64::MAX { 0f64 } else { end - 1f64 }
+                } else if end == f64::MAX {
+                    start + 1f64
+                } else {
+                    0.5f64 * (start + end)
+                };
+                if !inside_at(sample) {
+                    continue;
+                }
+                // touching a double root keeps us inside, glue the pieces back together
+                if intervals.len() > first_interval && intervals[intervals.len() - 1].exit_time == start {
+                    let last = intervals.len() - 1;
+                    intervals[last].exit_time = end;
+                    intervals[last].exit_normal = if end == f64::MAX { Vec3::new(0f64, 0f64, 0f64) } else { self.get_normal(&ray.point_at(end)) };
+                    continue;
+                }
+                let enter_normal = if start == -f64::MAX { Vec3::new(0f64, 0f64, 0f64) } else { self.get_normal(&ray.point_at(start)) };
+                let exit_normal = if end == f64::MAX { Vec3::new(0f64, 0f64, 0f64) } else { self.get_normal(&ray.point_at(end)) };
+                intervals.push(HitInterval {
+                    enter_time: start,
+                    enter_normal: enter_normal,
+                    exit_time: end,
+                    exit_normal: exit_normal,
+                });
+            }
+        }
+    }
+
+    //
+    // MovingSphere
+    //
+    // center is linearly interpolated between keyframes, held outside the first and last key
+    pub struct MovingSphere {
+        pub centers: Vec<Vec3>,
+        pub times: Vec<f64>, // ascending, parallel to centers
+        pub radius: f64,
+    }
+
+    impl MovingSphere {
+        pub fn center_at(&self, shutter_time: f64) -> Vec3 {
+            let last = self.centers.len() - 1;
+            if shutter_time <= self.times[0] {
+                let c = &self.centers[0];
+                return Vec3::new(c.x, c.y, c.z);
+            }
+            for key in 0..last {
+                if shutter_time < self.times[key + 1] {
+                    let t = (shutter_time - self.times[key]) / (self.times[key + 1] - self.times[key]);
+                    return &self.centers[key] + &(t * &(&self.centers[key + 1] - &self.centers[key]));
+                }
+            }
+            let c = &self.centers[last];
+            return Vec3::new(c.x, c.y, c.z);
+        }
+
+        pub fn sphere_at(&self, shutter_time: f64) -> Sphere {
+            return Sphere {
+                center: self.center_at(shutter_time),
+                radius: self.radius,
+            };
+        }
+
+        // piecewise linear motion stays inside the hull of its keys
+        pub fn get_motion_bounds(&self) -> Aabb {
+            let mut bounds = Aabb::empty();
+            for key in 0..self.centers.len() {
+                bounds = bounds.union(&self.sphere_at(self.times[key]).get_bounds());
+            }
+            return bounds;
+        }
     }
 }
 
@@ -159,11 +1021,15 @@ pub struct MaterialInput {
     pub incoming_ray: Ray,
     pub point: Vec3,
     pub normal: Vec3,
+    // hero wavelength in nanometers when rendering spectrally, 0 when rendering in rgb
+    pub wavelength: f64,
 }
 
 pub struct MaterialOutput {
     pub attenuation: Vec3,
     pub scattered: Ray,
+    // the scattered direction only holds for the hero wavelength
+    pub wavelength_dependent: bool,
 }
 
 impl MaterialOutput {
@@ -171,6 +1037,7 @@ impl MaterialOutput {
         return MaterialOutput {
             attenuation: Vec3::new(0f64, 0f64, 0f64),
             scattered: Ray::new(Vec3::new(0f64, 0f64, 0f64), Vec3::new(0f64, 0f64, 0f64)),
+            wavelength_dependent: false,
        };
     }
 }
@@ -180,17 +1047,38 @@ pub struct MaterialPackage<'a> {
     pub material_input: MaterialInput,
 }
 
-pub trait Material {
+pub trait Material: Sync {
     fn apply(&self, input: &MaterialInput, output: &mut MaterialOutput) -> bool;
+
+    // materials that can only be sampled (mirrors, glass) are specular, the rest can be connected to
+    fn is_specular(&self) -> bool {
+        return true;
+    }
+
+    // bsdf value for scattering toward dir, without the cosine
+    fn eval(&self, _input: &MaterialInput, _dir: &Vec3) -> Vec3 {
+        return Vec3::new(0f64, 0f64, 0f64);
+    }
+
+    // solid angle density of apply picking dir
+    fn scattering_pdf(&self, _input: &MaterialInput, _dir: &Vec3) -> f64 {
+        return 0f64;
+    }
+
+    // radiance leaving the surface back along the incoming ray
+    fn emitted(&self, _input: &MaterialInput) -> Vec3 {
+        return Vec3::new(0f64, 0f64, 0f64);
+    }
 }
 
 pub mod materials {
-    extern crate rand;
     use rusty_math::*;
     use super::Material;
     use super::MaterialInput;
     use super::MaterialOutput;
-    use rand::Rng;
+    use sampler;
+    use stats;
+    use std::f64::consts;
 
     pub struct Lambertian {
         pub albedo: Vec3,
@@ -198,7 +1086,9 @@ pub mod materials {
 
     impl Material for Lambertian {
         fn apply(&self, input: &MaterialInput, output: &mut MaterialOutput) -> bool {
-            let target = &(&input.point + &input.normal) + &super::random_in_unit_sphere();
+            stats::count_material("lambertian");
+            // cosine distributed, so the attenuation is just the albedo
+            let target = &(&input.point + &input.normal) + &super::random_unit_vector();
             let dir = &target - &input.point;
 
             output.scattered = Ray::new(
@@ -209,6 +1099,46 @@ pub mod materials {
 
             return true;
         }
+
+        fn is_specular(&self) -> bool {
+            return false;
+        }
+
+        fn eval(&self, input: &MaterialInput, dir: &Vec3) -> Vec3 {
+            if dir.dot(&input.normal) <= 0f64 {
+                return Vec3::new(0f64, 0f64, 0f64);
+            }
+            return &self.albedo / consts::PI;
+        }
+
+        fn scattering_pdf(&self, input: &MaterialInput, dir: &Vec3) -> f64 {
+            let cosine = dir.normalize().dot(&input.normal);
+            return cosine.max(0f64) / consts::PI;
+        }
+    }
+
+    // emits on the outside and absorbs everything, register it with RenderList::add_light so it can be sampled
+    pub struct DiffuseLight {
+        pub emission: Vec3,
+    }
+
+    impl Material for DiffuseLight {
+        fn apply(&self, _input: &MaterialInput, _output: &mut MaterialOutput) -> bool {
+            stats::count_material("diffuse light");
+            return false;
+        }
+
+        // not a mirror, paths may end here
+        fn is_specular(&self) -> bool {
+            return false;
+        }
+
+        fn emitted(&self, input: &MaterialInput) -> Vec3 {
+            if input.incoming_ray.dir.dot(&input.normal) >= 0f64 {
+                return Vec3::new(0f64, 0f64, 0f64);
+            }
+            return Vec3::new(self.emission.x, self.emission.y, self.emission.z);
+        }
     }
 
     pub struct Metal {
@@ -218,6 +1148,7 @@ pub mod materials {
 
     impl Material for Metal {
         fn apply(&self, input: &MaterialInput, output: &mut MaterialOutput) -> bool {
+            stats::count_material("metal");
             // get reflected
             let unit_dir = &(input.incoming_ray.dir.normalize());
             let surface_normal = &input.normal;
@@ -234,8 +1165,73 @@ pub mod materials {
         }
     }
 
+    // index of refraction as a function of wavelength, coefficients take wavelengths in micrometers
+    pub enum Dispersion {
+        None,
+        // n = a + b / lambda^2
+        Cauchy { a: f64, b: f64 },
+        // n^2 = 1 + sum of b_i lambda^2 / (lambda^2 - c_i)
+        Sellmeier { b: [f64; 3], c: [f64; 3] },
+    }
+
+    impl Dispersion {
+        pub fn index_at(&self, lambda_nm: f64) -> Option<f64> {
+            let l = lambda_nm * 1e-3f64;
+            let l2 = l * l;
+            return match *self {
+                Dispersion::None => None,
+                Dispersion::Cauchy { a, b } => Some(a + b / l2),
+                Dispersion::Sellmeier { b, c } => {
+                    let mut n2 = 1f64;
+                    for i in 0..3 {
+                        n2 += b[i] * l2 / (l2 - c[i]);
+                    }
+                    Some(n2.max(1f64).sqrt())
+                }
+            };
+        }
+    }
+
+    // refraction_index is used when rendering in rgb or when there is no dispersion
     pub struct Dielectric {
         pub refraction_index: f64,
+        pub dispersion: Dispersion,
+    }
+
+    impl Dielectric {
+        pub fn new(refraction_index: f64) -> Dielectric {
+            return Dielectric { refraction_index: refraction_index, dispersion: Dispersion::None };
+        }
+
+        // typical crown glass, bk7
+        pub fn crown_glass() -> Dielectric {
+            return Dielectric {
+                refraction_index: 1.5168f64,
+                dispersion: Dispersion::Sellmeier {
+                    b: [1.03961212f64, 0.231792344f64, 1.01046945f64],
+                    c: [0.00600069867f64, 0.0200179144f64, 103.560653f64],
+                },
+            };
+        }
+
+        // water at room temperature, disperses too little to need more than cauchy's two terms
+        pub fn water() -> Dielectric {
+            return Dielectric {
+                refraction_index: 1.333f64,
+                dispersion: Dispersion::Cauchy { a: 1.3199f64, b: 0.00653f64 },
+            };
+        }
+
+        // dense flint glass, sf11, disperses a lot
+        pub fn flint_glass() -> Dielectric {
+            return Dielectric {
+                refraction_index: 1.78472f64,
+                dispersion: Dispersion::Sellmeier {
+                    b: [1.73759695f64, 0.313747346f64, 1.89878101f64],
+                    c: [0.013188707f64, 0.0623068142f64, 155.23629f64],
+                },
+            };
+        }
     }
 
     fn schlick(cosine: f64, refraction_index: f64) -> f64 {
@@ -246,8 +1242,17 @@ pub mod materials {
 
     impl Material for Dielectric {
         fn apply(&self, input: &MaterialInput, output: &mut MaterialOutput) -> bool {
+            stats::count_material("dielectric");
             let outward_normal: Vec3;
 
+            let mut refraction_index = self.refraction_index;
+            if input.wavelength > 0f64 {
+                if let Some(n) = self.dispersion.index_at(input.wavelength) {
+                    refraction_index = n;
+                    output.wavelength_dependent = true;
+                }
+            }
+
             // get reflected
             let dir = &(input.incoming_ray.dir);
             let dir_normalize = dir.normalize();
@@ -266,11 +1271,11 @@ pub mod materials {
 
             if dir_dot_normal > 0f64 {
                 outward_normal = -1f64 * surface_normal;
-                ni_over_nt = self.refraction_index;
-                cosine = self.refraction_index * dir_dot_normal / dir_length;
+                ni_over_nt = refraction_index;
+                cosine = refraction_index * dir_dot_normal / dir_length;
             } else {
                 outward_normal = Vec3::new(surface_normal.x, surface_normal.y, surface_normal.z);
-                ni_over_nt = 1.0f64 / self.refraction_index;
+                ni_over_nt = 1.0f64 / refraction_index;
                 cosine = -dir_dot_normal / dir_length;
             }
 
@@ -285,14 +1290,14 @@ pub mod materials {
                 // fuck this syntax
                 refracted = &(ni_over_nt * &(&dir_normalize - &(dt * &outward_normal))) -
                             &(&outward_normal * discriminant.sqrt());
-                reflect_chance = schlick(cosine, self.refraction_index);
+                reflect_chance = schlick(cosine, refraction_index);
             } else {
                 // we reflect
                 reflect_chance = 1f64;
             }
 
             // roll to see if we reflect
-            let roll = rand::thread_rng().gen_range(0f64, 1f64);
+            let roll = sampler::next_f64();
             if roll < reflect_chance {
                 output.scattered = Ray::new(hit_point, reflected);
             } else {
@@ -302,3 +1307,69 @@ pub mod materials {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::materials::*;
+    use super::shapes::*;
+    use transform::Transform;
+
+    #[test]
+    fn dispersion_matches_the_catalogue_and_bends_blue_the_most() {
+        // the d line, where catalogues quote the index
+        let d_line = 587.56f64;
+        for glass in [Dielectric::crown_glass(), Dielectric::flint_glass(), Dielectric::water()].iter() {
+            let n = glass.dispersion.index_at(d_line).unwrap();
+            assert!((n - glass.refraction_index).abs() < 0.01f64, "{} vs {}", n, glass.refraction_index);
+            assert!(glass.dispersion.index_at(450f64).unwrap() > glass.dispersion.index_at(650f64).unwrap());
+        }
+        assert!(Dielectric::new(1.5f64).dispersion.index_at(d_line).is_none());
+    }
+
+    #[test]
+    fn instances_are_hit_where_their_transform_puts_them() {
+        let material = Lambertian { albedo: Vec3::new(0.5f64, 0.5f64, 0.5f64) };
+        let mut geometry = Geometry::new();
+        geometry.add_sphere(&Sphere { center: Vec3::new(0f64, 0f64, 50f64), radius: 1f64 }, &material);
+        geometry.add_sphere(&Sphere { center: Vec3::new(0f64, 0f64, 0f64), radius: 1f64 }, &material);
+        geometry.build();
+        // the unit sphere stretched to 2 along x, turned a quarter about z so the long axis lies along y, then
+        // moved to 5, 0, 0: the ellipsoid (x - 5)^2 + y^2 / 4 + z^2 = 1
+        let transform = Transform::scale(&Vec3::new(2f64, 1f64, 1f64))
+            .then(&Transform::rotate(&Vec3::new(0f64, 0f64, 1f64), 90f64))
+            .then(&Transform::translate(&Vec3::new(5f64, 0f64, 0f64)));
+        let far_sphere = Sphere { center: Vec3::new(-20f64, 20f64, 0f64), radius: 1f64 };
+        let mut world = RenderList::new();
+        world.add_sphere(&far_sphere, &material);
+        world.add_instance(&geometry, transform);
+        world.build();
+
+        // a point on the ellipsoid where its normal (x - 5, y / 4, 0) leans away from every axis
+        let angle = -std::f64::consts::FRAC_PI_4;
+        let point = Vec3::new(5f64 + angle.cos(), 2f64 * angle.sin(), 0f64);
+        let normal = Vec3::new(angle.cos(), 2f64 * angle.sin() / 4f64, 0f64).normalize();
+        // straight down the normal from outside, the ray meets the ellipsoid at the point first
+        let origin = &point + &(10f64 * &normal);
+        let dir = &Vec3::new(0f64, 0f64, 0f64) - &normal;
+        let mut hit_record = HitRecord::new();
+        assert!(world.try_get_hit_record(&Ray::new(origin, dir), 0f64, 0.001f64, 1000f64, &mut hit_record));
+        // the object space ray is not normalized, so the time along it is the world's
+        assert!((hit_record.time - 10f64).abs() < 1e-9f64);
+        assert_eq!((hit_record.instance, hit_record.index), (0, 1));
+        assert_eq!(world.object_id(&hit_record), 1);
+
+        let package = world.get_material_package(&hit_record);
+        assert!((&package.material_input.point - &point).length_squared() < 1e-18f64);
+        assert!((&package.material_input.normal - &normal).length_squared() < 1e-18f64);
+
+        // the sphere in the list itself, behind nothing
+        let mut hit_record = HitRecord::new();
+        let ray = Ray::new(Vec3::new(-20f64, 0f64, 0f64), Vec3::new(0f64, 1f64, 0f64));
+        assert!(world.try_get_hit_record(&ray, 0f64, 0.001f64, 1000f64, &mut hit_record));
+        assert_eq!((hit_record.instance, hit_record.index), (usize::MAX, 0));
+        assert!((hit_record.time - 19f64).abs() < 1e-9f64);
+        let package = world.get_material_package(&hit_record);
+        assert!((&package.material_input.normal - &Vec3::new(0f64, -1f64, 0f64)).length_squared() < 1e-18f64);
+    }
+}
@@ -1,5 +1,4 @@
 extern crate rusty_math;
-extern crate rand;
 
 use rusty_math::*;
 
@@ -8,76 +7,757 @@ use renderable::RenderList;
 use renderable::MaterialOutput;
 use camera::Camera;
 use render_buffer::RenderBufferI32;
-use rand::Rng;
+use render_buffer::RenderBufferF64;
+use render_buffer::RenderBufferLayers;
+use aov::Aov;
+use aov::AovSample;
+use aov::AovTarget;
+use bdpt::BdptIntegrator;
+use sppm::SppmIntegrator;
+use mlt::MltIntegrator;
+use exposure::Exposure;
+use filter::Filter;
+use filter::FilmTile;
+use preview::Preview;
+use media;
+use spectrum;
+use spectrum::Wavelengths;
+use sampler;
+use stats;
 use std::f64;
+use std::thread;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+
+// bounces that are always followed before russian roulette may end a path
+static RUSSIAN_ROULETTE_MIN_DEPTH: i32 = 3;
+
+// width and height in pixels of the squares of the image threads render at a time
+static TILE_SIZE: usize = 32;
 
 pub struct RenderPackage<'a> {
     pub render_list: &'a RenderList<'a>,
     pub camera: &'a Camera,
     pub output_buffer: &'a mut RenderBufferI32,
+    // receives the linear beauty and the aovs asked for in the render settings
+    pub aov_buffer: Option<&'a mut RenderBufferLayers>,
+    // shown tiles as they finish and the final image
+    pub preview: Option<&'a Preview>,
+}
+
+#[derive(Clone, Copy)]
+pub enum IntegratorKind {
+    // unidirectional, handles media and volumes
+    Path,
+    // bidirectional with light tracing, surfaces only
+    Bidirectional,
+    // stochastic progressive photon mapping, one iteration per sample, surfaces only
+    ProgressivePhotonMapping,
+    // primary sample space metropolis over the path tracer, samples per pixel count mutations
+    Metropolis,
+}
+
+impl IntegratorKind {
+    pub fn from_name(name: &str) -> Option<IntegratorKind> {
+        return match name {
+            "path" => Some(IntegratorKind::Path),
+            "bdpt" => Some(IntegratorKind::Bidirectional),
+            "sppm" => Some(IntegratorKind::ProgressivePhotonMapping),
+            "mlt" => Some(IntegratorKind::Metropolis),
+            _ => None,
+        };
+    }
 }
 
 pub struct RenderSettings {
     pub num_samples_per_pixel:i32,
+    // trace three wavelengths per path instead of rgb, needed for dispersion
+    pub spectral: bool,
+    pub integrator: IntegratorKind,
+    // bounces after which a path is ended, russian roulette usually gets there first
+    pub max_depth: i32,
+    // extra layers written to the render package's aov buffer
+    pub aovs: Vec<Aov>,
+    // scales the film before tone mapping, None leaves it as it is
+    pub exposure: Option<Exposure>,
+    // how camera samples are spread over the pixels around them
+    pub filter: Filter,
+}
+
+// a strategy for estimating the light arriving along camera rays
+// Sync so render threads can share one
+pub trait Integrator: Sync {
+    // estimate for one camera ray, in path space (rgb, or the lanes of wavelengths when rendering spectrally)
+    // contributions that land on other pixels get splatted into film as rgb, scaled like a single sample
+    fn radiance(
+        &self,
+        ray: &Ray,
+        shutter_time: f64,
+        wavelengths: Option<Wavelengths>,
+        render_list: &RenderList,
+        camera: &Camera,
+        film: &mut RenderBufferF64
+    ) -> Vec3;
+
+    // radiance, also filling in whatever aovs the integrator can tell apart. by default just the first surface
+    fn radiance_with_aovs(
+        &self,
+        ray: &Ray,
+        shutter_time: f64,
+        wavelengths: Option<Wavelengths>,
+        render_list: &RenderList,
+        camera: &Camera,
+        film: &mut RenderBufferF64,
+        aovs: &mut AovSample
+    ) -> Vec3 {
+        aovs.record_first_hit(ray, shutter_time, render_list);
+        return self.radiance(ray, shutter_time, wavelengths, render_list, camera, film);
+    }
+}
+
+pub struct PathIntegrator {
+    pub max_depth: i32,
+}
+
+impl Integrator for PathIntegrator {
+    fn radiance(
+        &self,
+        ray: &Ray,
+        shutter_time: f64,
+        wavelengths: Option<Wavelengths>,
+        render_list: &RenderList,
+        _camera: &Camera,
+        _film: &mut RenderBufferF64
+    ) -> Vec3 {
+        return color(ray, shutter_time, wavelengths, render_list, self.max_depth);
+    }
+
+    fn radiance_with_aovs(
+        &self,
+        ray: &Ray,
+        shutter_time: f64,
+        wavelengths: Option<Wavelengths>,
+        render_list: &RenderList,
+        _camera: &Camera,
+        _film: &mut RenderBufferF64,
+        aovs: &mut AovSample
+    ) -> Vec3 {
+        return trace(ray, shutter_time, wavelengths, render_list, self.max_depth, Some(aovs));
+    }
 }
 
-pub fn render(render_package: &mut RenderPackage, render_settings: &RenderSettings) {
+// returns the exposure scale the film was tone mapped with, other linear layers want it for tone_map_layer
+pub fn render(render_package: &mut RenderPackage, render_settings: &RenderSettings) -> f64 {
     let num_pixels_y = render_package.output_buffer.height;
     let num_pixels_x = render_package.output_buffer.width;
 
     let camera = render_package.camera;
+    let render_list = render_package.render_list;
+    let preview = render_package.preview;
+    if let Some(preview) = preview {
+        preview.begin(num_pixels_x, num_pixels_y, render_settings.num_samples_per_pixel);
+    }
 
-    for y in (0..num_pixels_y).rev() {
-        for x in 0..num_pixels_x {
-            let mut c = Vec3::new(0f64, 0f64, 0f64);
-            for s in 0..render_settings.num_samples_per_pixel {
-                let rand_offset_x = rand::thread_rng().gen_range(0f64,1f64);
-                let rand_offset_y = rand::thread_rng().gen_range(0f64,1f64);
-
-                let u = (x as f64 + rand_offset_x) / (num_pixels_x as f64);
-                let v = (y as f64 + rand_offset_y) / (num_pixels_y as f64);
+    let mut aov_target = match render_package.aov_buffer {
+        Some(ref mut layers) => Some(AovTarget::new(&mut **layers, &render_settings.aovs)),
+        None => None,
+    };
 
-                let r = camera.get_ray(u, v);
-                c += &color(&r, &render_package.render_list, 0i32);
+    // accumulate everything first, light tracing can touch any pixel at any time
+    let mut film = RenderBufferF64::new(num_pixels_x, num_pixels_y);
+    match render_settings.integrator {
+        IntegratorKind::Path => {
+            let integrator = PathIntegrator { max_depth: render_settings.max_depth };
+            render_camera_samples(&integrator, render_list, camera, render_settings, &mut film, &mut aov_target, preview);
+        }
+        IntegratorKind::Bidirectional => {
+            let mut integrator = BdptIntegrator::new();
+            integrator.max_depth = render_settings.max_depth.max(0) as usize;
+            render_camera_samples(&integrator, render_list, camera, render_settings, &mut film, &mut aov_target, preview);
+        }
+        IntegratorKind::ProgressivePhotonMapping => {
+            let mut integrator = SppmIntegrator::new();
+            integrator.max_depth = render_settings.max_depth.max(0) as usize;
+            integrator.render(render_list, camera, render_settings, &mut film);
+            if let Some(ref mut target) = aov_target {
+                render_first_hit_aovs(render_list, camera, render_settings, target);
+            }
+        }
+        IntegratorKind::Metropolis => {
+            MltIntegrator::new().render(render_list, camera, render_settings, &mut film);
+            if let Some(ref mut target) = aov_target {
+                render_first_hit_aovs(render_list, camera, render_settings, target);
             }
+        }
+    }
+    if let Some(ref mut target) = aov_target {
+        target.resolve(&film, render_settings.num_samples_per_pixel);
+    }
+
+    let exposure_scale = develop(&film, camera, render_settings, render_package.output_buffer);
+    if let Some(preview) = preview {
+        preview.finish(render_package.output_buffer);
+    }
+    return exposure_scale;
+}
+
+// exposes and tone maps film, sums of num_samples_per_pixel samples, into output_buffer. returns the exposure scale
+pub fn develop(film: &RenderBufferF64, camera: &Camera, render_settings: &RenderSettings, output_buffer: &mut RenderBufferI32) -> f64 {
+    let exposure_scale = match render_settings.exposure {
+        Some(ref exposure) => exposure.scale(camera.f_number(), film, render_settings.num_samples_per_pixel),
+        None => 1f64,
+    };
+
+    for y in (0..film.height).rev() {
+        for x in 0..film.width {
+            let (r, g, b) = film.get(x, y);
+            let mut c = Vec3::new(r, g, b);
             c /= render_settings.num_samples_per_pixel as f64;
-            c.x = c.x.sqrt();
-            c.y = c.y.sqrt();
-            c.z = c.z.sqrt();
+            c *= exposure_scale;
+            push_tone_mapped(output_buffer, &c);
+        }
+    }
+    return exposure_scale;
+}
+
+// linear rgb layer (a denoised beauty, say) to the same 8 bit image render produces, scaled by the exposure render returned
+pub fn tone_map_layer(layers: &RenderBufferLayers, layer: usize, exposure_scale: f64) -> RenderBufferI32 {
+    let mut output_buffer = RenderBufferI32::new(layers.width, layers.height);
+    for y in (0..layers.height).rev() {
+        for x in 0..layers.width {
+            let values = layers.get(layer, x, y);
+            let c = &Vec3::new(values[0], values[1], values[2]) * exposure_scale;
+            push_tone_mapped(&mut output_buffer, &c);
+        }
+    }
+    return output_buffer;
+}
+
+fn push_tone_mapped(output_buffer: &mut RenderBufferI32, color: &Vec3) {
+    let (ir, ig, ib) = tone_map(color);
+    output_buffer.push_pixel(ir, ig, ib);
+}
+
+// clamped and gamma 2 encoded, 0 to 255
+fn tone_map(color: &Vec3) -> (i32, i32, i32) {
+    let mut c = Vec3::new(color.x, color.y, color.z);
+    // spectral estimates can dip below zero outside the srgb gamut, lights can go past white
+    c.x = c.x.max(0f64).min(1f64);
+    c.y = c.y.max(0f64).min(1f64);
+    c.z = c.z.max(0f64).min(1f64);
+    c.x = c.x.sqrt();
+    c.y = c.y.sqrt();
+    c.z = c.z.sqrt();
+
+    let ir = (255.99 * c.x) as i32;
+    let ig = (255.99 * c.y) as i32;
+    let ib = (255.99 * c.z) as i32;
+
+    return (ir, ig, ib);
+}
+
+// pixels x0..x1, y0..y1 of an image with samples camera samples each, a part of a render that can be done
+// on its own and merged with the others later
+#[derive(Clone, Copy)]
+pub struct RenderRegion {
+    pub x0: usize,
+    pub y0: usize,
+    pub x1: usize,
+    pub y1: usize,
+    pub samples: i32,
+}
+
+// only the integrators that work through camera samples pixel by pixel can render a region of a width by
+// height image. returns the filtered sums around the region, resolve them once every region is in, and
+// what the integrator splatted, which can be anywhere on the image
+pub fn render_region(
+    render_list: &RenderList,
+    camera: &Camera,
+    render_settings: &RenderSettings,
+    width: usize,
+    height: usize,
+    region: &RenderRegion
+) -> Option<(FilmTile, RenderBufferF64)> {
+    return match render_settings.integrator {
+        IntegratorKind::Path => {
+            let integrator = PathIntegrator { max_depth: render_settings.max_depth };
+            Some(render_tiles(&integrator, render_list, camera, render_settings, width, height, region, &mut None, None))
+        }
+        IntegratorKind::Bidirectional => {
+            let mut integrator = BdptIntegrator::new();
+            integrator.max_depth = render_settings.max_depth.max(0) as usize;
+            Some(render_tiles(&integrator, render_list, camera, render_settings, width, height, region, &mut None, None))
+        }
+        IntegratorKind::ProgressivePhotonMapping | IntegratorKind::Metropolis => None,
+    };
+}
+
+// sums num_samples_per_pixel estimates into every pixel of film
+fn render_camera_samples(
+    integrator: &Integrator,
+    render_list: &RenderList,
+    camera: &Camera,
+    render_settings: &RenderSettings,
+    film: &mut RenderBufferF64,
+    aov_target: &mut Option<AovTarget>,
+    preview: Option<&Preview>
+) {
+    let region = RenderRegion {
+        x0: 0,
+        y0: 0,
+        x1: film.width,
+        y1: film.height,
+        samples: render_settings.num_samples_per_pixel,
+    };
+    let (samples, splats) = render_tiles(
+        integrator, render_list, camera, render_settings, film.width, film.height, &region, aov_target, preview
+    );
+    for i in 0..film.buffer.len() {
+        film.buffer[i] += splats.buffer[i];
+    }
+    samples.resolve_into(film, render_settings.num_samples_per_pixel);
+}
 
-            let ir = (255.99 * c.x) as i32;
-            let ig = (255.99 * c.y) as i32;
-            let ib = (255.99 * c.z) as i32;
+// the region is cut into tiles that are handed out to threads as they finish the last one, each thread
+// gets a film of its own for what integrators splat. aovs, if any, go to aov_target
+fn render_tiles(
+    integrator: &Integrator,
+    render_list: &RenderList,
+    camera: &Camera,
+    render_settings: &RenderSettings,
+    width: usize,
+    height: usize,
+    region: &RenderRegion,
+    aov_target: &mut Option<AovTarget>,
+    preview: Option<&Preview>
+) -> (FilmTile, RenderBufferF64) {
+    let filter = &render_settings.filter;
+    let samples_per_pixel = region.samples;
 
-            render_package.output_buffer.push_pixel(ir, ig, ib);
+    // x0, y0, x1, y1
+    let mut tiles: Vec<(usize, usize, usize, usize)> = Vec::new();
+    // top rows first, like reading the image
+    for y0 in (region.y0..region.y1).step_by(TILE_SIZE).rev() {
+        for x0 in (region.x0..region.x1).step_by(TILE_SIZE) {
+            tiles.push((x0, y0, (x0 + TILE_SIZE).min(region.x1), (y0 + TILE_SIZE).min(region.y1)));
         }
     }
+    let threads = match thread::available_parallelism() {
+        Ok(count) => count.get(),
+        Err(_) => 1,
+    }.min(tiles.len()).max(1);
+    let with_aovs = aov_target.is_some();
+    let next_tile = AtomicUsize::new(0);
+    let (tiles, next_tile) = (&tiles, &next_tile);
+
+    // per thread, its splat film and the tiles it rendered with their aov layers
+    let results: Vec<(RenderBufferF64, Vec<(FilmTile, Option<RenderBufferLayers>, usize, usize)>)> = thread::scope(|scope| {
+        let handles: Vec<_> = (0..threads).map(|_| {
+            return scope.spawn(move || {
+                let mut thread_film = RenderBufferF64::new(width, height);
+                let mut rendered = Vec::new();
+                loop {
+                    let index = next_tile.fetch_add(1, Ordering::Relaxed);
+                    if index >= tiles.len() {
+                        break;
+                    }
+                    let (x0, y0, x1, y1) = tiles[index];
+                    let mut film_tile = FilmTile::around(x0, y0, x1, y1, filter, width, height);
+                    let mut aov_layers = if with_aovs { Some(RenderBufferLayers::new(x1 - x0, y1 - y0)) } else { None };
+                    {
+                        let mut tile_target = match aov_layers {
+                            Some(ref mut layers) => Some(AovTarget::new(layers, &render_settings.aovs)),
+                            None => None,
+                        };
+                        render_tile(
+                            integrator, render_list, camera, render_settings, tiles[index], samples_per_pixel,
+                            &mut film_tile, &mut thread_film, &mut tile_target
+                        );
+                    }
+                    if let Some(preview) = preview {
+                        preview_tile(preview, &film_tile, tiles[index], height, samples_per_pixel);
+                    }
+                    rendered.push((film_tile, aov_layers, x0, y0));
+                }
+                stats::flush();
+                return (thread_film, rendered);
+            });
+        }).collect();
+        return handles.into_iter().map(|handle| handle.join().unwrap()).collect();
+    });
+
+    // windows of neighbouring tiles overlap by the filter's reach, their weighted sums add up before resolving
+    let mut samples = FilmTile::around(region.x0, region.y0, region.x1, region.y1, filter, width, height);
+    let mut splats = RenderBufferF64::new(width, height);
+    for &(ref thread_film, ref rendered) in results.iter() {
+        for i in 0..splats.buffer.len() {
+            splats.buffer[i] += thread_film.buffer[i];
+        }
+        for &(ref film_tile, ref aov_layers, x0, y0) in rendered.iter() {
+            film_tile.merge_into(&mut samples);
+            if let (&mut Some(ref mut target), &Some(ref layers)) = (&mut *aov_target, aov_layers) {
+                target.add_tile(x0, y0, layers);
+            }
+        }
+    }
+    return (samples, splats);
 }
 
-// if we hit, construct a material input, and then create the output using the new input
-fn color(ray: &Ray, render_list: &RenderList, depth: i32) -> Vec3 {
-    // render the list
-    let mut hit_record = HitRecord::new();
-    if render_list.try_get_hit_record(ray, 0.001f64, f64::MAX, &mut hit_record) {
-        let material_package = render_list.get_material_package(&hit_record.ray, hit_record.time, hit_record.index);
-        let mut material_output = MaterialOutput::new();
-        if (depth < 50) && material_package.material.apply(&material_package.material_input, &mut material_output) {
-     		let c = color(&material_output.scattered, render_list, depth + 1);
-            return Vec3::new(
-                c.x * material_output.attenuation.x,
-                c.y * material_output.attenuation.y,
-                c.z * material_output.attenuation.z
-            );
+// the tile's pixels as they are before the neighbouring tiles add their share, without exposure.
+// num_samples went into every pixel of the tile
+pub fn preview_tile(preview: &Preview, film_tile: &FilmTile, tile: (usize, usize, usize, usize), image_height: usize, num_samples: i32) {
+    let (x0, y0, x1, y1) = tile;
+    let mut rgb: Vec<u8> = Vec::with_capacity((x1 - x0) * (y1 - y0) * 3);
+    for y in (y0..y1).rev() {
+        for x in x0..x1 {
+            let (r, g, b) = tone_map(&film_tile.average(x, y));
+            rgb.push(r as u8);
+            rgb.push(g as u8);
+            rgb.push(b as u8);
         }
-        else {
-            return Vec3::new(0f64, 0f64, 0f64);
+    }
+    let camera_rays = ((x1 - x0) * (y1 - y0)) as u64 * num_samples.max(0) as u64;
+    preview.tile_finished(x0, image_height - y1, x1 - x0, y1 - y0, &rgb, camera_rays);
+}
+
+// camera samples for the pixels of one tile go through the filter into film_tile, splats into film.
+// aovs are not filtered, each sample only counts towards its own pixel of the tile's layers
+fn render_tile(
+    integrator: &Integrator,
+    render_list: &RenderList,
+    camera: &Camera,
+    render_settings: &RenderSettings,
+    tile: (usize, usize, usize, usize),
+    samples_per_pixel: i32,
+    film_tile: &mut FilmTile,
+    film: &mut RenderBufferF64,
+    aov_target: &mut Option<AovTarget>
+) {
+    let num_pixels_y = film.height;
+    let num_pixels_x = film.width;
+    let (x0, y0, x1, y1) = tile;
+    for y in (y0..y1).rev() {
+        for x in x0..x1 {
+            for sample_index in 0..samples_per_pixel {
+                let image_x = x as f64 + sampler::next_f64();
+                let image_y = y as f64 + sampler::next_f64();
+
+                let u = image_x / (num_pixels_x as f64);
+                let v = image_y / (num_pixels_y as f64);
+
+                // vignetting scales what the lens lets through, light paths splatted to the film are not vignetted
+                let (r, weight) = camera.get_weighted_ray(u, v);
+                stats::count_camera_ray();
+                let shutter_time = camera.sample_shutter_time();
+                let c: Vec3;
+                if let Some(ref mut target) = *aov_target {
+                    let mut aovs = AovSample::new();
+                    if render_settings.spectral {
+                        let wavelengths = Wavelengths::sample(sampler::next_f64());
+                        let radiance = integrator.radiance_with_aovs(
+                            &r, shutter_time, Some(wavelengths), render_list, camera, film, &mut aovs
+                        );
+                        c = &spectrum::wavelengths_to_rgb(&radiance, &wavelengths) * weight;
+                        aovs.to_rgb(&wavelengths);
+                    } else {
+                        c = &integrator.radiance_with_aovs(&r, shutter_time, None, render_list, camera, film, &mut aovs) * weight;
+                    }
+                    aovs.beauty = Vec3::new(c.x, c.y, c.z);
+                    target.accumulate(x - x0, y - y0, &aovs, sample_index);
+                } else if render_settings.spectral {
+                    let wavelengths = Wavelengths::sample(sampler::next_f64());
+                    let radiance = integrator.radiance(&r, shutter_time, Some(wavelengths), render_list, camera, film);
+                    c = &spectrum::wavelengths_to_rgb(&radiance, &wavelengths) * weight;
+                } else {
+                    c = &integrator.radiance(&r, shutter_time, None, render_list, camera, film) * weight;
+                }
+                film_tile.add_sample(&render_settings.filter, image_x, image_y, &c);
+            }
         }
     }
+}
 
-    // else color the background with a gradient
+// for integrators that do not work pixel by pixel, the first surface aovs get their own camera samples
+fn render_first_hit_aovs(
+    render_list: &RenderList,
+    camera: &Camera,
+    render_settings: &RenderSettings,
+    aov_target: &mut AovTarget
+) {
+    let num_pixels_y = aov_target.height();
+    let num_pixels_x = aov_target.width();
+    for y in 0..num_pixels_y {
+        for x in 0..num_pixels_x {
+            for sample_index in 0..render_settings.num_samples_per_pixel {
+                let u = (x as f64 + sampler::next_f64()) / (num_pixels_x as f64);
+                let v = (y as f64 + sampler::next_f64()) / (num_pixels_y as f64);
+                let r = camera.get_ray(u, v);
+                stats::count_camera_ray();
+                let shutter_time = camera.sample_shutter_time();
+                let mut aovs = AovSample::new();
+                aovs.record_first_hit(&r, shutter_time, render_list);
+                aov_target.accumulate(x, y, &aovs, sample_index);
+            }
+        }
+    }
+}
+
+// the background, a gradient from white at the horizon to blue overhead
+pub fn sky_color(dir: &Vec3) -> Vec3 {
     let white = Vec3::new(1f64, 1f64, 1f64);
     let blue = Vec3::new(0.5f64, 0.7f64, 1.0f64);
-    let t = 0.5f64 * ray.dir.normalize().y + 1.0f64;
+    let t = 0.5f64 * dir.normalize().y + 1.0f64;
 
     return &((1.0f64 - t) * &white) + &(t * &blue);
 }
+
+// Vec3 is not Copy, and colors get multiplied channel by channel. shared by the integrators and cameras
+pub fn copy(v: &Vec3) -> Vec3 {
+    return Vec3::new(v.x, v.y, v.z);
+}
+
+pub fn mul(a: &Vec3, b: &Vec3) -> Vec3 {
+    return Vec3::new(a.x * b.x, a.y * b.y, a.z * b.z);
+}
+
+pub fn is_black(v: &Vec3) -> bool {
+    return v.x == 0f64 && v.y == 0f64 && v.z == 0f64;
+}
+
+// if we hit, construct a material input, and then create the output using the new input
+// shutter_time stays fixed along the whole path
+// with wavelengths set the three lanes of every color are spectral samples rather than rgb
+// the path is followed in a loop, throughput is what everything found further along gets multiplied by
+pub fn color(ray: &Ray, shutter_time: f64, wavelengths: Option<Wavelengths>, render_list: &RenderList, max_depth: i32) -> Vec3 {
+    return trace(ray, shutter_time, wavelengths, render_list, max_depth, None);
+}
+
+// color, optionally splitting the estimate into aovs along the way
+fn trace(
+    ray: &Ray,
+    shutter_time: f64,
+    wavelengths: Option<Wavelengths>,
+    render_list: &RenderList,
+    max_depth: i32,
+    mut aovs: Option<&mut AovSample>
+) -> Vec3 {
+    let mut result = Vec3::new(0f64, 0f64, 0f64);
+    let mut throughput = Vec3::new(1f64, 1f64, 1f64);
+    let mut current = Ray::new(
+        Vec3::new(ray.origin.x, ray.origin.y, ray.origin.z),
+        Vec3::new(ray.dir.x, ray.dir.y, ray.dir.z)
+    );
+    let mut path_wavelengths = wavelengths;
+    let mut depth = 0i32;
+
+    loop {
+        // render the list
+        let mut hit_record = HitRecord::new();
+        let is_hit = render_list.try_get_hit_record(&current, shutter_time, 0.001f64, f64::MAX, &mut hit_record);
+
+        let surface_time = if is_hit { hit_record.time } else { f64::MAX };
+
+        // heterogeneous volumes are delta tracked up to the surface, a collision takes the place of the surface
+        let mut volume_collision = None;
+        if render_list.has_volumes() {
+            volume_collision = render_list.sample_volume_collision(&current, 0.001f64, surface_time);
+        }
+
+        // homogeneous media between us and the surface (or the sky) get a chance to scatter first
+        if render_list.has_media() {
+            let time_max = match volume_collision {
+                Some(ref collision) => collision.time,
+                None => surface_time,
+            };
+            let segments = render_list.get_media_segments(&current, 0.001f64, time_max);
+            let medium_sample = media::sample_interaction(&segments, 0.001f64, time_max, path_wavelengths.as_ref());
+            throughput = mul(&throughput, &medium_sample.weight);
+            if let Some(scatter) = medium_sample.scatter {
+                if depth >= max_depth {
+                    break;
+                }
+                current = Ray::new(
+                    current.point_at(scatter.time),
+                    media::sample_henyey_greenstein(&current.dir, scatter.g)
+                );
+                depth += 1;
+                if !survives_roulette(&mut throughput, depth) {
+                    break;
+                }
+                continue;
+            }
+        }
+
+        if let Some(collision) = volume_collision {
+            let emission = spectrum::radiance_to_path(&collision.emission, path_wavelengths.as_ref());
+            let contribution = mul(&throughput, &emission);
+            if let Some(ref mut sample) = aovs {
+                if depth == 0 {
+                    sample.record_volume(collision.time * current.dir.length_squared().sqrt(), &collision.albedo);
+                }
+                sample.add_lighting(&contribution, depth);
+            }
+            result += &contribution;
+            if depth >= max_depth {
+                break;
+            }
+            let albedo = spectrum::reflectance_to_path(&collision.albedo, path_wavelengths.as_ref());
+            throughput = mul(&throughput, &albedo);
+            current = Ray::new(
+                current.point_at(collision.time),
+                media::sample_henyey_greenstein(&current.dir, collision.g)
+            );
+        }
+        else if is_hit {
+            let mut material_package = render_list.get_material_package(&hit_record);
+            if let Some(ref w) = path_wavelengths {
+                material_package.material_input.wavelength = w.hero();
+            }
+            let emitted = spectrum::radiance_to_path(
+                &material_package.material.emitted(&material_package.material_input), path_wavelengths.as_ref()
+            );
+            let contribution = mul(&throughput, &emitted);
+            result += &contribution;
+            let mut material_output = MaterialOutput::new();
+            let scattered = depth < max_depth
+                && material_package.material.apply(&material_package.material_input, &mut material_output);
+            if let Some(ref mut sample) = aovs {
+                if depth == 0 {
+                    sample.record_surface(&hit_record, &material_package, &material_output, scattered, render_list);
+                    sample.specular = material_package.material.is_specular();
+                }
+                sample.add_lighting(&contribution, depth);
+            }
+            if !scattered {
+                break;
+            }
+            let mut attenuation = spectrum::reflectance_to_path(&material_output.attenuation, path_wavelengths.as_ref());
+            if let Some(ref mut w) = path_wavelengths {
+                // the other wavelengths would have gone elsewhere, the hero carries the whole estimate from here on
+                if material_output.wavelength_dependent && !w.secondary_terminated {
+                    w.secondary_terminated = true;
+                    attenuation = Vec3::new(3f64 * attenuation.x, 0f64, 0f64);
+                }
+            }
+            throughput = mul(&throughput, &attenuation);
+            current = material_output.scattered;
+        }
+        else {
+            // else color the background with a gradient
+            let sky = spectrum::radiance_to_path(&render_list.sky_color(&current.dir), path_wavelengths.as_ref());
+            let contribution = mul(&throughput, &sky);
+            if let Some(ref mut sample) = aovs {
+                sample.add_lighting(&contribution, depth);
+            }
+            result += &contribution;
+            break;
+        }
+
+        depth += 1;
+        if !survives_roulette(&mut throughput, depth) {
+            break;
+        }
+    }
+
+    stats::count_path(depth as usize);
+    return result;
+}
+
+// past the first few bounces dim paths are ended at random, the survivors are boosted so the estimate stays unbiased
+fn survives_roulette(throughput: &mut Vec3, depth: i32) -> bool {
+    if depth < RUSSIAN_ROULETTE_MIN_DEPTH {
+        return true;
+    }
+    let survival = throughput.x.max(throughput.y).max(throughput.z).min(1f64);
+    if survival <= 0f64 {
+        return false;
+    }
+    if survival < 1f64 {
+        if sampler::next_f64() >= survival {
+            return false;
+        }
+        *throughput = &*throughput / survival;
+    }
+    return true;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use renderable::shapes::Sphere;
+    use renderable::materials::Metal;
+
+    #[test]
+    fn roulette_keeps_the_expected_throughput() {
+        let count = 200000;
+        let mut mean = 0f64;
+        let mut survivors = 0;
+        for _ in 0..count {
+            let mut throughput = Vec3::new(0.3f64, 0.1f64, 0.05f64);
+            if survives_roulette(&mut throughput, RUSSIAN_ROULETTE_MIN_DEPTH) {
+                survivors += 1;
+                mean += throughput.x / count as f64;
+            }
+        }
+        assert!((survivors as f64 / count as f64 - 0.3f64).abs() < 0.01f64);
+        assert!((mean - 0.3f64).abs() < 0.01f64);
+    }
+
+    #[test]
+    fn roulette_leaves_short_and_bright_paths_alone() {
+        for depth in 0..RUSSIAN_ROULETTE_MIN_DEPTH {
+            let mut throughput = Vec3::new(0.001f64, 0f64, 0f64);
+            assert!(survives_roulette(&mut throughput, depth));
+            assert_eq!(throughput.x, 0.001f64);
+        }
+        let mut throughput = Vec3::new(2f64, 0.5f64, 0f64);
+        assert!(survives_roulette(&mut throughput, 40));
+        assert_eq!(throughput.x, 2f64);
+        assert!(!survives_roulette(&mut Vec3::new(0f64, 0f64, 0f64), 40));
+    }
+
+    // a ray bouncing straight up and down between two facing mirrors until max_depth or the roulette ends it,
+    // returns the bounces it took
+    fn between_mirrors(albedo: f64, max_depth: i32) -> usize {
+        let mirror = Metal { albedo: Vec3::new(albedo, albedo, albedo), fuzziness: 0f64 };
+        let below = Sphere { center: Vec3::new(0f64, -1001f64, 0f64), radius: 1000f64 };
+        let above = Sphere { center: Vec3::new(0f64, 1001f64, 0f64), radius: 1000f64 };
+        let mut world = RenderList::new();
+        world.add_sphere(&below, &mirror);
+        world.add_sphere(&above, &mirror);
+        world.build();
+        let ray = Ray::new(Vec3::new(0f64, 0f64, 0f64), Vec3::new(0f64, 1f64, 0f64));
+        let before = stats::totals().path_lengths;
+        let trapped = color(&ray, 0f64, None, &world, max_depth);
+        assert_eq!((trapped.x, trapped.y, trapped.z), (0f64, 0f64, 0f64));
+        let after = stats::totals().path_lengths;
+        // the totals are shared with tests on other threads, look for the one path length that came up
+        return (0..after.len()).rev().find(|&n| after[n] > *before.get(n).unwrap_or(&0)).unwrap();
+    }
+
+    #[test]
+    fn endless_paths_neither_overflow_nor_run_forever() {
+        // a perfect mirror never loses enough to be rouletted, only max_depth stops it
+        assert_eq!(between_mirrors(1f64, 100000), 100000);
+        assert!(between_mirrors(0.5f64, i32::MAX) < 1000);
+    }
+
+    #[test]
+    fn max_depth_zero_only_sees_what_the_camera_ray_hits() {
+        let mirror = Metal { albedo: Vec3::new(1f64, 1f64, 1f64), fuzziness: 0f64 };
+        let ball = Sphere { center: Vec3::new(0f64, 0f64, -3f64), radius: 1f64 };
+        let mut world = RenderList::new();
+        world.add_sphere(&ball, &mirror);
+        world.build();
+        let up = Ray::new(Vec3::new(0f64, 0f64, 0f64), Vec3::new(0f64, 1f64, 0f64));
+        let sky = color(&up, 0f64, None, &world, 0);
+        let expected = sky_color(&up.dir);
+        assert_eq!((sky.x, sky.y, sky.z), (expected.x, expected.y, expected.z));
+        // the mirror would show the sky behind the camera one bounce later
+        let at_the_ball = Ray::new(Vec3::new(0f64, 0f64, 0f64), Vec3::new(0f64, 0f64, -1f64));
+        let unlit = color(&at_the_ball, 0f64, None, &world, 0);
+        assert_eq!((unlit.x, unlit.y, unlit.z), (0f64, 0f64, 0f64));
+        let reflected = color(&at_the_ball, 0f64, None, &world, 1);
+        assert!(reflected.x > 0f64);
+    }
+}
@@ -0,0 +1,255 @@
+extern crate rand;
+
+use rand::Rng;
+use rand::SeedableRng;
+use rand::XorShiftRng;
+use std::cell::RefCell;
+use std::f64::consts;
+
+//
+// random numbers for everything a light path depends on
+//
+// normally these come straight from thread_rng. metropolis light transport needs to replay and perturb
+// them, so it installs an MltSampler on the rendering thread and every draw comes from that instead
+thread_local!(static INSTALLED: RefCell<Option<MltSampler>> = RefCell::new(None));
+
+// uniform in [0, 1)
+pub fn next_f64() -> f64 {
+    let installed = INSTALLED.with(|installed| {
+        return match *installed.borrow_mut() {
+            Some(ref mut sampler) => Some(sampler.next()),
+            None => None,
+        };
+    });
+    return match installed {
+        Some(value) => value,
+        None => rand::thread_rng().gen_range(0f64, 1f64),
+    };
+}
+
+// uniform in [low, high)
+pub fn next_range(low: f64, high: f64) -> f64 {
+    return low + (high - low) * next_f64();
+}
+
+// uniform in 0..count
+pub fn next_index(count: usize) -> usize {
+    let index = (next_f64() * count as f64) as usize;
+    return index.min(count - 1);
+}
+
+// a generator that always draws the same numbers for the same seed, for things that have to come out
+// the same in every process, like a scene built from random numbers
+pub fn seeded_rng(seed: u32) -> XorShiftRng {
+    return XorShiftRng::from_seed(scramble_seed(seed));
+}
+
+// swaps the sampler for this thread, None goes back to thread_rng. returns whatever was installed
+pub fn install(sampler: Option<MltSampler>) -> Option<MltSampler> {
+    return INSTALLED.with(|installed| {
+        return ::std::mem::replace(&mut *installed.borrow_mut(), sampler);
+    });
+}
+
+// runs f on the installed sampler, None if there is none. f must not draw samples itself
+pub fn with_installed<R, F>(f: F) -> Option<R>
+    where F: FnOnce(&mut MltSampler) -> R
+{
+    return INSTALLED.with(|installed| {
+        return match *installed.borrow_mut() {
+            Some(ref mut sampler) => Some(f(sampler)),
+            None => None,
+        };
+    });
+}
+
+//
+// MltSampler
+//
+// primary sample space state for one markov chain, Kelemen et al. 2002 with pbrt's lazy mutation:
+// samples are only brought up to date when a path actually asks for them
+struct PrimarySample {
+    value: f64,
+    // iteration this sample was last changed at
+    last_modification: u64,
+    value_backup: f64,
+    modification_backup: u64,
+}
+
+pub struct MltSampler {
+    m_rng: XorShiftRng,
+    // standard deviation of small step perturbations
+    m_sigma: f64,
+    m_large_step_probability: f64,
+    m_samples: Vec<PrimarySample>,
+    m_iteration: u64,
+    m_large_step: bool,
+    m_last_large_step_iteration: u64,
+    m_sample_index: usize,
+}
+
+impl MltSampler {
+    // the same seed always produces the same first path, that is how chains get started from the bootstrap
+    pub fn new(seed: u32, sigma: f64, large_step_probability: f64) -> MltSampler {
+        return MltSampler {
+            m_rng: XorShiftRng::from_seed(scramble_seed(seed)),
+            m_sigma: sigma,
+            m_large_step_probability: large_step_probability,
+            m_samples: Vec::new(),
+            m_iteration: 0,
+            m_large_step: true,
+            m_last_large_step_iteration: 0,
+            m_sample_index: 0,
+        };
+    }
+
+    pub fn start_iteration(&mut self) {
+        self.m_iteration += 1;
+        self.m_large_step = self.m_rng.gen_range(0f64, 1f64) < self.m_large_step_probability;
+        self.m_sample_index = 0;
+    }
+
+    pub fn accept(&mut self) {
+        if self.m_large_step {
+            self.m_last_large_step_iteration = self.m_iteration;
+        }
+    }
+
+    pub fn reject(&mut self) {
+        for sample in self.m_samples.iter_mut() {
+            if sample.last_modification == self.m_iteration {
+                sample.value = sample.value_backup;
+                sample.last_modification = sample.modification_backup;
+            }
+        }
+        self.m_iteration -= 1;
+    }
+
+    fn next(&mut self) -> f64 {
+        let index = self.m_sample_index;
+        self.m_sample_index += 1;
+        if index >= self.m_samples.len() {
+            // a dimension no path used before, perturbing a made up value would leave it stuck near zero
+            // (and rejection sampling loops that keep asking for more would never finish)
+            let value = self.m_rng.gen_range(0f64, 1f64);
+            self.m_samples.push(PrimarySample {
+                value: value,
+                last_modification: self.m_iteration,
+                value_backup: value,
+                modification_backup: self.m_iteration,
+            });
+            return value;
+        }
+
+        // catch up on the last large step this sample missed
+        if self.m_samples[index].last_modification < self.m_last_large_step_iteration {
+            self.m_samples[index].value = self.m_rng.gen_range(0f64, 1f64);
+            self.m_samples[index].last_modification = self.m_last_large_step_iteration;
+        }
+
+        let mut value = self.m_samples[index].value;
+        {
+            let sample = &mut self.m_samples[index];
+            sample.value_backup = sample.value;
+            sample.modification_backup = sample.last_modification;
+        }
+        if self.m_large_step {
+            value = self.m_rng.gen_range(0f64, 1f64);
+        } else {
+            // every skipped small step adds up to one wider gaussian
+            let steps = (self.m_iteration - self.m_samples[index].last_modification) as f64;
+            let u1 = self.m_rng.gen_range(1e-12f64, 1f64);
+            let u2 = self.m_rng.gen_range(0f64, 1f64);
+            let normal = (-2f64 * u1.ln()).sqrt() * (2f64 * consts::PI * u2).cos();
+            value += normal * self.m_sigma * steps.sqrt();
+            value -= value.floor();
+            if value >= 1f64 {
+                value = 0f64;
+            }
+        }
+        self.m_samples[index].value = value;
+        self.m_samples[index].last_modification = self.m_iteration;
+        return value;
+    }
+}
+
+// xorshift state from neighbouring seeds stays correlated for a long time, so spread every seed over all
+// four words first. splitmix64, never gives an all zero state
+fn scramble_seed(seed: u32) -> [u32; 4] {
+    let mut state = seed as u64;
+    let mut words = [0u32; 4];
+    for i in 0..2 {
+        state = state.wrapping_add(0x9e3779b97f4a7c15u64);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9u64);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111ebu64);
+        z = z ^ (z >> 31);
+        words[2 * i] = z as u32;
+        words[2 * i + 1] = (z >> 32) as u32 | 1u32;
+    }
+    return words;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn draw(sampler: &mut MltSampler, count: usize) -> Vec<f64> {
+        return (0..count).map(|_| sampler.next()).collect();
+    }
+
+    #[test]
+    fn the_same_seed_draws_the_same_first_path() {
+        let first = draw(&mut MltSampler::new(42, 0.01f64, 0.3f64), 8);
+        assert_eq!(first, draw(&mut MltSampler::new(42, 0.01f64, 0.3f64), 8));
+        assert!(first != draw(&mut MltSampler::new(43, 0.01f64, 0.3f64), 8));
+        assert!(first.iter().all(|value| *value >= 0f64 && *value < 1f64));
+    }
+
+    #[test]
+    fn rejected_mutations_are_undone() {
+        for &large_step_probability in [0f64, 1f64].iter() {
+            let mut sampler = MltSampler::new(7, 0.1f64, large_step_probability);
+            let current = draw(&mut sampler, 6);
+
+            sampler.start_iteration();
+            let proposed = draw(&mut sampler, 6);
+            assert!(proposed != current);
+            sampler.reject();
+
+            // a next step that barely moves starts out from where the chain was before the rejection
+            sampler.m_sigma = 1e-12f64;
+            sampler.m_large_step_probability = 0f64;
+            sampler.start_iteration();
+            let replayed = draw(&mut sampler, 6);
+            for (a, b) in replayed.iter().zip(current.iter()) {
+                assert!((a - b).abs() < 1e-9f64, "{:?} != {:?}", replayed, current);
+            }
+        }
+    }
+
+    #[test]
+    fn accepted_mutations_stick() {
+        let mut sampler = MltSampler::new(11, 0.1f64, 0f64);
+        draw(&mut sampler, 4);
+        sampler.start_iteration();
+        let proposed = draw(&mut sampler, 4);
+        sampler.accept();
+        sampler.m_sigma = 1e-12f64;
+        sampler.start_iteration();
+        let next = draw(&mut sampler, 4);
+        for (a, b) in next.iter().zip(proposed.iter()) {
+            assert!((a - b).abs() < 1e-9f64);
+        }
+    }
+
+    #[test]
+    fn installed_samplers_take_over_this_thread() {
+        let expected = draw(&mut MltSampler::new(3, 0.01f64, 0.3f64), 3);
+        assert!(install(Some(MltSampler::new(3, 0.01f64, 0.3f64))).is_none());
+        let drawn: Vec<f64> = (0..3).map(|_| next_f64()).collect();
+        assert!(install(None).is_some());
+        assert_eq!(drawn, expected);
+        assert!(with_installed(|_| ()).is_none());
+    }
+}
@@ -0,0 +1,469 @@
+extern crate rusty_math;
+
+use rusty_math::*;
+use std::f64;
+
+use bvh::Aabb;
+use renderable::Renderable;
+
+// a signed distance field, negative inside
+pub trait Sdf: Sync {
+    fn distance(&self, p: &Vec3) -> f64;
+    // upper bound on how fast distance can change, sphere tracing divides each step by this
+    fn lipschitz(&self) -> f64 {
+        return 1f64;
+    }
+}
+
+fn length(v: &Vec3) -> f64 {
+    return v.length_squared().sqrt();
+}
+
+fn mix(a: f64, b: f64, t: f64) -> f64 {
+    return a * (1f64 - t) + b * t;
+}
+
+fn clamp(x: f64, lo: f64, hi: f64) -> f64 {
+    return x.max(lo).min(hi);
+}
+
+//
+// primitives, all centered on the origin, move them with Translate
+//
+pub struct Sphere {
+    pub radius: f64,
+}
+
+impl Sdf for Sphere {
+    fn distance(&self, p: &Vec3) -> f64 {
+        return length(p) - self.radius;
+    }
+}
+
+pub struct RoundBox {
+    pub half_extents: Vec3,
+    pub rounding: f64,
+}
+
+impl Sdf for RoundBox {
+    fn distance(&self, p: &Vec3) -> f64 {
+        let q = Vec3::new(
+            p.x.abs() - self.half_extents.x + self.rounding,
+            p.y.abs() - self.half_extents.y + self.rounding,
+            p.z.abs() - self.half_extents.z + self.rounding,
+        );
+        let outside = Vec3::new(q.x.max(0f64), q.y.max(0f64), q.z.max(0f64));
+        return length(&outside) + q.x.max(q.y.max(q.z)).min(0f64) - self.rounding;
+    }
+}
+
+// lies in the xz plane
+pub struct Torus {
+    pub major_radius: f64,
+    pub minor_radius: f64,
+}
+
+impl Sdf for Torus {
+    fn distance(&self, p: &Vec3) -> f64 {
+        let ring = (p.x * p.x + p.z * p.z).sqrt() - self.major_radius;
+        return (ring * ring + p.y * p.y).sqrt() - self.minor_radius;
+    }
+}
+
+// distance estimator for the power n mandelbulb, roughly unit sized
+pub struct Mandelbulb {
+    pub power: f64,
+    pub iterations: usize,
+}
+
+impl Sdf for Mandelbulb {
+    fn distance(&self, p: &Vec3) -> f64 {
+        let (mut x, mut y, mut z) = (p.x, p.y, p.z);
+        let mut dr = 1f64;
+        let mut r = 0f64;
+        for _ in 0..self.iterations {
+            r = (x * x + y * y + z * z).sqrt();
+            // escaped, or stuck on the origin where the angles are undefined
+            if r > 2f64 || r < 1e-12f64 {
+                break;
+            }
+            let theta = (z / r).acos() * self.power;
+            let phi = y.atan2(x) * self.power;
+            dr = r.powf(self.power - 1f64) * self.power * dr + 1f64;
+            let zr = r.powf(self.power);
+            x = zr * theta.sin() * phi.cos() + p.x;
+            y = zr * theta.sin() * phi.sin() + p.y;
+            z = zr * theta.cos() + p.z;
+        }
+        if r < 1e-12f64 {
+            return 0f64;
+        }
+        return 0.5f64 * r.ln() * r / dr;
+    }
+}
+
+//
+// combinators
+//
+pub struct Translate<S: Sdf> {
+    pub sdf: S,
+    pub offset: Vec3,
+}
+
+impl<S: Sdf> Sdf for Translate<S> {
+    fn distance(&self, p: &Vec3) -> f64 {
+        return self.sdf.distance(&(p - &self.offset));
+    }
+
+    fn lipschitz(&self) -> f64 {
+        return self.sdf.lipschitz();
+    }
+}
+
+pub struct Scale<S: Sdf> {
+    pub sdf: S,
+    pub factor: f64,
+}
+
+impl<S: Sdf> Sdf for Scale<S> {
+    fn distance(&self, p: &Vec3) -> f64 {
+        return self.sdf.distance(&(p / self.factor)) * self.factor;
+    }
+
+    fn lipschitz(&self) -> f64 {
+        return self.sdf.lipschitz();
+    }
+}
+
+pub struct Union<A: Sdf, B: Sdf> {
+    pub a: A,
+    pub b: B,
+}
+
+impl<A: Sdf, B: Sdf> Sdf for Union<A, B> {
+    fn distance(&self, p: &Vec3) -> f64 {
+        return self.a.distance(p).min(self.b.distance(p));
+    }
+
+    fn lipschitz(&self) -> f64 {
+        return self.a.lipschitz().max(self.b.lipschitz());
+    }
+}
+
+pub struct Intersection<A: Sdf, B: Sdf> {
+    pub a: A,
+    pub b: B,
+}
+
+impl<A: Sdf, B: Sdf> Sdf for Intersection<A, B> {
+    fn distance(&self, p: &Vec3) -> f64 {
+        return self.a.distance(p).max(self.b.distance(p));
+    }
+
+    fn lipschitz(&self) -> f64 {
+        return self.a.lipschitz().max(self.b.lipschitz());
+    }
+}
+
+// a minus b
+pub struct Difference<A: Sdf, B: Sdf> {
+    pub a: A,
+    pub b: B,
+}
+
+impl<A: Sdf, B: Sdf> Sdf for Difference<A, B> {
+    fn distance(&self, p: &Vec3) -> f64 {
+        return self.a.distance(p).max(-self.b.distance(p));
+    }
+
+    fn lipschitz(&self) -> f64 {
+        return self.a.lipschitz().max(self.b.lipschitz());
+    }
+}
+
+// polynomial smooth min, blend_radius is how far apart the surfaces start melting together
+pub struct SmoothUnion<A: Sdf, B: Sdf> {
+    pub a: A,
+    pub b: B,
+    pub blend_radius: f64,
+}
+
+impl<A: Sdf, B: Sdf> Sdf for SmoothUnion<A, B> {
+    fn distance(&self, p: &Vec3) -> f64 {
+        let da = self.a.distance(p);
+        let db = self.b.distance(p);
+        let h = clamp(0.5f64 + 0.5f64 * (db - da) / self.blend_radius, 0f64, 1f64);
+        return mix(db, da, h) - self.blend_radius * h * (1f64 - h);
+    }
+
+    fn lipschitz(&self) -> f64 {
+        return self.a.lipschitz().max(self.b.lipschitz());
+    }
+}
+
+// infinite repetition on a grid of cells, the child should fit inside one cell
+pub struct Repeat<S: Sdf> {
+    pub sdf: S,
+    pub period: Vec3,
+}
+
+fn repeat_axis(x: f64, period: f64) -> f64 {
+    if period <= 0f64 {
+        return x;
+    }
+    return x - period * (x / period).round();
+}
+
+impl<S: Sdf> Sdf for Repeat<S> {
+    fn distance(&self, p: &Vec3) -> f64 {
+        let q = Vec3::new(
+            repeat_axis(p.x, self.period.x),
+            repeat_axis(p.y, self.period.y),
+            repeat_axis(p.z, self.period.z),
+        );
+        return self.sdf.distance(&q);
+    }
+
+    fn lipschitz(&self) -> f64 {
+        return self.sdf.lipschitz();
+    }
+}
+
+// sine ripples on top of the surface
+pub struct Displace<S: Sdf> {
+    pub sdf: S,
+    pub amplitude: f64,
+    pub frequency: f64,
+}
+
+impl<S: Sdf> Sdf for Displace<S> {
+    fn distance(&self, p: &Vec3) -> f64 {
+        let f = self.frequency;
+        let ripple = (f * p.x).sin() * (f * p.y).sin() * (f * p.z).sin();
+        return self.sdf.distance(p) + self.amplitude * ripple;
+    }
+
+    // the gradient of the ripple is at most amplitude * frequency * sqrt(3)
+    fn lipschitz(&self) -> f64 {
+        return self.sdf.lipschitz() + self.amplitude.abs() * self.frequency.abs() * 3f64.sqrt();
+    }
+}
+
+// rotates the xz plane by rate radians per unit of y
+pub struct Twist<S: Sdf> {
+    pub sdf: S,
+    pub rate: f64,
+    // largest distance from the y axis we care about, bounds the stretch
+    pub max_radius: f64,
+}
+
+impl<S: Sdf> Sdf for Twist<S> {
+    fn distance(&self, p: &Vec3) -> f64 {
+        let angle = self.rate * p.y;
+        let (s, c) = (angle.sin(), angle.cos());
+        let q = Vec3::new(c * p.x - s * p.z, p.y, s * p.x + c * p.z);
+        return self.sdf.distance(&q);
+    }
+
+    fn lipschitz(&self) -> f64 {
+        let stretch = self.rate * self.max_radius;
+        return self.sdf.lipschitz() * (1f64 + stretch * stretch).sqrt();
+    }
+}
+
+//
+// SdfShape
+//
+// something sphere traceable that can live in a render list, the bounds clip the march
+pub struct SdfShape {
+    pub sdf: Box<Sdf>,
+    pub bounds: Aabb,
+    pub max_steps: usize,
+    pub epsilon: f64,
+}
+
+impl SdfShape {
+    pub fn new(sdf: Box<Sdf>, bounds: Aabb) -> SdfShape {
+        return SdfShape {
+            sdf: sdf,
+            bounds: bounds,
+            max_steps: 256,
+            epsilon: 1e-4f64,
+        };
+    }
+}
+
+impl Renderable for SdfShape {
+    fn get_hit_time(&self, ray: &Ray, time_min: f64, time_max: f64) -> f64 {
+        let (start, end) = match self.bounds.clip(ray, time_min, time_max) {
+            Some(range) => range,
+            None => return time_min,
+        };
+
+        let dir_length = length(&ray.dir);
+        let step_scale = 1f64 / (self.sdf.lipschitz() * dir_length);
+
+        // march on whichever side of the surface we start on, so refracted rays can find their way out
+        let mut time = start;
+        let start_distance = self.sdf.distance(&ray.point_at(time));
+        let side = if start_distance < 0f64 { -1f64 } else { 1f64 };
+        // rays leaving the surface have to get clear of it before a hit counts
+        let mut left_surface = side * start_distance > 2f64 * self.epsilon;
+        for _ in 0..self.max_steps {
+            let distance = side * self.sdf.distance(&ray.point_at(time));
+            if distance < self.epsilon {
+                if left_surface && time > time_min {
+                    return time;
+                }
+            } else if distance > 2f64 * self.epsilon {
+                left_surface = true;
+            }
+            time += distance.max(self.epsilon) * step_scale;
+            if time >= end {
+                break;
+            }
+        }
+        return time_min;
+    }
+
+    // central differences
+    fn get_normal(&self, point: &Vec3) -> Vec3 {
+        let h = self.epsilon;
+        let dx = Vec3::new(h, 0f64, 0f64);
+        let dy = Vec3::new(0f64, h, 0f64);
+        let dz = Vec3::new(0f64, 0f64, h);
+        let gradient = Vec3::new(
+            self.sdf.distance(&(point + &dx)) - self.sdf.distance(&(point - &dx)),
+            self.sdf.distance(&(point + &dy)) - self.sdf.distance(&(point - &dy)),
+            self.sdf.distance(&(point + &dz)) - self.sdf.distance(&(point - &dz)),
+        );
+        if gradient.length_squared() == 0f64 {
+            return Vec3::new(0f64, 1f64, 0f64);
+        }
+        return gradient.normalize();
+    }
+
+    fn get_bounds(&self) -> Aabb {
+        return self.bounds.clone();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // points scattered through the cube [-1.5, 1.5]^3
+    fn sample_points() -> Vec<Vec3> {
+        return (0..500).map(|i| {
+            let t = i as f64;
+            return Vec3::new(1.5f64 * (1.3f64 * t).sin(), 1.5f64 * (0.7f64 * t + 1f64).sin(), 1.5f64 * (2.9f64 * t + 2f64).sin());
+        }).collect();
+    }
+
+    // the steepest the field gets along any of the sample points, by finite differences
+    fn steepest_slope(sdf: &Sdf) -> f64 {
+        let h = 1e-5f64;
+        let mut steepest = 0f64;
+        for p in sample_points().iter() {
+            let value = sdf.distance(p);
+            let gradient = Vec3::new(
+                sdf.distance(&(p + &Vec3::new(h, 0f64, 0f64))) - value,
+                sdf.distance(&(p + &Vec3::new(0f64, h, 0f64))) - value,
+                sdf.distance(&(p + &Vec3::new(0f64, 0f64, h))) - value,
+            );
+            steepest = steepest.max(length(&gradient) / h);
+        }
+        return steepest;
+    }
+
+    #[test]
+    fn primitives_give_exact_distances() {
+        let sphere = Sphere { radius: 0.5f64 };
+        assert!((sphere.distance(&Vec3::new(0f64, 2f64, 0f64)) - 1.5f64).abs() < 1e-12f64);
+        assert!((sphere.distance(&Vec3::new(0f64, 0f64, 0f64)) + 0.5f64).abs() < 1e-12f64);
+        let torus = Torus { major_radius: 1f64, minor_radius: 0.25f64 };
+        assert!((torus.distance(&Vec3::new(0f64, 0f64, 0f64)) - 0.75f64).abs() < 1e-12f64);
+        assert!((torus.distance(&Vec3::new(0f64, 1f64, 1f64)) - 0.75f64).abs() < 1e-12f64);
+        let cube = RoundBox { half_extents: Vec3::new(1f64, 1f64, 1f64), rounding: 0f64 };
+        assert!((cube.distance(&Vec3::new(3f64, 0f64, 0f64)) - 2f64).abs() < 1e-12f64);
+        assert!((cube.distance(&Vec3::new(2f64, 2f64, 1f64)) - 2f64.sqrt()).abs() < 1e-12f64);
+        assert!((cube.distance(&Vec3::new(0.5f64, 0f64, 0f64)) + 0.5f64).abs() < 1e-12f64);
+    }
+
+    #[test]
+    fn combinators_keep_distances_exact() {
+        let scaled = Scale { sdf: Sphere { radius: 1f64 }, factor: 3f64 };
+        assert!((scaled.distance(&Vec3::new(5f64, 0f64, 0f64)) - 2f64).abs() < 1e-12f64);
+        let moved = Translate { sdf: Sphere { radius: 1f64 }, offset: Vec3::new(0f64, 0f64, 4f64) };
+        assert!((moved.distance(&Vec3::new(0f64, 0f64, 0f64)) - 3f64).abs() < 1e-12f64);
+
+        // two unit spheres two apart along x, seen from the origin between them
+        let left = || Translate { sdf: Sphere { radius: 1.5f64 }, offset: Vec3::new(-1f64, 0f64, 0f64) };
+        let right = || Translate { sdf: Sphere { radius: 1.5f64 }, offset: Vec3::new(1f64, 0f64, 0f64) };
+        let far = Vec3::new(4f64, 0f64, 0f64);
+        assert!((Union { a: left(), b: right() }.distance(&far) - 1.5f64).abs() < 1e-12f64);
+        assert!((Intersection { a: left(), b: right() }.distance(&far) - 3.5f64).abs() < 1e-12f64);
+        // the origin is inside both, so it is outside what's left of the left one
+        assert!((Difference { a: left(), b: right() }.distance(&Vec3::new(0f64, 0f64, 0f64)) - 0.5f64).abs() < 1e-12f64);
+    }
+
+    #[test]
+    fn repetition_is_periodic_on_the_repeated_axes_only() {
+        let beads = Repeat { sdf: Sphere { radius: 0.25f64 }, period: Vec3::new(1f64, 0f64, 0f64) };
+        for p in sample_points().iter() {
+            let shifted = Vec3::new(p.x + 3f64, p.y, p.z);
+            assert!((beads.distance(p) - beads.distance(&shifted)).abs() < 1e-9f64);
+        }
+        assert!((beads.distance(&Vec3::new(7f64, 1f64, 0f64)) - 0.75f64).abs() < 1e-12f64);
+        // halfway between two cells is as far from both
+        assert!((beads.distance(&Vec3::new(2.5f64, 0f64, 0f64)) - 0.25f64).abs() < 1e-12f64);
+    }
+
+    #[test]
+    fn fields_never_change_faster_than_their_lipschitz_bound() {
+        let fields: Vec<Box<Sdf>> = vec![
+            Box::new(Twist { sdf: RoundBox { half_extents: Vec3::new(0.3f64, 1f64, 0.3f64), rounding: 0.05f64 }, rate: 3f64, max_radius: 2.6f64 }),
+            Box::new(Displace { sdf: Torus { major_radius: 0.8f64, minor_radius: 0.2f64 }, amplitude: 0.05f64, frequency: 20f64 }),
+            Box::new(SmoothUnion { a: Sphere { radius: 0.5f64 }, b: Torus { major_radius: 1f64, minor_radius: 0.2f64 }, blend_radius: 0.3f64 }),
+            Box::new(Scale { sdf: Repeat { sdf: Sphere { radius: 0.2f64 }, period: Vec3::new(0.5f64, 0.5f64, 0f64) }, factor: 2f64 }),
+        ];
+        for field in fields.iter() {
+            // a little slack for the finite differences
+            assert!(steepest_slope(&**field) <= field.lipschitz() * 1.001f64);
+        }
+        // twisting without a bound on the radius is steeper than a plain box
+        let unbounded = Twist { sdf: RoundBox { half_extents: Vec3::new(0.3f64, 1f64, 0.3f64), rounding: 0.05f64 }, rate: 3f64, max_radius: 0f64 };
+        assert!(steepest_slope(&unbounded) > unbounded.lipschitz());
+    }
+
+    #[test]
+    fn mandelbulb_estimate_never_overshoots() {
+        let bulb = Mandelbulb { power: 8f64, iterations: 8 };
+        // the bulb fits in a sphere of radius 1.2, so no estimate may reach further than the distance to it
+        for p in sample_points().iter() {
+            let estimate = bulb.distance(&(p * 2f64));
+            let to_bounds = length(&(p * 2f64)) - 1.2f64;
+            if to_bounds > 0f64 {
+                assert!(estimate > 0f64 && estimate <= length(&(p * 2f64)), "{} at {}", estimate, length(p));
+            }
+        }
+        // the center is inside
+        assert!(bulb.distance(&Vec3::new(0f64, 0f64, 0f64)) <= 0f64);
+    }
+
+    #[test]
+    fn sphere_tracing_lands_on_the_surface() {
+        let shape = SdfShape::new(
+            Box::new(Translate { sdf: Sphere { radius: 1f64 }, offset: Vec3::new(0f64, 0f64, -5f64) }),
+            Aabb::new(Vec3::new(-1.5f64, -1.5f64, -6.5f64), Vec3::new(1.5f64, 1.5f64, -3.5f64))
+        );
+        let ray = Ray::new(Vec3::new(0f64, 0f64, 0f64), Vec3::new(0f64, 0f64, -1f64));
+        let time = shape.get_hit_time(&ray, 1e-4f64, f64::MAX);
+        assert!((time - 4f64).abs() < 1e-3f64);
+        let normal = shape.get_normal(&ray.point_at(time));
+        assert!((normal.z - 1f64).abs() < 1e-3f64);
+        // and misses what it should
+        let past = Ray::new(Vec3::new(2f64, 0f64, 0f64), Vec3::new(0f64, 0f64, -1f64));
+        assert_eq!(shape.get_hit_time(&past, 1e-4f64, f64::MAX), 1e-4f64);
+    }
+}
@@ -0,0 +1,295 @@
+extern crate rusty_math;
+
+use rusty_math::*;
+
+// visible range we integrate over, in nanometers
+pub static LAMBDA_MIN: f64 = 380f64;
+pub static LAMBDA_MAX: f64 = 780f64;
+
+// piecewise gaussian used by the cie fits
+fn lobe(lambda: f64, mu: f64, sigma_low: f64, sigma_high: f64) -> f64 {
+    let sigma = if lambda < mu { sigma_low } else { sigma_high };
+    let t = (lambda - mu) / sigma;
+    return (-0.5f64 * t * t).exp();
+}
+
+// cie 1931 color matching functions, multi-lobe fit from Wyman, Sloan and Shirley 2013
+pub fn cie_xyz(lambda: f64) -> Vec3 {
+    let x = 1.056f64 * lobe(lambda, 599.8f64, 37.9f64, 31.0f64)
+        + 0.362f64 * lobe(lambda, 442.0f64, 16.0f64, 26.7f64)
+        - 0.065f64 * lobe(lambda, 501.1f64, 20.4f64, 26.2f64);
+    let y = 0.821f64 * lobe(lambda, 568.8f64, 46.9f64, 40.5f64)
+        + 0.286f64 * lobe(lambda, 530.9f64, 16.3f64, 31.1f64);
+    let z = 1.217f64 * lobe(lambda, 437.0f64, 11.8f64, 36.0f64)
+        + 0.681f64 * lobe(lambda, 459.0f64, 26.0f64, 13.8f64);
+    return Vec3::new(x, y, z);
+}
+
+// linear srgb primaries with a d65 white
+pub fn xyz_to_linear_srgb(xyz: &Vec3) -> Vec3 {
+    return Vec3::new(
+        3.2406f64 * xyz.x - 1.5372f64 * xyz.y - 0.4986f64 * xyz.z,
+        -0.9689f64 * xyz.x + 1.8758f64 * xyz.y + 0.0415f64 * xyz.z,
+        0.0557f64 * xyz.x - 0.2040f64 * xyz.y + 1.0570f64 * xyz.z,
+    );
+}
+
+pub fn luminance(rgb: &Vec3) -> f64 {
+    return 0.2126f64 * rgb.x + 0.7152f64 * rgb.y + 0.0722f64 * rgb.z;
+}
+
+// spectral radiance of a black body, lambda in nanometers
+pub fn planck(lambda: f64, kelvin: f64) -> f64 {
+    let h = 6.62607015e-34f64;
+    let c = 2.99792458e8f64;
+    let k = 1.380649e-23f64;
+    let l = lambda * 1e-9f64;
+    return (2f64 * h * c * c) / (l.powi(5) * ((h * c / (l * k * kelvin)).exp() - 1f64));
+}
+
+// color of a black body with unit luminance, negative channels are clipped
+pub fn blackbody_rgb(kelvin: f64) -> Vec3 {
+    let mut xyz = Vec3::new(0f64, 0f64, 0f64);
+    let mut lambda = LAMBDA_MIN;
+    while lambda <= LAMBDA_MAX {
+        xyz += &(planck(lambda, kelvin) * &cie_xyz(lambda));
+        lambda += 5f64;
+    }
+    if xyz.y <= 0f64 {
+        return Vec3::new(0f64, 0f64, 0f64);
+    }
+    let rgb = xyz_to_linear_srgb(&(&xyz / xyz.y));
+    return Vec3::new(rgb.x.max(0f64), rgb.y.max(0f64), rgb.z.max(0f64));
+}
+
+//
+// spectral rendering
+//
+// the path tracer carries radiance in a Vec3, in spectral mode the three lanes hold three wavelengths
+// (a hero wavelength plus two rotations of it across the visible range) instead of red, green and blue
+#[derive(Clone, Copy)]
+pub struct Wavelengths {
+    pub lambda: [f64; 3],
+    // set once something wavelength dependent (dispersion) bent the path for the hero only
+    pub secondary_terminated: bool,
+}
+
+impl Wavelengths {
+    // hero wavelength sampling, Wilkie et al. 2014
+    pub fn sample(u: f64) -> Wavelengths {
+        let range = LAMBDA_MAX - LAMBDA_MIN;
+        let hero = LAMBDA_MIN + u * range;
+        let mut lambda = [hero; 3];
+        for i in 1..3 {
+            let mut rotated = hero + i as f64 * range / 3f64;
+            if rotated > LAMBDA_MAX {
+                rotated -= range;
+            }
+            lambda[i] = rotated;
+        }
+        return Wavelengths { lambda: lambda, secondary_terminated: false };
+    }
+
+    pub fn hero(&self) -> f64 {
+        return self.lambda[0];
+    }
+}
+
+// three smooth bumps that add up to one everywhere, so a white albedo upsamples to a flat spectrum
+fn smoothstep(x: f64, edge0: f64, edge1: f64) -> f64 {
+    let t = ((x - edge0) / (edge1 - edge0)).max(0f64).min(1f64);
+    return t * t * (3f64 - 2f64 * t);
+}
+
+fn basis(lambda: f64) -> Vec3 {
+    let blue = 1f64 - smoothstep(lambda, 490f64, 510f64);
+    let red = smoothstep(lambda, 575f64, 595f64);
+    return Vec3::new(red, 1f64 - red - blue, blue);
+}
+
+// integrals the conversions need, computed once per thread
+struct SpectralTables {
+    // integral of the y matching function, normalizes a flat unit spectrum to unit luminance
+    y_integral: f64,
+    // rgb of a flat unit spectrum, divided out so that it stays white
+    white: Vec3,
+    // inverse of the rgb response of the three basis bumps
+    basis_inverse: [[f64; 3]; 3],
+}
+
+fn spectrum_to_xyz<F: Fn(f64) -> f64>(f: F) -> Vec3 {
+    let mut xyz = Vec3::new(0f64, 0f64, 0f64);
+    let mut lambda = LAMBDA_MIN;
+    while lambda <= LAMBDA_MAX {
+        xyz += &(f(lambda) * &cie_xyz(lambda));
+        lambda += 1f64;
+    }
+    return xyz;
+}
+
+fn invert3(m: &[[f64; 3]; 3]) -> [[f64; 3]; 3] {
+    let det = m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+        - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+        + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0]);
+    let inv_det = 1f64 / det;
+    return [
+        [
+            (m[1][1] * m[2][2] - m[1][2] * m[2][1]) * inv_det,
+            (m[0][2] * m[2][1] - m[0][1] * m[2][2]) * inv_det,
+            (m[0][1] * m[1][2] - m[0][2] * m[1][1]) * inv_det,
+        ],
+        [
+            (m[1][2] * m[2][0] - m[1][0] * m[2][2]) * inv_det,
+            (m[0][0] * m[2][2] - m[0][2] * m[2][0]) * inv_det,
+            (m[0][2] * m[1][0] - m[0][0] * m[1][2]) * inv_det,
+        ],
+        [
+            (m[1][0] * m[2][1] - m[1][1] * m[2][0]) * inv_det,
+            (m[0][1] * m[2][0] - m[0][0] * m[2][1]) * inv_det,
+            (m[0][0] * m[1][1] - m[0][1] * m[1][0]) * inv_det,
+        ],
+    ];
+}
+
+impl SpectralTables {
+    fn new() -> SpectralTables {
+        let y_integral = spectrum_to_xyz(|_| 1f64).y;
+        let white = xyz_to_linear_srgb(&(&spectrum_to_xyz(|_| 1f64) / y_integral));
+
+        // column j is the white balanced rgb of basis bump j
+        let mut response = [[0f64; 3]; 3];
+        for j in 0..3 {
+            let xyz = spectrum_to_xyz(|lambda| {
+                let b = basis(lambda);
+                return match j { 0 => b.x, 1 => b.y, _ => b.z };
+            });
+            let rgb = xyz_to_linear_srgb(&(&xyz / y_integral));
+            response[0][j] = rgb.x / white.x;
+            response[1][j] = rgb.y / white.y;
+            response[2][j] = rgb.z / white.z;
+        }
+        return SpectralTables {
+            y_integral: y_integral,
+            white: white,
+            basis_inverse: invert3(&response),
+        };
+    }
+}
+
+thread_local!(static SPECTRAL_TABLES: SpectralTables = SpectralTables::new());
+
+// smooth reflectance spectrum that converts back to (nearly) the same rgb, clamped to [0, 1]
+pub fn upsample_reflectance(rgb: &Vec3, wavelengths: &Wavelengths) -> Vec3 {
+    return SPECTRAL_TABLES.with(|tables| {
+        let m = &tables.basis_inverse;
+        let weights = Vec3::new(
+            m[0][0] * rgb.x + m[0][1] * rgb.y + m[0][2] * rgb.z,
+            m[1][0] * rgb.x + m[1][1] * rgb.y + m[1][2] * rgb.z,
+            m[2][0] * rgb.x + m[2][1] * rgb.y + m[2][2] * rgb.z,
+        );
+        let at = |lambda: f64| weights.dot(&basis(lambda)).max(0f64).min(1f64);
+        return Vec3::new(at(wavelengths.lambda[0]), at(wavelengths.lambda[1]), at(wavelengths.lambda[2]));
+    });
+}
+
+// for emitters and coefficients, scale into reflectance range and back out again
+pub fn upsample_unbounded(rgb: &Vec3, wavelengths: &Wavelengths) -> Vec3 {
+    let scale = rgb.x.max(rgb.y.max(rgb.z));
+    if scale <= 0f64 {
+        return Vec3::new(0f64, 0f64, 0f64);
+    }
+    return scale * &upsample_reflectance(&(rgb / scale), wavelengths);
+}
+
+// the path tracer hands rgb inputs through these, they are no-ops when rendering in rgb
+pub fn reflectance_to_path(rgb: &Vec3, wavelengths: Option<&Wavelengths>) -> Vec3 {
+    return match wavelengths {
+        Some(w) => upsample_reflectance(rgb, w),
+        None => Vec3::new(rgb.x, rgb.y, rgb.z),
+    };
+}
+
+pub fn radiance_to_path(rgb: &Vec3, wavelengths: Option<&Wavelengths>) -> Vec3 {
+    return match wavelengths {
+        Some(w) => upsample_unbounded(rgb, w),
+        None => Vec3::new(rgb.x, rgb.y, rgb.z),
+    };
+}
+
+// monte carlo estimate of the rgb of one path's wavelength samples, white balanced so flat spectra stay grey
+pub fn wavelengths_to_rgb(radiance: &Vec3, wavelengths: &Wavelengths) -> Vec3 {
+    return SPECTRAL_TABLES.with(|tables| {
+        // uniform pdf over the range, three samples per path
+        let weight = (LAMBDA_MAX - LAMBDA_MIN) / (3f64 * tables.y_integral);
+        let values = [radiance.x, radiance.y, radiance.z];
+        let mut xyz = Vec3::new(0f64, 0f64, 0f64);
+        for i in 0..3 {
+            xyz += &((values[i] * weight) * &cie_xyz(wavelengths.lambda[i]));
+        }
+        let rgb = xyz_to_linear_srgb(&xyz);
+        return Vec3::new(rgb.x / tables.white.x, rgb.y / tables.white.y, rgb.z / tables.white.z);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the rgb a color comes back as, averaged over stratified hero wavelengths
+    fn round_trip(rgb: &Vec3, to_path: &Fn(&Vec3, &Wavelengths) -> Vec3) -> Vec3 {
+        let count = 3000;
+        let mut sum = Vec3::new(0f64, 0f64, 0f64);
+        for i in 0..count {
+            let wavelengths = Wavelengths::sample((i as f64 + 0.5f64) / count as f64);
+            sum += &wavelengths_to_rgb(&to_path(rgb, &wavelengths), &wavelengths);
+        }
+        return &sum / count as f64;
+    }
+
+    fn assert_close(a: &Vec3, b: &Vec3, tolerance: f64) {
+        assert!((a.x - b.x).abs() <= tolerance && (a.y - b.y).abs() <= tolerance && (a.z - b.z).abs() <= tolerance,
+                "({}, {}, {}) != ({}, {}, {})", a.x, a.y, a.z, b.x, b.y, b.z);
+    }
+
+    #[test]
+    fn hero_wavelengths_spread_over_the_visible_range() {
+        for i in 0..100 {
+            let wavelengths = Wavelengths::sample(i as f64 / 100f64);
+            let mut sorted = wavelengths.lambda;
+            sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            assert!(sorted[0] >= LAMBDA_MIN && sorted[2] <= LAMBDA_MAX);
+            assert!((sorted[1] - sorted[0] - (LAMBDA_MAX - LAMBDA_MIN) / 3f64).abs() < 1e-9f64);
+            assert_eq!(wavelengths.hero(), wavelengths.lambda[0]);
+        }
+    }
+
+    #[test]
+    fn white_stays_white() {
+        let white = Vec3::new(1f64, 1f64, 1f64);
+        let flat = upsample_reflectance(&white, &Wavelengths::sample(0.37f64));
+        assert_close(&flat, &white, 1e-6f64);
+        assert_close(&round_trip(&white, &upsample_reflectance), &white, 0.01f64);
+    }
+
+    #[test]
+    fn colors_come_back_from_a_round_trip_through_spectra() {
+        for rgb in [Vec3::new(0.8f64, 0.3f64, 0.2f64), Vec3::new(0.25f64, 0.35f64, 0.8f64), Vec3::new(0.4f64, 0.6f64, 0.5f64)].iter() {
+            assert_close(&round_trip(rgb, &upsample_reflectance), rgb, 0.02f64);
+        }
+        // emitters keep their brightness
+        let bright = Vec3::new(4f64, 2f64, 1f64);
+        assert_close(&round_trip(&bright, &upsample_unbounded), &bright, 0.1f64);
+        // and rgb rendering passes everything straight through
+        assert_close(&radiance_to_path(&bright, None), &bright, 0f64);
+    }
+
+    #[test]
+    fn black_bodies_have_unit_luminance_and_cool_towards_red() {
+        let daylight = blackbody_rgb(6500f64);
+        assert!((luminance(&daylight) - 1f64).abs() < 0.05f64);
+        let candle = blackbody_rgb(1900f64);
+        assert!(candle.x > candle.y && candle.y > candle.z);
+        let hot = blackbody_rgb(12000f64);
+        assert!(hot.z > hot.x);
+    }
+}
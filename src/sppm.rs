@@ -0,0 +1,384 @@
+extern crate rusty_math;
+
+use rusty_math::*;
+use std::f64;
+use std::f64::consts;
+
+use bvh::Aabb;
+use kdtree::KdTree;
+use renderable::HitRecord;
+use renderable::RenderList;
+use renderable::Material;
+use renderable::MaterialInput;
+use renderable::MaterialOutput;
+use renderable::random_unit_vector;
+use renderer::RenderSettings;
+use renderer::copy;
+use renderer::is_black;
+use renderer::mul;
+use camera::Camera;
+use render_buffer::RenderBufferF64;
+use sampler;
+use stats;
+
+//
+// stochastic progressive photon mapping, Hachisuka and Jensen 2009
+//
+// every iteration traces one camera path per pixel through specular surfaces to the first diffuse hit
+// (the visible point), then shoots a batch of photons from the lights and the sky and stores them in a
+// kd-tree. each visible point gathers the photons around it and its pixel's search radius shrinks, so
+// the estimate converges while caustics stay sharp
+//
+// direct light at visible points is sampled explicitly, photons only count from their second hit on
+// renders in rgb and ignores media and heterogeneous volumes
+pub struct SppmIntegrator {
+    pub photons_per_iteration: usize,
+    pub initial_radius: f64,
+    // fraction of newly found photons kept in the pixel statistics, 2/3 in the paper
+    pub alpha: f64,
+    pub max_depth: usize,
+}
+
+struct SppmPixel {
+    radius: f64,
+    photon_count: f64,
+    // accumulated flux, tau in the paper
+    flux: Vec3,
+    // emitted and directly sampled light, summed over iterations
+    direct: Vec3,
+}
+
+struct VisiblePoint<'a> {
+    pixel: usize,
+    material: &'a Material,
+    input: MaterialInput,
+    beta: Vec3,
+}
+
+struct Photon {
+    // unit direction of travel
+    dir: Vec3,
+    normal: Vec3,
+    power: Vec3,
+}
+
+impl SppmPixel {
+    // found photons brought flux this iteration, only alpha of them are kept and the radius shrinks to match.
+    // the flux gathered so far is scaled down with the disk, as if the smaller one had collected it all along
+    fn add_photons(&mut self, found: f64, flux: &Vec3, alpha: f64) {
+        if found <= 0f64 {
+            return;
+        }
+        let count = self.photon_count + alpha * found;
+        let radius = self.radius * (count / (self.photon_count + found)).sqrt();
+        let shrink = (radius * radius) / (self.radius * self.radius);
+        self.flux = &(&self.flux + flux) * shrink;
+        self.photon_count = count;
+        self.radius = radius;
+    }
+
+    // average radiance over the iterations, total_photons shot over all of them
+    fn radiance(&self, iterations: usize, total_photons: f64) -> Vec3 {
+        let area = consts::PI * self.radius * self.radius;
+        return &(&self.direct / iterations as f64) + &(&self.flux / (total_photons * area));
+    }
+}
+
+impl SppmIntegrator {
+    pub fn new() -> SppmIntegrator {
+        return SppmIntegrator {
+            photons_per_iteration: 100000,
+            initial_radius: 0.15f64,
+            alpha: 2f64 / 3f64,
+            max_depth: 8,
+        };
+    }
+
+    // runs num_samples_per_pixel iterations, film ends up scaled like a sum of that many samples
+    pub fn render(&self, render_list: &RenderList, camera: &Camera, render_settings: &RenderSettings, film: &mut RenderBufferF64) {
+        let width = film.width;
+        let height = film.height;
+        let iterations = render_settings.num_samples_per_pixel.max(1) as usize;
+
+        let mut pixels: Vec<SppmPixel> = (0..(width * height)).map(|_| SppmPixel {
+            radius: self.initial_radius,
+            photon_count: 0f64,
+            flux: Vec3::new(0f64, 0f64, 0f64),
+            direct: Vec3::new(0f64, 0f64, 0f64),
+        }).collect();
+
+        for _ in 0..iterations {
+            // one moment per iteration so camera paths and photons see the same scene
+            let shutter_time = camera.sample_shutter_time();
+
+            let mut visible_points: Vec<VisiblePoint> = Vec::with_capacity(width * height);
+            for y in 0..height {
+                for x in 0..width {
+                    let u = (x as f64 + sampler::next_f64()) / (width as f64);
+                    let v = (y as f64 + sampler::next_f64()) / (height as f64);
+                    let pixel = y * width + x;
+                    let (ray, weight) = camera.get_weighted_ray(u, v);
+                    stats::count_camera_ray();
+                    if let Some(visible_point) = self.trace_camera_path(render_list, ray, weight, shutter_time, pixel, &mut pixels[pixel]) {
+                        visible_points.push(visible_point);
+                    }
+                }
+            }
+            let sky_target = self.sky_photon_target(&visible_points, camera);
+
+            let (positions, photons) = self.trace_photons(render_list, shutter_time, &sky_target);
+            let tree = KdTree::build(positions);
+
+            for visible_point in visible_points.iter() {
+                let pixel = &mut pixels[visible_point.pixel];
+                let mut found = 0f64;
+                let mut flux = Vec3::new(0f64, 0f64, 0f64);
+                let normal = &visible_point.input.normal;
+                tree.for_each_in_radius(&visible_point.input.point, pixel.radius, |index| {
+                    let photon = &photons[index];
+                    // photons on the other side of a thin wall do not count
+                    if photon.normal.dot(normal) <= 0f64 {
+                        return;
+                    }
+                    let f = visible_point.material.eval(&visible_point.input, &(-1f64 * &photon.dir));
+                    flux += &mul(&f, &photon.power);
+                    found += 1f64;
+                });
+                pixel.add_photons(found, &mul(&visible_point.beta, &flux), self.alpha);
+            }
+        }
+
+        let total_photons = (iterations * self.photons_per_iteration) as f64;
+        for y in 0..height {
+            for x in 0..width {
+                let radiance = pixels[y * width + x].radiance(iterations, total_photons);
+                let scale = iterations as f64;
+                film.add(x, y, radiance.x * scale, radiance.y * scale, radiance.z * scale);
+            }
+        }
+    }
+
+    // follows specular bounces to the first diffuse surface, adding whatever light is picked up on the way
+    // weight is what the camera let through along ray
+    fn trace_camera_path<'a>(
+        &self,
+        render_list: &RenderList<'a>,
+        ray: Ray,
+        weight: f64,
+        shutter_time: f64,
+        pixel_index: usize,
+        pixel: &mut SppmPixel
+    ) -> Option<VisiblePoint<'a>> {
+        let mut ray = ray;
+        let mut beta = Vec3::new(weight, weight, weight);
+        for _ in 0..self.max_depth {
+            let mut hit_record = HitRecord::new();
+            if !render_list.try_get_hit_record(&ray, shutter_time, 0.001f64, f64::MAX, &mut hit_record) {
+                pixel.direct += &mul(&beta, &render_list.sky_color(&ray.dir));
+                return None;
+            }
+            let material_package = render_list.get_material_package(&hit_record);
+            let material = material_package.material;
+            let input = material_package.material_input;
+            pixel.direct += &mul(&beta, &material.emitted(&input));
+
+            let mut material_output = MaterialOutput::new();
+            if !material.is_specular() {
+                pixel.direct += &mul(&beta, &self.sample_direct(render_list, material, &input, shutter_time));
+                return Some(VisiblePoint {
+                    pixel: pixel_index,
+                    material: material,
+                    input: input,
+                    beta: beta,
+                });
+            }
+            if !material.apply(&input, &mut material_output) {
+                return None;
+            }
+            beta = mul(&beta, &material_output.attenuation);
+            ray = material_output.scattered;
+        }
+        return None;
+    }
+
+    // one light sample and one material sample that only counts if it sees the sky
+    fn sample_direct(&self, render_list: &RenderList, material: &Material, input: &MaterialInput, shutter_time: f64) -> Vec3 {
+        let mut direct = Vec3::new(0f64, 0f64, 0f64);
+        if let Some(light_sample) = render_list.sample_light() {
+            let to_light = &light_sample.point - &input.point;
+            let dist_squared = to_light.length_squared();
+            let light_cosine = -light_sample.normal.dot(&to_light) / dist_squared.sqrt();
+            let f = material.eval(input, &to_light);
+            if light_cosine > 0f64 && !is_black(&f) && !render_list.is_occluded(&input.point, &light_sample.point, shutter_time) {
+                let cosine = input.normal.dot(&to_light).abs() / dist_squared.sqrt();
+                let g = cosine * light_cosine / dist_squared;
+                direct += &(&mul(&f, &light_sample.emission) * (g / light_sample.pdf));
+            }
+        }
+
+        let mut material_output = MaterialOutput::new();
+        if material.apply(input, &mut material_output) {
+            let mut hit_record = HitRecord::new();
+            let scattered = &material_output.scattered;
+            if !render_list.try_get_hit_record(scattered, shutter_time, 0.001f64, f64::MAX, &mut hit_record) {
+                direct += &mul(&material_output.attenuation, &render_list.sky_color(&scattered.dir));
+            }
+        }
+        return direct;
+    }
+
+    // where sky photons are aimed, the nearest nine tenths of the visible points
+    // a floor running to the horizon would otherwise spread them too thin to see
+    fn sky_photon_target(&self, visible_points: &[VisiblePoint], camera: &Camera) -> Aabb {
+        let mut bounds = Aabb::empty();
+        if visible_points.is_empty() {
+            return bounds;
+        }
+        let origin = camera.get_origin();
+        let mut distances: Vec<f64> = visible_points.iter()
+            .map(|p| (&p.input.point - &origin).length_squared())
+            .collect();
+        distances.sort_by(|a, b| a.partial_cmp(b).unwrap_or(::std::cmp::Ordering::Equal));
+        let cutoff = distances[(distances.len() - 1) * 9 / 10];
+        for visible_point in visible_points.iter() {
+            if (&visible_point.input.point - &origin).length_squared() <= cutoff {
+                bounds.grow(&visible_point.input.point);
+            }
+        }
+        return bounds;
+    }
+
+    // photons come from the lights and, for sky light, from a disk facing the scene that covers the target
+    // with room to spare. sky light that would bounce in from further out than that is lost
+    fn trace_photons(&self, render_list: &RenderList, shutter_time: f64, sky_target: &Aabb) -> (Vec<Vec3>, Vec<Photon>) {
+        let mut positions: Vec<Vec3> = Vec::new();
+        let mut photons: Vec<Photon> = Vec::new();
+
+        let has_sky = sky_target.min.x <= sky_target.max.x;
+        let has_lights = render_list.has_lights();
+        if !has_sky && !has_lights {
+            return (positions, photons);
+        }
+        let sky_probability = if !has_lights { 1f64 } else if !has_sky { 0f64 } else { 0.5f64 };
+        let center = sky_target.centroid();
+        let disk_radius = sky_target.extent().length_squared().sqrt().max(1e-3f64);
+
+        for _ in 0..self.photons_per_iteration {
+            let ray: Ray;
+            let mut power: Vec3;
+            if sampler::next_f64() < sky_probability {
+                let dir = random_unit_vector();
+                let helper = if dir.x.abs() > 0.9f64 { Vec3::new(0f64, 1f64, 0f64) } else { Vec3::new(1f64, 0f64, 0f64) };
+                let u = helper.cross(&dir).normalize();
+                let v = dir.cross(&u);
+                let r = disk_radius * sampler::next_f64().sqrt();
+                let phi = sampler::next_range(0f64, 2f64 * consts::PI);
+                let on_disk = &(&u * (r * phi.cos())) + &(&v * (r * phi.sin()));
+                let origin = &(&center - &(disk_radius * &dir)) + &on_disk;
+                // disk area over the uniform direction pdf
+                let weight = consts::PI * disk_radius * disk_radius * 4f64 * consts::PI / sky_probability;
+                power = &render_list.sky_color(&(-1f64 * &dir)) * weight;
+                ray = Ray::new(origin, dir);
+            } else {
+                let light_sample = match render_list.sample_light() {
+                    Some(sample) => sample,
+                    None => continue,
+                };
+                // cosine weighted, the cosine and pi cancel against the pdf
+                let dir = &light_sample.normal + &random_unit_vector();
+                let weight = consts::PI / (light_sample.pdf * (1f64 - sky_probability));
+                power = &light_sample.emission * weight;
+                ray = Ray::new(copy(&light_sample.point), dir);
+            }
+
+            let mut ray = ray;
+            for depth in 0..self.max_depth {
+                let mut hit_record = HitRecord::new();
+                if !render_list.try_get_hit_record(&ray, shutter_time, 0.001f64, f64::MAX, &mut hit_record) {
+                    break;
+                }
+                let material_package = render_list.get_material_package(&hit_record);
+                let material = material_package.material;
+                let input = &material_package.material_input;
+                if !material.is_specular() {
+                    // sky photons can start inside big objects, a diffuse surface seen from behind stops them
+                    if ray.dir.dot(&input.normal) >= 0f64 {
+                        break;
+                    }
+                    // the first hit is direct light, visible points sample that themselves
+                    if depth > 0 {
+                        positions.push(copy(&input.point));
+                        photons.push(Photon {
+                            dir: ray.dir.normalize(),
+                            normal: copy(&input.normal),
+                            power: copy(&power),
+                        });
+                    }
+                }
+                let mut material_output = MaterialOutput::new();
+                if !material.apply(input, &mut material_output) {
+                    break;
+                }
+                power = mul(&power, &material_output.attenuation);
+                if is_black(&power) {
+                    break;
+                }
+                ray = material_output.scattered;
+            }
+        }
+        return (positions, photons);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bdpt::tests::lit_scene_mean;
+    use bdpt::tests::mean_radiance;
+    use bdpt::tests::settings;
+    use renderer::IntegratorKind;
+
+    // mostly direct light, sampled at the visible points and averaged over the iterations, with the photons
+    // bringing what bounces between the ball and the floor
+    #[test]
+    fn photon_mapping_agrees_with_the_path_tracer() {
+        let path = mean_radiance(IntegratorKind::Path, 0f64, (0f64, 0f64));
+        let photon_mapping = lit_scene_mean(0f64, (0f64, 0f64), |world, camera, film| {
+            let settings = settings(IntegratorKind::ProgressivePhotonMapping, 64);
+            let mut integrator = SppmIntegrator::new();
+            integrator.photons_per_iteration = 20000;
+            integrator.max_depth = settings.max_depth as usize;
+            integrator.render(world, camera, &settings, film);
+            return settings.num_samples_per_pixel;
+        });
+        assert!(path > 0.05f64);
+        assert!((photon_mapping - path).abs() < 0.05f64 * path, "sppm {} against path {}", photon_mapping, path);
+    }
+
+    #[test]
+    fn shrinking_keeps_a_uniform_photon_density() {
+        // every iteration the same number of photons per unit area, each bringing the same power. the estimate
+        // has to stay that density times the power however far the radius has shrunk
+        let (density, power) = (40f64, 0.25f64);
+        let mut pixel = SppmPixel {
+            radius: 0.5f64,
+            photon_count: 0f64,
+            flux: Vec3::new(0f64, 0f64, 0f64),
+            direct: Vec3::new(3f64, 3f64, 3f64),
+        };
+        let iterations = 50;
+        for _ in 0..iterations {
+            let found = density * consts::PI * pixel.radius * pixel.radius;
+            pixel.add_photons(found, &Vec3::new(found * power, found * power, found * power), 2f64 / 3f64);
+        }
+        assert!(pixel.radius < 0.25f64);
+        // photons_per_iteration is one, per iteration the flux over the disk is the power per unit area
+        let radiance = pixel.radiance(iterations, iterations as f64);
+        assert!((radiance.x - (density * power + 3f64 / iterations as f64)).abs() < 1e-9f64, "{}", radiance.x);
+
+        // and without photons nothing changes
+        let radius = pixel.radius;
+        pixel.add_photons(0f64, &Vec3::new(0f64, 0f64, 0f64), 2f64 / 3f64);
+        assert_eq!(pixel.radius, radius);
+    }
+}
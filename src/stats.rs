@@ -0,0 +1,349 @@
+use std::cell::RefCell;
+use std::sync::Mutex;
+use std::time::Duration;
+
+//
+// render statistics
+//
+// counters are kept per thread so counting on the hot paths is a plain increment, a thread adds them into
+// the process totals with flush. rendering threads flush when they run out of work, whatever the main
+// thread counted is flushed when the totals are read
+pub struct Counters {
+    // generated by the camera
+    pub camera_rays: u64,
+    // closest hit queries, the camera's rays among them
+    pub closest_hit_rays: u64,
+    // visibility tests between two points
+    pub shadow_rays: u64,
+    // rays tested against a primitive, or an instance's own tree, in a leaf of a bvh
+    pub intersection_tests: u64,
+    // bvh nodes whose bounds a ray was tested against
+    pub bvh_nodes_visited: u64,
+    // scattering evaluations per material type
+    pub material_evaluations: Vec<(&'static str, u64)>,
+    // path_lengths[n] camera paths ended after n bounces
+    pub path_lengths: Vec<u64>,
+}
+
+impl Counters {
+    pub const fn new() -> Counters {
+        return Counters {
+            camera_rays: 0,
+            closest_hit_rays: 0,
+            shadow_rays: 0,
+            intersection_tests: 0,
+            bvh_nodes_visited: 0,
+            material_evaluations: Vec::new(),
+            path_lengths: Vec::new(),
+        };
+    }
+
+    // closest hit queries past the camera, bounces and light subpaths
+    pub fn secondary_rays(&self) -> u64 {
+        return self.closest_hit_rays.saturating_sub(self.camera_rays);
+    }
+
+    pub fn total_rays(&self) -> u64 {
+        return self.closest_hit_rays.max(self.camera_rays) + self.shadow_rays;
+    }
+
+    fn add(&mut self, other: &Counters) {
+        self.camera_rays += other.camera_rays;
+        self.closest_hit_rays += other.closest_hit_rays;
+        self.shadow_rays += other.shadow_rays;
+        self.intersection_tests += other.intersection_tests;
+        self.bvh_nodes_visited += other.bvh_nodes_visited;
+        for &(name, count) in other.material_evaluations.iter() {
+            self.add_material_evaluations(name, count);
+        }
+        if self.path_lengths.len() < other.path_lengths.len() {
+            self.path_lengths.resize(other.path_lengths.len(), 0);
+        }
+        for (length, count) in other.path_lengths.iter().enumerate() {
+            self.path_lengths[length] += *count;
+        }
+    }
+
+    // a handful of material types, a list is quicker to search than hashing the name
+    fn add_material_evaluations(&mut self, name: &'static str, count: u64) {
+        match self.material_evaluations.iter_mut().find(|entry| entry.0 == name) {
+            Some(entry) => entry.1 += count,
+            None => self.material_evaluations.push((name, count)),
+        };
+    }
+}
+
+thread_local!(static LOCAL: RefCell<Counters> = RefCell::new(Counters::new()));
+static TOTALS: Mutex<Counters> = Mutex::new(Counters::new());
+
+pub fn count_camera_ray() {
+    LOCAL.with(|local| local.borrow_mut().camera_rays += 1);
+}
+
+pub fn count_closest_hit_ray() {
+    LOCAL.with(|local| local.borrow_mut().closest_hit_rays += 1);
+}
+
+pub fn count_shadow_ray() {
+    LOCAL.with(|local| local.borrow_mut().shadow_rays += 1);
+}
+
+// what one ray did in one bvh
+pub fn count_traversal(nodes_visited: u64, intersection_tests: u64) {
+    LOCAL.with(|local| {
+        let mut local = local.borrow_mut();
+        local.bvh_nodes_visited += nodes_visited;
+        local.intersection_tests += intersection_tests;
+    });
+}
+
+pub fn count_material(name: &'static str) {
+    LOCAL.with(|local| local.borrow_mut().add_material_evaluations(name, 1));
+}
+
+pub fn count_path(bounces: usize) {
+    LOCAL.with(|local| {
+        let mut local = local.borrow_mut();
+        if local.path_lengths.len() <= bounces {
+            local.path_lengths.resize(bounces + 1, 0);
+        }
+        local.path_lengths[bounces] += 1;
+    });
+}
+
+// adds this thread's counters to the totals and starts it over
+pub fn flush() {
+    let local = LOCAL.with(|local| local.replace(Counters::new()));
+    TOTALS.lock().unwrap().add(&local);
+}
+
+// everything counted so far by threads that flushed, this one included
+pub fn totals() -> Counters {
+    flush();
+    let mut totals = Counters::new();
+    totals.add(&TOTALS.lock().unwrap());
+    return totals;
+}
+
+//
+// Timings
+//
+// wall clock seconds per phase of the run, a phase timed more than once (every frame renders) adds up
+pub struct Timings {
+    m_phases: Vec<(&'static str, f64)>,
+}
+
+impl Timings {
+    pub fn new() -> Timings {
+        return Timings { m_phases: Vec::new() };
+    }
+
+    pub fn add(&mut self, phase: &'static str, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        match self.m_phases.iter_mut().find(|entry| entry.0 == phase) {
+            Some(entry) => entry.1 += seconds,
+            None => self.m_phases.push((phase, seconds)),
+        };
+    }
+
+    pub fn seconds(&self, phase: &str) -> f64 {
+        return match self.m_phases.iter().find(|entry| entry.0 == phase) {
+            Some(entry) => entry.1,
+            None => 0f64,
+        };
+    }
+
+    pub fn total_seconds(&self) -> f64 {
+        return self.m_phases.iter().map(|entry| entry.1).sum();
+    }
+}
+
+//
+// reports
+//
+
+// rows of the path length histogram in the summary, longer paths share the last row. the json has them all
+static SUMMARY_PATH_LENGTHS: usize = 16;
+
+// millions of rays of every kind per second spent in the render phase
+pub fn mrays_per_second(counters: &Counters, timings: &Timings) -> f64 {
+    let seconds = timings.seconds("render");
+    if seconds <= 0f64 {
+        return 0f64;
+    }
+    return counters.total_rays() as f64 / seconds / 1e6f64;
+}
+
+// what gets printed at the end of a run
+pub fn summary(counters: &Counters, timings: &Timings) -> String {
+    let mut lines: Vec<String> = Vec::new();
+    lines.push(String::from("render statistics"));
+    lines.push(format!("  camera rays          {:>14}", counters.camera_rays));
+    lines.push(format!("  secondary rays       {:>14}", counters.secondary_rays()));
+    lines.push(format!("  shadow rays          {:>14}", counters.shadow_rays));
+    lines.push(format!("  intersection tests   {:>14}", counters.intersection_tests));
+    lines.push(format!("  bvh nodes visited    {:>14}", counters.bvh_nodes_visited));
+    let rays = counters.total_rays();
+    if rays > 0 {
+        lines.push(format!(
+            "  per ray              {:>14.1} nodes, {:.1} tests",
+            counters.bvh_nodes_visited as f64 / rays as f64,
+            counters.intersection_tests as f64 / rays as f64
+        ));
+    }
+    if !counters.material_evaluations.is_empty() {
+        lines.push(String::from("  material evaluations"));
+        for &(name, count) in counters.material_evaluations.iter() {
+            lines.push(format!("    {:<18} {:>14}", name, count));
+        }
+    }
+    let paths: u64 = counters.path_lengths.iter().sum();
+    if paths > 0 {
+        lines.push(String::from("  path length (bounces)"));
+        for (bounces, count) in counters.path_lengths.iter().enumerate().take(SUMMARY_PATH_LENGTHS) {
+            lines.push(format!(
+                "    {:<18} {:>14}  {:>5.1}%", bounces, count, 100f64 * *count as f64 / paths as f64
+            ));
+        }
+        let longer: u64 = counters.path_lengths.iter().skip(SUMMARY_PATH_LENGTHS).sum();
+        if longer > 0 {
+            lines.push(format!(
+                "    {:<18} {:>14}  {:>5.1}%", format!("{}+", SUMMARY_PATH_LENGTHS), longer, 100f64 * longer as f64 / paths as f64
+            ));
+        }
+    }
+    lines.push(String::from("  time"));
+    for &(phase, seconds) in timings.m_phases.iter() {
+        lines.push(format!("    {:<18} {:>13.2}s", phase, seconds));
+    }
+    lines.push(format!("    {:<18} {:>13.2}s", "total", timings.total_seconds()));
+    lines.push(format!("  {:<20} {:>14.3}", "mrays/s", mrays_per_second(counters, timings)));
+    return lines.join("\n");
+}
+
+// the same as one json object, for tracking runs across commits
+pub fn json(counters: &Counters, timings: &Timings) -> String {
+    let materials: Vec<String> = counters.material_evaluations.iter()
+        .map(|&(name, count)| format!("\"{}\":{}", name, count))
+        .collect();
+    let path_lengths: Vec<String> = counters.path_lengths.iter().map(|count| count.to_string()).collect();
+    let mut phases: Vec<String> = timings.m_phases.iter()
+        .map(|&(phase, seconds)| format!("\"{}\":{:.4}", phase, seconds))
+        .collect();
+    phases.push(format!("\"total\":{:.4}", timings.total_seconds()));
+    return format!(
+        "{{\"camera_rays\":{},\"secondary_rays\":{},\"shadow_rays\":{},\"intersection_tests\":{},\"bvh_nodes_visited\":{},\"material_evaluations\":{{{}}},\"path_lengths\":[{}],\"seconds\":{{{}}},\"mrays_per_second\":{:.4}}}\n",
+        counters.camera_rays, counters.secondary_rays(), counters.shadow_rays, counters.intersection_tests,
+        counters.bvh_nodes_visited, materials.join(","), path_lengths.join(","), phases.join(","),
+        mrays_per_second(counters, timings)
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn a_thread_counts_on_its_own_until_it_flushes() {
+        // a thread of its own, the test threads around it count into the same totals
+        thread::spawn(|| {
+            for _ in 0..3 {
+                count_camera_ray();
+            }
+            for _ in 0..5 {
+                count_closest_hit_ray();
+            }
+            count_shadow_ray();
+            count_shadow_ray();
+            count_traversal(10, 4);
+            count_traversal(6, 1);
+            count_material("lambertian");
+            count_material("metal");
+            count_material("lambertian");
+            count_path(3);
+            count_path(0);
+            count_path(3);
+            LOCAL.with(|local| {
+                let local = local.borrow();
+                assert_eq!((local.camera_rays, local.closest_hit_rays, local.shadow_rays), (3, 5, 2));
+                assert_eq!((local.bvh_nodes_visited, local.intersection_tests), (16, 5));
+                assert_eq!(local.material_evaluations, vec![("lambertian", 2), ("metal", 1)]);
+                assert_eq!(local.path_lengths, vec![1, 0, 0, 2]);
+                assert_eq!((local.secondary_rays(), local.total_rays()), (2, 7));
+            });
+
+            let before = TOTALS.lock().unwrap().shadow_rays;
+            flush();
+            assert!(TOTALS.lock().unwrap().shadow_rays >= before + 2);
+            LOCAL.with(|local| assert_eq!(local.borrow().total_rays(), 0));
+        }).join().unwrap();
+    }
+
+    #[test]
+    fn counters_merge_materials_and_path_lengths() {
+        let mut counters = Counters::new();
+        counters.add_material_evaluations("metal", 4);
+        counters.path_lengths = vec![1, 2];
+        let mut other = Counters::new();
+        other.camera_rays = 7;
+        other.add_material_evaluations("dielectric", 1);
+        other.add_material_evaluations("metal", 2);
+        other.path_lengths = vec![0, 1, 0, 5];
+        counters.add(&other);
+        assert_eq!(counters.camera_rays, 7);
+        assert_eq!(counters.material_evaluations, vec![("metal", 6), ("dielectric", 1)]);
+        assert_eq!(counters.path_lengths, vec![1, 3, 0, 5]);
+        // a camera ray is a closest hit query too, but a count without them still adds up
+        assert_eq!((counters.secondary_rays(), counters.total_rays()), (0, 7));
+    }
+
+    #[test]
+    fn timings_add_up_per_phase() {
+        let mut timings = Timings::new();
+        timings.add("render", Duration::from_millis(1500));
+        timings.add("output", Duration::from_millis(250));
+        timings.add("render", Duration::from_millis(1500));
+        assert_eq!(timings.seconds("render"), 3f64);
+        assert_eq!(timings.seconds("denoise"), 0f64);
+        assert_eq!(timings.total_seconds(), 3.25f64);
+
+        let mut counters = Counters::new();
+        counters.camera_rays = 1000000;
+        counters.closest_hit_rays = 4000000;
+        counters.shadow_rays = 2000000;
+        assert_eq!(mrays_per_second(&counters, &timings), 2f64);
+        assert_eq!(mrays_per_second(&counters, &Timings::new()), 0f64);
+    }
+
+    #[test]
+    fn reports_show_every_counter() {
+        let mut counters = Counters::new();
+        counters.camera_rays = 2;
+        counters.closest_hit_rays = 3;
+        counters.shadow_rays = 1;
+        counters.intersection_tests = 8;
+        counters.bvh_nodes_visited = 12;
+        counters.add_material_evaluations("metal", 3);
+        counters.path_lengths = vec![0; 20];
+        counters.path_lengths[1] = 1;
+        counters.path_lengths[18] = 1;
+        let mut timings = Timings::new();
+        timings.add("render", Duration::from_millis(500));
+
+        assert_eq!(
+            json(&counters, &timings),
+            "{\"camera_rays\":2,\"secondary_rays\":1,\"shadow_rays\":1,\"intersection_tests\":8,\"bvh_nodes_visited\":12,\
+             \"material_evaluations\":{\"metal\":3},\"path_lengths\":[0,1,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,1,0],\
+             \"seconds\":{\"render\":0.5000,\"total\":0.5000},\"mrays_per_second\":0.0000}\n"
+        );
+
+        // the summary folds the long paths into one row
+        let summary = summary(&counters, &timings);
+        let rows: Vec<Vec<&str>> = summary.lines().map(|line| line.split_whitespace().collect()).collect();
+        assert!(rows.contains(&vec!["metal", "3"]));
+        assert!(rows.contains(&vec!["16+", "1", "50.0%"]));
+        assert!(!rows.iter().any(|row| row[0] == "18"));
+        assert!(rows.contains(&vec!["per", "ray", "3.0", "nodes,", "2.0", "tests"]));
+    }
+}
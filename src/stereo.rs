@@ -0,0 +1,277 @@
+extern crate rusty_math;
+
+use rusty_math::*;
+use std::f64::consts;
+
+use camera::Camera;
+use camera::PerspectiveCamera;
+use camera::EquirectangularCamera;
+use camera::Shutter;
+use render_buffer::RenderBufferI32;
+
+//
+// stereo pairs for vr
+//
+// a rig is two perspective cameras interaxial apart that agree on a convergence distance, things at that
+// distance land in the same place in both eyes and so appear at the depth of the screen
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum StereoConvergence {
+    // parallel eyes with their image planes shifted towards each other, no vertical parallax
+    OffAxis,
+    // both eyes rotated to look at the convergence point, cheaper rigs do this and get keystoning
+    ToeIn,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum StereoLayout {
+    // left eye in the left half of a double wide image
+    SideBySide,
+    // left eye in the top half of a double tall image
+    TopBottom,
+    // one image per eye
+    Separate,
+}
+
+pub struct StereoRig {
+    pub pos: Vec3,
+    pub look_at: Vec3,
+    pub up: Vec3,
+    pub vertical_fov_degrees: f64,
+    pub aspect_ratio: f64,
+    pub aperture: f64,
+    pub focus_dist: f64,
+    // distance between the two lenses, around 0.065 for human eyes in meters
+    pub interaxial: f64,
+    // distance along the view direction to the zero parallax plane
+    pub convergence_distance: f64,
+    pub convergence: StereoConvergence,
+}
+
+impl StereoRig {
+    // (left, right)
+    pub fn eyes(&self) -> (PerspectiveCamera, PerspectiveCamera) {
+        return (self.eye(-0.5f64), self.eye(0.5f64));
+    }
+
+    // side is -1/2 for the left eye and 1/2 for the right
+    fn eye(&self, side: f64) -> PerspectiveCamera {
+        let forward = (&self.look_at - &self.pos).normalize();
+        let right = forward.cross(&self.up).normalize();
+        let eye_offset = (side * self.interaxial) * &right;
+        let eye_pos = &self.pos + &eye_offset;
+        let convergence_point = &self.pos + &(self.convergence_distance * &forward);
+        let eye_look_at = match self.convergence {
+            StereoConvergence::OffAxis => &eye_pos + &forward,
+            StereoConvergence::ToeIn => convergence_point,
+        };
+        // the eye's window is centered on the eye at the lens and between the eyes at convergence_distance
+        let lens_shift = match self.convergence {
+            StereoConvergence::OffAxis => {
+                let half_width = self.aspect_ratio * (self.vertical_fov_degrees * consts::PI / 360f64).tan();
+                (-side * self.interaxial / (2f64 * half_width * self.convergence_distance), 0f64)
+            }
+            StereoConvergence::ToeIn => (0f64, 0f64),
+        };
+        return PerspectiveCamera::new(
+            &eye_pos,
+            &eye_look_at,
+            Vec3::new(self.up.x, self.up.y, self.up.z),
+            self.vertical_fov_degrees,
+            self.aspect_ratio,
+            self.aperture,
+            self.focus_dist,
+            lens_shift,
+            (0f64, 0f64)
+        );
+    }
+}
+
+//
+// OmniStereoCamera
+//
+// omni-directional stereo, an equirectangular panorama where every ray starts on a circle of diameter
+// interaxial, tangent to the direction it looks in. each column of the image sees what an eye would
+// see turning its head to face it. the circle shrinks towards the poles so looking straight up or
+// down does not swap the eyes
+pub struct OmniStereoCamera {
+    panorama: EquirectangularCamera,
+    up: Vec3,
+    // -1/2 * interaxial for the left eye, 1/2 * interaxial for the right
+    eye_offset: f64,
+}
+
+impl OmniStereoCamera {
+    // (left, right)
+    pub fn pair(pos: &Vec3, look_at: &Vec3, up: &Vec3, interaxial: f64) -> (OmniStereoCamera, OmniStereoCamera) {
+        return (
+            OmniStereoCamera::new(pos, look_at, up, -0.5f64 * interaxial),
+            OmniStereoCamera::new(pos, look_at, up, 0.5f64 * interaxial),
+        );
+    }
+
+    fn new(pos: &Vec3, look_at: &Vec3, up: &Vec3, eye_offset: f64) -> OmniStereoCamera {
+        return OmniStereoCamera {
+            panorama: EquirectangularCamera::new(pos, look_at, up),
+            up: up.normalize(),
+            eye_offset: eye_offset,
+        };
+    }
+}
+
+impl Camera for OmniStereoCamera {
+    fn get_ray(&self, s: f64, t: f64) -> Ray {
+        let ray = self.panorama.get_ray(s, t);
+        // dir x up is horizontal, to the right of dir and as long as the cosine of its latitude
+        let right = &ray.dir.cross(&self.up) / ray.dir.length_squared().sqrt();
+        return Ray::new(&ray.origin + &(self.eye_offset * &right), Vec3::new(ray.dir.x, ray.dir.y, ray.dir.z));
+    }
+
+    fn get_origin(&self) -> Vec3 {
+        return self.panorama.get_origin();
+    }
+
+    fn shutter(&self) -> &Shutter {
+        return self.panorama.shutter();
+    }
+
+    fn shutter_mut(&mut self) -> &mut Shutter {
+        return self.panorama.shutter_mut();
+    }
+
+    // the origin moves with the direction, light paths are not connected to it
+    fn direction_pdf(&self, _lens_point: &Vec3, _dir: &Vec3) -> f64 {
+        return 0f64;
+    }
+
+    fn project(&self, _lens_point: &Vec3, _point: &Vec3) -> Option<(f64, f64)> {
+        return None;
+    }
+
+    fn is_delta(&self) -> bool {
+        return true;
+    }
+}
+
+// both eyes in one image, they must be the same size. None for Separate, each eye keeps its own image
+pub fn combine(left: &RenderBufferI32, right: &RenderBufferI32, layout: StereoLayout) -> Option<RenderBufferI32> {
+    let row_length = left.width * 3;
+    return match layout {
+        StereoLayout::SideBySide => {
+            let mut combined = RenderBufferI32::new(left.width * 2, left.height);
+            for row in 0..left.height {
+                let start = row * row_length;
+                combined.buffer.extend_from_slice(&left.buffer[start..start + row_length]);
+                combined.buffer.extend_from_slice(&right.buffer[start..start + row_length]);
+            }
+            Some(combined)
+        }
+        StereoLayout::TopBottom => {
+            // rows are top down, so the left eye simply goes first
+            let mut combined = RenderBufferI32::new(left.width, left.height * 2);
+            combined.buffer.extend_from_slice(&left.buffer);
+            combined.buffer.extend_from_slice(&right.buffer);
+            Some(combined)
+        }
+        StereoLayout::Separate => None,
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rig(convergence: StereoConvergence) -> StereoRig {
+        return StereoRig {
+            pos: Vec3::new(0f64, 1f64, 0f64),
+            look_at: Vec3::new(0f64, 1f64, -1f64),
+            up: Vec3::new(0f64, 1f64, 0f64),
+            vertical_fov_degrees: 40f64,
+            aspect_ratio: 1.5f64,
+            aperture: 0f64,
+            focus_dist: 3f64,
+            interaxial: 0.065f64,
+            convergence_distance: 3f64,
+            convergence: convergence,
+        };
+    }
+
+    fn seen_by(eye: &PerspectiveCamera, point: &Vec3) -> (f64, f64) {
+        return eye.project(&eye.get_origin(), point).unwrap();
+    }
+
+    #[test]
+    fn off_axis_eyes_agree_at_the_convergence_distance_without_vertical_parallax() {
+        let (left, right) = rig(StereoConvergence::OffAxis).eyes();
+        assert!(((&right.get_origin() - &left.get_origin()).length_squared().sqrt() - 0.065f64).abs() < 1e-12f64);
+        // a point off to the side and up on the zero parallax plane, one beyond it and one in front of it
+        let on_screen = seen_by(&left, &Vec3::new(0.4f64, 1.3f64, -3f64));
+        assert!((on_screen.0 - seen_by(&right, &Vec3::new(0.4f64, 1.3f64, -3f64)).0).abs() < 1e-9f64);
+        for &depth in [1.5f64, 3f64, 10f64].iter() {
+            let point = Vec3::new(0.4f64, 1.3f64, -depth);
+            let (left_s, left_t) = seen_by(&left, &point);
+            let (right_s, right_t) = seen_by(&right, &point);
+            assert!((left_t - right_t).abs() < 1e-9f64, "vertical parallax at {}", depth);
+            // behind the screen the right eye sees things further right, in front of it further left
+            let parallax = right_s - left_s;
+            if depth > 3f64 {
+                assert!(parallax > 1e-4f64);
+            } else if depth < 3f64 {
+                assert!(parallax < -1e-4f64);
+            }
+        }
+    }
+
+    #[test]
+    fn toe_in_eyes_look_at_the_convergence_point() {
+        let (left, right) = rig(StereoConvergence::ToeIn).eyes();
+        let convergence_point = Vec3::new(0f64, 1f64, -3f64);
+        for eye in [left, right].iter() {
+            let (s, t) = seen_by(eye, &convergence_point);
+            assert!((s - 0.5f64).abs() < 1e-9f64 && (t - 0.5f64).abs() < 1e-9f64);
+        }
+        // away from the center the turned image planes disagree vertically, the keystoning
+        let (left, right) = rig(StereoConvergence::ToeIn).eyes();
+        let corner = Vec3::new(0.8f64, 1.6f64, -3f64);
+        assert!((seen_by(&left, &corner).1 - seen_by(&right, &corner).1).abs() > 1e-5f64);
+    }
+
+    #[test]
+    fn omni_stereo_eyes_look_the_same_way_from_either_side() {
+        let pos = Vec3::new(0f64, 1f64, 0f64);
+        let up = Vec3::new(0f64, 1f64, 0f64);
+        let (left, right) = OmniStereoCamera::pair(&pos, &Vec3::new(0f64, 1f64, -1f64), &up, 0.065f64);
+        for &(s, t) in [(0.5f64, 0.5f64), (0.1f64, 0.4f64), (0.8f64, 0.75f64)].iter() {
+            let left_ray = left.get_ray(s, t);
+            let right_ray = right.get_ray(s, t);
+            assert!((left_ray.dir.normalize().dot(&right_ray.dir.normalize()) - 1f64).abs() < 1e-12f64);
+            // the eyes sit across the view, level, and closer together away from the horizon
+            let baseline = &right_ray.origin - &left_ray.origin;
+            let cos_latitude = ((t - 0.5f64) * consts::PI).cos();
+            assert!((baseline.length_squared().sqrt() - 0.065f64 * cos_latitude).abs() < 1e-12f64);
+            assert!(baseline.dot(&left_ray.dir).abs() < 1e-12f64 && baseline.y.abs() < 1e-12f64);
+            // the right eye is on the right, dir x up points there
+            assert!(baseline.dot(&left_ray.dir.cross(&up)) > 0f64);
+        }
+    }
+
+    fn filled(width: usize, height: usize, value: i32) -> RenderBufferI32 {
+        let mut buffer = RenderBufferI32::new(width, height);
+        buffer.buffer = vec![value; width * height * 3];
+        return buffer;
+    }
+
+    #[test]
+    fn combine_lays_the_eyes_out() {
+        let left = filled(2, 2, 1);
+        let right = filled(2, 2, 2);
+        let side_by_side = combine(&left, &right, StereoLayout::SideBySide).unwrap();
+        assert_eq!((side_by_side.width, side_by_side.height), (4, 2));
+        assert_eq!(side_by_side.buffer, vec![1, 1, 1, 1, 1, 1, 2, 2, 2, 2, 2, 2, 1, 1, 1, 1, 1, 1, 2, 2, 2, 2, 2, 2]);
+        let top_bottom = combine(&left, &right, StereoLayout::TopBottom).unwrap();
+        assert_eq!((top_bottom.width, top_bottom.height), (2, 4));
+        assert_eq!(&top_bottom.buffer[..12], &left.buffer[..]);
+        assert_eq!(&top_bottom.buffer[12..], &right.buffer[..]);
+        assert!(combine(&left, &right, StereoLayout::Separate).is_none());
+    }
+}
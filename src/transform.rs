@@ -0,0 +1,581 @@
+extern crate rusty_math;
+
+use rusty_math::*;
+use std::f64::consts;
+
+use bvh::Aabb;
+
+// row major 4x4, points are treated as column vectors (M * p)
+#[derive(Clone, Copy)]
+pub struct Matrix4 {
+    pub m: [[f64; 4]; 4],
+}
+
+impl Matrix4 {
+    pub fn identity() -> Matrix4 {
+        return Matrix4 {
+            m: [
+                [1f64, 0f64, 0f64, 0f64],
+                [0f64, 1f64, 0f64, 0f64],
+                [0f64, 0f64, 1f64, 0f64],
+                [0f64, 0f64, 0f64, 1f64],
+            ],
+        };
+    }
+
+    pub fn mul(&self, other: &Matrix4) -> Matrix4 {
+        let mut result = [[0f64; 4]; 4];
+        for row in 0..4 {
+            for col in 0..4 {
+                let mut sum = 0f64;
+                for k in 0..4 {
+                    sum += self.m[row][k] * other.m[k][col];
+                }
+                result[row][col] = sum;
+            }
+        }
+        return Matrix4 { m: result };
+    }
+
+    // gauss-jordan with partial pivoting, None for singular matrices
+    pub fn inverse(&self) -> Option<Matrix4> {
+        let mut a = self.m;
+        let mut inv = Matrix4::identity().m;
+        for col in 0..4 {
+            let mut pivot = col;
+            for row in (col + 1)..4 {
+                if a[row][col].abs() > a[pivot][col].abs() {
+                    pivot = row;
+                }
+            }
+            if a[pivot][col].abs() < 1e-12f64 {
+                return None;
+            }
+            a.swap(col, pivot);
+            inv.swap(col, pivot);
+
+            let scale = 1f64 / a[col][col];
+            for k in 0..4 {
+                a[col][k] *= scale;
+                inv[col][k] *= scale;
+            }
+            for row in 0..4 {
+                if row == col {
+                    continue;
+                }
+                let factor = a[row][col];
+                for k in 0..4 {
+                    a[row][k] -= factor * a[col][k];
+                    inv[row][k] -= factor * inv[col][k];
+                }
+            }
+        }
+        return Some(Matrix4 { m: inv });
+    }
+
+    pub fn transpose(&self) -> Matrix4 {
+        let mut result = [[0f64; 4]; 4];
+        for row in 0..4 {
+            for col in 0..4 {
+                result[row][col] = self.m[col][row];
+            }
+        }
+        return Matrix4 { m: result };
+    }
+}
+
+// keeps the inverse around so we never have to invert at render time
+#[derive(Clone, Copy)]
+pub struct Transform {
+    pub matrix: Matrix4,
+    pub inverse: Matrix4,
+}
+
+impl Transform {
+    pub fn translate(offset: &Vec3) -> Transform {
+        let mut matrix = Matrix4::identity();
+        let mut inverse = Matrix4::identity();
+        matrix.m[0][3] = offset.x;
+        matrix.m[1][3] = offset.y;
+        matrix.m[2][3] = offset.z;
+        inverse.m[0][3] = -offset.x;
+        inverse.m[1][3] = -offset.y;
+        inverse.m[2][3] = -offset.z;
+        return Transform { matrix: matrix, inverse: inverse };
+    }
+
+    // a zero factor can't be undone, that is a mistake in the scene rather than something to render
+    pub fn scale(factors: &Vec3) -> Transform {
+        let mut matrix = Matrix4::identity();
+        matrix.m[0][0] = factors.x;
+        matrix.m[1][1] = factors.y;
+        matrix.m[2][2] = factors.z;
+        return match Transform::new(matrix) {
+            Some(transform) => transform,
+            None => panic!("can't scale by {}, {}, {}", factors.x, factors.y, factors.z),
+        };
+    }
+
+    pub fn uniform_scale(factor: f64) -> Transform {
+        return Transform::scale(&Vec3::new(factor, factor, factor));
+    }
+
+    // rotation about an arbitrary axis (Rodrigues), the inverse of a rotation is its transpose
+    pub fn rotate(axis: &Vec3, angle_degrees: f64) -> Transform {
+        let a = axis.normalize();
+        let theta_rads = angle_degrees * consts::PI / 180f64;
+        let sin_theta = theta_rads.sin();
+        let cos_theta = theta_rads.cos();
+
+        let mut matrix = Matrix4::identity();
+        matrix.m[0][0] = a.x * a.x + (1f64 - a.x * a.x) * cos_theta;
+        matrix.m[0][1] = a.x * a.y * (1f64 - cos_theta) - a.z * sin_theta;
+        matrix.m[0][2] = a.x * a.z * (1f64 - cos_theta) + a.y * sin_theta;
+
+        matrix.m[1][0] = a.x * a.y * (1f64 - cos_theta) + a.z * sin_theta;
+        matrix.m[1][1] = a.y * a.y + (1f64 - a.y * a.y) * cos_theta;
+        matrix.m[1][2] = a.y * a.z * (1f64 - cos_theta) - a.x * sin_theta;
+
+        matrix.m[2][0] = a.x * a.z * (1f64 - cos_theta) - a.y * sin_theta;
+        matrix.m[2][1] = a.y * a.z * (1f64 - cos_theta) + a.x * sin_theta;
+        matrix.m[2][2] = a.z * a.z + (1f64 - a.z * a.z) * cos_theta;
+
+        return Transform { matrix: matrix, inverse: matrix.transpose() };
+    }
+
+    // apply self first, then other
+    pub fn then(&self, other: &Transform) -> Transform {
+        return Transform {
+            matrix: other.matrix.mul(&self.matrix),
+            inverse: self.inverse.mul(&other.inverse),
+        };
+    }
+
+    // None when the matrix can't be inverted, a zero scale say. it would flatten whatever it places
+    pub fn new(matrix: Matrix4) -> Option<Transform> {
+        return match matrix.inverse() {
+            Some(inverse) => Some(Transform { matrix: matrix, inverse: inverse }),
+            None => None,
+        };
+    }
+
+    pub fn transform_point(&self, p: &Vec3) -> Vec3 {
+        return apply_point(&self.matrix, p);
+    }
+
+    // normals go through the inverse transpose to stay perpendicular under non-uniform scale
+    pub fn transform_normal(&self, n: &Vec3) -> Vec3 {
+        let m = &self.inverse.m;
+        return Vec3::new(
+            m[0][0] * n.x + m[1][0] * n.y + m[2][0] * n.z,
+            m[0][1] * n.x + m[1][1] * n.y + m[2][1] * n.z,
+            m[0][2] * n.x + m[1][2] * n.y + m[2][2] * n.z,
+        );
+    }
+
+    pub fn inverse_transform_point(&self, p: &Vec3) -> Vec3 {
+        return apply_point(&self.inverse, p);
+    }
+
+    pub fn inverse_transform_vector(&self, v: &Vec3) -> Vec3 {
+        return apply_vector(&self.inverse, v);
+    }
+
+    // the direction is not renormalized so hit times stay valid in both spaces
+    pub fn inverse_transform_ray(&self, ray: &Ray) -> Ray {
+        return Ray::new(
+            self.inverse_transform_point(&ray.origin),
+            self.inverse_transform_vector(&ray.dir),
+        );
+    }
+
+    pub fn transform_bounds(&self, bounds: &Aabb) -> Aabb {
+        let mut result = Aabb::empty();
+        for corner in 0..8 {
+            let p = Vec3::new(
+                if corner & 1 == 0 { bounds.min.x } else { bounds.max.x },
+                if corner & 2 == 0 { bounds.min.y } else { bounds.max.y },
+                if corner & 4 == 0 { bounds.min.z } else { bounds.max.z },
+            );
+            result.grow(&self.transform_point(&p));
+        }
+        return result;
+    }
+}
+
+fn apply_point(m: &Matrix4, p: &Vec3) -> Vec3 {
+    let m = &m.m;
+    let x = m[0][0] * p.x + m[0][1] * p.y + m[0][2] * p.z + m[0][3];
+    let y = m[1][0] * p.x + m[1][1] * p.y + m[1][2] * p.z + m[1][3];
+    let z = m[2][0] * p.x + m[2][1] * p.y + m[2][2] * p.z + m[2][3];
+    let w = m[3][0] * p.x + m[3][1] * p.y + m[3][2] * p.z + m[3][3];
+    if w == 1f64 {
+        return Vec3::new(x, y, z);
+    }
+    return Vec3::new(x / w, y / w, z / w);
+}
+
+fn apply_vector(m: &Matrix4, v: &Vec3) -> Vec3 {
+    let m = &m.m;
+    return Vec3::new(
+        m[0][0] * v.x + m[0][1] * v.y + m[0][2] * v.z,
+        m[1][0] * v.x + m[1][1] * v.y + m[1][2] * v.z,
+        m[2][0] * v.x + m[2][1] * v.y + m[2][2] * v.z,
+    );
+}
+
+//
+// Quaternion
+//
+#[derive(Clone, Copy)]
+pub struct Quaternion {
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+    pub w: f64,
+}
+
+impl Quaternion {
+    // expects a pure rotation in the upper 3x3
+    pub fn from_matrix(matrix: &Matrix4) -> Quaternion {
+        let m = &matrix.m;
+        let trace = m[0][0] + m[1][1] + m[2][2];
+        if trace > 0f64 {
+            let s = (trace + 1f64).sqrt() * 2f64;
+            return Quaternion {
+                w: 0.25f64 * s,
+                x: (m[2][1] - m[1][2]) / s,
+                y: (m[0][2] - m[2][0]) / s,
+                z: (m[1][0] - m[0][1]) / s,
+            };
+        }
+        if m[0][0] > m[1][1] && m[0][0] > m[2][2] {
+            let s = (1f64 + m[0][0] - m[1][1] - m[2][2]).sqrt() * 2f64;
+            return Quaternion {
+                w: (m[2][1] - m[1][2]) / s,
+                x: 0.25f64 * s,
+                y: (m[0][1] + m[1][0]) / s,
+                z: (m[0][2] + m[2][0]) / s,
+            };
+        }
+        if m[1][1] > m[2][2] {
+            let s = (1f64 + m[1][1] - m[0][0] - m[2][2]).sqrt() * 2f64;
+            return Quaternion {
+                w: (m[0][2] - m[2][0]) / s,
+                x: (m[0][1] + m[1][0]) / s,
+                y: 0.25f64 * s,
+                z: (m[1][2] + m[2][1]) / s,
+            };
+        }
+        let s = (1f64 + m[2][2] - m[0][0] - m[1][1]).sqrt() * 2f64;
+        return Quaternion {
+            w: (m[1][0] - m[0][1]) / s,
+            x: (m[0][2] + m[2][0]) / s,
+            y: (m[1][2] + m[2][1]) / s,
+            z: 0.25f64 * s,
+        };
+    }
+
+    pub fn to_matrix(&self) -> Matrix4 {
+        let (x, y, z, w) = (self.x, self.y, self.z, self.w);
+        let mut matrix = Matrix4::identity();
+        matrix.m[0][0] = 1f64 - 2f64 * (y * y + z * z);
+        matrix.m[0][1] = 2f64 * (x * y - z * w);
+        matrix.m[0][2] = 2f64 * (x * z + y * w);
+        matrix.m[1][0] = 2f64 * (x * y + z * w);
+        matrix.m[1][1] = 1f64 - 2f64 * (x * x + z * z);
+        matrix.m[1][2] = 2f64 * (y * z - x * w);
+        matrix.m[2][0] = 2f64 * (x * z - y * w);
+        matrix.m[2][1] = 2f64 * (y * z + x * w);
+        matrix.m[2][2] = 1f64 - 2f64 * (x * x + y * y);
+        return matrix;
+    }
+
+    pub fn dot(&self, other: &Quaternion) -> f64 {
+        return self.x * other.x + self.y * other.y + self.z * other.z + self.w * other.w;
+    }
+
+    pub fn normalize(&self) -> Quaternion {
+        let length = self.dot(self).sqrt();
+        return Quaternion {
+            x: self.x / length,
+            y: self.y / length,
+            z: self.z / length,
+            w: self.w / length,
+        };
+    }
+
+    // shortest arc, falls back to nlerp when the rotations are nearly identical
+    pub fn slerp(&self, other: &Quaternion, t: f64) -> Quaternion {
+        let mut end = *other;
+        let mut cos_theta = self.dot(other);
+        if cos_theta < 0f64 {
+            end = Quaternion { x: -end.x, y: -end.y, z: -end.z, w: -end.w };
+            cos_theta = -cos_theta;
+        }
+        let (a, b) = if cos_theta > 0.9995f64 {
+            (1f64 - t, t)
+        } else {
+            let theta = cos_theta.acos();
+            let sin_theta = theta.sin();
+            (((1f64 - t) * theta).sin() / sin_theta, (t * theta).sin() / sin_theta)
+        };
+        return Quaternion {
+            x: a * self.x + b * end.x,
+            y: a * self.y + b * end.y,
+            z: a * self.z + b * end.z,
+            w: a * self.w + b * end.w,
+        }.normalize();
+    }
+}
+
+//
+// AnimatedTransform
+//
+#[derive(Clone, Copy, PartialEq)]
+pub enum TransformInterpolation {
+    // interpolate matrix elements, cheap but shears and shrinks through rotations
+    Linear,
+    // split into translation, rotation and scale and interpolate those separately
+    Decomposed,
+}
+
+#[derive(Clone, Copy)]
+struct Decomposition {
+    translation: [f64; 3],
+    rotation: Quaternion,
+    scale: Matrix4,
+}
+
+// polar decomposition M = T R S, see Shoemake and Duff "Matrix Animation and Polar Decomposition"
+// the matrix has to be invertible, which the matrix of every Transform is
+fn decompose(matrix: &Matrix4) -> Decomposition {
+    let translation = [matrix.m[0][3], matrix.m[1][3], matrix.m[2][3]];
+
+    let mut m = *matrix;
+    for i in 0..3 {
+        m.m[i][3] = 0f64;
+        m.m[3][i] = 0f64;
+    }
+    m.m[3][3] = 1f64;
+
+    // average with the inverse transpose until we converge on the rotation
+    let mut r = m;
+    for _ in 0..100 {
+        let r_it = r.inverse().expect("the rotation of an invertible matrix is invertible").transpose();
+        let mut next = r;
+        let mut norm = 0f64;
+        for row in 0..3 {
+            let mut row_sum = 0f64;
+            for col in 0..3 {
+                next.m[row][col] = 0.5f64 * (r.m[row][col] + r_it.m[row][col]);
+                row_sum += (r.m[row][col] - next.m[row][col]).abs();
+            }
+            norm = norm.max(row_sum);
+        }
+        r = next;
+        if norm < 1e-10f64 {
+            break;
+        }
+    }
+
+    return Decomposition {
+        translation: translation,
+        rotation: Quaternion::from_matrix(&r),
+        scale: r.inverse().expect("the rotation of an invertible matrix is invertible").mul(&m),
+    };
+}
+
+// a transform that moves while the shutter is open
+#[derive(Clone, Copy)]
+pub struct AnimatedTransform {
+    pub start: Transform,
+    pub end: Transform,
+    pub start_time: f64,
+    pub end_time: f64,
+    pub interpolation: TransformInterpolation,
+    m_start_decomposed: Decomposition,
+    m_end_decomposed: Decomposition,
+    m_is_animated: bool,
+}
+
+// number of sub-steps used to bound rotating motion
+static MOTION_BOUNDS_STEPS: usize = 32;
+
+impl AnimatedTransform {
+    pub fn new(
+        start: Transform,
+        start_time: f64,
+        end: Transform,
+        end_time: f64,
+        interpolation: TransformInterpolation
+    ) -> AnimatedTransform {
+        let mut is_animated = false;
+        for row in 0..4 {
+            for col in 0..4 {
+                if start.matrix.m[row][col] != end.matrix.m[row][col] {
+                    is_animated = true;
+                }
+            }
+        }
+        return AnimatedTransform {
+            start: start,
+            end: end,
+            start_time: start_time,
+            end_time: end_time,
+            interpolation: interpolation,
+            m_start_decomposed: decompose(&start.matrix),
+            m_end_decomposed: decompose(&end.matrix),
+            m_is_animated: is_animated,
+        };
+    }
+
+    pub fn fixed(transform: Transform) -> AnimatedTransform {
+        return AnimatedTransform::new(transform, 0f64, transform, 1f64, TransformInterpolation::Linear);
+    }
+
+    pub fn interpolate(&self, shutter_time: f64) -> Transform {
+        if !self.m_is_animated || shutter_time <= self.start_time {
+            return self.start;
+        }
+        if shutter_time >= self.end_time {
+            return self.end;
+        }
+        let t = (shutter_time - self.start_time) / (self.end_time - self.start_time);
+
+        match self.interpolation {
+            TransformInterpolation::Linear => {
+                let mut matrix = Matrix4::identity();
+                for row in 0..4 {
+                    for col in 0..4 {
+                        matrix.m[row][col] = (1f64 - t) * self.start.matrix.m[row][col] + t * self.end.matrix.m[row][col];
+                    }
+                }
+                // blending matrices can pass through a singular one (a half turn collapses to a plane),
+                // the nearer end stands in for that instant
+                return match Transform::new(matrix) {
+                    Some(transform) => transform,
+                    None => if t < 0.5f64 { self.start } else { self.end },
+                };
+            }
+            TransformInterpolation::Decomposed => {
+                let a = &self.m_start_decomposed;
+                let b = &self.m_end_decomposed;
+
+                let mut translation = Matrix4::identity();
+                for i in 0..3 {
+                    translation.m[i][3] = (1f64 - t) * a.translation[i] + t * b.translation[i];
+                }
+                let rotation = a.rotation.slerp(&b.rotation, t).to_matrix();
+                let mut scale = Matrix4::identity();
+                for row in 0..3 {
+                    for col in 0..3 {
+                        scale.m[row][col] = (1f64 - t) * a.scale.m[row][col] + t * b.scale.m[row][col];
+                    }
+                }
+                return match Transform::new(translation.mul(&rotation).mul(&scale)) {
+                    Some(transform) => transform,
+                    None => if t < 0.5f64 { self.start } else { self.end },
+                };
+            }
+        }
+    }
+
+    // linear motion is bounded by its end points, decomposed motion can swing outside them so we sample it
+    pub fn transform_bounds(&self, bounds: &Aabb) -> Aabb {
+        let start_bounds = self.start.transform_bounds(bounds);
+        if !self.m_is_animated {
+            return start_bounds;
+        }
+        let mut result = start_bounds.union(&self.end.transform_bounds(bounds));
+        if self.interpolation == TransformInterpolation::Decomposed {
+            for step in 1..MOTION_BOUNDS_STEPS {
+                let t = step as f64 / MOTION_BOUNDS_STEPS as f64;
+                let shutter_time = self.start_time + t * (self.end_time - self.start_time);
+                result = result.union(&self.interpolate(shutter_time).transform_bounds(bounds));
+            }
+            // pad for the arc between samples
+            let pad = &result.extent() * (0.5f64 / MOTION_BOUNDS_STEPS as f64);
+            result = Aabb::new(&result.min - &pad, &result.max + &pad);
+        }
+        return result;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_near(a: &Vec3, b: &Vec3) {
+        assert!((a - b).length_squared() < 1e-18f64, "{} {} {} != {} {} {}", a.x, a.y, a.z, b.x, b.y, b.z);
+    }
+
+    fn placement() -> Transform {
+        return Transform::scale(&Vec3::new(2f64, 0.5f64, 3f64))
+            .then(&Transform::rotate(&Vec3::new(1f64, 1f64, 0f64), 35f64))
+            .then(&Transform::translate(&Vec3::new(1f64, -2f64, 4f64)));
+    }
+
+    #[test]
+    fn inverse_undoes_the_matrix() {
+        let transform = placement();
+        let inverse = transform.matrix.inverse().unwrap();
+        let product = transform.matrix.mul(&inverse);
+        for row in 0..4 {
+            for col in 0..4 {
+                let expected = if row == col { 1f64 } else { 0f64 };
+                assert!((product.m[row][col] - expected).abs() < 1e-12f64);
+            }
+        }
+        let p = Vec3::new(0.3f64, -1.2f64, 5f64);
+        assert_near(&transform.inverse_transform_point(&transform.transform_point(&p)), &p);
+    }
+
+    #[test]
+    fn singular_matrices_have_no_inverse() {
+        let mut flat = Matrix4::identity();
+        flat.m[1][1] = 0f64;
+        assert!(flat.inverse().is_none());
+        assert!(Transform::new(flat).is_none());
+        assert!(Transform::new(placement().matrix).is_some());
+    }
+
+    #[test]
+    #[should_panic]
+    fn scaling_by_zero_is_refused() {
+        Transform::scale(&Vec3::new(1f64, 0f64, 1f64));
+    }
+
+    #[test]
+    fn normals_stay_perpendicular_under_non_uniform_scale() {
+        let transform = placement();
+        // a plane through the origin with normal n contains the tangents t
+        let n = Vec3::new(1f64, 1f64, 0f64);
+        let t = Vec3::new(1f64, -1f64, 0.5f64);
+        let tangent = &transform.transform_point(&t) - &transform.transform_point(&Vec3::new(0f64, 0f64, 0f64));
+        assert!(transform.transform_normal(&n).dot(&tangent).abs() < 1e-12f64);
+    }
+
+    #[test]
+    fn decomposed_motion_turns_through_the_middle() {
+        let start = Transform::rotate(&Vec3::new(0f64, 1f64, 0f64), 0f64);
+        let end = Transform::rotate(&Vec3::new(0f64, 1f64, 0f64), 90f64);
+        let motion = AnimatedTransform::new(start, 0f64, end, 1f64, TransformInterpolation::Decomposed);
+        let point = Vec3::new(1f64, 0f64, 0f64);
+        assert_near(&motion.interpolate(0f64).transform_point(&point), &Vec3::new(1f64, 0f64, 0f64));
+        assert_near(&motion.interpolate(1f64).transform_point(&point), &Vec3::new(0f64, 0f64, -1f64));
+        // on the arc, not the chord
+        let half = 0.5f64.sqrt();
+        assert_near(&motion.interpolate(0.5f64).transform_point(&point), &Vec3::new(half, 0f64, -half));
+    }
+
+    #[test]
+    fn motion_bounds_cover_the_arc() {
+        let start = Transform::rotate(&Vec3::new(0f64, 1f64, 0f64), 0f64);
+        let end = Transform::rotate(&Vec3::new(0f64, 1f64, 0f64), 120f64);
+        let motion = AnimatedTransform::new(start, 0f64, end, 1f64, TransformInterpolation::Decomposed);
+        let point = Aabb::new(Vec3::new(1f64, 0f64, 0f64), Vec3::new(1f64, 0f64, 0f64));
+        // a quarter of the way round the point is at z = -1, outside the box around both ends
+        assert!(motion.transform_bounds(&point).min.z <= -0.99f64);
+    }
+}
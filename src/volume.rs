@@ -0,0 +1,723 @@
+extern crate rusty_math;
+
+use rusty_math::*;
+use std::collections::HashMap;
+use std::f64;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+
+use bvh::Aabb;
+use spectrum;
+use sampler;
+
+//
+// voxel grids
+//
+// both file formats share a little endian header:
+//   magic[4] ("DGRD" dense, "SBRK" sparse bricks), u32 version (1),
+//   u32 nx, ny, nz, u32 channels (1 density, 2 density + temperature in kelvin),
+//   f32 bounds min xyz, f32 bounds max xyz
+// dense: nx * ny * nz voxels follow, x fastest, channels interleaved per voxel
+// sparse: u32 brick_size, u32 brick_count, then per brick u32 bx, by, bz (brick coordinates)
+//   and brick_size^3 voxels laid out like the dense grid, bricks that are missing are empty
+pub trait VoxelGrid: Sync {
+    fn resolution(&self) -> (usize, usize, usize);
+    fn channels(&self) -> usize;
+    fn voxel(&self, x: usize, y: usize, z: usize, channel: usize) -> f32;
+}
+
+pub struct DenseGrid {
+    pub nx: usize,
+    pub ny: usize,
+    pub nz: usize,
+    pub channels: usize,
+    pub data: Vec<f32>,
+}
+
+impl DenseGrid {
+    // handy for procedural volumes, f returns one value per channel at normalized coordinates
+    pub fn from_fn<F>(nx: usize, ny: usize, nz: usize, channels: usize, f: F) -> DenseGrid
+        where F: Fn(f64, f64, f64) -> Vec<f32>
+    {
+        let mut data = Vec::with_capacity(nx * ny * nz * channels);
+        for z in 0..nz {
+            for y in 0..ny {
+                for x in 0..nx {
+                    let values = f(
+                        (x as f64 + 0.5f64) / nx as f64,
+                        (y as f64 + 0.5f64) / ny as f64,
+                        (z as f64 + 0.5f64) / nz as f64
+                    );
+                    for channel in 0..channels {
+                        data.push(values[channel]);
+                    }
+                }
+            }
+        }
+        return DenseGrid { nx: nx, ny: ny, nz: nz, channels: channels, data: data };
+    }
+
+    pub fn save(&self, path: &Path, bounds: &Aabb) -> io::Result<()> {
+        let mut bytes: Vec<u8> = Vec::with_capacity(48 + self.data.len() * 4);
+        bytes.extend_from_slice(b"DGRD");
+        write_header(&mut bytes, self.nx, self.ny, self.nz, self.channels, bounds);
+        for value in self.data.iter() {
+            push_f32(&mut bytes, *value);
+        }
+        let mut file = File::create(path)?;
+        return file.write_all(&bytes);
+    }
+}
+
+impl VoxelGrid for DenseGrid {
+    fn resolution(&self) -> (usize, usize, usize) {
+        return (self.nx, self.ny, self.nz);
+    }
+
+    fn channels(&self) -> usize {
+        return self.channels;
+    }
+
+    fn voxel(&self, x: usize, y: usize, z: usize, channel: usize) -> f32 {
+        return self.data[((z * self.ny + y) * self.nx + x) * self.channels + channel];
+    }
+}
+
+pub struct SparseBrickGrid {
+    pub nx: usize,
+    pub ny: usize,
+    pub nz: usize,
+    pub channels: usize,
+    pub brick_size: usize,
+    m_bricks: HashMap<(usize, usize, usize), usize>,
+    m_data: Vec<f32>,
+}
+
+impl SparseBrickGrid {
+    // keeps only the bricks of grid that have some density in them
+    pub fn from_dense(grid: &DenseGrid, brick_size: usize) -> SparseBrickGrid {
+        let bricks_along = |n: usize| (n + brick_size - 1) / brick_size;
+        let mut bricks = HashMap::new();
+        let mut data = Vec::new();
+        for bz in 0..bricks_along(grid.nz) {
+            for by in 0..bricks_along(grid.ny) {
+                for bx in 0..bricks_along(grid.nx) {
+                    // voxels past the edge of the grid pad the brick with zeros
+                    let mut values = Vec::with_capacity(brick_size * brick_size * brick_size * grid.channels);
+                    let mut occupied = false;
+                    for z in (bz * brick_size)..((bz + 1) * brick_size) {
+                        for y in (by * brick_size)..((by + 1) * brick_size) {
+                            for x in (bx * brick_size)..((bx + 1) * brick_size) {
+                                let inside = x < grid.nx && y < grid.ny && z < grid.nz;
+                                for channel in 0..grid.channels {
+                                    let value = if inside { grid.voxel(x, y, z, channel) } else { 0f32 };
+                                    values.push(value);
+                                }
+                                occupied |= inside && grid.voxel(x, y, z, 0) > 0f32;
+                            }
+                        }
+                    }
+                    if occupied {
+                        bricks.insert((bx, by, bz), bricks.len());
+                        data.extend(values);
+                    }
+                }
+            }
+        }
+        return SparseBrickGrid {
+            nx: grid.nx,
+            ny: grid.ny,
+            nz: grid.nz,
+            channels: grid.channels,
+            brick_size: brick_size,
+            m_bricks: bricks,
+            m_data: data,
+        };
+    }
+
+    pub fn save(&self, path: &Path, bounds: &Aabb) -> io::Result<()> {
+        let mut bytes: Vec<u8> = Vec::with_capacity(56 + self.m_bricks.len() * 12 + self.m_data.len() * 4);
+        bytes.extend_from_slice(b"SBRK");
+        write_header(&mut bytes, self.nx, self.ny, self.nz, self.channels, bounds);
+        push_u32(&mut bytes, self.brick_size as u32);
+        push_u32(&mut bytes, self.m_bricks.len() as u32);
+        // in the order they sit in m_data, so the indices come back the same
+        let mut bricks: Vec<(&(usize, usize, usize), &usize)> = self.m_bricks.iter().collect();
+        bricks.sort_by_key(|entry| *entry.1);
+        let brick_values = self.brick_size * self.brick_size * self.brick_size * self.channels;
+        for &(&(bx, by, bz), &brick) in bricks.iter() {
+            push_u32(&mut bytes, bx as u32);
+            push_u32(&mut bytes, by as u32);
+            push_u32(&mut bytes, bz as u32);
+            for value in self.m_data[brick * brick_values..(brick + 1) * brick_values].iter() {
+                push_f32(&mut bytes, *value);
+            }
+        }
+        let mut file = File::create(path)?;
+        return file.write_all(&bytes);
+    }
+}
+
+impl VoxelGrid for SparseBrickGrid {
+    fn resolution(&self) -> (usize, usize, usize) {
+        return (self.nx, self.ny, self.nz);
+    }
+
+    fn channels(&self) -> usize {
+        return self.channels;
+    }
+
+    fn voxel(&self, x: usize, y: usize, z: usize, channel: usize) -> f32 {
+        let b = self.brick_size;
+        return match self.m_bricks.get(&(x / b, y / b, z / b)) {
+            None => 0f32,
+            Some(&brick) => {
+                let local = ((z % b) * b + (y % b)) * b + (x % b);
+                self.m_data[(brick * b * b * b + local) * self.channels + channel]
+            }
+        };
+    }
+}
+
+fn push_u32(bytes: &mut Vec<u8>, value: u32) {
+    for i in 0..4 {
+        bytes.push(((value >> (8 * i)) & 0xff) as u8);
+    }
+}
+
+fn push_f32(bytes: &mut Vec<u8>, value: f32) {
+    push_u32(bytes, value.to_bits());
+}
+
+fn write_header(bytes: &mut Vec<u8>, nx: usize, ny: usize, nz: usize, channels: usize, bounds: &Aabb) {
+    push_u32(bytes, 1);
+    push_u32(bytes, nx as u32);
+    push_u32(bytes, ny as u32);
+    push_u32(bytes, nz as u32);
+    push_u32(bytes, channels as u32);
+    for v in [&bounds.min, &bounds.max].iter() {
+        push_f32(bytes, v.x as f32);
+        push_f32(bytes, v.y as f32);
+        push_f32(bytes, v.z as f32);
+    }
+}
+
+struct ByteReader<'b> {
+    bytes: &'b [u8],
+    offset: usize,
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    return io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+}
+
+impl<'b> ByteReader<'b> {
+    fn read_u32(&mut self) -> io::Result<u32> {
+        if self.offset + 4 > self.bytes.len() {
+            return Err(invalid_data("unexpected end of grid file"));
+        }
+        let b = &self.bytes[self.offset..self.offset + 4];
+        self.offset += 4;
+        return Ok((b[0] as u32) | ((b[1] as u32) << 8) | ((b[2] as u32) << 16) | ((b[3] as u32) << 24));
+    }
+
+    fn read_f32(&mut self) -> io::Result<f32> {
+        return Ok(f32::from_bits(self.read_u32()?));
+    }
+
+    // fails unless the file holds at least bytes more, None being a size too big to count. checked before
+    // allocating what the header asks for, a broken header could ask for anything
+    fn expect(&self, bytes: Option<usize>) -> io::Result<()> {
+        return match bytes {
+            Some(bytes) if bytes <= self.bytes.len() - self.offset => Ok(()),
+            _ => Err(invalid_data("grid file shorter than its header says")),
+        };
+    }
+}
+
+// None when the product overflows
+fn checked_product(factors: &[usize]) -> Option<usize> {
+    return factors.iter().try_fold(1usize, |product, factor| product.checked_mul(*factor));
+}
+
+// loads either grid format, returns the grid with the world space box it fills
+pub fn load_grid(path: &Path) -> io::Result<(Box<VoxelGrid>, Aabb)> {
+    let mut bytes = Vec::new();
+    File::open(path)?.read_to_end(&mut bytes)?;
+    if bytes.len() < 4 {
+        return Err(invalid_data("grid file too short"));
+    }
+    let magic = [bytes[0], bytes[1], bytes[2], bytes[3]];
+    let mut reader = ByteReader { bytes: &bytes, offset: 4 };
+
+    if reader.read_u32()? != 1 {
+        return Err(invalid_data("unsupported grid version"));
+    }
+    let nx = reader.read_u32()? as usize;
+    let ny = reader.read_u32()? as usize;
+    let nz = reader.read_u32()? as usize;
+    let channels = reader.read_u32()? as usize;
+    if nx == 0 || ny == 0 || nz == 0 || channels == 0 || channels > 2 {
+        return Err(invalid_data("bad grid resolution or channel count"));
+    }
+    let mut corners = [0f64; 6];
+    for i in 0..6 {
+        corners[i] = reader.read_f32()? as f64;
+    }
+    let bounds = Aabb::new(
+        Vec3::new(corners[0], corners[1], corners[2]),
+        Vec3::new(corners[3], corners[4], corners[5]),
+    );
+
+    if &magic == b"DGRD" {
+        reader.expect(checked_product(&[nx, ny, nz, channels, 4]))?;
+        let count = nx * ny * nz * channels;
+        let mut data = Vec::with_capacity(count);
+        for _ in 0..count {
+            data.push(reader.read_f32()?);
+        }
+        let grid = DenseGrid { nx: nx, ny: ny, nz: nz, channels: channels, data: data };
+        return Ok((Box::new(grid), bounds));
+    }
+
+    if &magic == b"SBRK" {
+        let brick_size = reader.read_u32()? as usize;
+        let brick_count = reader.read_u32()? as usize;
+        if brick_size == 0 {
+            return Err(invalid_data("bad brick size"));
+        }
+        let brick_values = match checked_product(&[brick_size, brick_size, brick_size, channels]) {
+            Some(brick_values) => brick_values,
+            None => return Err(invalid_data("bad brick size")),
+        };
+        // three coordinates and the values for every brick
+        let brick_bytes = brick_values.checked_mul(4).and_then(|bytes| bytes.checked_add(12));
+        reader.expect(brick_bytes.and_then(|bytes| bytes.checked_mul(brick_count)))?;
+        let mut bricks = HashMap::new();
+        let mut data = Vec::with_capacity(brick_count * brick_values);
+        for brick in 0..brick_count {
+            let bx = reader.read_u32()? as usize;
+            let by = reader.read_u32()? as usize;
+            let bz = reader.read_u32()? as usize;
+            if bx * brick_size >= nx || by * brick_size >= ny || bz * brick_size >= nz {
+                return Err(invalid_data("brick outside of the grid"));
+            }
+            bricks.insert((bx, by, bz), brick);
+            for _ in 0..brick_values {
+                data.push(reader.read_f32()?);
+            }
+        }
+        let grid = SparseBrickGrid {
+            nx: nx,
+            ny: ny,
+            nz: nz,
+            channels: channels,
+            brick_size: brick_size,
+            m_bricks: bricks,
+            m_data: data,
+        };
+        return Ok((Box::new(grid), bounds));
+    }
+
+    return Err(invalid_data("unknown grid format"));
+}
+
+//
+// MajorantGrid
+//
+// coarse upper bounds on density so delta tracking can take long steps through thin regions
+static MAJORANT_CELL_VOXELS: usize = 8;
+
+struct MajorantGrid {
+    nx: usize,
+    ny: usize,
+    nz: usize,
+    values: Vec<f64>,
+}
+
+impl MajorantGrid {
+    fn build(grid: &VoxelGrid) -> MajorantGrid {
+        let (gx, gy, gz) = grid.resolution();
+        let cells = |n: usize| (n + MAJORANT_CELL_VOXELS - 1) / MAJORANT_CELL_VOXELS;
+        let (nx, ny, nz) = (cells(gx), cells(gy), cells(gz));
+        let mut values = vec![0f64; nx * ny * nz];
+        for z in 0..gz {
+            for y in 0..gy {
+                for x in 0..gx {
+                    let density = grid.voxel(x, y, z, 0) as f64;
+                    if density <= 0f64 {
+                        continue;
+                    }
+                    // trilinear lookups reach one voxel over, so a voxel counts for every cell it touches
+                    for dz in 0..3 {
+                        for dy in 0..3 {
+                            for dx in 0..3 {
+                                let cx = ((x + dx).saturating_sub(1) / MAJORANT_CELL_VOXELS).min(nx - 1);
+                                let cy = ((y + dy).saturating_sub(1) / MAJORANT_CELL_VOXELS).min(ny - 1);
+                                let cz = ((z + dz).saturating_sub(1) / MAJORANT_CELL_VOXELS).min(nz - 1);
+                                let cell = &mut values[(cz * ny + cy) * nx + cx];
+                                if density > *cell {
+                                    *cell = density;
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        return MajorantGrid { nx: nx, ny: ny, nz: nz, values: values };
+    }
+}
+
+//
+// HeterogeneousVolume
+//
+pub struct VolumeCollision {
+    pub time: f64,
+    // collision estimate for emission, already weighted by the chance of absorbing
+    pub emission: Vec3,
+    // throughput multiplier for carrying on as a scatter
+    pub albedo: Vec3,
+    pub g: f64,
+}
+
+pub struct HeterogeneousVolume {
+    m_grid: Box<VoxelGrid>,
+    m_majorants: MajorantGrid,
+    pub bounds: Aabb,
+    pub density_scale: f64,
+    pub albedo: Vec3,
+    pub g: f64,
+    // blackbody emission from the temperature channel, zero turns it off
+    pub emission_scale: f64,
+    // temperature that emits with emission_scale luminance, brightness follows stefan-boltzmann from there
+    pub reference_temperature: f64,
+}
+
+impl HeterogeneousVolume {
+    pub fn new(grid: Box<VoxelGrid>, bounds: Aabb, density_scale: f64, albedo: Vec3, g: f64) -> HeterogeneousVolume {
+        let majorants = MajorantGrid::build(&*grid);
+        return HeterogeneousVolume {
+            m_grid: grid,
+            m_majorants: majorants,
+            bounds: bounds,
+            density_scale: density_scale,
+            albedo: albedo,
+            g: g,
+            emission_scale: 0f64,
+            reference_temperature: 1500f64,
+        };
+    }
+
+    pub fn set_blackbody(&mut self, emission_scale: f64, reference_temperature: f64) {
+        self.emission_scale = emission_scale;
+        self.reference_temperature = reference_temperature;
+    }
+
+    // trilinear lookup at a world space point, voxel centers sit at half integer grid coordinates
+    fn lookup(&self, p: &Vec3, channel: usize) -> f64 {
+        let (nx, ny, nz) = self.m_grid.resolution();
+        let extent = self.bounds.extent();
+        let gx = (p.x - self.bounds.min.x) / extent.x * nx as f64 - 0.5f64;
+        let gy = (p.y - self.bounds.min.y) / extent.y * ny as f64 - 0.5f64;
+        let gz = (p.z - self.bounds.min.z) / extent.z * nz as f64 - 0.5f64;
+
+        let (x0, fx) = split_coordinate(gx, nx);
+        let (y0, fy) = split_coordinate(gy, ny);
+        let (z0, fz) = split_coordinate(gz, nz);
+        let x1 = (x0 + 1).min(nx - 1);
+        let y1 = (y0 + 1).min(ny - 1);
+        let z1 = (z0 + 1).min(nz - 1);
+
+        let v = |x: usize, y: usize, z: usize| self.m_grid.voxel(x, y, z, channel) as f64;
+        let c00 = v(x0, y0, z0) * (1f64 - fx) + v(x1, y0, z0) * fx;
+        let c10 = v(x0, y1, z0) * (1f64 - fx) + v(x1, y1, z0) * fx;
+        let c01 = v(x0, y0, z1) * (1f64 - fx) + v(x1, y0, z1) * fx;
+        let c11 = v(x0, y1, z1) * (1f64 - fx) + v(x1, y1, z1) * fx;
+        let c0 = c00 * (1f64 - fy) + c10 * fy;
+        let c1 = c01 * (1f64 - fy) + c11 * fy;
+        return c0 * (1f64 - fz) + c1 * fz;
+    }
+
+    pub fn sigma_t(&self, p: &Vec3) -> f64 {
+        return self.lookup(p, 0).max(0f64) * self.density_scale;
+    }
+
+    pub fn emission(&self, p: &Vec3) -> Vec3 {
+        if self.emission_scale <= 0f64 || self.m_grid.channels() < 2 {
+            return Vec3::new(0f64, 0f64, 0f64);
+        }
+        let kelvin = self.lookup(p, 1);
+        if kelvin < 500f64 {
+            return Vec3::new(0f64, 0f64, 0f64);
+        }
+        let intensity = self.emission_scale * (kelvin / self.reference_temperature).powi(4);
+        return intensity * &spectrum::blackbody_rgb(kelvin);
+    }
+
+    // 3d dda over the majorant cells the ray crosses inside [time_min, time_max]
+    // visit gets (start, end, majorant sigma_t) per cell and returns false to stop early
+    fn walk_majorants<F>(&self, ray: &Ray, time_min: f64, time_max: f64, mut visit: F)
+        where F: FnMut(f64, f64, f64) -> bool
+    {
+        let (start, end) = match self.bounds.clip(ray, time_min, time_max) {
+            Some(range) => range,
+            None => return,
+        };
+        let m = &self.m_majorants;
+        let extent = self.bounds.extent();
+        let resolution = [m.nx, m.ny, m.nz];
+        let origin = [ray.origin.x - self.bounds.min.x, ray.origin.y - self.bounds.min.y, ray.origin.z - self.bounds.min.z];
+        let dir = [ray.dir.x, ray.dir.y, ray.dir.z];
+        // cells are MAJORANT_CELL_VOXELS voxels wide, the last one along an axis takes whatever is left over
+        let (gx, gy, gz) = self.m_grid.resolution();
+        let cell_voxels = MAJORANT_CELL_VOXELS as f64;
+        let cell_size = [extent.x * cell_voxels / gx as f64, extent.y * cell_voxels / gy as f64, extent.z * cell_voxels / gz as f64];
+
+        let mut cell = [0usize; 3];
+        let mut next_crossing = [f64::MAX; 3];
+        let mut delta = [f64::MAX; 3];
+        let mut step = [0i64; 3];
+        for axis in 0..3 {
+            let entry = origin[axis] + start * dir[axis];
+            let index = (entry / cell_size[axis]).floor().max(0f64) as usize;
+            cell[axis] = index.min(resolution[axis] - 1);
+            if dir[axis] > 0f64 {
+                step[axis] = 1;
+                next_crossing[axis] = ((cell[axis] + 1) as f64 * cell_size[axis] - origin[axis]) / dir[axis];
+                delta[axis] = cell_size[axis] / dir[axis];
+            } else if dir[axis] < 0f64 {
+                step[axis] = -1;
+                next_crossing[axis] = (cell[axis] as f64 * cell_size[axis] - origin[axis]) / dir[axis];
+                delta[axis] = -cell_size[axis] / dir[axis];
+            }
+        }
+
+        let mut t = start;
+        while t < end {
+            let axis = if next_crossing[0] < next_crossing[1] && next_crossing[0] < next_crossing[2] {
+                0
+            } else if next_crossing[1] < next_crossing[2] {
+                1
+            } else {
+                2
+            };
+            let cell_end = next_crossing[axis].min(end);
+            let majorant = m.values[(cell[2] * m.ny + cell[1]) * m.nx + cell[0]] * self.density_scale;
+            if cell_end > t && !visit(t, cell_end, majorant) {
+                return;
+            }
+            t = cell_end;
+
+            let next = cell[axis] as i64 + step[axis];
+            if next < 0 || next >= resolution[axis] as i64 {
+                return;
+            }
+            cell[axis] = next as usize;
+            next_crossing[axis] += delta[axis];
+        }
+    }
+
+    // delta tracking, returns the first real collision before time_max
+    pub fn sample_collision(&self, ray: &Ray, time_min: f64, time_max: f64) -> Option<VolumeCollision> {
+        let mut collision: Option<VolumeCollision> = None;
+        self.walk_majorants(ray, time_min, time_max, |start, end, majorant| {
+            if majorant <= 0f64 {
+                return true;
+            }
+            let mut t = start;
+            loop {
+                let u = sampler::next_f64();
+                t -= (1f64 - u).ln() / majorant;
+                if t >= end {
+                    return true;
+                }
+                let p = ray.point_at(t);
+                let sigma_t = self.sigma_t(&p);
+                // null collisions keep going, real ones stop the walk
+                if sampler::next_f64() < sigma_t / majorant {
+                    let absorb = Vec3::new(1f64 - self.albedo.x, 1f64 - self.albedo.y, 1f64 - self.albedo.z);
+                    let emitted = self.emission(&p);
+                    collision = Some(VolumeCollision {
+                        time: t,
+                        emission: Vec3::new(absorb.x * emitted.x, absorb.y * emitted.y, absorb.z * emitted.z),
+                        albedo: Vec3::new(self.albedo.x, self.albedo.y, self.albedo.z),
+                        g: self.g,
+                    });
+                    return false;
+                }
+            }
+        });
+        return collision;
+    }
+}
+
+fn split_coordinate(g: f64, n: usize) -> (usize, f64) {
+    if g <= 0f64 {
+        return (0, 0f64);
+    }
+    let base = g.floor();
+    let index = base as usize;
+    if index >= n - 1 {
+        return (n - 1, 0f64);
+    }
+    return (index, g - base);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    // a lumpy ball with a temperature channel, zero outside so sparse bricks lose nothing
+    fn ball(n: usize) -> DenseGrid {
+        return DenseGrid::from_fn(n, n, n, 2, |x, y, z| {
+            let r = ((x - 0.5f64).powi(2) + (y - 0.4f64).powi(2) + (z - 0.5f64).powi(2)).sqrt();
+            let density = (0.3f64 - r).max(0f64) * (1f64 + (9f64 * x).sin());
+            let temperature = if density > 0f64 { 1000f64 + 100f64 * z } else { 0f64 };
+            return vec![density as f32, temperature as f32];
+        });
+    }
+
+    fn bounds() -> Aabb {
+        return Aabb::new(Vec3::new(-1f64, 0.5f64, 2f64), Vec3::new(3f64, 2.5f64, 4.25f64));
+    }
+
+    fn assert_same_voxels(a: &VoxelGrid, b: &VoxelGrid) {
+        assert_eq!(a.resolution(), b.resolution());
+        assert_eq!(a.channels(), b.channels());
+        let (nx, ny, nz) = a.resolution();
+        for z in 0..nz {
+            for y in 0..ny {
+                for x in 0..nx {
+                    for channel in 0..a.channels() {
+                        assert_eq!(a.voxel(x, y, z, channel), b.voxel(x, y, z, channel));
+                    }
+                }
+            }
+        }
+    }
+
+    fn assert_same_bounds(a: &Aabb, b: &Aabb) {
+        assert_eq!((a.min.x, a.min.y, a.min.z), (b.min.x, b.min.y, b.min.z));
+        assert_eq!((a.max.x, a.max.y, a.max.z), (b.max.x, b.max.y, b.max.z));
+    }
+
+    fn temp_file(name: &str) -> ::std::path::PathBuf {
+        return env::temp_dir().join(format!("volume_test_{}_{}", ::std::process::id(), name));
+    }
+
+    #[test]
+    fn dense_grids_come_back_from_a_file_unchanged() {
+        let grid = ball(13);
+        let path = temp_file("dense.dgrd");
+        grid.save(&path, &bounds()).unwrap();
+        let (loaded, loaded_bounds) = load_grid(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_same_voxels(&grid, &*loaded);
+        assert_same_bounds(&bounds(), &loaded_bounds);
+    }
+
+    #[test]
+    fn sparse_grids_come_back_from_a_file_unchanged() {
+        let grid = ball(21);
+        let sparse = SparseBrickGrid::from_dense(&grid, 4);
+        // the corners of the grid are empty and don't get bricks
+        assert!(sparse.m_bricks.len() < 6 * 6 * 6);
+        assert_same_voxels(&grid, &sparse);
+        let path = temp_file("sparse.sbrk");
+        sparse.save(&path, &bounds()).unwrap();
+        let (loaded, loaded_bounds) = load_grid(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_same_voxels(&grid, &*loaded);
+        assert_same_bounds(&bounds(), &loaded_bounds);
+    }
+
+    #[test]
+    fn broken_grid_files_are_refused() {
+        let path = temp_file("broken.dgrd");
+        ball(4).save(&path, &bounds()).unwrap();
+        let mut bytes = fs::read(&path).unwrap();
+        // cut off in the middle of the voxels
+        bytes.truncate(bytes.len() - 10);
+        fs::write(&path, &bytes).unwrap();
+        assert!(load_grid(&path).is_err());
+        bytes[0] = b'X';
+        fs::write(&path, &bytes).unwrap();
+        assert!(load_grid(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn headers_asking_for_more_than_the_file_holds_are_refused() {
+        let path = temp_file("hostile.grid");
+        let huge = u32::MAX as usize;
+        // dense voxels, bricks too big to count, and too many bricks
+        for &(magic, n, brick_size, brick_count) in [(b"DGRD", huge, 0, 0), (b"SBRK", 4, huge, 1), (b"SBRK", 4, 4, huge)].iter() {
+            let mut bytes = magic.to_vec();
+            write_header(&mut bytes, n, n, n, 2, &bounds());
+            if magic == b"SBRK" {
+                push_u32(&mut bytes, brick_size as u32);
+                push_u32(&mut bytes, brick_count as u32);
+            }
+            fs::write(&path, &bytes).unwrap();
+            match load_grid(&path) {
+                Err(why) => assert_eq!(why.kind(), io::ErrorKind::InvalidData),
+                Ok(_) => panic!("loaded a grid from a header alone"),
+            };
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    // (start, end, majorant) of every cell the ray walks through
+    fn walk(volume: &HeterogeneousVolume, ray: &Ray) -> Vec<(f64, f64, f64)> {
+        let mut cells = Vec::new();
+        volume.walk_majorants(ray, 0f64, f64::MAX, |start, end, majorant| {
+            cells.push((start, end, majorant));
+            return true;
+        });
+        return cells;
+    }
+
+    #[test]
+    fn majorants_bound_the_density_when_cells_dont_divide_the_grid() {
+        // 13 voxels make a full cell of 8 and a last one of 5, the only density sits in voxel 6 right by the seam
+        let grid = DenseGrid::from_fn(13, 13, 13, 1, |x, y, z| {
+            let voxel = |c: f64| (c * 13f64) as usize;
+            return vec![if voxel(x) == 6 && voxel(y) == 6 && voxel(z) == 6 { 1f32 } else { 0f32 }];
+        });
+        // a unit per voxel
+        let volume = HeterogeneousVolume::new(
+            Box::new(grid),
+            Aabb::new(Vec3::new(0f64, 0f64, 0f64), Vec3::new(13f64, 13f64, 13f64)),
+            1f64,
+            Vec3::new(0.5f64, 0.5f64, 0.5f64),
+            0f64
+        );
+        let rays = [
+            Ray::new(Vec3::new(-1f64, 6.5f64, 6.5f64), Vec3::new(1f64, 0f64, 0f64)),
+            Ray::new(Vec3::new(14f64, 6.6f64, 6.4f64), Vec3::new(-1f64, 0f64, 0f64)),
+            Ray::new(Vec3::new(-1f64, -1f64, -1f64), Vec3::new(1f64, 1f64, 1f64)),
+            Ray::new(Vec3::new(6.8f64, 15f64, 6.3f64), Vec3::new(0.1f64, -2f64, 0.05f64)),
+        ];
+        for ray in rays.iter() {
+            let cells = walk(&volume, ray);
+            let (start, end) = volume.bounds.clip(ray, 0f64, f64::MAX).unwrap();
+            // the cells cover the whole way through the box without gaps
+            assert!((cells[0].0 - start).abs() < 1e-9f64);
+            assert!((cells[cells.len() - 1].1 - end).abs() < 1e-9f64);
+            for pair in cells.windows(2) {
+                assert!((pair[0].1 - pair[1].0).abs() < 1e-9f64);
+            }
+            for &(cell_start, cell_end, majorant) in cells.iter() {
+                for i in 0..50 {
+                    let t = cell_start + (cell_end - cell_start) * (i as f64 + 0.5f64) / 50f64;
+                    assert!(volume.sigma_t(&ray.point_at(t)) <= majorant, "density above the majorant at {}", t);
+                }
+            }
+        }
+    }
+}
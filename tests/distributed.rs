@@ -0,0 +1,137 @@
+use std::env;
+use std::fs;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::net::TcpListener;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process;
+use std::process::Child;
+use std::process::Command;
+use std::process::ExitStatus;
+use std::process::Stdio;
+use std::sync::mpsc;
+use std::sync::mpsc::Receiver;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+//
+// a coordinator and two workers on 127.0.0.1, the first worker is killed in the middle of a region
+//
+
+static WIDTH: usize = 128;
+static HEIGHT: usize = 64;
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("distributed_test_{}_{}", process::id(), name));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    return dir;
+}
+
+fn raytracer(dir: &Path, args: &[&str]) -> Child {
+    return Command::new(env!("CARGO_BIN_EXE_raytracer"))
+        .args(args)
+        .current_dir(dir)
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+}
+
+// the lines a process prints, as they come
+fn lines(child: &mut Child) -> Receiver<String> {
+    let (sender, receiver) = mpsc::channel();
+    let stdout = child.stdout.take().unwrap();
+    thread::spawn(move || {
+        for line in BufReader::new(stdout).lines() {
+            match line {
+                Ok(line) => if sender.send(line).is_err() { return; },
+                Err(_) => return,
+            }
+        }
+    });
+    return receiver;
+}
+
+// everything printed up to and including the first line containing text
+fn wait_for(lines: &Receiver<String>, text: &str) -> Vec<String> {
+    let mut seen = Vec::new();
+    loop {
+        match lines.recv_timeout(Duration::from_secs(120)) {
+            Ok(line) => {
+                let found = line.contains(text);
+                seen.push(line);
+                if found {
+                    return seen;
+                }
+            }
+            Err(_) => panic!("no line with {} after {:?}", text, seen),
+        }
+    }
+}
+
+fn wait(child: &mut Child) -> ExitStatus {
+    let start = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait().unwrap() {
+            return status;
+        }
+        if start.elapsed() > Duration::from_secs(600) {
+            let _ = child.kill();
+            panic!("the render never finished");
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[test]
+fn every_region_finishes_after_a_worker_dies() {
+    let coordinator_dir = temp_dir("coordinator");
+    let worker_dir = temp_dir("worker");
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port().to_string();
+    let address = format!("127.0.0.1:{}", port);
+
+    // 4 regions, each a few seconds of work for a debug build
+    let mut coordinator = raytracer(
+        &coordinator_dir,
+        &["--coordinator", &port, "--size", "128x64", "--spp", "32", "--seed", "1", "--stats-json", "stats.json"]
+    );
+    let coordinator_lines = lines(&mut coordinator);
+    wait_for(&coordinator_lines, "waiting for workers");
+
+    let mut doomed = raytracer(&worker_dir, &["--worker", &address]);
+    let doomed_lines = lines(&mut doomed);
+    wait_for(&doomed_lines, "joined the coordinator");
+    wait_for(&coordinator_lines, "joined");
+    // long enough to build the scene and start on a region, not to finish it
+    thread::sleep(Duration::from_millis(1500));
+    doomed.kill().unwrap();
+    doomed.wait().unwrap();
+    wait_for(&coordinator_lines, "lost worker");
+
+    let mut survivor = raytracer(&worker_dir, &["--worker", &address]);
+    let survivor_lines = lines(&mut survivor);
+    assert!(wait(&mut coordinator).success());
+    wait_for(&coordinator_lines, "write to file successful");
+    assert!(wait(&mut survivor).success());
+    wait_for(&survivor_lines, "the coordinator has everything it needs");
+
+    // every pixel came back, each half of the image is a region of its own
+    let image = fs::read_to_string(coordinator_dir.join("image.ppm")).unwrap();
+    let numbers: Vec<usize> = image.split_whitespace().skip(1).map(|n| n.parse().unwrap()).collect();
+    assert_eq!(&numbers[..3], &[WIDTH, HEIGHT, 255][..]);
+    let pixels = &numbers[3..];
+    assert_eq!(pixels.len(), WIDTH * HEIGHT * 3);
+    for &(x0, x1) in [(0usize, 64usize), (64usize, 128usize)].iter() {
+        let lit = (0..HEIGHT).any(|y| (x0..x1).any(|x| pixels[(y * WIDTH + x) * 3..(y * WIDTH + x) * 3 + 3].iter().any(|c| *c > 0)));
+        assert!(lit, "nothing rendered in columns {}..{}", x0, x1);
+    }
+
+    // the statistics file is the coordinator's alone
+    assert!(coordinator_dir.join("stats.json").exists());
+    assert!(!worker_dir.join("stats.json").exists());
+
+    let _ = fs::remove_dir_all(&coordinator_dir);
+    let _ = fs::remove_dir_all(&worker_dir);
+}